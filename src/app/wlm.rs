@@ -1,6 +1,5 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-#[path = "../core/mod.rs"]
-mod core;
+use localsend_core::core;
 
 use eframe::egui::{self, Color32, Rounding, Stroke, Vec2, RichText, Frame, Margin};
 use std::sync::{Arc, Mutex};
@@ -69,6 +68,19 @@ struct AppState {
     show_download_complete: bool,
     // 设置对话框
     show_settings: bool,
+    // 可选的广播网卡，None 表示全部
+    interfaces: Vec<core::NetworkInterface>,
+    selected_interface: Option<String>,
+    // 传输历史（旧的在前），启动时从磁盘加载
+    history: Vec<core::HistoryEntry>,
+    history_path: PathBuf,
+    show_history: bool,
+    // 当前接收的文件来自哪个 IP，完成时写入历史
+    incoming_peer: String,
+    // 不允许发送的文件扩展名（如 "key"、".pem"，不区分大小写），空表示不限制
+    blocked_extensions: Vec<String>,
+    // 发送时使用的传输预设
+    transfer_profile: core::TransferProfile,
     // 状态重置时间
     status_reset_time: Option<Instant>,
     // 速度计算
@@ -98,6 +110,14 @@ impl Default for AppState {
             last_received_file: None,
             show_download_complete: false,
             show_settings: false,
+            interfaces: Vec::new(),
+            selected_interface: None,
+            history: Vec::new(),
+            history_path: PathBuf::from("history.jsonl"),
+            show_history: false,
+            incoming_peer: String::new(),
+            blocked_extensions: Vec::new(),
+            transfer_profile: core::TransferProfile::default(),
             status_reset_time: None,
             transferred_bytes: 0,
             total_bytes: 0,
@@ -124,8 +144,11 @@ impl core::DiscoveryCallback for DesktopDiscoveryCallback {
     fn on_device_found(&self, device_info: core::DeviceInfo) {
         let mut state = self.state.lock().unwrap();
 
-        // 基于 IP 地址去重：同一 IP 只保留一个设备
-        if let Some(existing) = state.devices.iter_mut().find(|d| d.ip == device_info.ip) {
+        // 按设备 ID 或 IP 地址去重：多网卡设备从各个地址发来的通告合并为一个，
+        // 发送时连不上列表里的地址会自动改用它的其他地址
+        if let Some(existing) = state.devices.iter_mut()
+            .find(|d| d.device_id == device_info.device_id || d.has_ip(&device_info.ip))
+        {
             // 更新已有设备信息
             existing.name = device_info.name;
             existing.control_port = device_info.control_port;
@@ -145,11 +168,12 @@ struct DesktopTransferCallback {
 }
 
 impl core::TransferCallback for DesktopTransferCallback {
-    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> bool {
+    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> core::ReceiveDecision {
         let mut state = self.state.lock().unwrap();
         state.is_transferring = true;
         state.current_filename = file_name.clone();
         state.status_msg = format!("正在接收 {} 来自 {}", file_name, sender_ip);
+        state.incoming_peer = sender_ip;
         state.progress = 0.0;
         state.show_download_complete = false;
         // 初始化速度追踪
@@ -163,7 +187,7 @@ impl core::TransferCallback for DesktopTransferCallback {
         self.ctx.request_repaint();
 
         info!("自动接收文件: {}", file_name);
-        true
+        core::ReceiveDecision::accept()
     }
 
     fn on_progress(&self, transferred: u64, total: u64) {
@@ -205,6 +229,10 @@ impl core::TransferCallback for DesktopTransferCallback {
         }
         
         if success {
+            // 接收端回调的 msg 是最终落盘的文件名（重名时可能已被改名）
+            if !msg.is_empty() {
+                state.current_filename = msg;
+            }
             // 构建完整文件路径
             let file_path = std::path::Path::new(&state.save_dir)
                 .join(&state.current_filename)
@@ -217,6 +245,52 @@ impl core::TransferCallback for DesktopTransferCallback {
             state.status_msg = format!("✗ 传输失败: {}", msg);
         }
         state.status_reset_time = Some(Instant::now());
+
+        let entry = core::HistoryEntry::new(
+            core::TransferDirection::Received,
+            state.incoming_peer.clone(),
+            state.current_filename.clone(),
+            state.total_bytes,
+            success,
+        );
+        record_history(&mut state, entry);
+        self.ctx.request_repaint();
+    }
+}
+
+struct SenderCallback {
+    state: Arc<Mutex<AppState>>,
+    ctx: egui::Context,
+    // 写入历史用：目标 IP、文件名（多文件时为汇总描述）和总大小
+    peer: String,
+    file_name: String,
+    size: u64,
+}
+
+impl core::TransferCallback for SenderCallback {
+    fn on_receive_request(&self, _: String, _: u64, _: String) -> core::ReceiveDecision { core::ReceiveDecision::accept() }
+    fn on_progress(&self, transferred: u64, total: u64) {
+        let mut s = self.state.lock().unwrap();
+        if total > 0 {
+            s.progress = transferred as f32 / total as f32;
+        }
+        self.ctx.request_repaint();
+    }
+    fn on_complete(&self, success: bool, msg: String) {
+        let mut s = self.state.lock().unwrap();
+        s.is_transferring = false;
+        s.status_msg = if success { "✓ 发送成功".into() } else { format!("✗ 发送失败: {}", msg) };
+        s.progress = if success { 1.0 } else { 0.0 };
+        s.status_reset_time = Some(Instant::now());
+
+        let entry = core::HistoryEntry::new(
+            core::TransferDirection::Sent,
+            self.peer.clone(),
+            self.file_name.clone(),
+            self.size,
+            success,
+        );
+        record_history(&mut s, entry);
         self.ctx.request_repaint();
     }
 }
@@ -228,6 +302,7 @@ impl core::TransferCallback for DesktopTransferCallback {
 struct LocalSendApp {
     state: Arc<Mutex<AppState>>,
     theme: Theme,
+    discovery: Option<core::DiscoveryHandle>,
 }
 
 impl LocalSendApp {
@@ -264,6 +339,11 @@ impl LocalSendApp {
             s.my_name = device_name.clone();
             s.my_port = 4061;
             s.save_dir = save_dir.clone();
+            s.interfaces = core::list_interfaces();
+            s.history_path = dirs::data_dir()
+                .map(|d| d.join("localsend").join("history.jsonl"))
+                .unwrap_or_else(|| PathBuf::from("history.jsonl"));
+            s.history = core::load_history(&s.history_path);
         }
 
         let disc_cb = DesktopDiscoveryCallback {
@@ -276,24 +356,47 @@ impl LocalSendApp {
             ctx: cc.egui_ctx.clone(),
         };
 
-        core::start_listening(
+        let mut start_errors = Vec::new();
+        let discovery = match core::try_start_listening_with_config(
             4060,
             device_name.clone(),
             device_name.clone(),
-            Box::new(disc_cb)
-        );
+            Box::new(disc_cb),
+            // 回复 HERE 时公布下载目录的剩余空间，对方选择设备时可以提前看出放不下
+            core::DiscoveryConfig {
+                save_dir: Some(PathBuf::from(&save_dir)),
+                ..Default::default()
+            },
+        ) {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                start_errors.push(format!("设备发现启动失败: {}", e));
+                None
+            }
+        };
 
-        core::start_file_server(
+        // 下载目录里的同名文件不覆盖，自动改名
+        if let Err(e) = core::try_start_file_server_with_config(
             4061,
             save_dir,
-            Box::new(trans_cb)
-        );
+            Box::new(trans_cb),
+            core::ServerConfig {
+                conflict_policy: core::ConflictPolicy::Rename,
+                ..Default::default()
+            },
+        ) {
+            start_errors.push(format!("接收服务启动失败: {}", e));
+        }
+        if !start_errors.is_empty() {
+            state.lock().unwrap().status_msg = start_errors.join("；");
+        }
 
         core::send_discover_once(4060, device_name.clone(), device_name);
 
         Self { 
             state,
             theme: Theme::default(),
+            discovery,
         }
     }
 
@@ -304,39 +407,125 @@ impl LocalSendApp {
             .map(|f| f.to_string_lossy().to_string())
             .unwrap_or_default();
 
+        // 先校验选中的路径，避免把目录/特殊文件/禁止的类型交给 core
+        let (blocked, profile) = {
+            let s = state_ref.lock().unwrap();
+            (s.blocked_extensions.clone(), s.transfer_profile)
+        };
+        let size = match validate_send_path(&file_path, &blocked) {
+            Ok(size) => size,
+            Err(reason) => {
+                let mut s = state_ref.lock().unwrap();
+                s.is_transferring = false;
+                s.progress = 0.0;
+                s.status_msg = format!("✗ 无法发送 {}: {}", file_name, reason);
+                s.status_reset_time = Some(Instant::now());
+                return;
+            }
+        };
+
         {
             let mut s = state_ref.lock().unwrap();
             s.status_msg = format!("准备发送: {}", file_name);
-            s.current_filename = file_name;
+            s.current_filename = file_name.clone();
             s.is_transferring = true;
             s.progress = 0.0;
         }
 
-        struct SenderCallback {
-            state: Arc<Mutex<AppState>>,
-            ctx: egui::Context,
+        let cb = SenderCallback { state: state_ref, ctx, peer: target_ip.clone(), file_name, size };
+        core::send_file_with_options(target_ip, 4061, path_str, profile.send_options(), Box::new(cb));
+    }
+
+    fn send_files(&self, target_ip: String, file_paths: Vec<PathBuf>, ctx: egui::Context) {
+        if file_paths.len() == 1 {
+            return self.send_file(target_ip, file_paths[0].clone(), ctx);
         }
-        impl core::TransferCallback for SenderCallback {
-            fn on_receive_request(&self, _: String, _: u64, _: String) -> bool { true }
-            fn on_progress(&self, transferred: u64, total: u64) {
-                let mut s = self.state.lock().unwrap();
-                if total > 0 {
-                    s.progress = transferred as f32 / total as f32;
+
+        let state_ref = self.state.clone();
+        let (blocked, profile) = {
+            let s = state_ref.lock().unwrap();
+            (s.blocked_extensions.clone(), s.transfer_profile)
+        };
+        let mut paths = Vec::new();
+        let mut total_size = 0u64;
+        for file_path in &file_paths {
+            match validate_send_path(file_path, &blocked) {
+                Ok(size) => total_size += size,
+                Err(reason) => {
+                    let mut s = state_ref.lock().unwrap();
+                    s.status_msg = format!("✗ 无法发送 {}: {}", file_path.to_string_lossy(), reason);
+                    s.status_reset_time = Some(Instant::now());
+                    return;
                 }
-                self.ctx.request_repaint();
-            }
-            fn on_complete(&self, success: bool, msg: String) {
-                let mut s = self.state.lock().unwrap();
-                s.is_transferring = false;
-                s.status_msg = if success { "✓ 发送成功".into() } else { format!("✗ 发送失败: {}", msg) };
-                s.progress = if success { 1.0 } else { 0.0 };
-                s.status_reset_time = Some(Instant::now());
-                self.ctx.request_repaint();
             }
+            paths.push(file_path.to_string_lossy().to_string());
+        }
+
+        {
+            let mut s = state_ref.lock().unwrap();
+            s.status_msg = format!("准备发送 {} 个文件", paths.len());
+            s.current_filename = String::new();
+            s.is_transferring = true;
+            s.progress = 0.0;
+        }
+
+        // 整批只请求一次，对方不用逐个确认，文件在一条连接上依次发出
+        let options = core::SendOptions {
+            batch: true,
+            ..profile.send_options()
+        };
+        let cb = SenderCallback {
+            state: state_ref,
+            ctx,
+            peer: target_ip.clone(),
+            file_name: format!("{} 个文件", paths.len()),
+            size: total_size,
+        };
+        core::send_files(target_ip, 4061, paths, options, Box::new(cb));
+    }
+
+    /// 后台检测与设备之间两个方向能否连通，结果显示在状态栏
+    fn diagnose_device(&self, device: core::DeviceInfo, ctx: egui::Context) {
+        let state_ref = self.state.clone();
+        {
+            let mut s = state_ref.lock().unwrap();
+            s.status_msg = format!("正在诊断与 {} 的连接...", device.name);
+            s.status_reset_time = None;
         }
+        thread::spawn(move || {
+            let report = core::connectivity_check(&device, 4061);
+            let msg = match (report.outbound, report.inbound) {
+                (false, _) => format!("✗ 无法连接 {}，请检查对方是否在线或被防火墙拦截", device.name),
+                (true, Some(true)) => format!("✓ 与 {} 双向连通", device.name),
+                (true, Some(false)) => format!("⚠ 能连上 {}，但对方连不回本机，请检查本机防火墙", device.name),
+                (true, None) => format!("能连上 {}，对方不支持反向检测", device.name),
+            };
+            let mut s = state_ref.lock().unwrap();
+            s.status_msg = msg;
+            s.status_reset_time = Some(Instant::now());
+            ctx.request_repaint();
+        });
+    }
 
-        let cb = SenderCallback { state: state_ref, ctx };
-        core::send_file(target_ip, 4061, path_str, 4, Box::new(cb));
+    /// 后台在本机回环上跑一遍发现、握手、传输和校验，结果显示在状态栏
+    fn run_self_test(&self, ctx: egui::Context) {
+        let state_ref = self.state.clone();
+        {
+            let mut s = state_ref.lock().unwrap();
+            s.status_msg = "正在自检...".to_string();
+            s.status_reset_time = None;
+        }
+        thread::spawn(move || {
+            let report = core::self_test();
+            let msg = match report.stages.iter().find(|s| !s.passed) {
+                None => "✓ 自检通过".to_string(),
+                Some(failed) => format!("✗ 自检未通过: {}: {}", failed.stage, failed.detail),
+            };
+            let mut s = state_ref.lock().unwrap();
+            s.status_msg = msg;
+            s.status_reset_time = Some(Instant::now());
+            ctx.request_repaint();
+        });
     }
 
     fn send_file_with_picker(&self, target_ip: String, ctx: egui::Context) {
@@ -426,6 +615,7 @@ impl LocalSendApp {
         };
         
         let mut open_settings = false;
+        let mut open_history = false;
         let mut do_refresh = false;
         
         Frame::none()
@@ -456,6 +646,16 @@ impl LocalSendApp {
                             open_settings = true;
                         }
                         
+                        // 历史按钮
+                        let history_btn = ui.add(
+                            egui::Button::new(RichText::new("🕘").size(18.0).color(theme.text_secondary))
+                                .fill(Color32::TRANSPARENT)
+                                .stroke(Stroke::NONE)
+                        );
+                        if history_btn.clicked() {
+                            open_history = true;
+                        }
+                        
                         // 刷新按钮
                         let refresh_btn = ui.add(
                             egui::Button::new(RichText::new("⟳").size(18.0).color(theme.text_secondary))
@@ -473,10 +673,28 @@ impl LocalSendApp {
         if open_settings {
             self.state.lock().unwrap().show_settings = true;
         }
+        if open_history {
+            self.state.lock().unwrap().show_history = true;
+        }
         if do_refresh {
             let name = my_name.clone();
-            thread::spawn(move || {
-                core::send_discover_once(4060, name.clone(), name);
+            let discovery = self.discovery.clone();
+            let state = self.state.clone();
+            thread::spawn(move || match discovery {
+                // 通过句柄发送，遵循设置里选择的广播网卡；第一次广播可能丢失，没有回复时自动重发几次
+                Some(handle) => {
+                    let found = core::discover_devices(
+                        4060,
+                        name,
+                        &handle,
+                        Duration::from_secs(4),
+                        core::DiscoverRetry::default(),
+                    );
+                    if matches!(found, Ok(ref devices) if devices.is_empty()) {
+                        state.lock().unwrap().status_msg = "未发现设备，请确认对方已打开应用并在同一局域网".to_string();
+                    }
+                }
+                None => core::send_discover_once(4060, name.clone(), name),
             });
         }
     }
@@ -525,8 +743,24 @@ impl LocalSendApp {
                                 .size(12.0)
                                 .color(theme.accent));
                         }
+
+                        // 本机还有发送在进行时可以一次全部取消，各自回调“传输已取消”
+                        if core::active_transfer_count() > 0 {
+                            ui.add_space(4.0);
+                            let cancel_btn = ui.add(
+                                egui::Button::new(RichText::new("取消全部发送")
+                                    .size(12.0)
+                                    .color(theme.text_secondary))
+                                    .fill(Color32::TRANSPARENT)
+                                    .stroke(Stroke::new(1.0, theme.border))
+                                    .rounding(Rounding::same(6.0))
+                            );
+                            if cancel_btn.clicked() {
+                                core::cancel_all_transfers();
+                            }
+                        }
                     }
-                    
+
                     // 保存位置
                     ui.add_space(4.0);
                     ui.label(RichText::new(format!("📁 保存位置: {}", state.save_dir))
@@ -538,7 +772,8 @@ impl LocalSendApp {
 
     fn render_device_list(&self, ui: &mut egui::Ui, ctx: &egui::Context) {
         let theme = &self.theme;
-        let state = self.state.lock().unwrap();
+        // 复制一份设备列表后立即释放锁，卡片里的按钮还需要再次加锁
+        let devices = self.state.lock().unwrap().devices.clone();
         
         // 标题
         ui.horizontal(|ui| {
@@ -549,9 +784,23 @@ impl LocalSendApp {
                 .strong());
             
             ui.add_space(8.0);
-            ui.label(RichText::new(format!("({})", state.devices.len()))
+            ui.label(RichText::new(format!("({})", devices.len()))
                 .size(14.0)
                 .color(theme.text_muted));
+
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                ui.add_space(16.0);
+                let export_btn = ui.add(
+                    egui::Button::new(RichText::new("导出设备列表")
+                        .size(12.0)
+                        .color(theme.text_secondary))
+                        .fill(Color32::TRANSPARENT)
+                        .stroke(Stroke::NONE)
+                );
+                if export_btn.clicked() {
+                    self.export_devices();
+                }
+            });
         });
         
         ui.add_space(8.0);
@@ -560,7 +809,7 @@ impl LocalSendApp {
         egui::ScrollArea::vertical()
             .id_source("device_list")
             .show(ui, |ui| {
-                if state.devices.is_empty() {
+                if devices.is_empty() {
                     // 空状态
                     ui.vertical_centered(|ui| {
                         ui.add_space(40.0);
@@ -581,7 +830,7 @@ impl LocalSendApp {
                     });
                 } else {
                     // 设备卡片
-                    for device in &state.devices {
+                    for device in &devices {
                         self.render_device_card(ui, device, ctx.clone());
                         ui.add_space(8.0);
                     }
@@ -589,6 +838,19 @@ impl LocalSendApp {
             });
     }
 
+    // 把发现到的设备导出成文件，方便反馈问题时附上
+    fn export_devices(&self) {
+        let path = match rfd::FileDialog::new().set_file_name("devices.txt").save_file() {
+            Some(p) => p,
+            None => return,
+        };
+        let msg = match std::fs::write(&path, core::export_devices_snapshot()) {
+            Ok(()) => format!("✓ 设备列表已导出到 {}", path.display()),
+            Err(e) => format!("✗ 导出设备列表失败: {}", e),
+        };
+        self.state.lock().unwrap().status_msg = msg;
+    }
+
     fn render_device_card(&self, ui: &mut egui::Ui, device: &core::DeviceInfo, ctx: egui::Context) {
         let theme = &self.theme;
         
@@ -625,6 +887,12 @@ impl LocalSendApp {
                             .size(12.0)
                             .color(theme.text_muted)
                             .monospace());
+                        if let Some(last) = core::last_transfer(&device.ip) {
+                            let color = if last.success { theme.success } else { theme.text_muted };
+                            ui.label(RichText::new(last_transfer_label(&last))
+                                .size(11.0)
+                                .color(color));
+                        }
                     });
                     
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
@@ -638,50 +906,20 @@ impl LocalSendApp {
                         );
                         
                         if send_btn.clicked() {
-                            let ip = device.ip.clone();
-                            let ctx_clone = ctx.clone();
-                            // 使用文件选择器
-                            if let Some(file) = rfd::FileDialog::new().pick_file() {
-                                let state_ref = self.state.clone();
-                                let file_name = file.file_name()
-                                    .map(|f| f.to_string_lossy().to_string())
-                                    .unwrap_or_default();
-                                let path_str = file.to_string_lossy().to_string();
-                                
-                                {
-                                    let mut s = state_ref.lock().unwrap();
-                                    s.status_msg = format!("准备发送: {}", file_name);
-                                    s.current_filename = file_name;
-                                    s.is_transferring = true;
-                                    s.progress = 0.0;
-                                }
-
-                                struct SenderCallback {
-                                    state: Arc<Mutex<AppState>>,
-                                    ctx: egui::Context,
-                                }
-                                impl core::TransferCallback for SenderCallback {
-                                    fn on_receive_request(&self, _: String, _: u64, _: String) -> bool { true }
-                                    fn on_progress(&self, transferred: u64, total: u64) {
-                                        let mut s = self.state.lock().unwrap();
-                                        if total > 0 {
-                                            s.progress = transferred as f32 / total as f32;
-                                        }
-                                        self.ctx.request_repaint();
-                                    }
-                                    fn on_complete(&self, success: bool, msg: String) {
-                                        let mut s = self.state.lock().unwrap();
-                                        s.is_transferring = false;
-                                        s.status_msg = if success { "✓ 发送成功".into() } else { format!("✗ 发送失败: {}", msg) };
-                                        s.progress = if success { 1.0 } else { 0.0 };
-                                        s.status_reset_time = Some(Instant::now());
-                                        self.ctx.request_repaint();
-                                    }
-                                }
-
-                                let cb = SenderCallback { state: state_ref, ctx: ctx_clone };
-                                core::send_file(ip, 4061, path_str, 4, Box::new(cb));
-                            }
+                            // 使用文件选择器，取消时不改动任何状态
+                            self.send_file_with_picker(device.ip.clone(), ctx.clone());
+                        }
+
+                        let diagnose_btn = ui.add(
+                            egui::Button::new(RichText::new("诊断")
+                                .size(13.0)
+                                .color(theme.text_primary))
+                                .fill(theme.bg_tertiary)
+                                .rounding(Rounding::same(6.0))
+                                .min_size(Vec2::new(60.0, 32.0))
+                        );
+                        if diagnose_btn.clicked() {
+                            self.diagnose_device(device.clone(), ctx.clone());
                         }
                     });
                 });
@@ -726,6 +964,15 @@ impl LocalSendApp {
                 self.render_settings(ctx);
             }
         }
+        
+        // 传输历史
+        {
+            let state = self.state.lock().unwrap();
+            if state.show_history {
+                drop(state);
+                self.render_history(ctx);
+            }
+        }
     }
 
     fn render_drag_overlay(&self, ctx: &egui::Context) {
@@ -790,6 +1037,10 @@ impl LocalSendApp {
                 let devices = state.devices.clone();
                 let pending = state.pending_files.clone();
                 drop(state);
+                let pending_size: u64 = pending.iter()
+                    .filter_map(|p| std::fs::metadata(p).ok())
+                    .map(|m| m.len())
+                    .sum();
                 
                 ui.label(RichText::new(format!("即将发送 {} 个文件", pending_count))
                     .size(14.0)
@@ -803,8 +1054,17 @@ impl LocalSendApp {
                         .color(Color32::from_rgb(255, 180, 100)));
                 } else {
                     for device in &devices {
-                        let btn = ui.add(
-                            egui::Button::new(RichText::new(format!("📱 {} ({})", device.name, device.ip))
+                        // 对方公布了剩余空间且放不下这些文件时不让选
+                        let no_room = device.free_space.is_some_and(|free| free < pending_size);
+                        let label = if no_room {
+                            format!("📱 {} ({}) · 空间不足，剩余 {}", device.name, device.ip,
+                                format_bytes(device.free_space.unwrap_or(0)))
+                        } else {
+                            format!("📱 {} ({})", device.name, device.ip)
+                        };
+                        let btn = ui.add_enabled(
+                            !no_room,
+                            egui::Button::new(RichText::new(label)
                                 .size(14.0)
                                 .color(theme.text_primary))
                                 .fill(theme.bg_tertiary)
@@ -817,9 +1077,7 @@ impl LocalSendApp {
                             let ctx_clone = ctx.clone();
                             
                             // 发送所有待发送文件
-                            for file_path in &pending {
-                                self.send_file(ip.clone(), file_path.clone(), ctx_clone.clone());
-                            }
+                            self.send_files(ip, pending.clone(), ctx_clone);
                             
                             let mut state = self.state.lock().unwrap();
                             state.show_device_picker = false;
@@ -982,6 +1240,9 @@ impl LocalSendApp {
                 
                 let state = self.state.lock().unwrap();
                 let current_save_dir = state.save_dir.clone();
+                let interfaces = state.interfaces.clone();
+                let mut selected_interface = state.selected_interface.clone();
+                let mut transfer_profile = state.transfer_profile;
                 drop(state);
                 
                 ui.label(RichText::new("保存位置")
@@ -1020,15 +1281,82 @@ impl LocalSendApp {
                     if choose_btn.clicked() {
                         if let Some(folder) = rfd::FileDialog::new().pick_folder() {
                             let new_path = folder.to_string_lossy().to_string();
+                            if let Some(handle) = &self.discovery {
+                                handle.set_save_dir(Some(folder));
+                            }
                             let mut state = self.state.lock().unwrap();
                             state.save_dir = new_path;
                         }
                     }
                 });
                 
+                ui.add_space(16.0);
+                
+                ui.label(RichText::new("广播网卡")
+                    .size(14.0)
+                    .color(theme.text_primary)
+                    .strong());
+                
+                ui.add_space(8.0);
+                
+                let selected_text = interface_label(&interfaces, selected_interface.as_deref());
+                let previous = selected_interface.clone();
+                egui::ComboBox::from_id_source("broadcast_interface")
+                    .width(300.0)
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut selected_interface, None, "全部网卡");
+                        for iface in &interfaces {
+                            ui.selectable_value(
+                                &mut selected_interface,
+                                Some(iface.name.clone()),
+                                interface_label(&interfaces, Some(&iface.name)),
+                            );
+                        }
+                    });
+                
+                if selected_interface != previous {
+                    if let Some(handle) = &self.discovery {
+                        handle.set_interface(selected_interface.clone());
+                    }
+                    self.state.lock().unwrap().selected_interface = selected_interface;
+                }
+                
+                ui.add_space(16.0);
+                
+                ui.label(RichText::new("传输预设")
+                    .size(14.0)
+                    .color(theme.text_primary)
+                    .strong());
+                
+                ui.add_space(8.0);
+                
+                egui::ComboBox::from_id_source("transfer_profile")
+                    .width(300.0)
+                    .selected_text(transfer_profile.label())
+                    .show_ui(ui, |ui| {
+                        for profile in core::TransferProfile::ALL {
+                            ui.selectable_value(&mut transfer_profile, profile, profile.label());
+                        }
+                    });
+                self.state.lock().unwrap().transfer_profile = transfer_profile;
+                
                 ui.add_space(20.0);
                 
                 ui.horizontal(|ui| {
+                    let self_test_btn = ui.add(
+                        egui::Button::new(RichText::new("自检")
+                            .size(13.0)
+                            .color(theme.text_primary))
+                            .fill(theme.bg_tertiary)
+                            .rounding(Rounding::same(6.0))
+                            .min_size(Vec2::new(70.0, 32.0))
+                    );
+                    
+                    if self_test_btn.clicked() {
+                        self.run_self_test(ctx.clone());
+                    }
+                    
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                         let close_btn = ui.add(
                             egui::Button::new(RichText::new("完成")
@@ -1047,6 +1375,86 @@ impl LocalSendApp {
                 });
             });
     }
+
+    fn render_history(&self, ctx: &egui::Context) {
+        let theme = &self.theme;
+        
+        egui::Window::new("传输历史")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .frame(Frame::none()
+                .fill(theme.bg_secondary)
+                .rounding(Rounding::same(12.0))
+                .stroke(Stroke::new(1.0, theme.border))
+                .inner_margin(Margin::same(20.0)))
+            .show(ctx, |ui| {
+                ui.set_min_width(420.0);
+                
+                let history = self.state.lock().unwrap().history.clone();
+                
+                if history.is_empty() {
+                    ui.label(RichText::new("暂无记录")
+                        .size(13.0)
+                        .color(theme.text_secondary));
+                } else {
+                    egui::ScrollArea::vertical().max_height(320.0).show(ui, |ui| {
+                        // 新的在上
+                        for entry in history.iter().rev() {
+                            let arrow = match entry.direction {
+                                core::TransferDirection::Sent => "↑",
+                                core::TransferDirection::Received => "↓",
+                            };
+                            let color = if entry.success { theme.text_primary } else { theme.text_muted };
+                            ui.horizontal(|ui| {
+                                ui.label(RichText::new(format!("{} {}", arrow, entry.file_name))
+                                    .size(13.0)
+                                    .color(color));
+                                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                                    ui.label(RichText::new(format!("{} · {}", entry.peer, format_bytes(entry.size)))
+                                        .size(11.0)
+                                        .color(theme.text_secondary));
+                                });
+                            });
+                        }
+                    });
+                }
+                
+                ui.add_space(20.0);
+                
+                ui.horizontal(|ui| {
+                    let clear_btn = ui.add(
+                        egui::Button::new(RichText::new("清空")
+                            .size(13.0)
+                            .color(theme.text_primary))
+                            .fill(theme.bg_tertiary)
+                            .rounding(Rounding::same(6.0))
+                            .min_size(Vec2::new(70.0, 32.0))
+                    );
+                    if clear_btn.clicked() {
+                        let mut state = self.state.lock().unwrap();
+                        if let Err(e) = core::clear_history(&state.history_path) {
+                            error!("清空历史失败: {:?}", e);
+                        }
+                        state.history.clear();
+                    }
+                    
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        let close_btn = ui.add(
+                            egui::Button::new(RichText::new("关闭")
+                                .size(13.0)
+                                .color(theme.bg_primary))
+                                .fill(theme.accent)
+                                .rounding(Rounding::same(6.0))
+                                .min_size(Vec2::new(80.0, 32.0))
+                        );
+                        if close_btn.clicked() {
+                            self.state.lock().unwrap().show_history = false;
+                        }
+                    });
+                });
+            });
+    }
 }
 
 impl eframe::App for LocalSendApp {
@@ -1072,6 +1480,8 @@ fn main() -> eframe::Result<()> {
 
 fn configure_fonts(ctx: &egui::Context) {
     let mut fonts = egui::FontDefinitions::default();
+    // 实际加载成功的字体，按优先级排列
+    let mut loaded = Vec::new();
 
     // 加载中文字体
     let chinese_font_path = "C:\\Windows\\Fonts\\simhei.ttf";
@@ -1080,6 +1490,7 @@ fn configure_fonts(ctx: &egui::Context) {
             "chinese_font".to_owned(),
             egui::FontData::from_owned(bytes),
         );
+        loaded.push("chinese_font".to_owned());
         info!("中文字体加载成功: {}", chinese_font_path);
     } else {
         error!("加载中文字体失败: {}", chinese_font_path);
@@ -1092,25 +1503,37 @@ fn configure_fonts(ctx: &egui::Context) {
             "emoji_font".to_owned(),
             egui::FontData::from_owned(bytes),
         );
+        loaded.push("emoji_font".to_owned());
         info!("Emoji 字体加载成功: {}", emoji_font_path);
     } else {
         error!("加载 Emoji 字体失败: {}", emoji_font_path);
     }
 
     // 设置字体优先级：中文字体 -> Emoji 字体 -> 默认字体
-    if let Some(family) = fonts.families.get_mut(&egui::FontFamily::Proportional) {
-        family.insert(0, "emoji_font".to_owned());
-        family.insert(0, "chinese_font".to_owned());
-    }
-
-    if let Some(family) = fonts.families.get_mut(&egui::FontFamily::Monospace) {
-        family.insert(0, "emoji_font".to_owned());
-        family.insert(0, "chinese_font".to_owned());
-    }
+    install_font_priority(&mut fonts, &loaded);
 
     ctx.set_fonts(fonts);
 }
 
+/// 把已加载的字体放到 Proportional 和 Monospace 的最前面。
+/// 字体族不存在时（定制的 egui 或以后的版本）先创建，保证字体一定生效；
+/// 只插入真正加载了数据的字体，引用不存在的字体会让 egui 在渲染时 panic
+fn install_font_priority(fonts: &mut egui::FontDefinitions, loaded: &[String]) {
+    if loaded.is_empty() {
+        return;
+    }
+    for family in [egui::FontFamily::Proportional, egui::FontFamily::Monospace] {
+        let list = fonts.families.entry(family.clone()).or_insert_with(|| {
+            info!("字体族 {:?} 不存在，已创建", family);
+            Vec::new()
+        });
+        for (i, name) in loaded.iter().enumerate() {
+            list.insert(i, name.clone());
+        }
+        info!("字体族 {:?} 已加入字体: {:?}", family, loaded);
+    }
+}
+
 fn configure_theme(ctx: &egui::Context) {
     let mut visuals = egui::Visuals::dark();
     
@@ -1131,6 +1554,22 @@ fn configure_theme(ctx: &egui::Context) {
     ctx.set_visuals(visuals);
 }
 
+/// 校验待发送路径：必须是可读的普通文件（符号链接会被解析），扩展名不在 blocked 里
+fn validate_send_path(path: &std::path::Path, blocked: &[String]) -> Result<u64, String> {
+    if let Some(ext) = path.extension().map(|e| e.to_string_lossy().to_lowercase())
+        && blocked.iter().any(|b| b.trim_start_matches('.').to_lowercase() == ext)
+    {
+        return Err(format!("不允许发送 .{} 类型的文件", ext));
+    }
+    // fs::metadata 会跟随符号链接，拿到的是目标的信息
+    let meta = std::fs::metadata(path).map_err(|e| format!("无法读取文件信息 ({})", e))?;
+    if !meta.is_file() {
+        return Err(if meta.is_dir() { "选中的是文件夹".into() } else { "不是普通文件".into() });
+    }
+    std::fs::File::open(path).map_err(|e| format!("文件不可读 ({})", e))?;
+    Ok(meta.len())
+}
+
 /// 格式化速度为人类可读的字符串
 fn format_speed(bytes_per_sec: f64) -> String {
     if bytes_per_sec >= 1_000_000_000.0 {
@@ -1155,4 +1594,87 @@ fn format_bytes(bytes: u64) -> String {
     } else {
         format!("{} B", bytes)
     }
-}
\ No newline at end of file
+}
+/// 设备卡片上的最近一次传输，如 "↑ 发送成功 · 3 分钟前"
+fn last_transfer_label(last: &core::LastTransfer) -> String {
+    let action = match last.direction {
+        core::TransferDirection::Sent => "↑ 发送",
+        core::TransferDirection::Received => "↓ 接收",
+    };
+    let result = if last.success { "成功" } else { "失败" };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let ago = match now.saturating_sub(last.timestamp) {
+        s if s < 60 => "刚刚".to_string(),
+        s if s < 3600 => format!("{} 分钟前", s / 60),
+        s if s < 86400 => format!("{} 小时前", s / 3600),
+        s => format!("{} 天前", s / 86400),
+    };
+    format!("{}{} · {}", action, result, ago)
+}
+
+/// 广播网卡下拉框里显示的文字：网卡名 + 网段
+fn interface_label(interfaces: &[core::NetworkInterface], selected: Option<&str>) -> String {
+    match selected {
+        None => "全部网卡".to_string(),
+        Some(name) => match interfaces.iter().find(|i| i.name == name) {
+            Some(iface) => format!("{} ({})", iface.name, iface.subnet()),
+            None => format!("{} (不可用)", name),
+        },
+    }
+}
+
+/// 追加一条传输记录到内存和磁盘，两边都只保留最近的记录
+fn record_history(state: &mut AppState, entry: core::HistoryEntry) {
+    if let Err(e) = core::append_history(&state.history_path, &entry, core::DEFAULT_HISTORY_LIMIT) {
+        error!("写入传输历史失败: {:?}", e);
+    }
+    state.history.push(entry);
+    let excess = state.history.len().saturating_sub(core::DEFAULT_HISTORY_LIMIT);
+    state.history.drain(..excess);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loaded_fonts_lead_both_families_even_when_missing() {
+        let mut fonts = egui::FontDefinitions::default();
+        fonts.families.remove(&egui::FontFamily::Monospace);
+        let loaded = vec!["chinese_font".to_owned(), "emoji_font".to_owned()];
+
+        install_font_priority(&mut fonts, &loaded);
+
+        for family in [egui::FontFamily::Proportional, egui::FontFamily::Monospace] {
+            assert_eq!(fonts.families[&family][..2], loaded[..], "{:?}", family);
+        }
+    }
+
+    #[test]
+    fn nothing_is_registered_when_no_font_loaded() {
+        let mut fonts = egui::FontDefinitions::default();
+        let before = fonts.families.clone();
+        install_font_priority(&mut fonts, &[]);
+        assert_eq!(fonts.families, before);
+    }
+
+    #[test]
+    fn disallowed_extension_is_rejected_before_sending() {
+        let dir = std::env::temp_dir().join(format!("locsd-wlm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("server.KEY");
+        let txt = dir.join("notes.txt");
+        std::fs::write(&key, b"secret").unwrap();
+        std::fs::write(&txt, b"hello").unwrap();
+        let blocked = vec![".key".to_string()];
+
+        assert_eq!(validate_send_path(&key, &blocked), Err("不允许发送 .key 类型的文件".to_string()));
+        assert_eq!(validate_send_path(&txt, &blocked), Ok(5));
+        assert_eq!(validate_send_path(&key, &[]), Ok(6));
+        assert_eq!(validate_send_path(&dir, &blocked), Err("选中的是文件夹".to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
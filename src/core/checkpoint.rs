@@ -0,0 +1,138 @@
+//! 分片内的校验检查点：发送方每发出 interval 字节，就在数据流里插入一次从分片开头算起的
+//! 累计 SHA-256（32 字节），接收方边收边算、逐个比对，第一个对不上的检查点就中止这条连接，
+//! 不必等到整个文件收完才发现白传了。最后不足 interval 的一段没有检查点。
+
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+use super::TransferError;
+
+const DIGEST_LEN: usize = 32;
+
+/// 检查点间隔的下限，太密的话摘要本身就成了可观的额外流量
+pub const MIN_CHECKPOINT_INTERVAL: u64 = 64 * 1024;
+
+/// 在写出的数据里按间隔插入检查点，interval 为 None 时原样写出
+pub(super) struct CheckpointWriter<W> {
+    inner: W,
+    interval: Option<u64>,
+    hasher: Sha256,
+    // 距上一个检查点已写出的数据字节数
+    since: u64,
+}
+
+impl<W: Write> CheckpointWriter<W> {
+    pub(super) fn new(inner: W, interval: Option<u64>) -> Self {
+        Self { inner, interval, hasher: Sha256::new(), since: 0 }
+    }
+}
+
+impl<W: Write> Write for CheckpointWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(interval) = self.interval else {
+            return self.inner.write(buf);
+        };
+        let take = buf.len().min((interval - self.since) as usize);
+        self.inner.write_all(&buf[..take])?;
+        self.hasher.update(&buf[..take]);
+        self.since += take as u64;
+        if self.since == interval {
+            self.inner.write_all(&self.hasher.clone().finalize())?;
+            self.since = 0;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 读出数据并核对其中的检查点，读到的只有数据本身。检查点对不上时返回包着
+/// TransferError::ChecksumMismatch 的错误；interval 为 None 时原样读取
+pub(super) struct CheckpointReader<R> {
+    inner: R,
+    interval: Option<u64>,
+    hasher: Sha256,
+    since: u64,
+}
+
+impl<R: Read> CheckpointReader<R> {
+    pub(super) fn new(inner: R, interval: Option<u64>) -> Self {
+        Self { inner, interval, hasher: Sha256::new(), since: 0 }
+    }
+}
+
+impl<R: Read> Read for CheckpointReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(interval) = self.interval else {
+            return self.inner.read(buf);
+        };
+        let want = buf.len().min((interval - self.since) as usize);
+        let n = self.inner.read(&mut buf[..want])?;
+        self.hasher.update(&buf[..n]);
+        self.since += n as u64;
+        if self.since == interval {
+            let mut digest = [0u8; DIGEST_LEN];
+            self.inner.read_exact(&mut digest)?;
+            if digest[..] != self.hasher.clone().finalize()[..] {
+                return Err(io::Error::other(TransferError::ChecksumMismatch));
+            }
+            self.since = 0;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: u64 = MIN_CHECKPOINT_INTERVAL;
+
+    fn with_checkpoints(data: &[u8]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        CheckpointWriter::new(&mut stream, Some(INTERVAL)).write_all(data).unwrap();
+        stream
+    }
+
+    #[test]
+    fn checkpoints_are_interleaved_and_stripped_again() {
+        let data: Vec<u8> = (0..INTERVAL * 5 / 2).map(|i| i as u8).collect();
+        let stream = with_checkpoints(&data);
+        // 两个完整间隔各带一个检查点，最后半段没有
+        assert_eq!(stream.len(), data.len() + 2 * DIGEST_LEN);
+
+        let mut read = Vec::new();
+        CheckpointReader::new(&stream[..], Some(INTERVAL)).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn mismatch_stops_at_the_first_bad_checkpoint() {
+        let data = vec![9u8; (INTERVAL * 3) as usize];
+        let mut stream = with_checkpoints(&data);
+        stream[INTERVAL as usize + DIGEST_LEN + 1] ^= 1;
+
+        let mut remaining = &stream[..];
+        let mut read = Vec::new();
+        let err = CheckpointReader::new(&mut remaining, Some(INTERVAL)).read_to_end(&mut read).unwrap_err();
+
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<TransferError>()),
+            Some(TransferError::ChecksumMismatch)
+        ));
+        assert_eq!(remaining.len() as u64, INTERVAL + DIGEST_LEN as u64);
+    }
+
+    #[test]
+    fn no_interval_passes_data_through() {
+        let mut stream = Vec::new();
+        CheckpointWriter::new(&mut stream, None).write_all(b"plain").unwrap();
+        assert_eq!(stream, b"plain");
+        let mut read = String::new();
+        CheckpointReader::new(&stream[..], None).read_to_string(&mut read).unwrap();
+        assert_eq!(read, "plain");
+    }
+}
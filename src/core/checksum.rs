@@ -0,0 +1,102 @@
+//! 接收端的外部校验：用户事先从别的渠道拿到了文件的 SHA-256，按文件名登记后，
+//! 对应文件收完时在本地重新计算并比对，不依赖发送方提供的任何信息。
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+use super::lock;
+use super::pairing::from_hex;
+
+/// 按文件名登记的预期 SHA-256，放进 ServerConfig::expected_checksums 后生效，
+/// 服务运行期间可以随时增删
+#[derive(Debug, Default)]
+pub struct ExpectedChecksums {
+    entries: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl ExpectedChecksums {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记 file_name（发送方请求的文件名，目录传输时是带目录的相对路径）的预期 SHA-256，
+    /// sha256_hex 为 64 位十六进制，不区分大小写。同名的旧值会被替换
+    pub fn insert(&self, file_name: &str, sha256_hex: &str) -> Result<(), String> {
+        let digest: [u8; 32] = from_hex(sha256_hex.trim())
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("无效的 SHA-256: {}", sha256_hex))?;
+        lock(&self.entries).insert(file_name.to_string(), digest);
+        Ok(())
+    }
+
+    /// 取消登记，返回之前是否登记过
+    pub fn remove(&self, file_name: &str) -> bool {
+        lock(&self.entries).remove(file_name).is_some()
+    }
+
+    pub(crate) fn get(&self, file_name: &str) -> Option<[u8; 32]> {
+        lock(&self.entries).get(file_name).copied()
+    }
+}
+
+// 计算磁盘上文件的 SHA-256
+pub(crate) fn file_sha256(path: &Path) -> io::Result<[u8; 32]> {
+    sha256_of(File::open(path)?).map(|(digest, _)| digest)
+}
+
+// 计算文件前 len 字节的 SHA-256，文件不足 len 字节时出错
+pub(crate) fn prefix_sha256(path: &Path, len: u64) -> io::Result<[u8; 32]> {
+    match sha256_of(File::open(path)?.take(len))? {
+        (digest, read) if read == len => Ok(digest),
+        (_, read) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("只有 {} 字节，不足 {}", read, len))),
+    }
+}
+
+// 读完 reader，返回 SHA-256 和读到的字节数
+fn sha256_of(mut reader: impl Read) -> io::Result<([u8; 32], u64)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut read = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    Ok((hasher.finalize().into(), read))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::temp_dir;
+
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn registered_digest_matches_the_file_on_disk() {
+        let path = temp_dir("sha256").join("abc.txt");
+        std::fs::write(&path, b"abc").unwrap();
+        let checksums = ExpectedChecksums::new();
+
+        checksums.insert("abc.txt", &format!(" {} ", ABC.to_uppercase())).unwrap();
+        assert_eq!(checksums.get("abc.txt"), Some(file_sha256(&path).unwrap()));
+        assert!(checksums.remove("abc.txt"));
+        assert!(!checksums.remove("abc.txt"));
+    }
+
+    #[test]
+    fn malformed_digest_is_refused() {
+        let checksums = ExpectedChecksums::new();
+        assert!(checksums.insert("a", "xyz").is_err());
+        assert!(checksums.insert("a", &ABC[..62]).is_err());
+        assert_eq!(checksums.get("a"), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! 连通性诊断：本机能连上对方不代表对方能连回本机（常见于只有一侧开了防火墙），
+//! 这时发现和传输只在一个方向上正常，另一个方向悄无声息地失败。
+//! 诊断时先测本机到对方传输端口的连接，再通过 PING 请对方反向连一次本机。
+
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use super::{read_header_line, write_auth, DeviceInfo};
+
+// 单个方向的连接超时
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 与某台设备之间两个方向的可达情况
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectivityReport {
+    /// 本机能否连上对方的传输端口
+    pub outbound: bool,
+    /// 对方能否连回本机的传输端口；None 表示没能问到（连不上对方，或对方版本不支持）
+    pub inbound: Option<bool>,
+}
+
+impl ConnectivityReport {
+    /// 只有本机到对方这一个方向可达
+    pub fn is_asymmetric(&self) -> bool {
+        self.outbound && self.inbound == Some(false)
+    }
+}
+
+/// 诊断与 peer 之间的连通性。port 是双方传输服务的端口，对方会反向连接本机的同一端口，
+/// 因此本机的文件服务应当已经启动。会阻塞到两个方向都测完（最多约 9 秒）
+pub fn connectivity_check(peer: &DeviceInfo, port: u16) -> ConnectivityReport {
+    let unreachable = ConnectivityReport { outbound: false, inbound: None };
+    let ip: IpAddr = match peer.ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            warn!("Core: 诊断的目标地址无效: {}", peer.ip);
+            return unreachable;
+        }
+    };
+    let mut stream = match TcpStream::connect_timeout(&SocketAddr::new(ip, port), CHECK_TIMEOUT) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Core: 诊断时无法连接 {}:{}: {:?}", ip, port, e);
+            return unreachable;
+        }
+    };
+
+    // 对方反向连接也要时间，读超时留出余量
+    stream.set_read_timeout(Some(CHECK_TIMEOUT * 2)).ok();
+    let request = format!("PING|{}\n", port);
+    let inbound = write_auth(&mut stream)
+        .and_then(|_| stream.write_all(request.as_bytes()))
+        .ok()
+        .and_then(|_| read_header_line(&mut stream))
+        .and_then(|reply| match reply.as_str() {
+            "PONG|ok" => Some(true),
+            "PONG|fail" => Some(false),
+            _ => None,
+        });
+
+    let report = ConnectivityReport { outbound: true, inbound };
+    info!("Core: {} 的连通性诊断结果: {:?}", peer.name, report);
+    report
+}
+
+// 处理 PING|port：尝试连接对方的 port 后立即断开，回 PONG|ok 或 PONG|fail
+pub(crate) fn answer_ping<W: Write>(socket: &mut W, peer: &str, port: u16) {
+    let reachable = match peer.parse::<IpAddr>() {
+        Ok(ip) => TcpStream::connect_timeout(&SocketAddr::new(ip, port), CHECK_TIMEOUT).is_ok(),
+        // serve_connection 的连接不知道对方地址，无从反向连接
+        Err(_) => false,
+    };
+    info!("Core: 反向连接 {}:{} {}", peer, port, if reachable { "成功" } else { "失败" });
+    let reply: &[u8] = if reachable { b"PONG|ok\n" } else { b"PONG|fail\n" };
+    let _ = socket.write_all(reply);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn peer() -> DeviceInfo {
+        DeviceInfo { device_id: "stub".into(), name: "stub".into(), ip: "127.0.0.1".into(), control_port: 0, free_space: None }
+    }
+
+    // 对 PING 固定回复 reply 的对方
+    fn stub_peer(reply: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, format!("PING|{}\n", port));
+            reader.get_mut().write_all(reply.as_bytes()).unwrap();
+        });
+        port
+    }
+
+    #[test]
+    fn one_way_connectivity_is_reported_as_asymmetric() {
+        let report = connectivity_check(&peer(), stub_peer("PONG|fail\n"));
+        assert_eq!(report, ConnectivityReport { outbound: true, inbound: Some(false) });
+        assert!(report.is_asymmetric());
+
+        let report = connectivity_check(&peer(), stub_peer("PONG|ok\n"));
+        assert!(!report.is_asymmetric() && report.inbound == Some(true));
+        // 不认识 PING 的旧版本
+        assert_eq!(connectivity_check(&peer(), stub_peer("ERR|UnknownType\n")).inbound, None);
+    }
+
+    #[test]
+    fn unreachable_peer_is_not_asked_to_connect_back() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert_eq!(connectivity_check(&peer(), closed), ConnectivityReport { outbound: false, inbound: None });
+    }
+
+    #[test]
+    fn ping_connects_back_to_the_requested_port() {
+        let open = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut reply = Vec::new();
+        answer_ping(&mut reply, "127.0.0.1", open.local_addr().unwrap().port());
+        assert_eq!(reply, b"PONG|ok\n");
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut reply = Vec::new();
+        answer_ping(&mut reply, "127.0.0.1", closed);
+        assert_eq!(reply, b"PONG|fail\n");
+    }
+}
@@ -0,0 +1,153 @@
+//! 目录发送时的遍历。目录里的符号链接可能指向目录树之外，也可能指回上层目录形成环，
+//! 按 SymlinkPolicy 决定是否跟随；跟随时记录当前路径上的各级目录，指回其中之一的链接跳过。
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+/// 目录发送时遇到符号链接的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// 跳过所有符号链接
+    #[default]
+    Skip,
+    /// 跟随符号链接，指回上层目录的链接会被跳过，不会死循环
+    Follow,
+    /// 只跟随解析后仍在发送目录之内的符号链接
+    FollowWithinRoot,
+}
+
+// 遍历目录的结果，相对路径都以目录名开头、用 '/' 分隔，同一目录内按名字排序
+#[derive(Default)]
+pub(crate) struct DirEntries {
+    // (相对路径, 实际路径)
+    pub(crate) files: Vec<(String, PathBuf)>,
+    // 不含任何文件和子目录的空目录，没有数据会落到里面，需要单独告诉对方创建
+    pub(crate) empty_dirs: Vec<String>,
+}
+
+// 遍历 root 下的所有普通文件和空目录
+pub(crate) fn collect_files(root: &Path, policy: SymlinkPolicy) -> io::Result<DirEntries> {
+    let root_name = root.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "无效的目录路径"))?;
+    let canonical_root = fs::canonicalize(root)?;
+    if !canonical_root.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "不是目录"));
+    }
+
+    let mut entries = DirEntries::default();
+    let mut ancestors = vec![canonical_root.clone()];
+    walk(root, &root_name, &canonical_root, policy, &mut ancestors, &mut entries)?;
+    Ok(entries)
+}
+
+fn walk(
+    dir: &Path,
+    rel: &str,
+    root: &Path,
+    policy: SymlinkPolicy,
+    ancestors: &mut Vec<PathBuf>,
+    out: &mut DirEntries,
+) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.filter_map(Result::ok).collect();
+    entries.sort_by_key(|e| e.file_name());
+    let found = out.files.len() + out.empty_dirs.len();
+
+    for entry in entries {
+        let path = entry.path();
+        let rel_path = format!("{}/{}", rel, entry.file_name().to_string_lossy());
+        let is_link = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+        if is_link && !follow_link(&path, root, policy) {
+            debug!("Core: 按策略 {:?} 跳过符号链接 {:?}", policy, path);
+            continue;
+        }
+
+        // metadata 会解析符号链接，断开的链接在这里失败
+        let meta = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Core: 跳过无法读取的 {:?}: {:?}", path, e);
+                continue;
+            }
+        };
+        if meta.is_dir() {
+            let canonical = fs::canonicalize(&path)?;
+            if ancestors.contains(&canonical) {
+                warn!("Core: {:?} 指回上层目录，跳过以免死循环", path);
+                continue;
+            }
+            ancestors.push(canonical);
+            walk(&path, &rel_path, root, policy, ancestors, out)?;
+            ancestors.pop();
+        } else if meta.is_file() {
+            out.files.push((rel_path, path));
+        }
+    }
+    // 里面的东西都被跳过了也算空目录，保留目录本身
+    if out.files.len() + out.empty_dirs.len() == found {
+        out.empty_dirs.push(rel.to_string());
+    }
+    Ok(())
+}
+
+fn follow_link(path: &Path, root: &Path, policy: SymlinkPolicy) -> bool {
+    match policy {
+        SymlinkPolicy::Skip => false,
+        SymlinkPolicy::Follow => true,
+        SymlinkPolicy::FollowWithinRoot => fs::canonicalize(path).map(|t| t.starts_with(root)).unwrap_or(false),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::core::test_util::temp_dir;
+    use std::os::unix::fs::symlink;
+
+    // root/a.txt、root/sub/b.txt，root/loop 指回 root 自己，
+    // root/inner 指向 root/sub/b.txt，root/outside 指向 root 之外的目录
+    fn tree() -> PathBuf {
+        let base = temp_dir("symlinks");
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(base.join("elsewhere")).unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        fs::write(root.join("sub/b.txt"), b"b").unwrap();
+        fs::write(base.join("elsewhere/x.txt"), b"x").unwrap();
+        symlink(&root, root.join("loop")).unwrap();
+        symlink(root.join("sub/b.txt"), root.join("inner")).unwrap();
+        symlink(base.join("elsewhere"), root.join("outside")).unwrap();
+        root
+    }
+
+    fn names(policy: SymlinkPolicy) -> Vec<String> {
+        collect_files(&tree(), policy).unwrap().files.into_iter().map(|(rel, _)| rel).collect()
+    }
+
+    #[test]
+    fn link_to_a_parent_directory_does_not_loop() {
+        assert_eq!(names(SymlinkPolicy::Skip), vec!["root/a.txt", "root/sub/b.txt"]);
+        assert_eq!(
+            names(SymlinkPolicy::FollowWithinRoot),
+            vec!["root/a.txt", "root/inner", "root/sub/b.txt"]
+        );
+        assert_eq!(
+            names(SymlinkPolicy::Follow),
+            vec!["root/a.txt", "root/inner", "root/outside/x.txt", "root/sub/b.txt"]
+        );
+    }
+
+    #[test]
+    fn directory_with_only_skipped_links_is_kept_empty() {
+        let root = tree();
+        fs::create_dir(root.join("links")).unwrap();
+        symlink(root.join("a.txt"), root.join("links/a")).unwrap();
+        fs::create_dir(root.join("void")).unwrap();
+
+        let entries = collect_files(&root, SymlinkPolicy::Skip).unwrap();
+        assert_eq!(entries.empty_dirs, vec!["root/links", "root/void"]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// 历史文件默认保留的记录条数
+pub const DEFAULT_HISTORY_LIMIT: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Sent,
+    Received,
+}
+
+/// 一次传输的记录，历史文件里每行一条 JSON
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub direction: TransferDirection,
+    /// 对方的 IP
+    pub peer: String,
+    pub file_name: String,
+    pub size: u64,
+    pub success: bool,
+    /// Unix 时间戳（秒）
+    pub timestamp: u64,
+}
+
+impl HistoryEntry {
+    /// 以当前时间创建一条记录
+    pub fn new(direction: TransferDirection, peer: String, file_name: String, size: u64, success: bool) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self { direction, peer, file_name, size, success, timestamp }
+    }
+}
+
+/// 读取历史记录，按写入顺序（旧的在前）。文件不存在时返回空列表，
+/// 无法解析的行会被跳过
+pub fn load_history(path: &Path) -> Vec<HistoryEntry> {
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("Core: 跳过无法解析的历史记录: {}", e),
+        }
+    }
+    entries
+}
+
+/// 追加一条记录，超过 limit 条时只保留最近的 limit 条
+pub fn append_history(path: &Path, entry: &HistoryEntry, limit: usize) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let line = serde_json::to_string(entry).map_err(io::Error::other)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    drop(file);
+
+    let entries = load_history(path);
+    if entries.len() > limit {
+        // 先写临时文件再替换，中途失败不会丢掉原有历史
+        let tmp = path.with_extension("tmp");
+        let mut out = fs::File::create(&tmp)?;
+        for entry in &entries[entries.len() - limit..] {
+            let line = serde_json::to_string(entry).map_err(io::Error::other)?;
+            writeln!(out, "{}", line)?;
+        }
+        out.sync_all()?;
+        fs::rename(&tmp, path)?;
+    }
+    Ok(())
+}
+
+/// 删除全部历史记录
+pub fn clear_history(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::temp_dir;
+
+    fn entry(name: &str) -> HistoryEntry {
+        HistoryEntry::new(TransferDirection::Received, "192.168.1.5".into(), name.into(), 42, true)
+    }
+
+    #[test]
+    fn history_survives_a_restart_and_keeps_the_latest() {
+        let path = temp_dir("history").join("nested").join("history.jsonl");
+        for name in ["a", "b", "c"] {
+            append_history(&path, &entry(name), 2).unwrap();
+        }
+
+        // 重启后只有磁盘上的文件，重新读出来
+        let names: Vec<String> = load_history(&path).into_iter().map(|e| e.file_name).collect();
+        assert_eq!(names, vec!["b", "c"]);
+
+        clear_history(&path).unwrap();
+        assert!(load_history(&path).is_empty());
+        clear_history(&path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let path = temp_dir("history-bad").join("history.jsonl");
+        append_history(&path, &entry("a"), 10).unwrap();
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{broken\n\n").unwrap();
+        append_history(&path, &entry("b"), 10).unwrap();
+
+        assert_eq!(load_history(&path).len(), 2);
+    }
+}
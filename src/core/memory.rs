@@ -0,0 +1,138 @@
+//! 直接收进内存：配置同步、剪贴板图片这类小数据没必要落盘，
+//! 临时起一个只接受一次传输的服务，收完把文件名和内容交还给调用方。
+
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use super::reaper::IdleReaper;
+use super::sink::MemorySink;
+use super::{handle_incoming_connection, lock, FileServer, ReceiveDecision, ServerConfig, TransferCallback};
+
+/// receive_into_memory 最多接收的字节数，更大的请求直接拒绝
+pub const MAX_MEMORY_RECEIVE: u64 = 64 * 1024 * 1024;
+
+// 等待新连接时的轮询间隔
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// 在 port 上等待一次传输并收进内存，返回文件名和内容，不读写文件系统。
+/// 只接受第一个不超过 MAX_MEMORY_RECEIVE 的请求，其余一律拒绝；
+/// timeout 内没有收完返回 TimedOut，返回时端口随之释放
+pub fn receive_into_memory(port: u16, timeout: Duration) -> io::Result<(String, Vec<u8>)> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    info!("Core: 在 {} 端口等待接收到内存", port);
+
+    let sink = MemorySink::new(MAX_MEMORY_RECEIVE as usize);
+    let name = Arc::new(Mutex::new(None));
+    let (done_tx, done_rx) = mpsc::channel();
+    let config = ServerConfig::default();
+    let server = Arc::new(FileServer {
+        save_dir: String::new(),
+        reaper: config.header_timeout.map(IdleReaper::start),
+        config,
+        callback: Box::new(MemoryReceiver { sink: sink.clone(), name: name.clone(), done: done_tx }),
+        accepted: Mutex::new(HashMap::new()),
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match done_rx.try_recv() {
+            Ok((true, _)) => {
+                let name = lock(&name).take().unwrap_or_default();
+                return Ok((name, sink.take()));
+            }
+            Ok((false, msg)) => return Err(io::Error::other(msg)),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => {}
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "等待接收超时"));
+        }
+        match listener.accept() {
+            Ok((socket, _)) => {
+                socket.set_nonblocking(false)?;
+                let server = server.clone();
+                thread::spawn(move || handle_incoming_connection(socket, server));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// 只接受第一个请求，数据写进共享的内存缓冲，结果通过 done 交回等待的线程
+struct MemoryReceiver {
+    sink: MemorySink,
+    name: Arc<Mutex<Option<String>>>,
+    done: Sender<(bool, String)>,
+}
+
+impl TransferCallback for MemoryReceiver {
+    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> ReceiveDecision {
+        let mut name = lock(&self.name);
+        if name.is_some() {
+            warn!("Core: 已经在接收 {:?}，拒绝 {} 发来的 {}", name, sender_ip, file_name);
+            return ReceiveDecision::reject();
+        }
+        if file_size > MAX_MEMORY_RECEIVE {
+            warn!("Core: {} 有 {} 字节，超过内存接收上限，拒绝", file_name, file_size);
+            return ReceiveDecision::reject();
+        }
+        *name = Some(file_name);
+        ReceiveDecision::accept_into_sink(self.sink.clone())
+    }
+
+    fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+    fn on_complete(&self, success: bool, msg: String) {
+        let _ = self.done.send((success, msg));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::temp_dir;
+    use crate::core::{transfer_file, SendHandle, SendLimits};
+    use std::fs;
+
+    #[test]
+    fn one_megabyte_payload_comes_back_in_memory() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let receiver = thread::spawn(move || receive_into_memory(port, Duration::from_secs(10)));
+        let source = temp_dir("memory-receive").join("clip.png");
+        let data: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        // 接收端可能还没开始监听，连不上就稍后重试
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let sent = loop {
+            let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &SendHandle::new(4), &SendLimits::default(), None);
+            if sent.is_ok() || Instant::now() >= deadline {
+                break sent;
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+
+        assert_eq!(sent, Ok(data.len() as u64));
+        let (name, bytes) = receiver.join().unwrap().unwrap();
+        assert_eq!(name, "clip.png");
+        assert!(bytes == data);
+    }
+
+    #[test]
+    fn only_the_first_request_within_the_limit_is_accepted() {
+        let (done, _) = mpsc::channel();
+        let receiver = MemoryReceiver { sink: MemorySink::new(16), name: Arc::new(Mutex::new(None)), done };
+
+        assert!(!receiver.on_receive_request("huge.bin".into(), MAX_MEMORY_RECEIVE + 1, "peer".into()).accept);
+        assert!(receiver.on_receive_request("a.txt".into(), 3, "peer".into()).accept);
+        assert!(!receiver.on_receive_request("b.txt".into(), 3, "peer".into()).accept);
+    }
+}
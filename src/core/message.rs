@@ -0,0 +1,153 @@
+//! 发现协议的 UDP 报文。每个报文都是 `类型|设备ID|设备名|端口` 形式的 UTF-8 文本，
+//! HERE 可以在后面多带一段剩余空间，下线时发的 BYE 只带设备 ID。编码和解析集中在这里，
+//! 广播、回复和监听线程都不再自己拼接或切分字符串。
+
+use std::net::SocketAddr;
+
+use super::DeviceInfo;
+
+/// 一条发现报文
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryMessage {
+    /// DISCOVER|id|name|port：寻找局域网内的设备，收到的一方向 来源IP:port 回复 Here
+    Discover { device_id: String, name: String, port: u16 },
+    /// HERE|id|name|port[|free]：对 Discover 的回复，free 是保存目录的剩余空间（字节），
+    /// 没有公布时省略这一段
+    Here { device_id: String, name: String, port: u16, free_space: Option<u64> },
+    /// BYE|id：设备即将下线，收到的一方把它从已知设备里删掉
+    Bye { device_id: String },
+}
+
+impl DiscoveryMessage {
+    /// 编码为报文文本
+    pub fn encode(&self) -> String {
+        match self {
+            DiscoveryMessage::Discover { device_id, name, port } => format!("DISCOVER|{}|{}|{}", device_id, name, port),
+            DiscoveryMessage::Here { device_id, name, port, free_space: None } => {
+                format!("HERE|{}|{}|{}", device_id, name, port)
+            }
+            DiscoveryMessage::Here { device_id, name, port, free_space: Some(free) } => {
+                format!("HERE|{}|{}|{}|{}", device_id, name, port, free)
+            }
+            DiscoveryMessage::Bye { device_id } => format!("BYE|{}", device_id),
+        }
+    }
+
+    /// 解析收到的报文，格式不对时返回 None
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        Self::parse(raw).ok()
+    }
+
+    // 与 decode 相同，失败时给出原因，交给 on_invalid_packet
+    pub(crate) fn parse(raw: &[u8]) -> Result<Self, &'static str> {
+        let msg = std::str::from_utf8(raw).map_err(|_| "不是有效的 UTF-8")?;
+        let parts: Vec<&str> = msg.split('|').collect();
+        if parts[0] != "DISCOVER" && parts[0] != "HERE" && parts[0] != "BYE" {
+            return Err("未知的消息类型");
+        }
+        // 只有 HERE 可以多一段剩余空间，BYE 只有设备 ID
+        let fields_ok = match parts[0] {
+            "BYE" => parts.len() == 2,
+            "HERE" => parts.len() == 4 || parts.len() == 5,
+            _ => parts.len() == 4,
+        };
+        if !fields_ok {
+            return Err("字段数量不对");
+        }
+        if parts[1].is_empty() {
+            return Err("设备 ID 为空");
+        }
+        if parts[0] == "BYE" {
+            return Ok(DiscoveryMessage::Bye { device_id: parts[1].to_string() });
+        }
+        let port: u16 = parts[3].parse().map_err(|_| "端口无效")?;
+
+        let (device_id, name) = (parts[1].to_string(), parts[2].to_string());
+        Ok(if parts[0] == "DISCOVER" {
+            DiscoveryMessage::Discover { device_id, name, port }
+        } else {
+            let free_space = match parts.get(4) {
+                Some(free) => Some(free.parse().map_err(|_| "剩余空间无效")?),
+                None => None,
+            };
+            DiscoveryMessage::Here { device_id, name, port, free_space }
+        })
+    }
+
+    pub fn device_id(&self) -> &str {
+        match self {
+            DiscoveryMessage::Discover { device_id, .. }
+            | DiscoveryMessage::Here { device_id, .. }
+            | DiscoveryMessage::Bye { device_id } => device_id,
+        }
+    }
+
+    // 报文描述的设备，地址取自数据报的来源，IPv4 映射地址换成 IPv4 形式。BYE 不描述设备，返回 None
+    pub(crate) fn device_info(&self, source: SocketAddr) -> Option<DeviceInfo> {
+        let (device_id, name, port, free_space) = match self {
+            DiscoveryMessage::Discover { device_id, name, port } => (device_id, name, port, None),
+            DiscoveryMessage::Here { device_id, name, port, free_space } => (device_id, name, port, *free_space),
+            DiscoveryMessage::Bye { .. } => return None,
+        };
+        Some(DeviceInfo {
+            device_id: device_id.clone(),
+            name: name.clone(),
+            ip: source.ip().to_canonical().to_string(),
+            control_port: *port,
+            free_space,
+        })
+    }
+}
+
+// 设备名里的 | 会被当成字段分隔符，控制字符会打乱报文，都换成 _
+pub(crate) fn sanitize_device_name(name: &str) -> String {
+    name.chars().map(|c| if c == '|' || c.is_control() { '_' } else { c }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(msg: DiscoveryMessage) {
+        assert_eq!(DiscoveryMessage::decode(msg.encode().as_bytes()), Some(msg));
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        round_trip(DiscoveryMessage::Discover { device_id: "dev-1".into(), name: "客厅电脑".into(), port: 53317 });
+        round_trip(DiscoveryMessage::Here { device_id: "dev-2".into(), name: "手机".into(), port: 4061, free_space: None });
+        round_trip(DiscoveryMessage::Here {
+            device_id: "dev-3".into(),
+            name: String::new(),
+            port: 1,
+            free_space: Some(64 << 20),
+        });
+        round_trip(DiscoveryMessage::Bye { device_id: "dev-4".into() });
+    }
+
+    #[test]
+    fn malformed_packets_are_rejected_with_a_reason() {
+        assert_eq!(DiscoveryMessage::parse(b"HELLO|a|b|1"), Err("未知的消息类型"));
+        assert_eq!(DiscoveryMessage::parse(b"DISCOVER|a|b|1|2"), Err("字段数量不对"));
+        assert_eq!(DiscoveryMessage::parse(b"BYE|a|b"), Err("字段数量不对"));
+        assert_eq!(DiscoveryMessage::parse(b"BYE|"), Err("设备 ID 为空"));
+        assert_eq!(DiscoveryMessage::parse(b"HERE|a|b|port"), Err("端口无效"));
+        assert_eq!(DiscoveryMessage::parse(b"HERE|a|b|1|lots"), Err("剩余空间无效"));
+        assert_eq!(DiscoveryMessage::parse(&[0xff, b'|']), Err("不是有效的 UTF-8"));
+    }
+
+    #[test]
+    fn bye_describes_no_device() {
+        let source: SocketAddr = "192.168.1.7:4061".parse().unwrap();
+        assert!(DiscoveryMessage::Bye { device_id: "dev".into() }.device_info(source).is_none());
+        let here = DiscoveryMessage::Here { device_id: "dev".into(), name: "n".into(), port: 9, free_space: None };
+        assert_eq!(here.device_info(source).map(|d| (d.ip, d.control_port)), Some(("192.168.1.7".to_string(), 9)));
+    }
+
+    #[test]
+    fn sanitized_name_keeps_the_packet_parseable() {
+        let name = sanitize_device_name("A|B\nC");
+        assert_eq!(name, "A_B_C");
+        round_trip(DiscoveryMessage::Discover { device_id: "dev".into(), name, port: 4061 });
+    }
+}
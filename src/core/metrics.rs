@@ -0,0 +1,221 @@
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 进程内的全局传输统计，各传输线程直接原子累加
+pub struct Metrics {
+    transfers_sent: AtomicU64,
+    transfers_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    socket_bytes_sent: AtomicU64,
+    socket_bytes_received: AtomicU64,
+    errors: AtomicU64,
+    rejects: AtomicU64,
+}
+
+/// 某一时刻的统计快照
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub transfers_sent: u64,
+    pub transfers_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// 实际写入传输连接的字节数，包括消息头、应答和帧长度等协议开销。
+    /// 与 bytes_sent（文件内容）对比可以看出协议开销。发给本进程自己的文件服务时不经过连接，不计入
+    pub socket_bytes_sent: u64,
+    /// 实际从传输连接读到的字节数，含义同 socket_bytes_sent
+    pub socket_bytes_received: u64,
+    pub errors: u64,
+    /// 本机作为接收端拒绝的请求数
+    pub rejects: u64,
+}
+
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            transfers_sent: AtomicU64::new(0),
+            transfers_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            socket_bytes_sent: AtomicU64::new(0),
+            socket_bytes_received: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            rejects: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn transfer_sent(&self) {
+        self.transfers_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn transfer_received(&self) {
+        self.transfers_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_sent(&self, n: u64) {
+        self.bytes_sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_received(&self, n: u64) {
+        self.bytes_received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reject(&self) {
+        self.rejects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            transfers_sent: self.transfers_sent.load(Ordering::Relaxed),
+            transfers_received: self.transfers_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            socket_bytes_sent: self.socket_bytes_sent.load(Ordering::Relaxed),
+            socket_bytes_received: self.socket_bytes_received.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            rejects: self.rejects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 读取当前的全局统计
+pub fn metrics_snapshot() -> MetricsSnapshot {
+    METRICS.snapshot()
+}
+
+impl MetricsSnapshot {
+    /// 按 Prometheus 文本格式输出，方便挂到 HTTP 接口上
+    pub fn to_prometheus(&self) -> String {
+        let counters = [
+            ("locsd_transfers_sent_total", "Files sent successfully", self.transfers_sent),
+            ("locsd_transfers_received_total", "Files received successfully", self.transfers_received),
+            ("locsd_bytes_sent_total", "File bytes sent", self.bytes_sent),
+            ("locsd_bytes_received_total", "File bytes received", self.bytes_received),
+            ("locsd_socket_bytes_sent_total", "Raw bytes written to transfer sockets", self.socket_bytes_sent),
+            ("locsd_socket_bytes_received_total", "Raw bytes read from transfer sockets", self.socket_bytes_received),
+            ("locsd_errors_total", "Failed transfers and I/O errors", self.errors),
+            ("locsd_rejects_total", "Incoming requests rejected", self.rejects),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+// 包在传输连接外面，把实际读写的字节数计入 socket_bytes_*；
+// 通过 Deref 仍可调用 TcpStream 自己的方法（设置超时等）
+pub(crate) struct CountingStream<S> {
+    inner: S,
+    // 计入哪份统计，正常都是全局的 METRICS
+    metrics: &'static Metrics,
+}
+
+impl<S> CountingStream<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self { inner, metrics: &METRICS }
+    }
+}
+
+impl<S> Deref for CountingStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> DerefMut for CountingStream<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: Read> Read for CountingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.metrics.socket_bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for CountingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.metrics.socket_bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_counters_include_protocol_overhead() {
+        // 单独一份统计，不受同时运行的其他传输影响
+        static COUNTERS: Metrics = Metrics::new();
+        let header = b"DATA|a.bin|0|tid=1\n";
+        let payload = vec![1u8; 4096];
+
+        let mut wire = CountingStream { inner: Vec::new(), metrics: &COUNTERS };
+        wire.write_all(header).unwrap();
+        wire.write_all(&payload).unwrap();
+        COUNTERS.add_bytes_sent(payload.len() as u64);
+        let mut back = CountingStream { inner: &wire.inner[..], metrics: &COUNTERS };
+        io::copy(&mut back, &mut io::sink()).unwrap();
+        COUNTERS.add_bytes_received(payload.len() as u64);
+
+        let snapshot = COUNTERS.snapshot();
+        let raw = (header.len() + payload.len()) as u64;
+        assert_eq!((snapshot.bytes_sent, snapshot.bytes_received), (4096, 4096));
+        assert_eq!((snapshot.socket_bytes_sent, snapshot.socket_bytes_received), (raw, raw));
+        assert!(snapshot.to_prometheus().contains(&format!("locsd_socket_bytes_sent_total {}\n", raw)));
+    }
+
+    #[test]
+    fn snapshot_and_prometheus_report_each_counter() {
+        static COUNTERS: Metrics = Metrics::new();
+        COUNTERS.transfer_sent();
+        COUNTERS.transfer_received();
+        COUNTERS.transfer_received();
+        COUNTERS.add_bytes_sent(10);
+        COUNTERS.add_bytes_received(20);
+        COUNTERS.error();
+        COUNTERS.reject();
+
+        let snapshot = COUNTERS.snapshot();
+        assert_eq!(
+            snapshot,
+            MetricsSnapshot {
+                transfers_sent: 1,
+                transfers_received: 2,
+                bytes_sent: 10,
+                bytes_received: 20,
+                errors: 1,
+                rejects: 1,
+                ..MetricsSnapshot::default()
+            }
+        );
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("# TYPE locsd_transfers_received_total counter\nlocsd_transfers_received_total 2\n"));
+        assert!(text.contains("locsd_rejects_total 1\n"));
+        assert_eq!(text.lines().filter(|l| !l.starts_with('#')).count(), 8);
+    }
+}
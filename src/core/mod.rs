@@ -1,12 +1,75 @@
-use std::net::{IpAddr, Ipv4Addr, UdpSocket, TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket, TcpListener, TcpStream};
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use log::{info, error, debug, warn};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use if_addrs::{get_if_addrs, IfAddr};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::fs::{self, File, OpenOptions};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use threadpool::ThreadPool;
+
+mod checkpoint;
+mod checksum;
+mod connectivity;
+mod directory;
+mod history;
+mod memory;
+mod message;
+mod metrics;
+mod pairing;
+mod profile;
+mod reaper;
+mod regions;
+mod registry;
+mod resume;
+mod scan;
+mod selftest;
+mod sink;
+#[cfg(test)]
+mod test_util;
+mod transfers;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod writer;
+
+pub use checkpoint::MIN_CHECKPOINT_INTERVAL;
+pub use checksum::ExpectedChecksums;
+pub use connectivity::{connectivity_check, ConnectivityReport};
+pub use directory::SymlinkPolicy;
+pub use history::{
+    append_history, clear_history, load_history, HistoryEntry, TransferDirection, DEFAULT_HISTORY_LIMIT,
+};
+pub use memory::{receive_into_memory, MAX_MEMORY_RECEIVE};
+pub use message::DiscoveryMessage;
+pub use metrics::{metrics_snapshot, MetricsSnapshot};
+pub use pairing::{confirm_pairing, PairingStore, PAIRING_CODE_TTL};
+pub use profile::TransferProfile;
+pub use regions::send_file_regions;
+pub use registry::{
+    device_addresses, export_devices_snapshot, known_devices, last_transfer, DeviceRecord, DeviceRegistry, LastTransfer,
+};
+pub use sink::{MemorySink, ReceiveSink, SharedSink, TransferFilter};
+pub use scan::{scan_subnet, scan_subnet_with_config, ScanCallback, ScanConfig, ScanHandle};
+pub use selftest::{self_test, self_test_with_config, SelfTestConfig, SelfTestReport, SelfTestStage, StageResult};
+pub use transfers::{active_transfer_count, cancel_all_transfers, CancelToken};
+use checkpoint::{CheckpointReader, CheckpointWriter};
+use message::sanitize_device_name;
+use metrics::{CountingStream, METRICS};
+use reaper::IdleReaper;
+use registry::DEVICES;
+use transfers::TRANSFERS;
+use writer::BoundedWriter;
+
+// 持锁线程 panic 后锁会被毒化，这里照样取出数据继续用，
+// 避免一个分片线程崩溃连带所有传输线程一起 panic
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 #[derive(Clone, Debug)]
 pub struct DeviceInfo {
@@ -14,10 +77,48 @@ pub struct DeviceInfo {
     pub name: String,
     pub ip: String,
     pub control_port: u16,
+    /// 对方在 HERE 中公布的保存目录剩余空间（字节，按 MiB 向下取整），未公布时为 None
+    pub free_space: Option<u64>,
+}
+
+impl DeviceInfo {
+    /// 设备地址是否就是 ip，::ffff:a.b.c.d 与 a.b.c.d 视为同一个地址
+    pub fn has_ip(&self, ip: &str) -> bool {
+        canonical_ip(&self.ip) == canonical_ip(ip)
+    }
+
+    /// 这台设备所有已知的地址（多网卡设备从每个地址都会通告），与本机同网段的排在前面。
+    /// 发送时连不上 ip 会按这个顺序改用其他地址
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addresses = device_addresses(&self.device_id);
+        let ip = canonical_ip(&self.ip);
+        if !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+        addresses
+    }
+}
+
+/// 把 IPv4 映射的 IPv6 地址（::ffff:a.b.c.d）换成 a.b.c.d，其他地址原样返回。
+/// 双栈套接字上 IPv4 对端的地址是映射形式，而发现记录的是 IPv4 形式，按 IP 比较前先统一
+pub fn canonical_ip(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(addr) => addr.to_canonical().to_string(),
+        Err(_) => ip.to_string(),
+    }
 }
 
 pub trait DiscoveryCallback: Send + Sync {
     fn on_device_found(&self, device_info: DeviceInfo);
+
+    /// 收到但无法解析的数据报，用于排查与其他客户端的互通问题，默认忽略
+    fn on_invalid_packet(&self, _raw: &[u8], _source: SocketAddr, _reason: &str) {}
+
+    /// 监听套接字连续出错、监听线程已退出时调用，之后不会再发现新设备，默认忽略
+    fn on_listener_stopped(&self, _error: &io::Error) {}
+
+    /// 对方发来 BYE 表示即将下线，已从已知设备中删除，默认忽略
+    fn on_device_lost(&self, _device_id: String) {}
 }
 
 fn caculate_broadcast(ip: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
@@ -27,121 +128,566 @@ fn caculate_broadcast(ip: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
     Ipv4Addr::from(broadcast_u32)
 }
 
+/// 发现服务参数
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// 单个发现包允许的最大字节数，超过的包会被丢弃而不是截断后解析
+    pub max_packet_size: usize,
+    /// 隐身模式：只收集别人的通告，不回复 HERE 也不广播 DISCOVER
+    pub invisible: bool,
+    /// 只在这块网卡上广播（按网卡名匹配），None 表示所有网卡
+    pub interface: Option<String>,
+    /// 监听和发送使用的本机地址，默认 0.0.0.0。指定网卡地址时只在该网卡上收发
+    pub bind_addr: Ipv4Addr,
+    /// 设置后回复 HERE 时附带这个目录所在磁盘的剩余空间，发送方可以据此提前排除放不下文件的设备；
+    /// None 表示不公布。运行中可用 DiscoveryHandle::set_save_dir 修改
+    pub save_dir: Option<PathBuf>,
+    /// DISCOVER 广播包的 IP TTL，默认 DEFAULT_BROADCAST_TTL，只在本网段内传播。
+    /// 单播发现和 HERE 回复可能需要跨网段，使用系统默认值
+    pub broadcast_ttl: u32,
+    /// 周期广播改为逐个发往这些地址，为空时发往各网卡的广播地址。
+    /// 用于不转发广播的网络（部分 VPN、虚拟网卡），或者在回环地址上测试
+    pub broadcast_targets: Vec<Ipv4Addr>,
+}
+
+/// DISCOVER 广播默认的 IP TTL
+pub const DEFAULT_BROADCAST_TTL: u32 = 1;
+
+// 发现包默认的最大字节数
+const DEFAULT_MAX_PACKET_SIZE: usize = 1024;
+
+// HERE 中公布的剩余空间的取整单位
+const FREE_SPACE_UNIT: u64 = 1024 * 1024;
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            invisible: false,
+            interface: None,
+            bind_addr: Ipv4Addr::UNSPECIFIED,
+            save_dir: None,
+            broadcast_ttl: DEFAULT_BROADCAST_TTL,
+            broadcast_targets: Vec::new(),
+        }
+    }
+}
+
+/// 发现服务句柄，监听线程和广播线程共享其中的状态，可在运行时修改
+#[derive(Clone)]
+pub struct DiscoveryHandle {
+    alias: Arc<Mutex<String>>,
+    broadcasting: Arc<AtomicBool>,
+    invisible: Arc<AtomicBool>,
+    interface: Arc<Mutex<Option<String>>>,
+    save_dir: Arc<Mutex<Option<PathBuf>>>,
+    // 监听或广播启动后记下的端口和本机设备 ID，announce_now 用它们发 DISCOVER
+    identity: Arc<Mutex<Option<(u16, String)>>>,
+    bind_addr: Ipv4Addr,
+    broadcast_ttl: u32,
+    targets: Vec<Ipv4Addr>,
+    // 收 HERE 时允许的最大字节数，取自 DiscoveryConfig::max_packet_size
+    max_packet_size: usize,
+    stop: Arc<AtomicBool>,
+    // 监听线程收包的地址，stop 向这里发一个空包把阻塞在 recv_from 上的线程叫醒
+    listener: Option<SocketAddr>,
+}
+
+impl DiscoveryHandle {
+    fn new(device_name: String) -> Self {
+        Self {
+            alias: Arc::new(Mutex::new(sanitize_device_name(&device_name))),
+            broadcasting: Arc::new(AtomicBool::new(true)),
+            invisible: Arc::new(AtomicBool::new(false)),
+            interface: Arc::new(Mutex::new(None)),
+            save_dir: Arc::new(Mutex::new(None)),
+            identity: Arc::new(Mutex::new(None)),
+            bind_addr: Ipv4Addr::UNSPECIFIED,
+            broadcast_ttl: DEFAULT_BROADCAST_TTL,
+            targets: Vec::new(),
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            stop: Arc::new(AtomicBool::new(false)),
+            listener: None,
+        }
+    }
+
+    // 本轮广播的目标地址：配置了目标时只用它们；绑定了具体地址时只用对应网卡，否则按网卡设置
+    fn broadcast_targets(&self) -> Vec<String> {
+        if !self.targets.is_empty() {
+            return self.targets.iter().map(|ip| ip.to_string()).collect();
+        }
+        if !self.bind_addr.is_unspecified() {
+            let bound: Vec<String> = list_interfaces().iter()
+                .filter(|i| i.ip == self.bind_addr)
+                .map(|i| i.broadcast.to_string())
+                .collect();
+            if !bound.is_empty() {
+                return bound;
+            }
+        }
+        get_target_broadcats(self.interface().as_deref())
+    }
+
+    /// 限定只在某块网卡上广播（名字取自 list_interfaces），None 恢复为所有网卡
+    pub fn set_interface(&self, name: Option<String>) {
+        info!("Core: 广播网卡设置为 {}", name.as_deref().unwrap_or("全部"));
+        *lock(&self.interface) = name;
+    }
+
+    pub fn interface(&self) -> Option<String> {
+        lock(&self.interface).clone()
+    }
+
+    /// 修改在 HERE 中公布剩余空间的目录，None 表示不再公布
+    pub fn set_save_dir(&self, dir: Option<PathBuf>) {
+        *lock(&self.save_dir) = dir;
+    }
+
+    // 保存目录的剩余空间，按 MiB 向下取整，不公布精确值；查询失败时不公布
+    fn free_space(&self) -> Option<u64> {
+        let dir = lock(&self.save_dir).clone()?;
+        match fs2::available_space(&dir) {
+            Ok(free) => Some(free / FREE_SPACE_UNIT * FREE_SPACE_UNIT),
+            Err(e) => {
+                warn!("Core: 无法查询 {:?} 的剩余空间: {:?}", dir, e);
+                None
+            }
+        }
+    }
+
+    /// 隐身模式下仍然记录别人的 DISCOVER/HERE，但自己不回复 HERE、不广播，
+    /// 适合在公共网络里只浏览设备而不暴露自己
+    pub fn set_invisible(&self, invisible: bool) {
+        self.invisible.store(invisible, Ordering::Relaxed);
+        info!("Core: 隐身模式已{}", if invisible { "开启" } else { "关闭" });
+    }
+
+    pub fn is_invisible(&self) -> bool {
+        self.invisible.load(Ordering::Relaxed)
+    }
+
+    /// 暂停/恢复周期性 DISCOVER 广播。暂停期间仍然监听，
+    /// 收到别人的 DISCOVER 也照常回复 HERE
+    pub fn set_broadcasting(&self, enabled: bool) {
+        self.broadcasting.store(enabled, Ordering::Relaxed);
+        info!("Core: 周期广播已{}", if enabled { "恢复" } else { "暂停" });
+    }
+
+    pub fn is_broadcasting(&self) -> bool {
+        self.broadcasting.load(Ordering::Relaxed)
+    }
+
+    // 周期广播线程本轮是否发出 DISCOVER
+    fn announcing(&self) -> bool {
+        self.is_broadcasting() && !self.is_invisible()
+    }
+
+    /// 立即广播一次 DISCOVER，不等下一轮周期广播；隐身模式下或监听/广播尚未启动时不发送
+    pub fn announce_now(&self) {
+        if self.is_invisible() {
+            return;
+        }
+        match lock(&self.identity).clone() {
+            Some((port, device_id)) => {
+                info!("Core: 立即宣告本机");
+                send_discover_once_with(port, device_id, self);
+            }
+            None => warn!("Core: 发现服务尚未启动，无法宣告本机"),
+        }
+    }
+
+    /// 应用回到前台：恢复周期广播并立即宣告一次，后台期间别人可能已经把本机当作离线
+    pub fn enter_foreground(&self) {
+        self.set_broadcasting(true);
+        self.announce_now();
+    }
+
+    /// 应用进入后台：暂停周期广播省电，监听和回复 HERE 照常进行，仍然可以被发现和接收文件
+    pub fn enter_background(&self) {
+        self.set_broadcasting(false);
+    }
+
+    /// 修改设备名，之后发出的 DISCOVER/HERE 都使用新名字。
+    /// 名字里的 | 和控制字符会破坏报文格式，换成 _
+    pub fn set_alias(&self, name: &str) {
+        let name = sanitize_device_name(name);
+        info!("Core: 设备名已修改为 {}", name);
+        *lock(&self.alias) = name;
+    }
+
+    pub fn alias(&self) -> String {
+        lock(&self.alias).clone()
+    }
+
+    /// 停止监听线程和周期广播线程，端口随之释放。停止后不能再启动，需要重新 start_*
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(addr) = self.listener
+            && let Ok(socket) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        {
+            let _ = socket.send_to(&[], addr);
+        }
+        info!("Core: 发现服务已停止");
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    // 等待 duration，期间被 stop 时提前返回
+    fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !self.is_stopped() && Instant::now() < until {
+            thread::sleep(until.saturating_duration_since(Instant::now()).min(Duration::from_millis(100)));
+        }
+    }
+}
+
+// Windows 上数据报大于接收缓冲区时 recv_from 返回 WSAEMSGSIZE
+const WSAEMSGSIZE: i32 = 10040;
+
+// 收到的一个发现包
+enum Datagram {
+    Packet(usize, SocketAddr),
+    // 超过允许的长度，缓冲区里是截断后的前 max_size 字节
+    Oversized(SocketAddr),
+}
+
+// 收一个发现包，buf 要比 max_size 多 1 字节：读满说明数据报比上限大，已被系统截断。
+// Windows 上这时 recv_from 返回 WSAEMSGSIZE，拿不到来源地址，记为 0.0.0.0:0
+fn recv_discovery(socket: &UdpSocket, buf: &mut [u8], max_size: usize) -> io::Result<Datagram> {
+    match socket.recv_from(buf) {
+        Ok((size, addr)) if size > max_size => Ok(Datagram::Oversized(addr)),
+        Ok((size, addr)) => Ok(Datagram::Packet(size, addr)),
+        Err(e) if e.raw_os_error() == Some(WSAEMSGSIZE) => {
+            Ok(Datagram::Oversized(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))
+        }
+        Err(e) => Err(e),
+    }
+}
+// recv_from 连续失败这么多次就认为套接字已不可用，退出监听线程
+const MAX_RECV_ERRORS: u32 = 10;
+// 每次失败后等待的时间随连续失败次数增加，上限 1 秒
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+/// start_* 系列函数启动失败的原因
+#[derive(Debug)]
+pub enum StartError {
+    /// 端口已被其他程序（或本程序的另一个实例）占用。只有同一协议才会冲突：
+    /// 发现走 UDP、传输走 TCP，两者配置成同一个端口号可以同时启动
+    PortInUse,
+    /// 没有权限绑定该端口或开启广播
+    PermissionDenied,
+    /// 没有可用的网络接口
+    NoInterface,
+    Io(io::Error),
+}
+
+impl StartError {
+    fn from_io(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::AddrInUse => StartError::PortInUse,
+            io::ErrorKind::PermissionDenied => StartError::PermissionDenied,
+            io::ErrorKind::AddrNotAvailable => StartError::NoInterface,
+            _ => StartError::Io(e),
+        }
+    }
+}
+
+impl std::fmt::Display for StartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartError::PortInUse => write!(f, "端口已被占用"),
+            StartError::PermissionDenied => write!(f, "没有权限"),
+            StartError::NoInterface => write!(f, "没有可用的网络接口"),
+            StartError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StartError {}
+
 pub fn start_listening(
     port: u16,
     device_id: String,
     device_name: String,
     callback: Box<dyn DiscoveryCallback>
-) {
-    let callback = Arc::new(callback);
+) -> DiscoveryHandle {
+    start_listening_with_config(port, device_id, device_name, callback, DiscoveryConfig::default())
+}
 
-    let self_id_check = device_id.clone();
+pub fn start_listening_with_config(
+    port: u16,
+    device_id: String,
+    device_name: String,
+    callback: Box<dyn DiscoveryCallback>,
+    config: DiscoveryConfig,
+) -> DiscoveryHandle {
+    let fallback = DiscoveryHandle::new(device_name.clone());
+    match try_start_listening_with_config(port, device_id, device_name, callback, config) {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Core: UDP 绑定失败: {}", e);
+            fallback
+        }
+    }
+}
 
-    thread::spawn(move || {
-        info!("Core: UDP 线程启动，正在监听 0.0.0.0:{}", port);
+/// 与 start_listening_with_config 相同，但在当前线程完成绑定，失败时返回原因
+pub fn try_start_listening_with_config(
+    port: u16,
+    device_id: String,
+    device_name: String,
+    callback: Box<dyn DiscoveryCallback>,
+    config: DiscoveryConfig,
+) -> Result<DiscoveryHandle, StartError> {
+    let socket = UdpSocket::bind((config.bind_addr, port)).map_err(StartError::from_io)?;
+    let (handle, _) = listen_on(socket, port, device_id, device_name, callback, config, Arc::new(AtomicBool::new(false)));
+    Ok(handle)
+}
 
-        let socket = match UdpSocket::bind(format!("0.0.0.0:{}", port)) {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Core: UDP 绑定失败: {:?}", e);
-                return;
-            }
-        };
+// 在已绑定的 socket 上启动监听线程，HERE 里通告 port。
+// stop 置位后收到的下一个数据报让线程退出，套接字随之关闭（自检用它收尾）
+pub(crate) fn listen_on(
+    socket: UdpSocket,
+    port: u16,
+    device_id: String,
+    device_name: String,
+    callback: Box<dyn DiscoveryCallback>,
+    config: DiscoveryConfig,
+    stop: Arc<AtomicBool>,
+) -> (DiscoveryHandle, thread::JoinHandle<()>) {
+    if let Err(e) = socket.set_broadcast(true) {
+        error!("Core: 设置广播失败: {:?}", e);
+    }
 
-        if let Err(e) = socket.set_broadcast(true) {
-            error!("Core: 设置广播失败: {:?}", e);
-        }
+    let callback = Arc::new(callback);
 
-        let mut buf = [0u8; 1024];
+    let self_id_check = device_id.clone();
+    let mut handle = DiscoveryHandle::new(device_name);
+    handle.bind_addr = config.bind_addr;
+    handle.broadcast_ttl = config.broadcast_ttl;
+    handle.max_packet_size = config.max_packet_size;
+    handle.targets = config.broadcast_targets;
+    handle.stop = stop.clone();
+    handle.listener = socket.local_addr().ok().map(|addr| match addr.ip() {
+        ip if ip.is_unspecified() => SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port())),
+        _ => addr,
+    });
+    handle.invisible.store(config.invisible, Ordering::Relaxed);
+    *lock(&handle.interface) = config.interface;
+    *lock(&handle.save_dir) = config.save_dir;
+    *lock(&handle.identity) = Some((port, device_id.clone()));
+    let shared = handle.clone();
+
+    let thread = thread::spawn(move || {
+        info!("Core: UDP 线程启动，正在监听 {}:{}", config.bind_addr, port);
+
+        let max_size = config.max_packet_size;
+        let mut buf = vec![0u8; max_size + 1];
+        let mut recv_errors = 0u32;
 
         loop {
-            let (size, addr) = match socket.recv_from(&mut buf) {
-                Ok(v) => v,
+            let received = recv_discovery(&socket, &mut buf, max_size);
+            if stop.load(Ordering::Relaxed) {
+                info!("Core: UDP 线程在 {}:{} 上停止监听", config.bind_addr, port);
+                break;
+            }
+            let (size, addr) = match received {
+                Ok(Datagram::Packet(size, addr)) => (size, addr),
+                Ok(Datagram::Oversized(addr)) => {
+                    recv_errors = 0;
+                    warn!("Core: 丢弃来自 {} 的超长发现包 (超过 {} 字节)", addr, max_size);
+                    callback.on_invalid_packet(&buf[..max_size], addr, "超过最大长度");
+                    continue;
+                }
                 Err(e) => {
+                    // 套接字失效时 recv_from 会立即返回错误，直接 continue 会空转占满 CPU
+                    recv_errors += 1;
+                    if recv_errors >= MAX_RECV_ERRORS {
+                        error!("Core: UDP 接收连续失败 {} 次，停止监听: {:?}", recv_errors, e);
+                        callback.on_listener_stopped(&e);
+                        break;
+                    }
                     error!("Core: UDP 接收失败: {:?}", e);
+                    thread::sleep((RECV_ERROR_BACKOFF * recv_errors).min(Duration::from_secs(1)));
                     continue;
                 }
             };
+            recv_errors = 0;
 
-            let msg = String::from_utf8_lossy(&buf[..size]);
-            let parts: Vec<&str> = msg.split('|').collect();
+            let packet = match DiscoveryMessage::parse(&buf[..size]) {
+                Ok(p) => p,
+                Err(reason) => {
+                    debug!("Core: 丢弃来自 {} 的无效发现包: {}", addr, reason);
+                    callback.on_invalid_packet(&buf[..size], addr, reason);
+                    continue;
+                }
+            };
 
-            if parts.len() > 1 && parts[1] == self_id_check {
+            if packet.device_id() == self_id_check {
                 continue;
             }
-
-            if msg.starts_with("DISCOVER|") {
-                let parts: Vec<&str> = msg.split('|').collect();
-                if parts.len() == 4 {
-                    let device = DeviceInfo {
-                        device_id: parts[1].to_string(),
-                        name: parts[2].to_string(),
-                        ip: addr.ip().to_string(),
-                        control_port: parts[3].parse().unwrap_or(4060),
-                    };
-                    callback.on_device_found(device);
+            let device = match packet.device_info(addr) {
+                Some(device) => device,
+                None => {
+                    // BYE：对方即将下线
+                    let device_id = packet.device_id().to_string();
+                    info!("Core: 设备 {} ({}) 已下线", device_id, addr);
+                    DEVICES.remove(&device_id);
+                    callback.on_device_lost(device_id);
+                    continue;
                 }
+            };
+            match packet {
+                DiscoveryMessage::Discover { .. } => {
+                    // DISCOVER 通常从随机端口发出（send_discover_once、广播线程），只有回到来源端口
+                    // 发送方才收得到；对方的监听线程在通告的端口上，回复也发一份过去，两边都能发现本机
+                    let mut targets = vec![addr];
+                    if device.control_port != addr.port() {
+                        targets.push(SocketAddr::new(addr.ip(), device.control_port));
+                    }
+                    DEVICES.record(&device);
+                    callback.on_device_found(device);
 
-                let response = format!(
-                    "HERE|{}|{}|{}",
-                    device_id,
-                    device_name,
-                    port
-                );
+                    if shared.is_invisible() {
+                        continue;
+                    }
 
-                let target_port = if parts.len() == 4 { parts[3].parse().unwrap_or(4060) } else { 4060 };
-                let target_addr = format!("{}:{}", addr.ip(), target_port);
+                    let response = DiscoveryMessage::Here {
+                        device_id: device_id.clone(),
+                        name: shared.alias(),
+                        port,
+                        free_space: shared.free_space(),
+                    }.encode();
 
-                if let Err(e) = socket.send_to(response.as_bytes(), &target_addr) {
-                    error!("Core: 回复 HERE 失败 (至 {}): {:?}", target_addr, e);
+                    for target in targets {
+                        let target_addr = target.to_string();
+                        if let Err(e) = send_udp_with_retry(&socket, response.as_bytes(), &target_addr) {
+                            log_udp_send_error("回复 HERE", &target_addr, &e);
+                        }
+                    }
                 }
-            }
-
-            else if msg.starts_with("HERE|") {
-                let parts: Vec<&str> = msg.split('|').collect();
-                if parts.len() == 4 {
-                    let device = DeviceInfo {
-                        device_id: parts[1].to_string(),
-                        name: parts[2].to_string(),
-                        ip: addr.ip().to_string(),
-                        control_port: parts[3].parse().unwrap_or(4060),
-                    };
-
+                // HERE，BYE 在上面已经处理
+                _ => {
+                    DEVICES.record(&device);
                     callback.on_device_found(device);
                 }
             }
         }
     });
+
+    (handle, thread)
 }
 
 pub fn start_discovery_broadcaster(
     port: u16,
     device_id: String,
     device_name: String,
+) -> DiscoveryHandle {
+    let handle = DiscoveryHandle::new(device_name);
+    start_discovery_broadcaster_with(port, device_id, &handle);
+    handle
+}
+
+/// 启动周期广播，设备名从 handle 读取，可与 start_listening 返回的句柄共用
+pub fn start_discovery_broadcaster_with(
+    port: u16,
+    device_id: String,
+    handle: &DiscoveryHandle,
 ) {
-    thread::spawn(move || {
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("无法绑定发送套接字");  // 0就是随机端口，好强
-        socket.set_broadcast(true).expect("无法设置广播权限");
+    if let Err(e) = try_start_discovery_broadcaster_with(port, device_id, handle) {
+        error!("Core: 无法启动发现广播: {}", e);
+    }
+}
 
-        let msg = format!("DISCOVER|{}|{}|{}", device_id, device_name, port);
+/// 与 start_discovery_broadcaster_with 相同，但套接字准备失败时返回原因。
+/// 启动时没有可用网卡（例如 Wi-Fi 还没连上）不算失败，广播线程每轮重新检查网卡，连上后即开始正常广播
+pub fn try_start_discovery_broadcaster_with(
+    port: u16,
+    device_id: String,
+    handle: &DiscoveryHandle,
+) -> Result<(), StartError> {
+    let socket = broadcast_socket(handle.bind_addr, handle.broadcast_ttl).map_err(StartError::from_io)?;
+    lock(&handle.identity).get_or_insert_with(|| (port, device_id.clone()));
 
+    let handle = handle.clone();
+    thread::spawn(move || {
+        let mut interval = BROADCAST_INTERVAL;
         loop {
-            let target_ips = get_target_broadcats();
+            if handle.is_stopped() {
+                info!("Core: 周期广播线程退出");
+                break;
+            }
+            if !handle.announcing() {
+                // 暂停时短间隔轮询，恢复后能尽快发出广播
+                handle.pause(Duration::from_millis(500));
+                continue;
+            }
+
+            // 网卡全部消失（断网）时仍向全局广播地址发送，但逐轮拉长间隔，
+            // 只在状态切换时记日志，网卡恢复后立即回到正常间隔
+            let online = !list_interfaces().is_empty();
+            let next = next_broadcast_interval(interval, online);
+            if online && interval != BROADCAST_INTERVAL {
+                info!("Core: 网卡已恢复，广播间隔恢复为 {:?}", BROADCAST_INTERVAL);
+            } else if !online && interval == BROADCAST_INTERVAL {
+                warn!("Core: 没有可用的网卡，广播间隔逐步延长，最长 {:?}", OFFLINE_BROADCAST_MAX_INTERVAL);
+            }
+            interval = next;
+
+            // 每轮重新读取设备名，改名后下一次广播即生效
+            let msg = DiscoveryMessage::Discover { device_id: device_id.clone(), name: handle.alias(), port }.encode();
+            let target_ips = if online || !handle.targets.is_empty() {
+                handle.broadcast_targets()
+            } else {
+                vec!["255.255.255.255".to_string()]
+            };
 
             for target_ip in target_ips {
                 let broadcast_addr = format!("{}:{}", target_ip, port);
 
-                if let Err(e) = socket.send_to(msg.as_bytes(), &broadcast_addr) {
-                    error!("发现广播失败: {:?}", e);
+                if let Err(e) = send_udp_with_retry(&socket, msg.as_bytes(), &broadcast_addr) {
+                    log_udp_send_error("发现广播", &broadcast_addr, &e);
                 } else {
                     debug!("已向 {} 发送 DISCOVER 广播", target_ip);
                 }
             }
 
-
-            thread::sleep(Duration::from_secs(5));
+            if online {
+                handle.pause(interval);
+            } else {
+                // 断网期间每秒检查一次网卡，恢复后不必等完拉长的间隔
+                let resume_at = Instant::now() + interval;
+                while Instant::now() < resume_at && list_interfaces().is_empty() && !handle.is_stopped() {
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
         }
     });
+    Ok(())
+}
+
+// 正常情况下周期广播的间隔
+const BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+// 没有可用网卡时广播间隔逐轮翻倍，最长到这个值
+const OFFLINE_BROADCAST_MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+// 根据本轮是否有可用网卡计算下一轮的广播间隔
+fn next_broadcast_interval(current: Duration, online: bool) -> Duration {
+    if online {
+        BROADCAST_INTERVAL
+    } else {
+        (current * 2).min(OFFLINE_BROADCAST_MAX_INTERVAL)
+    }
+}
+
+// 发 DISCOVER 广播用的套接字，绑定随机端口并设置 TTL
+fn broadcast_socket(bind_addr: Ipv4Addr, ttl: u32) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((bind_addr, 0))?;  // 0就是随机端口，好强
+    socket.set_broadcast(true)?;
+    socket.set_ttl(ttl)?;
+    Ok(socket)
 }
 
 pub fn send_discover_once(
@@ -149,32 +695,228 @@ pub fn send_discover_once(
     device_id: String,
     device_name: String,
 ) {
-    if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
-        socket.set_broadcast(true).ok();
-        let targets = get_target_broadcats();
+    if let Ok(socket) = broadcast_socket(Ipv4Addr::UNSPECIFIED, DEFAULT_BROADCAST_TTL) {
+        let targets = get_target_broadcats(None);
+        let msg = DiscoveryMessage::Discover { device_id, name: sanitize_device_name(&device_name), port }.encode();
         for target_ip in targets {
-            let msg = format!("DISCOVER|{}|{}|{}", device_id, device_name, port);
-            let _ = socket.send_to(msg.as_bytes(), format!("{}:{}", target_ip, port));
+            let target_addr = format!("{}:{}", target_ip, port);
+            if let Err(e) = send_udp_with_retry(&socket, msg.as_bytes(), &target_addr) {
+                log_udp_send_error("发现广播", &target_addr, &e);
+            }
+        }
+    }
+}
+
+/// 立即广播一次 DISCOVER，设备名和广播网卡取自 handle
+pub fn send_discover_once_with(port: u16, device_id: String, handle: &DiscoveryHandle) {
+    let msg = DiscoveryMessage::Discover { device_id, name: handle.alias(), port };
+    broadcast_with(port, &msg, handle, "发现广播");
+}
+
+/// 下线前广播一次 BYE，收到的设备把本机从已知设备里删掉。隐身模式下本来就没有公开自己，不发送
+pub fn send_bye(port: u16, device_id: String, handle: &DiscoveryHandle) {
+    if handle.is_invisible() {
+        return;
+    }
+    broadcast_with(port, &DiscoveryMessage::Bye { device_id }, handle, "下线广播");
+}
+
+// 按 handle 的绑定地址和网卡设置把 msg 广播到 port
+fn broadcast_with(port: u16, msg: &DiscoveryMessage, handle: &DiscoveryHandle, what: &str) {
+    if let Ok(socket) = broadcast_socket(handle.bind_addr, handle.broadcast_ttl) {
+        let msg = msg.encode();
+        for target_ip in handle.broadcast_targets() {
+            let target_addr = format!("{}:{}", target_ip, port);
+            if let Err(e) = send_udp_with_retry(&socket, msg.as_bytes(), &target_addr) {
+                log_udp_send_error(what, &target_addr, &e);
+            }
+        }
+    }
+}
+
+/// discover_devices 一直没收到回复时重发 DISCOVER 的方式
+#[derive(Clone, Copy, Debug)]
+pub struct DiscoverRetry {
+    /// 第一次之外最多再发几次，0 表示不重发
+    pub retries: u32,
+    /// 第一次重发前等待的时间，之后每次翻倍
+    pub interval: Duration,
+}
+
+impl Default for DiscoverRetry {
+    fn default() -> Self {
+        Self { retries: 3, interval: Duration::from_millis(500) }
+    }
+}
+
+/// 广播 DISCOVER 并在 timeout 内收集回复 HERE 的设备，设备名和广播网卡取自 handle。
+/// 第一次广播可能丢失，一直没有回复时按 retry 在 timeout 内重发，收到回复后不再重发。
+/// 回复发到本次查询的临时端口，不需要先启动监听
+pub fn discover_devices(
+    port: u16,
+    device_id: String,
+    handle: &DiscoveryHandle,
+    timeout: Duration,
+    retry: DiscoverRetry,
+) -> io::Result<Vec<DeviceInfo>> {
+    let socket = broadcast_socket(handle.bind_addr, handle.broadcast_ttl)?;
+    let msg = DiscoveryMessage::Discover { device_id: device_id.clone(), name: handle.alias(), port }.encode();
+    let send = || {
+        for target_ip in handle.broadcast_targets() {
+            let target_addr = format!("{}:{}", target_ip, port);
+            if let Err(e) = send_udp_with_retry(&socket, msg.as_bytes(), &target_addr) {
+                log_udp_send_error("发现广播", &target_addr, &e);
+            }
+        }
+    };
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<DeviceInfo> = Vec::new();
+    let mut sent = 0u32;
+    let mut interval = retry.interval;
+    let mut next_send = Instant::now();
+    let max_size = handle.max_packet_size;
+    let mut buf = vec![0u8; max_size + 1];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let resend = found.is_empty() && sent <= retry.retries;
+        if resend && now >= next_send {
+            send();
+            sent += 1;
+            next_send = now + interval;
+            interval *= 2;
+        }
+        let wait_until = if resend { next_send.min(deadline) } else { deadline };
+        socket.set_read_timeout(Some(wait_until.saturating_duration_since(now).max(Duration::from_millis(1))))?;
+        let (size, addr) = match recv_discovery(&socket, &mut buf, max_size) {
+            Ok(Datagram::Packet(size, addr)) => (size, addr),
+            Ok(Datagram::Oversized(addr)) => {
+                warn!("Core: 丢弃来自 {} 的超长回复 (超过 {} 字节)", addr, max_size);
+                continue;
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        let packet = match DiscoveryMessage::parse(&buf[..size]) {
+            Ok(p @ DiscoveryMessage::Here { .. }) if p.device_id() != device_id => p,
+            _ => continue,
+        };
+        let Some(device) = packet.device_info(addr) else { continue };
+        DEVICES.record(&device);
+        if !found.iter().any(|d| d.device_id == device.device_id) {
+            found.push(device);
+        }
+    }
+    if found.is_empty() {
+        info!("Core: 发送 {} 次 DISCOVER 后仍未发现设备", sent);
+    }
+    Ok(found)
+}
+
+/// 直接向已知 IP 发送一次 DISCOVER，用于广播被拦截的网络。对方回复的 HERE 会发到本机的
+/// port 端口，由 start_listening 的监听线程照常交给 on_device_found，带上完整的设备信息
+pub fn discover_unicast(ip: &str, port: u16, device_id: String, handle: &DiscoveryHandle) -> io::Result<()> {
+    let socket = UdpSocket::bind((handle.bind_addr, 0))?;
+    let msg = DiscoveryMessage::Discover { device_id, name: handle.alias(), port }.encode();
+    let target_addr = format!("{}:{}", ip, port);
+    send_udp_with_retry(&socket, msg.as_bytes(), &target_addr)
+        .inspect_err(|e| log_udp_send_error("单播发现", &target_addr, e))?;
+    debug!("已向 {} 发送单播 DISCOVER", target_addr);
+    Ok(())
+}
+
+// 瞬时发送错误的重试次数和间隔
+const UDP_SEND_RETRIES: u32 = 3;
+const UDP_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const ENOBUFS: i32 = 105;
+#[cfg(target_os = "windows")]
+const ENOBUFS: i32 = 10055; // WSAENOBUFS
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+const ENOBUFS: i32 = 55;
+
+// 发送缓冲区满、被信号打断之类的错误稍后重试即可，
+// 网络不可达、权限不足等属于永久错误，重试没有意义
+fn is_transient_send_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut
+    ) || e.raw_os_error() == Some(ENOBUFS)
+}
+
+// 对瞬时错误最多重试 UDP_SEND_RETRIES 次，send 为一次实际发送
+fn retry_transient<F: FnMut() -> io::Result<usize>>(mut send: F) -> io::Result<usize> {
+    let mut attempt = 0;
+    loop {
+        match send() {
+            Err(e) if is_transient_send_error(&e) && attempt < UDP_SEND_RETRIES => {
+                attempt += 1;
+                debug!("UDP 发送瞬时失败，第 {} 次重试: {:?}", attempt, e);
+                thread::sleep(UDP_RETRY_DELAY);
+            }
+            result => return result,
         }
     }
 }
 
+fn send_udp_with_retry(socket: &UdpSocket, buf: &[u8], addr: &str) -> io::Result<usize> {
+    retry_transient(|| socket.send_to(buf, addr))
+}
+
+fn log_udp_send_error(what: &str, addr: &str, e: &io::Error) {
+    if is_transient_send_error(e) {
+        warn!("Core: {} 至 {} 重试 {} 次后仍失败，本次丢弃: {:?}", what, addr, UDP_SEND_RETRIES, e);
+    } else {
+        error!("Core: {} 至 {} 失败 (不可恢复): {:?}", what, addr, e);
+    }
+}
+
+
+
+/// 本机一块可以广播的 IPv4 网卡
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub broadcast: Ipv4Addr,
+}
+
+impl NetworkInterface {
+    /// 网段的 CIDR 写法，例如 192.168.1.0/24
+    pub fn subnet(&self) -> String {
+        let network = u32::from(self.ip) & u32::from(self.netmask);
+        format!("{}/{}", Ipv4Addr::from(network), u32::from(self.netmask).count_ones())
+    }
 
+    /// ip 是否在这块网卡的网段内
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(ip) & mask == u32::from(self.ip) & mask
+    }
+}
 
-fn get_target_broadcats() -> Vec<String> {
-    let mut broadcasts = Vec::new();
+/// 列出所有非回环、能算出广播地址的 IPv4 网卡
+pub fn list_interfaces() -> Vec<NetworkInterface> {
+    let mut result = Vec::new();
 
     match get_if_addrs() {
         Ok(ifaces) => {
             for iface in ifaces {
                 if iface.is_loopback() { continue; }
                 if let IfAddr::V4(v4_addr) = iface.addr {
-                    let ip = v4_addr.ip;
-                    let mask = v4_addr.netmask;
-                    let broadcast = caculate_broadcast(ip, mask);
-
+                    let broadcast = caculate_broadcast(v4_addr.ip, v4_addr.netmask);
                     if !broadcast.is_unspecified() {
-                        broadcasts.push(broadcast.to_string());
+                        result.push(NetworkInterface {
+                            name: iface.name,
+                            ip: v4_addr.ip,
+                            netmask: v4_addr.netmask,
+                            broadcast,
+                        });
                     }
                 }
             }
@@ -183,6 +925,26 @@ fn get_target_broadcats() -> Vec<String> {
             error!("无法获取网络接口信息: {:?}", e);
         }
     }
+    result
+}
+
+// 按网卡名挑出广播地址；指定的网卡不存在（已断开）时退回到所有网卡
+fn select_broadcast_targets(ifaces: &[NetworkInterface], only: Option<&str>) -> Vec<String> {
+    if let Some(name) = only {
+        let selected: Vec<String> = ifaces.iter()
+            .filter(|i| i.name == name)
+            .map(|i| i.broadcast.to_string())
+            .collect();
+        if !selected.is_empty() {
+            return selected;
+        }
+        warn!("所选网卡 {} 不可用，改为在所有网卡上广播", name);
+    }
+    ifaces.iter().map(|i| i.broadcast.to_string()).collect()
+}
+
+fn get_target_broadcats(only: Option<&str>) -> Vec<String> {
+    let mut broadcasts = select_broadcast_targets(&list_interfaces(), only);
     if broadcasts.is_empty() {
         warn!("未找到有效网卡，回退到全局广播 255.255.255.255");
         broadcasts.push("255.255.255.255".to_string());
@@ -191,64 +953,556 @@ fn get_target_broadcats() -> Vec<String> {
     broadcasts
 }
 
-pub trait TransferCallback: Send + Sync {
-    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> bool;
-    fn on_progress(&self, transferred: u64, total: u64);
-    fn on_complete(&self, success: bool, msg: String);
+// 目标是否是本机（回环地址或任一网卡上的地址）
+fn is_local_address(ip: &str) -> bool {
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(ip) => ip.to_canonical(),
+        Err(_) => return false,
+    };
+    ip.is_loopback()
+        || get_if_addrs().map(|ifaces| ifaces.iter().any(|i| i.ip() == ip)).unwrap_or(false)
 }
 
-pub fn start_file_server(
-    port: u16,
-    save_dir: String,
-    callback: Box<dyn TransferCallback>,
-) {
-    let callback = Arc::new(callback);
-    let save_dir = Arc::new(save_dir);
+/// 接收端对一次发送请求的答复
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveDecision {
+    pub accept: bool,
+    /// 本次文件的保存目录，None 时使用 start_file_server 传入的 save_dir
+    pub dir: Option<PathBuf>,
+    /// 数据写到这里而不是保存目录里的文件，设置后忽略 dir
+    pub sink: Option<SharedSink>,
+}
 
-    thread::spawn(move || {
-        info!("Core: 文件传输服务启动，监听 0.0.0.0:{}", port);
-        let listener = match TcpListener::bind(format!("0.0.0.0:{}", port)) {
-            Ok(l) => l,
-            Err(e) => {
-                error!("Core: 无法绑定传输端口: {:?}", e);
-                return;
-            }
-        };
+impl ReceiveDecision {
+    pub fn accept() -> Self {
+        Self { accept: true, dir: None, sink: None }
+    }
+
+    pub fn reject() -> Self {
+        Self { accept: false, dir: None, sink: None }
+    }
+
+    pub fn accept_into(dir: impl Into<PathBuf>) -> Self {
+        Self { accept: true, dir: Some(dir.into()), sink: None }
+    }
+
+    /// 接收到自定义的接收端（管道、进程输入等），不在磁盘上创建文件
+    pub fn accept_into_sink(sink: impl ReceiveSink + 'static) -> Self {
+        Self { accept: true, dir: None, sink: Some(SharedSink::new(Box::new(sink))) }
+    }
+}
+
+/// 对方用一次请求发来的一批文件
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchRequest {
+    /// (文件名, 大小)，按发送顺序排列
+    pub files: Vec<(String, u64)>,
+    /// 目录传输中的空目录（相对路径），接收时直接创建
+    pub dirs: Vec<String>,
+}
+
+impl BatchRequest {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size).sum()
+    }
+
+    /// 用于提示的概括，如 "a.txt 等 3 个文件"
+    pub fn summary(&self) -> String {
+        match self.files.as_slice() {
+            [] => self.dirs.first().cloned().unwrap_or_else(|| "0 个文件".to_string()),
+            [(name, _)] => name.clone(),
+            [(name, _), ..] => format!("{} 等 {} 个文件", name, self.files.len()),
+        }
+    }
+}
+
+// 兼容只关心 同意/拒绝 的回调实现
+impl From<bool> for ReceiveDecision {
+    fn from(accept: bool) -> Self {
+        Self { accept, dir: None, sink: None }
+    }
+}
+
+pub trait TransferCallback: Send + Sync {
+    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> ReceiveDecision;
+    fn on_progress(&self, transferred: u64, total: u64);
+    fn on_complete(&self, success: bool, msg: String);
+
+    /// 冲突策略为 Overwrite 且目标文件已存在时调用。Some(true) 覆盖，
+    /// Some(false) 保留原文件、新文件自动改名；None（默认）按配置的策略处理
+    fn on_overwrite_confirm(&self, _existing_path: &Path) -> Option<bool> {
+        None
+    }
+
+    /// 文件没有收全，但收到的比例达到 ServerConfig::min_completion 时代替 on_complete 调用。
+    /// 默认转为 on_complete(true, ..)，消息里注明不完整
+    fn on_partial_complete(&self, file_name: String, received: u64, total: u64) {
+        self.on_complete(true, format!("{} (不完整: {}/{} 字节)", file_name, received, total));
+    }
+
+    /// 传输失败时在 on_complete(false, ..) 之前调用，给出可以按类型处理的原因，默认忽略
+    fn on_error(&self, _error: TransferError) {}
+
+    /// 对方批量发送多个文件时只询问这一次，答复对整批生效（不支持 sink）。
+    /// 默认把概括和总大小交给 on_receive_request；之后每个文件收完仍各自回调 on_complete
+    fn on_batch_request(&self, batch: &BatchRequest, sender_ip: String) -> ReceiveDecision {
+        self.on_receive_request(batch.summary(), batch.total_size(), sender_ip)
+    }
+}
+
+/// 发送失败的原因
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    /// 发送过程中源文件的大小或修改时间变了，已中止，避免对方收到拼接错乱的文件
+    FileChanged,
+    /// 超过了 max_duration 设置的时限，不论进度如何都已中止
+    Timeout,
+    /// 收完的文件与 ServerConfig::expected_checksums 登记的 SHA-256 不符
+    ChecksumMismatch,
+    /// 发送被 CancelToken 或 cancel_all_transfers 取消
+    Cancelled,
+    /// 其他错误，附带说明
+    Failed(String),
+}
+
+impl TransferError {
+    // send_chunk 返回的 io 错误，源文件被修改时里面包着 FileChanged
+    fn from_io(e: io::Error) -> Self {
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<TransferError>()) {
+            Some(inner) => inner.clone(),
+            None => TransferError::Failed(e.to_string()),
+        }
+    }
+}
+
+impl From<String> for TransferError {
+    fn from(msg: String) -> Self {
+        TransferError::Failed(msg)
+    }
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::FileChanged => write!(f, "发送过程中文件被修改，已中止"),
+            TransferError::Timeout => write!(f, "传输超过时限，已中止"),
+            TransferError::ChecksumMismatch => write!(f, "文件校验和与预期不符"),
+            TransferError::Cancelled => write!(f, "传输已取消"),
+            TransferError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// 并行连接数的默认上限，send_file 等不带 SendOptions 的接口也按这个值限制
+pub const DEFAULT_MAX_PARALLEL: u64 = 16;
+
+/// 发送参数
+#[derive(Clone, Debug)]
+pub struct SendOptions {
+    /// 并行线程数，建议 4-8
+    pub parallel_cnt: u64,
+    /// parallel_cnt 的上限，超过时按上限处理并记录日志。每条并行连接都占一个文件描述符
+    /// 和一个临时端口，数量过大时连接会因资源耗尽而失败，拖垮整个传输
+    pub max_parallel: u64,
+    /// 多文件发送时复用一组长连接，每个文件只发一个轻量的头，不再重新握手建连
+    pub reuse_connections: bool,
+    /// 自适应并行：从一条连接开始，按实测吞吐逐步增减，parallel_cnt 作为上限
+    pub adaptive: bool,
+    /// 整次发送（多文件时为整批）最长允许的时间，超过后不论进度直接中止并回调
+    /// on_error(Timeout)；None 表示不限制
+    pub max_duration: Option<Duration>,
+    /// 多文件发送时先发一份清单，对方只确认一次，之后在一条连接上依次发送各个文件。
+    /// 优先于 reuse_connections，parallel_cnt 不起作用
+    pub batch: bool,
+    /// send_directory 遍历目录时遇到符号链接的处理方式
+    pub symlinks: SymlinkPolicy,
+    /// 单文件并行发送时每个分片每隔这么多字节插入一次校验检查点，接收方边收边核对，
+    /// 第一个对不上的检查点就中止，适合丢包、出错多的链路。小于 MIN_CHECKPOINT_INTERVAL 时按下限处理；
+    /// None 表示不插入。对方必须支持检查点
+    pub checkpoint_interval: Option<u64>,
+    /// send_directory 时请求续传：对方上次已经完整收到的文件直接跳过，收了一部分的从断点接着发。
+    /// 对方按清单里附带的修改时间和 SHA-256 核对，源文件改过的照样整个重发；为此发送前要把每个文件读一遍。
+    /// 第一次发送也要设置，对方才会记录续传进度；对方不支持时照常整个目录重发
+    pub resume: bool,
+    /// 用来单独取消这一次发送，取消后回调 on_error(Cancelled)；None 时只能用 cancel_all_transfers 取消
+    pub cancel_token: Option<CancelToken>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            parallel_cnt: 4,
+            max_parallel: DEFAULT_MAX_PARALLEL,
+            reuse_connections: false,
+            adaptive: false,
+            max_duration: None,
+            batch: false,
+            symlinks: SymlinkPolicy::Skip,
+            checkpoint_interval: None,
+            resume: false,
+            cancel_token: None,
+        }
+    }
+}
+
+impl SendOptions {
+    fn effective_parallel(&self) -> u64 {
+        clamp_parallel(self.parallel_cnt, self.max_parallel)
+    }
+}
+
+// 把并行数限制在 [1, max] 之内，超出上限时记录日志
+fn clamp_parallel(parallel_cnt: u64, max: u64) -> u64 {
+    let max = max.max(1);
+    if parallel_cnt > max {
+        warn!("Core: 并行数 {} 超过上限 {}，按 {} 处理", parallel_cnt, max, max);
+        return max;
+    }
+    parallel_cnt.max(1)
+}
+
+/// 发送进度句柄，按分片记录已发送的字节数，UI 可以据此分别显示每条并行连接的进度
+#[derive(Clone)]
+pub struct SendHandle {
+    chunks: Arc<Vec<AtomicU64>>,
+}
+
+impl SendHandle {
+    fn new(parallel_cnt: u64) -> Self {
+        Self {
+            chunks: Arc::new((0..parallel_cnt.max(1)).map(|_| AtomicU64::new(0)).collect()),
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// 各分片已发送的字节数，下标即分片序号
+    pub fn chunk_progress(&self) -> Vec<u64> {
+        self.chunks.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    /// 所有分片已发送的字节数之和
+    pub fn total_sent(&self) -> u64 {
+        self.chunks.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+}
+
+/// 接收到的文件与已有文件重名时的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// 直接覆盖已有文件
+    Overwrite,
+    /// 自动改名为 `name (1).ext`、`name (2).ext` ...
+    Rename,
+}
+
+/// 文件服务参数
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub conflict_policy: ConflictPolicy,
+    /// 每条数据连接写盘缓冲的上限（字节）。设置后由后台线程写盘，
+    /// 缓冲满时停止读 socket；None 表示收到即写
+    pub write_buffer_cap: Option<usize>,
+    /// 保存目录剩余空间至少为文件大小的多少倍才交给回调决定，否则直接回复 REJ|LowSpace；
+    /// None 表示不检查
+    pub free_space_ratio: Option<f64>,
+    /// 处理连接的固定线程数，连接多于线程时排队等待；
+    /// None 表示每个连接单独开一个线程。MUX 长连接会一直占用一个线程，
+    /// 线程数应不小于发送方的并行数
+    pub worker_threads: Option<usize>,
+    /// 设置后只接收已配对设备的连接，并处理 PAIR 配对请求；None 表示不要求配对
+    pub pairing: Option<Arc<PairingStore>>,
+    /// 数据连接全部关闭时文件没有收全，但收到的字节数不少于文件大小的这个比例，
+    /// 且 partial_idle_timeout 内没有新的数据连接，就按部分成功结束（on_partial_complete），
+    /// 缺的部分保持预分配的零。默认 1.0，即必须完整
+    pub min_completion: f64,
+    /// 没收全的文件在最后一条数据连接关闭后还要等多久才按部分完成结束。
+    /// 分片连接有先有后，自适应模式每块数据单独开连接，连接数中途归零不代表发送方已经发完
+    pub partial_idle_timeout: Duration,
+    /// 从接受请求算起，一个文件最长允许接收多久，超过后不论进度直接中止并回调
+    /// on_error(Timeout)；None 表示不限制
+    pub max_duration: Option<Duration>,
+    /// 连接建立后必须在这段时间内发来第一行消息头，否则被强制关闭，
+    /// 避免端口扫描之类的空连接一直占着处理线程；None 表示不限制
+    pub header_timeout: Option<Duration>,
+    /// 设置后，登记过预期 SHA-256 的文件收完时在本地重新计算并比对，不符则回调
+    /// on_error(ChecksumMismatch) 并按失败结束（文件保留在磁盘上）。
+    /// 部分完成的文件和交给回调接收端的数据不做校验；None 表示不校验
+    pub expected_checksums: Option<Arc<ExpectedChecksums>>,
+    /// 希望发送方使用的最小分片大小（字节），在 ACC 中告知发送方。发送方会减少并行连接，
+    /// 让每个分片不小于这个值，连续写入大块数据，适合随机写很慢的 SD 卡之类的存储；
+    /// None 表示由发送方决定
+    pub preferred_chunk_size: Option<u64>,
+    /// 写入保存目录前对数据做的变换，None 表示原样写入。
+    /// 配置后发送方改用单条连接顺序发送，收完不核对文件长度和 expected_checksums
+    pub filter: Option<Arc<dyn TransferFilter>>,
+    /// 接受不带传输编号（tid=）的单独 DATA 连接，供还不会带编号的旧版本发送方使用。
+    /// 只认当前已接受、尚未收完的同名文件；默认 false，这类连接一律丢弃
+    pub allow_untagged_data: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            conflict_policy: ConflictPolicy::Overwrite,
+            write_buffer_cap: None,
+            free_space_ratio: None,
+            worker_threads: None,
+            pairing: None,
+            min_completion: 1.0,
+            partial_idle_timeout: Duration::from_secs(5),
+            max_duration: None,
+            header_timeout: Some(Duration::from_secs(10)),
+            expected_checksums: None,
+            preferred_chunk_size: None,
+            filter: None,
+            allow_untagged_data: false,
+        }
+    }
+}
+
+// REQ 中表示大小未知的占位符
+const UNKNOWN_SIZE: &str = "?";
+// REQ 第四段带上这个标记表示发送方只用一条连接顺序写入，接收方不用预分配
+const SEQUENTIAL: &str = "seq";
+// ACC 第三段以这个前缀开头时，后面是接收方希望的最小分片大小
+const CHUNK_PREFIX: &str = "chunk=";
+// ACC 和 DATA 末尾以这个前缀开头的一段是接收方分配的传输编号
+const TRANSFER_ID_PREFIX: &str = "tid=";
+// DATA 中以这个前缀开头的一段是检查点间隔，数据流里每隔这么多字节插入一次累计 SHA-256
+const CHECKPOINT_PREFIX: &str = "ck=";
+// DIR 第三段带上这个标记表示请求续传，也用作 SendOptions::resume 的协议标记
+const RESUME: &str = "resume";
+// 一次批量请求最多包含的文件数
+const MAX_BATCH_FILES: usize = 10000;
+// DIR 清单中空目录一行的 size 段
+const EMPTY_DIR: &str = "dir";
+
+// 文件服务各连接线程共享的状态
+struct FileServer {
+    save_dir: String,
+    config: ServerConfig,
+    callback: Box<dyn TransferCallback>,
+    // 已接受的文件（按最终文件名索引），DATA 连接据此找到回调指定的目录。
+    // 进度按文件分别统计，同时接收多个文件（包括发给自己）时互不干扰
+    accepted: Mutex<HashMap<String, AcceptedFile>>,
+    // 按 header_timeout 关闭迟迟不发头部的连接，serve_connection 不使用
+    reaper: Option<Arc<IdleReaper>>,
+}
+
+// 传输编号，每次接受请求时分配，所有文件服务共用，不会重复
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+struct AcceptedFile {
+    // 传输编号，在 ACC 中告知发送方，DATA 带着它才能写入这个文件。
+    // 同名文件被新的传输覆盖登记后，旧传输的分片就对不上了
+    id: u64,
+    path: PathBuf,
+    total: u64,
+    received: u64,
+    // 正在写这个文件的 DATA 连接数
+    connections: u32,
+    // 没收全时最后一条 DATA 连接关闭的时间，有新连接时清空
+    idle_since: Option<Instant>,
+    // 已经回调过完成（或部分完成），之后到达的数据不再回调
+    finished: bool,
+    // 回调指定的接收端，完成后释放，让管道另一头读到 EOF
+    sink: Option<SharedSink>,
+    // 接受请求的时间，用于 max_duration
+    started: Instant,
+    // 发送方 IP，用于记录设备的最近传输结果
+    peer: String,
+    // 接受请求时登记的预期 SHA-256，收完后比对
+    expected_sha256: Option<[u8; 32]>,
+    // 区段同步（PATCH）：写入已有文件，total 只是区段字节数，收完不检查文件长度
+    patch: bool,
+    // 数据经过 ServerConfig::filter 变换后写入，文件长度与 total 无关，收完不做核对
+    filtered: bool,
+    // 属于目录传输时记录续传进度的文件，收完后在里面记一笔
+    progress: Option<PathBuf>,
+}
+
+impl FileServer {
+    // 回调 on_complete，同时记下发送方设备的最近一次传输结果。
+    // 结束的传输随之移除登记，之后到达的 DATA 对不上任何传输，长期运行时登记也不会越积越多
+    fn complete(&self, filename: &str, success: bool, msg: String) {
+        self.record_result(filename, success);
+        lock(&self.accepted).remove(filename);
+        self.callback.on_complete(success, msg);
+    }
+
+    // 回调 on_partial_complete，同样记下结果并移除登记
+    fn partial_complete(&self, filename: &str, received: u64, total: u64) {
+        METRICS.transfer_received();
+        self.record_result(filename, true);
+        lock(&self.accepted).remove(filename);
+        self.callback.on_partial_complete(filename.to_string(), received, total);
+    }
+
+    fn record_result(&self, filename: &str, success: bool) {
+        let peer = lock(&self.accepted).get(filename).map(|f| f.peer.clone());
+        if let Some(peer) = peer.filter(|p| !p.is_empty()) {
+            DEVICES.record_transfer(&peer, TransferDirection::Received, success);
+        }
+    }
+}
+
+pub fn start_file_server(
+    port: u16,
+    save_dir: String,
+    callback: Box<dyn TransferCallback>,
+) {
+    start_file_server_with_config(port, save_dir, callback, ServerConfig::default())
+}
+
+pub fn start_file_server_with_config(
+    port: u16,
+    save_dir: String,
+    callback: Box<dyn TransferCallback>,
+    config: ServerConfig,
+) {
+    if let Err(e) = try_start_file_server_with_config(port, save_dir, callback, config) {
+        error!("Core: 无法绑定传输端口: {}", e);
+    }
+}
+
+/// 与 start_file_server_with_config 相同，但在当前线程完成绑定，失败时返回原因。
+/// 传输端口是 TCP 端口，可以和发现服务（UDP）使用同一个端口号
+pub fn try_start_file_server_with_config(
+    port: u16,
+    save_dir: String,
+    callback: Box<dyn TransferCallback>,
+    config: ServerConfig,
+) -> Result<(), StartError> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).map_err(StartError::from_io)?;
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or(port);
+    let (server, _) = serve_on(listener, save_dir, callback, config, Arc::new(AtomicBool::new(false)));
+    register_local_server(port, &server);
+    Ok(())
+}
+
+// 本进程启动的文件服务，按端口登记。发给本机这些端口的文件不经过网络，直接交给对应的服务接收
+static LOCAL_SERVERS: Mutex<Vec<(u16, Weak<FileServer>)>> = Mutex::new(Vec::new());
+
+fn register_local_server(port: u16, server: &Arc<FileServer>) {
+    let mut servers = lock(&LOCAL_SERVERS);
+    servers.retain(|(p, s)| *p != port && s.strong_count() > 0);
+    servers.push((port, Arc::downgrade(server)));
+}
+
+// 本进程在 port 上的文件服务，已经停止的顺便清掉
+fn local_server(port: u16) -> Option<Arc<FileServer>> {
+    let mut servers = lock(&LOCAL_SERVERS);
+    servers.retain(|(_, s)| s.strong_count() > 0);
+    servers.iter().find(|(p, _)| *p == port).and_then(|(_, s)| s.upgrade())
+}
+
+// 在已绑定的 listener 上启动文件服务线程，返回服务状态和线程句柄。
+// stop 置位后接受的下一条连接让线程退出，监听端口随之关闭（自检用它收尾）
+fn serve_on(
+    listener: TcpListener,
+    save_dir: String,
+    callback: Box<dyn TransferCallback>,
+    config: ServerConfig,
+    stop: Arc<AtomicBool>,
+) -> (Arc<FileServer>, thread::JoinHandle<()>) {
+    let server = Arc::new(FileServer {
+        save_dir,
+        reaper: config.header_timeout.map(IdleReaper::start),
+        config,
+        callback,
+        accepted: Mutex::new(HashMap::new()),
+    });
 
-        let progress_counter = Arc::new(Mutex::new(0u64));
-        let current_file_size = Arc::new(Mutex::new(0u64));
+    let serving = server.clone();
+    let thread = thread::spawn(move || {
+        let server = serving;
+        let addr = listener.local_addr().map(|a| a.to_string()).unwrap_or_default();
+        info!("Core: 文件传输服务启动，监听 {}", addr);
+
+        let pool = server.config.worker_threads.map(|n| {
+            info!("Core: 使用 {} 个工作线程处理连接", n.max(1));
+            ThreadPool::with_name("file-server".into(), n.max(1))
+        });
 
         for stream in listener.incoming() {
+            if stop.load(Ordering::Relaxed) {
+                info!("Core: 文件传输服务在 {} 上停止", addr);
+                break;
+            }
             match stream {
-                Ok(mut socket) => {
-                    let callback = callback.clone();
-                    let save_dir = save_dir.clone();
-                    let progress = progress_counter.clone();
-                    let total_size_store = current_file_size.clone();
-
-                    thread::spawn(move || {
-                        handle_incoming_connection(socket, save_dir, callback, progress, total_size_store);
-                    });
+                Ok(socket) => {
+                    let server = server.clone();
+                    let job = move || handle_incoming_connection(socket, server);
+                    match &pool {
+                        Some(pool) => pool.execute(job),
+                        None => {
+                            thread::spawn(job);
+                        }
+                    }
                 }
                 Err(e) => error!("Core: 连接接收失败: {:?}", e),
             }
         }
     });
+    (server, thread)
 }
 
-fn handle_incoming_connection(
-    mut socket: TcpStream,
-    save_dir: Arc<String>,
-    callback: Arc<Box<dyn TransferCallback>>,
-    progress_counter: Arc<Mutex<u64>>,
-    total_size_store: Arc<Mutex<u64>>,
-) {
-    let mut buf = [0u8; 1024];
-    let bytes_read = match socket.peek(&mut buf) {
-        Ok(n) => n,
-        Err(_) => return,
-    };
+// 发送方的配对身份，设置后每条传输连接开头都带上 AUTH 行
+static PAIRING_IDENTITY: Mutex<Option<Arc<PairingStore>>> = Mutex::new(None);
+
+/// 设置发送时使用的配对身份，None 表示不认证（对方要求配对时会被拒绝）
+pub fn set_pairing_identity(store: Option<Arc<PairingStore>>) {
+    *lock(&PAIRING_IDENTITY) = store;
+}
+
+// 对方有多个地址时，每个地址的连接超时
+const ADDRESS_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// 建立到接收方的传输连接，设置了配对身份时先发送认证行
+fn connect_peer(ip: &str, port: u16) -> io::Result<CountingStream<TcpStream>> {
+    let mut stream = CountingStream::new(connect_any_address(ip, port)?);
+    write_auth(&mut stream)?;
+    Ok(stream)
+}
+
+// ip 所属设备有多个已知地址时按 connect_candidates 的顺序逐个尝试，返回第一个连上的
+fn connect_any_address(ip: &str, port: u16) -> io::Result<TcpStream> {
+    let candidates = DEVICES.connect_candidates(ip);
+    if candidates.len() <= 1 {
+        return TcpStream::connect(format!("{}:{}", ip, port));
+    }
+    let mut last_error = None;
+    for candidate in &candidates {
+        let result = match candidate.parse::<IpAddr>() {
+            Ok(addr) => TcpStream::connect_timeout(&SocketAddr::new(addr, port), ADDRESS_CONNECT_TIMEOUT),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "无效的地址")),
+        };
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                warn!("Core: 无法通过 {}:{} 连接对方: {:?}", candidate, port, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "没有可用的地址")))
+}
+
+fn write_auth<W: Write>(stream: &mut W) -> io::Result<()> {
+    let identity = lock(&PAIRING_IDENTITY).clone();
+    if let Some(store) = identity {
+        stream.write_all(store.auth_line().as_bytes())?;
+    }
+    Ok(())
+}
 
+// 逐字节读取一行头部（不含 '\n'），连接关闭或出错返回 None
+fn read_header_line<R: Read>(socket: &mut R) -> Option<String> {
     let mut header_buf = Vec::new();
     let mut char_buf = [0u8; 1];
     loop {
@@ -256,208 +1510,3856 @@ fn handle_incoming_connection(
             if char_buf[0] == b'\n' { break; }
             header_buf.push(char_buf[0]);
         } else {
-            return;
+            return None;
         }
     }
+    Some(String::from_utf8_lossy(&header_buf).into_owned())
+}
 
-    let header_str = String::from_utf8_lossy(&header_buf);
-    let parts: Vec<&str> = header_str.split('|').collect();
-
-    if parts[0] == "REQ" && parts.len() >= 3 {
-        let filename = parts[1];
-        let size: u64 = parts[2].parse().unwrap_or(0);
-        let sender_ip = socket.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
-
-        if callback.on_receive_request(filename.to_string(), size, sender_ip) {
-            let path = Path::new(save_dir.as_str()).join(filename);
-            if let Ok(file) = File::create(&path) {
-                if let Err(e) = file.set_len(size) {
-                    error!("无法预分配文件大小: {:?}", e);
-                }
-                if let Ok(mut t) = total_size_store.lock() { *t = size; }
-                if let Ok(mut p) = progress_counter.lock() { *p = 0; }
+// 解析后的 TCP 消息头
+enum Header<'a> {
+    // REQ|name|size[|seq]，size 为 None 表示大小未知
+    Req { name: &'a str, size: Option<u64>, sequential: bool },
+    // DATA|name|offset[|len][|tid=N][|ck=N]，len 只在 MUX 会话里使用，tid 为接收方在 ACC 中分配的传输编号，
+    // ck 为检查点间隔
+    Data { name: &'a str, offset: u64, len: Option<u64>, id: Option<u64>, checkpoint: Option<u64> },
+    Mux,
+    // BATCH|count 或 DIR|count[|resume]，后面跟 count 行 name|size 清单。
+    // DIR 的 name 是目录传输的相对路径，接收方保留目录结构；size 为 dir 的行是空目录。
+    // 带 resume 时接收方按上次的进度续传
+    Batch { count: usize, keep_paths: bool, resume: bool },
+    // PING|port，请接收方反向连接发起方的 port，用于连通性诊断
+    Ping { port: u16 },
+    // PATCH|name|size，把 size 字节的区段写入接收方已有的 name，不截断
+    Patch { name: &'a str, size: u64 },
+}
 
-                let _ = socket.write_all(b"ACC\n"); // Accept
+// 解析一行消息头，任何输入都有结果：失败时返回发给对方的错误原因
+fn parse_header(line: &str) -> Result<Header<'_>, &'static str> {
+    let line = line.trim_end_matches(['\r', ' ', '\t']);
+    if line.trim().is_empty() {
+        return Err("EmptyHeader");
+    }
+    let parts: Vec<&str> = line.split('|').collect();
+    match parts[0] {
+        "REQ" if parts.len() >= 3 => {
+            let size = if parts[2] == UNKNOWN_SIZE {
+                None
             } else {
-                let _ = socket.write_all(b"REJ|CreateFileErr\n");
+                Some(parts[2].parse().map_err(|_| "BadHeader")?)
+            };
+            Ok(Header::Req { name: parts[1], size, sequential: parts.get(3) == Some(&SEQUENTIAL) })
+        }
+        "DATA" if (3..=6).contains(&parts.len()) => {
+            let offset = parts[2].parse().map_err(|_| "BadHeader")?;
+            let (mut len, mut id, mut checkpoint) = (None, None, None);
+            // 可选段的顺序固定为 len、tid=、ck=，各至多一次。检查点间隔不能小于下限，
+            // 按下限改小的话与发送方插入检查点的位置对不上，只能拒绝
+            for field in &parts[3..] {
+                if let Some(n) = field.strip_prefix(CHECKPOINT_PREFIX)
+                    && checkpoint.is_none()
+                {
+                    checkpoint = Some(n.parse().ok().filter(|&n| n >= MIN_CHECKPOINT_INTERVAL).ok_or("BadHeader")?);
+                } else if let Some(n) = field.strip_prefix(TRANSFER_ID_PREFIX)
+                    && id.is_none()
+                    && checkpoint.is_none()
+                {
+                    id = Some(n.parse().map_err(|_| "BadHeader")?);
+                } else if len.is_none() && id.is_none() && checkpoint.is_none() {
+                    len = Some(field.parse().map_err(|_| "BadHeader")?);
+                } else {
+                    return Err("BadHeader");
+                }
             }
-        } else {
-            let _ = socket.write_all(b"REJ\n"); // Reject
+            Ok(Header::Data { name: parts[1], offset, len, id, checkpoint })
         }
+        "MUX" => Ok(Header::Mux),
+        "BATCH" | "DIR" if parts.len() >= 2 => match parts[1].parse() {
+            Ok(count) if (1..=MAX_BATCH_FILES).contains(&count) => {
+                let keep_paths = parts[0] == "DIR";
+                Ok(Header::Batch { count, keep_paths, resume: keep_paths && parts.get(2) == Some(&RESUME) })
+            }
+            _ => Err("BadHeader"),
+        },
+        "PING" if parts.len() >= 2 => Ok(Header::Ping { port: parts[1].parse().map_err(|_| "BadHeader")? }),
+        "PATCH" if parts.len() == 3 => {
+            Ok(Header::Patch { name: parts[1], size: parts[2].parse().map_err(|_| "BadHeader")? })
+        }
+        "REQ" | "DATA" | "BATCH" | "DIR" | "PING" | "PATCH" => Err("BadHeader"),
+        _ => Err("UnknownType"),
+    }
+}
 
-    } else if parts[0] == "DATA" && parts.len() >= 3 {
-        let filename = parts[1];
-        let offset: u64 = parts[2].parse().unwrap_or(0);
-
-        let path = Path::new(save_dir.as_str()).join(filename);
+// 无法处理的消息头统一回 ERR|原因，让对方立刻知道失败原因而不是等到超时
+fn reply_error<W: Write>(socket: &mut W, header: &str, reason: &str) {
+    warn!("收到无效的消息头 ({}): {:?}", reason, header);
+    let _ = socket.write_all(format!("ERR|{}\n", reason).as_bytes());
+}
 
-        let mut file = match OpenOptions::new().write(true).open(&path) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("无法打开文件写入数据: {:?}", e);
-                return;
-            }
-        };
+fn handle_incoming_connection(socket: TcpStream, server: Arc<FileServer>) {
+    // 有时限时读操作也不能无限阻塞，否则卡住的连接永远等不到检查时限的机会
+    if let Some(max) = server.config.max_duration {
+        socket.set_read_timeout(Some(max)).ok();
+    }
+    let peer = socket.peer_addr().map(|a| a.ip().to_canonical().to_string()).unwrap_or_default();
 
-        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-            error!("Seek失败: {:?}", e);
-            return;
+    guard_callback_panic(&peer, || {
+        // 第一行头部到达之前登记给回收线程，超时会被关闭，读操作随之返回 None
+        let guard = server.reaper.as_ref().and_then(|r| r.watch(&socket, &peer));
+        let mut socket = CountingStream::new(socket);
+        let header = read_header_line(&mut socket);
+        drop(guard);
+        if let Some(header) = header {
+            dispatch_header(socket, header, &peer, &server);
         }
+    });
+}
 
-        let mut buffer = [0u8; 64 * 1024];
-        let mut last_progress_update = 0u64;
-        loop {
-            match socket.read(&mut buffer) {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    if let Err(e) = file.write_all(&buffer[..n]) {
-                        error!("写入文件失败: {:?}", e);
-                        break;
-                    }
+// 回调由使用者实现，可能 panic（FFI/JNI 桥接里尤其容易出现）。在这里截住，
+// 只中止这一条连接上的传输并记录日志，panic 不会带走线程池的工作线程，也不会继续展开到 FFI 调用方。
+// 共享的 Mutex 都经 lock 取用，即使因此中毒也能继续使用。返回 f 是否正常结束
+fn guard_callback_panic<F: FnOnce()>(peer: &str, f: F) -> bool {
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    if let Err(payload) = &result {
+        let reason = payload.downcast_ref::<&str>().copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("未知原因");
+        error!("Core: 处理 {} 的连接时发生 panic（多半来自回调），已中止该连接: {}", peer, reason);
+        METRICS.error();
+    }
+    result.is_ok()
+}
 
-                    let mut p = progress_counter.lock().unwrap();
-                    *p += n as u64;
-                    let current_total = *p;
-                    drop(p);
+/// 在调用方已经建立好的连接（SSH 端口转发、中继、自定义通道等）上按接收端处理一次会话，
+/// 会话结束后返回。单条连接上发送方应使用 send_file_over，它走 MUX 会话，可以连续发多个文件。
+/// 回调里的 sender_ip 为空字符串
+pub fn serve_connection<S: Read + Write>(
+    stream: S,
+    save_dir: String,
+    callback: Box<dyn TransferCallback>,
+    config: ServerConfig,
+) {
+    let server = Arc::new(FileServer {
+        save_dir,
+        config,
+        callback,
+        accepted: Mutex::new(HashMap::new()),
+        reaper: None,
+    });
+    guard_callback_panic("", || serve_stream(stream, "", &server));
+}
 
-                    let total = *total_size_store.lock().unwrap();
+// 读消息头并分派，peer 是对方 IP，用于回调
+fn serve_stream<S: Read + Write>(mut socket: S, peer: &str, server: &Arc<FileServer>) {
+    if let Some(header) = read_header_line(&mut socket) {
+        dispatch_header(socket, header, peer, server);
+    }
+}
 
-                    if current_total - last_progress_update > 1024 * 1024 || current_total == total {
-                        callback.on_progress(current_total, total);
-                        last_progress_update = current_total;
-                    }
+// 按第一行消息头分派到各个处理流程
+fn dispatch_header<S: Read + Write>(mut socket: S, mut header_str: String, peer: &str, server: &Arc<FileServer>) {
+    if header_str.starts_with("PAIR|") {
+        let result = match &server.config.pairing {
+            Some(store) => store.accept_pairing(&mut socket, &header_str),
+            None => Err("PairingDisabled"),
+        };
+        if let Err(reason) = result {
+            reply_error(&mut socket, &header_str, reason);
+        }
+        return;
+    }
 
-                    if current_total >= total && total > 0 {
-                        // 注意：这里可能会被多个线程触发，实际应该加状态判断
-                        // 但为了简单，多调一次 on_complete 问题不大，Java端防抖即可
-                        callback.on_complete(true, filename.to_string());
-                    }
+    // 对方带了认证行：要求配对时校验，不要求时忽略，之后才是真正的消息头
+    if header_str.starts_with("AUTH|") {
+        if let Some(store) = &server.config.pairing
+            && let Err(reason) = store.verify_auth(&header_str)
+        {
+            METRICS.reject();
+            return reply_error(&mut socket, &header_str, reason);
+        }
+        header_str = match read_header_line(&mut socket) {
+            Some(h) => h,
+            None => return,
+        };
+    } else if server.config.pairing.is_some() {
+        METRICS.reject();
+        return reply_error(&mut socket, &header_str, "NotPaired");
+    }
 
-                }
-                Err(_) => break,
+    match parse_header(&header_str) {
+        Ok(Header::Req { name, size, sequential }) => {
+            let accepted = handle_request(&mut socket, server, peer, name, size, sequential);
+            // 大小未知（管道/标准输入）时数据紧跟在握手之后，走同一条连接
+            if let Some(final_name) = accepted
+                && size.is_none()
+            {
+                handle_stream_body(&mut socket, server, &final_name);
+            }
+        }
+        Ok(Header::Data { name, offset, id, checkpoint, .. }) => {
+            handle_data(&mut socket, server, name, offset, id, checkpoint)
+        }
+        Ok(Header::Mux) => handle_mux_session(socket, peer, server, Vec::new()),
+        // 整批同意后数据按 MUX 帧依次到达
+        Ok(Header::Batch { count, keep_paths, resume }) => {
+            if let Some(accepted) = handle_batch(&mut socket, server, peer, count, keep_paths, resume) {
+                handle_mux_session(socket, peer, server, accepted);
             }
         }
+        Ok(Header::Ping { port }) => connectivity::answer_ping(&mut socket, peer, port),
+        Ok(Header::Patch { name, size }) => regions::handle_patch(&mut socket, server, peer, name, size),
+        Err(reason) => reply_error(&mut socket, &header_str, reason),
     }
 }
 
-pub fn send_file(
-    target_ip: String,
-    port: u16,
-    file_path: String,
-    parallel_cnt: u64, // 并行线程数，建议 4-8
-    callback: Box<dyn TransferCallback> // 用于回传发送进度
-) {
-    thread::spawn(move || {
-        let path = Path::new(&file_path);
-        if !path.exists() {
-            callback.on_complete(false, "文件不存在".into());
-            return;
-        }
+// 按冲突策略创建目标文件，返回文件和最终使用的文件名。
+// Rename 策略下用 create_new 逐个尝试候选名，检查和创建是同一个原子操作，
+// 并发接收同名文件时每个请求都会拿到不同的名字。
+fn create_target_file(dir: &Path, filename: &str, policy: ConflictPolicy) -> io::Result<(File, String)> {
+    if policy == ConflictPolicy::Overwrite {
+        return File::create(dir.join(filename)).map(|f| (f, filename.to_string()));
+    }
 
-        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
-        let file_len = path.metadata().unwrap().len();
+    let (stem, ext) = match filename.rfind('.') {
+        Some(i) if i > 0 => (&filename[..i], &filename[i..]),
+        _ => (filename, ""),
+    };
 
-        // 1. 发送握手请求 (REQ)
-        let mut stream = match TcpStream::connect(format!("{}:{}", target_ip, port)) {
-            Ok(s) => s,
-            Err(e) => {
-                callback.on_complete(false, format!("连接失败: {:?}", e));
-                return;
-            }
+    for i in 0..10000u32 {
+        let candidate = if i == 0 {
+            filename.to_string()
+        } else {
+            format!("{} ({}){}", stem, i, ext)
         };
+        match OpenOptions::new().write(true).create_new(true).open(dir.join(&candidate)) {
+            Ok(f) => return Ok((f, candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "同名文件过多"))
+}
+
+// 只保留对方文件名的最后一段，防止 "../" 或绝对路径写到保存目录之外
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let normalized = name.replace('\\', "/");
+    let last = normalized.rsplit('/').next()?.trim();
+    if last.is_empty() || last == "." || last == ".." {
+        return None;
+    }
+    Some(last.to_string())
+}
 
-        let req_msg = format!("REQ|{}|{}\n", file_name, file_len);
-        let _ = stream.write_all(req_msg.as_bytes());
+// 目录传输的相对路径，统一成 '/' 分隔并去掉空段。
+// 带 "."、".." 或盘符的路径可能跳出保存目录，整条拒绝
+fn sanitize_relative_path(name: &str) -> Option<String> {
+    let parts: Vec<&str> = name.split(['/', '\\']).map(str::trim).filter(|p| !p.is_empty()).collect();
+    if parts.is_empty() || parts.iter().any(|p| *p == "." || *p == ".." || p.contains(':')) {
+        return None;
+    }
+    Some(parts.join("/"))
+}
 
-        // 等待响应
-        let mut resp_buf = [0u8; 1024];
-        let n = stream.read(&mut resp_buf).unwrap_or(0);
-        let response = String::from_utf8_lossy(&resp_buf[..n]);
+// path 解析符号链接后是否仍在 root 之内
+fn is_within(root: &Path, path: &Path) -> bool {
+    match (fs::canonicalize(root), fs::canonicalize(path)) {
+        (Ok(root), Ok(path)) => path.starts_with(root),
+        _ => false,
+    }
+}
 
-        if !response.starts_with("ACC") {
-            callback.on_complete(false, "对方拒绝接收".into());
-            return;
+// path 创建出来后是否会在 root 之内：按它已经存在的最近一级判断，还不存在的各级来自清理过的相对路径。
+// 创建目录之前调用，指向别处的符号链接在创建任何东西之前就被拦下
+fn will_be_within(root: &Path, path: &Path) -> bool {
+    let mut existing = path;
+    while fs::symlink_metadata(existing).is_err() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return false,
         }
+    }
+    is_within(root, existing)
+}
 
-        drop(stream); // 关闭握手连接
-
-        // 2. 计算分片并并行发送
-        let chunk_size = file_len / parallel_cnt;
-        let mut handles = vec![];
-        let progress = Arc::new(Mutex::new(0u64));
-        // 使用原子布尔值标记是否有线程出错，任何一个线程出错则整体失败
-        let error_occurred = Arc::new(std::sync::atomic::AtomicBool::new(false));
+// 剩余空间是否不少于 size * ratio，查询失败时不拦截
+fn has_free_space(dir: &Path, size: u64, ratio: f64) -> bool {
+    match fs2::available_space(dir) {
+        Ok(free) => free as f64 >= size as f64 * ratio,
+        Err(e) => {
+            warn!("Core: 无法查询 {:?} 的剩余空间: {:?}", dir, e);
+            true
+        }
+    }
+}
 
-        info!("Core: 开始并行传输，线程数: {}", parallel_cnt);
+// 处理 REQ：询问回调，同意则创建文件，成功时返回最终文件名。
+// 并行分片会按偏移乱序写入，需要先预分配；顺序写入的文件随数据增长即可。
+// 发送方收到 ACC 就会开分片连接，DATA 可能由别的线程先处理，
+// 所以登记和预分配必须在回 ACC 之前完成
+fn handle_request<W: Write>(
+    socket: &mut W,
+    server: &FileServer,
+    sender_ip: &str,
+    name: &str,
+    size: Option<u64>,
+    sequential: bool,
+) -> Option<String> {
+    let filename = match sanitize_file_name(name) {
+        Some(n) => n,
+        None => {
+            let _ = socket.write_all(b"REJ|BadName\n");
+            return None;
+        }
+    };
+    let size = size.unwrap_or(0);
 
-        for i in 0..parallel_cnt {
-            let ip = target_ip.clone();
-            let fname = file_name.clone();
-            let fpath = file_path.clone();
-            let progress_ref = progress.clone();
-            let error_flag = error_occurred.clone();
-            
-            // 计算当前线程负责的范围
-            let start = i * chunk_size;
-            let mut length = chunk_size;
-            if i == parallel_cnt - 1 {
-                length = file_len - start; // 最后一个线程处理剩余所有
-            }
+    if let Some(ratio) = server.config.free_space_ratio
+        && !has_free_space(Path::new(server.save_dir.as_str()), size, ratio)
+    {
+        warn!("Core: 剩余空间不足，拒绝接收 {} ({} 字节)", filename, size);
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ|LowSpace\n");
+        return None;
+    }
 
-            let handle = thread::spawn(move || {
-                if let Err(e) = send_chunk(&ip, port, &fpath, &fname, start, length, progress_ref) {
-                    error!("线程 {} 传输失败: {:?}", i, e);
-                    error_flag.store(true, std::sync::atomic::Ordering::Relaxed);
-                }
+    let decision = server.callback.on_receive_request(filename.clone(), size, sender_ip.to_string());
+    if decision.accept {
+        if let Some(sink) = decision.sink {
+            // 不能定位的接收端没法承接乱序到达的并行分片，让发送方改用单条连接顺序发送
+            let sequential_only = !sequential && !sink.is_seekable();
+            let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+            lock(&server.accepted).insert(filename.clone(), AcceptedFile {
+                id,
+                path: PathBuf::new(),
+                total: size,
+                received: 0,
+                connections: 0,
+                idle_since: None,
+                finished: false,
+                sink: Some(sink),
+                started: Instant::now(),
+                peer: sender_ip.to_string(),
+                expected_sha256: None,
+                patch: false,
+                filtered: false,
+                progress: None,
             });
-            handles.push(handle);
+            let reply = if sequential_only {
+                info!("Core: {} 的接收端不能定位，要求对方顺序发送", filename);
+                sequential_reply(&filename, id)
+            } else {
+                accept_reply(server, &filename, sequential, id)
+            };
+            let _ = socket.write_all(reply.as_bytes());
+            return Some(filename);
         }
 
-        // 等待所有线程完成
-        for h in handles {
-            let _ = h.join();
+        let dir = decision.dir.unwrap_or_else(|| PathBuf::from(server.save_dir.as_str()));
+        if let Some((final_name, id)) = create_accepted_file(server, &dir, &filename, size, sequential, sender_ip) {
+            // Accept，附带最终文件名和传输编号，发送方后续的 DATA 使用它们。
+            // 经过变换写入的文件同样不能乱序写
+            let reply = if server.config.filter.is_some() && !sequential {
+                sequential_reply(&final_name, id)
+            } else {
+                accept_reply(server, &final_name, sequential, id)
+            };
+            let _ = socket.write_all(reply.as_bytes());
+            return Some(final_name);
+        } else {
+            METRICS.error();
+            let _ = socket.write_all(b"REJ|CreateFileErr\n");
         }
+    } else {
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ\n"); // Reject
+    }
+    None
+}
 
-        if error_occurred.load(std::sync::atomic::Ordering::Relaxed) {
-             callback.on_complete(false, "传输过程中发生错误，请检查日志".into());
-        } else {
-             callback.on_complete(true, "发送完成".into());
+// ACC|name[|chunk=N]|tid=N，配置了 preferred_chunk_size 时附带 chunk=N；
+// 发送方本来就只用一条连接时不用附带
+fn accept_reply(server: &FileServer, filename: &str, sequential: bool, id: u64) -> String {
+    match server.config.preferred_chunk_size {
+        Some(chunk) if !sequential => {
+            format!("ACC|{}|{}{}|{}{}\n", filename, CHUNK_PREFIX, chunk, TRANSFER_ID_PREFIX, id)
         }
-    });
+        _ => format!("ACC|{}|{}{}\n", filename, TRANSFER_ID_PREFIX, id),
+    }
 }
 
-fn send_chunk(
-    ip: &str,
-    port: u16,
-    path: &str,
+// ACC|name|seq|tid=N，要求发送方只用一条连接顺序发送
+fn sequential_reply(filename: &str, id: u64) -> String {
+    format!("ACC|{}|{}|{}{}\n", filename, SEQUENTIAL, TRANSFER_ID_PREFIX, id)
+}
+
+// 按冲突策略在 dir 下创建已同意接收的文件并登记，返回最终文件名和分配的传输编号。
+// filename 可以是清理过的相对路径（目录传输），中间目录一并创建，最终名字也带着这些目录。
+// 返回时文件已经建好、预分配完毕并关闭（配置了变换时文件交给登记的接收端，保持打开），调用方之后才能回 ACC
+fn create_accepted_file(
+    server: &FileServer,
+    dir: &Path,
     filename: &str,
-    offset: u64,
-    length: u64,
-    progress: Arc<Mutex<u64>>
-) -> std::io::Result<()> {
-    let mut file = File::open(path)?;
-    file.seek(SeekFrom::Start(offset))?;
+    size: u64,
+    sequential: bool,
+    sender_ip: &str,
+) -> Option<(String, u64)> {
+    create_accepted_file_with(server, dir, filename, size, sequential, sender_ip, server.config.conflict_policy)
+}
 
-    let mut stream = TcpStream::connect(format!("{}:{}", ip, port))?;
-    stream.set_nodelay(true).ok();
+// 与 create_accepted_file 相同，但按给定的 policy 处理重名
+fn create_accepted_file_with(
+    server: &FileServer,
+    dir: &Path,
+    filename: &str,
+    size: u64,
+    sequential: bool,
+    sender_ip: &str,
+    mut policy: ConflictPolicy,
+) -> Option<(String, u64)> {
+    let (subdir, base) = match filename.rsplit_once('/') {
+        Some((subdir, base)) => (Some(subdir), base),
+        None => (None, filename),
+    };
+    let target_dir = subdir.map(|d| dir.join(d)).unwrap_or_else(|| dir.to_path_buf());
+    if let Err(e) = fs::create_dir_all(&target_dir) {
+        error!("无法创建保存目录 {:?}: {:?}", target_dir, e);
+    }
+    // 保存目录里已有的符号链接可能把子目录引到别处
+    if subdir.is_some() && !is_within(dir, &target_dir) {
+        warn!("Core: {:?} 解析后不在保存目录之内，拒绝写入", target_dir);
+        return None;
+    }
+
+    if policy == ConflictPolicy::Overwrite {
+        let existing = target_dir.join(base);
+        let is_link = fs::symlink_metadata(&existing).is_ok_and(|m| m.file_type().is_symlink());
+        if is_link {
+            // 覆盖会顺着链接写到别处，改名另存
+            warn!("Core: {:?} 是符号链接，不覆盖，改名保存", existing);
+            policy = ConflictPolicy::Rename;
+        } else if existing.exists() && server.callback.on_overwrite_confirm(&existing) == Some(false) {
+            info!("Core: 不覆盖已有文件 {:?}，改名保存", existing);
+            policy = ConflictPolicy::Rename;
+        }
+    }
+    let (file, final_base) = create_target_file(&target_dir, base, policy).ok()?;
+    let final_name = match subdir {
+        Some(subdir) => format!("{}/{}", subdir, final_base),
+        None => final_base.clone(),
+    };
+    // 配置了变换时数据经它写进文件，顺序写入，不预分配
+    let sink = match &server.config.filter {
+        Some(filter) => Some(SharedSink::new(Box::new(filter.wrap_writer(&final_name, size, Box::new(file))))),
+        None => {
+            if !sequential
+                && let Err(e) = file.set_len(size)
+            {
+                error!("无法预分配文件大小: {:?}", e);
+            }
+            None
+        }
+    };
+    let path = target_dir.join(&final_base);
+    let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    lock(&server.accepted).insert(final_name.clone(), AcceptedFile {
+        id,
+        path,
+        total: size,
+        received: 0,
+        connections: 0,
+        idle_since: None,
+        finished: false,
+        filtered: sink.is_some(),
+        sink,
+        started: Instant::now(),
+        peer: sender_ip.to_string(),
+        expected_sha256: server.config.expected_checksums.as_ref().and_then(|c| c.get(filename)),
+        patch: false,
+        progress: None,
+    });
+    Some((final_name, id))
+}
 
-    // 发送数据头: DATA|filename|offset\n
-    let header = format!("DATA|{}|{}\n", filename, offset);
-    stream.write_all(header.as_bytes())?;
+// 处理 BATCH/DIR：读完清单后只询问一次回调，同意则一次性创建所有文件，
+// 回 ACC 后逐行给出每个文件的最终名字。keep_paths 为 true（DIR）时按相对路径保留目录结构，
+// 并记录续传进度；resume 为 true 时按上次的进度续传，每个名字后面附上 |已有字节数。
+// 返回接受的 (最终名字, 大小)，接着按 MUX 会话接收这些文件的数据；None 表示不再继续
+fn handle_batch<S: Read + Write>(
+    socket: &mut S,
+    server: &FileServer,
+    sender_ip: &str,
+    count: usize,
+    keep_paths: bool,
+    resume: bool,
+) -> Option<Vec<(String, u64)>> {
+    let sanitize = if keep_paths { sanitize_relative_path } else { sanitize_file_name };
+    let mut batch = BatchRequest::default();
+    // 续传清单里每个文件附带的来源标识，与 batch.files 一一对应
+    let mut sources = Vec::new();
+    for _ in 0..count {
+        let line = read_header_line(socket)?;
+        let (line, source) = match resume::split_identity(&line).filter(|_| resume) {
+            Some((entry, source)) => (entry, Some(source)),
+            None => (line.as_str(), None),
+        };
+        // 大小在最后一段，文件名里即使有 '|' 也不影响
+        let entry = match line.rsplit_once('|').and_then(|(name, size)| Some((sanitize(name)?, size.trim()))) {
+            Some((name, EMPTY_DIR)) if keep_paths => {
+                batch.dirs.push(name);
+                continue;
+            }
+            Some((name, size)) => size.parse().ok().map(|size| (name, size)),
+            None => None,
+        };
+        match entry {
+            Some(entry) => {
+                batch.files.push(entry);
+                sources.push(source);
+            }
+            None => {
+                let _ = socket.write_all(b"REJ|BadName\n");
+                return None;
+            }
+        }
+    }
+
+    if let Some(ratio) = server.config.free_space_ratio
+        && !has_free_space(Path::new(server.save_dir.as_str()), batch.total_size(), ratio)
+    {
+        warn!("Core: 剩余空间不足，拒绝接收 {} ({} 字节)", batch.summary(), batch.total_size());
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ|LowSpace\n");
+        return None;
+    }
+
+    let decision = server.callback.on_batch_request(&batch, sender_ip.to_string());
+    if !decision.accept {
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ\n");
+        return None;
+    }
+    if decision.sink.is_some() {
+        warn!("Core: 批量接收不支持写入自定义接收端，拒绝 {}", batch.summary());
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ|SinkUnsupported\n");
+        return None;
+    }
+
+    let dir = decision.dir.unwrap_or_else(|| PathBuf::from(server.save_dir.as_str()));
+    for empty_dir in &batch.dirs {
+        if !create_empty_dir(&dir, empty_dir) {
+            METRICS.error();
+            let _ = socket.write_all(b"REJ|CreateFileErr\n");
+            return None;
+        }
+    }
+
+    // 只为请求续传的目录传输记进度；经过变换写入的文件长度对不上，没法从断点续写，也不记
+    let progress = (keep_paths && resume).then(|| resume::progress_path(&dir, &batch.files)).flatten()
+        .filter(|_| server.config.filter.is_none());
+    let previous = match &progress {
+        Some(path) => resume::load_progress(path),
+        None => HashMap::new(),
+    };
+
+    // 整文件按顺序到达，不需要预分配。续传的文件沿用上次的名字，从已有的字节之后接着收。
+    // 清单里重复的名字总是改名保存：每个文件各有一份登记，收完一个移除登记时不影响另一个
+    let mut names: Vec<(String, u64, bool)> = Vec::with_capacity(batch.files.len());
+    for (i, (name, size)) in batch.files.iter().enumerate() {
+        let repeated = batch.files[..i].iter().any(|(n, _)| n == name);
+        let policy = if repeated { ConflictPolicy::Rename } else { server.config.conflict_policy };
+        let accepted = match previous.get(name).filter(|p| p.size == *size && !repeated) {
+            Some(p) => resume::resume_accepted_file(server, &dir, name, p, sources[i].as_ref(), sender_ip)
+                .map(|offset| (p.saved_as.clone(), offset, false)),
+            None => create_accepted_file_with(server, &dir, name, *size, true, sender_ip, policy)
+                .map(|(final_name, _)| (final_name, 0, true)),
+        };
+        match accepted {
+            Some(entry) => names.push(entry),
+            None => {
+                // 这次新建的空文件一并撤掉，续传的文件保留已有数据，整批按拒绝处理
+                let mut accepted = lock(&server.accepted);
+                for (n, _, created) in &names {
+                    if let Some(f) = accepted.remove(n)
+                        && *created
+                    {
+                        let _ = fs::remove_file(&f.path);
+                    }
+                }
+                METRICS.error();
+                let _ = socket.write_all(b"REJ|CreateFileErr\n");
+                return None;
+            }
+        }
+    }
+    info!("Core: 接受 {} ({} 字节)", batch.summary(), batch.total_size());
+
+    if let Some(path) = &progress {
+        // 续传时直接跳过的文件保留“已收完”的记录，核对时内容与来源标识一致
+        let entries: Vec<_> = batch.files.iter().zip(&names).zip(&sources)
+            .map(|(((name, size), (saved_as, offset, created)), source)| resume::ProgressEntry {
+                name: name.clone(),
+                saved_as: saved_as.clone(),
+                size: *size,
+                source: source.clone(),
+                done: source.as_ref().filter(|_| !created && *size > 0 && offset == size).map(|s| s.sha256.clone()),
+            })
+            .collect();
+        match resume::start_progress(path, &entries) {
+            Ok(()) => {
+                let mut accepted = lock(&server.accepted);
+                for (saved_as, _, _) in &names {
+                    if let Some(f) = accepted.get_mut(saved_as) {
+                        f.progress = Some(path.clone());
+                    }
+                }
+            }
+            Err(e) => warn!("Core: 无法记录续传进度 {:?}: {:?}", path, e),
+        }
+    }
+
+    let mut reply = String::from("ACC\n");
+    for (name, offset, _) in &names {
+        reply.push_str(name);
+        if resume {
+            reply.push_str(&format!("|{}", offset));
+        }
+        reply.push('\n');
+    }
+    socket.write_all(reply.as_bytes()).ok()?;
+    Some(names.into_iter().zip(&batch.files).map(|((name, _, _), (_, size))| (name, *size)).collect())
+}
+
+// 在 dir 下创建目录传输里的空目录，和文件一样不允许经由符号链接落到保存目录之外
+fn create_empty_dir(dir: &Path, rel_path: &str) -> bool {
+    let target = dir.join(rel_path);
+    if let Err(e) = fs::create_dir_all(&target) {
+        error!("无法创建目录 {:?}: {:?}", target, e);
+        return false;
+    }
+    if !is_within(dir, &target) {
+        warn!("Core: {:?} 解析后不在保存目录之内，拒绝写入", target);
+        return false;
+    }
+    true
+}
+
+// 打开已接受的文件并定位到 offset，按配置决定是否套一层有上限的写缓冲。
+// 只打开登记过的文件，没有经过握手的名字返回 None
+fn open_for_write(server: &FileServer, filename: &str, offset: u64) -> Option<Box<dyn Write>> {
+    let registered = lock(&server.accepted).get(filename).map(|f| (f.path.clone(), f.sink.clone()));
+    let path = match registered {
+        Some((_, Some(sink))) => return Some(Box::new(sink.writer(offset))),
+        Some((p, None)) => p,
+        None => {
+            warn!("Core: {} 没有登记，不写入", filename);
+            return None;
+        }
+    };
+
+    let mut file = match OpenOptions::new().write(true).open(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("无法打开文件写入数据: {:?}", e);
+            METRICS.error();
+            return None;
+        }
+    };
+
+    if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+        error!("Seek失败: {:?}", e);
+        METRICS.error();
+        return None;
+    }
+    match server.config.write_buffer_cap {
+        Some(cap) => Some(Box::new(BoundedWriter::new(file, cap))),
+        None => Some(Box::new(file)),
+    }
+}
+
+// DATA 属于哪次传输，返回 filename 当前登记的传输编号。带了编号就必须和登记的一致；
+// 没带编号的只认本连接上 REQ/BATCH 已经接受过的文件（session，MUX 会话和 BATCH 清单的帧），
+// 或者在 allow_untagged_data 打开时认尚未收完的登记（旧版本发送方）。
+// 其余一律返回 Err 丢弃这条连接，没有经过握手的数据不能写进保存目录
+fn claim_transfer(server: &FileServer, filename: &str, id: Option<u64>, session: &[(String, u64)]) -> Result<u64, String> {
+    let registered = lock(&server.accepted).get(filename).map(|f| (f.id, f.finished));
+    match (id, registered) {
+        (_, None) => Err(format!("{} 没有对应已接受的传输", filename)),
+        (Some(id), Some((current, _))) if id != current => {
+            Err(format!("传输编号 {} 与 {} 当前登记的 {} 不符", id, filename, current))
+        }
+        (Some(_), Some((current, _))) => Ok(current),
+        (None, Some((current, _))) if session.iter().any(|(name, _)| name == filename) => Ok(current),
+        (None, Some((current, false))) if server.config.allow_untagged_data => Ok(current),
+        (None, Some(_)) => Err(format!("写入 {} 的 DATA 没有带传输编号", filename)),
+    }
+}
+
+// 处理 DATA：一条连接写一个分片，直到对方关闭连接。带检查点时边收边核对，对不上就按校验失败结束。
+// 写盘缓冲里的数据要等 flush 才落到文件里，所以收尾放在最后一条连接 flush 并关闭之后：
+// 只有那时文件里才一定有全部收到的数据，长度和校验和的核对才有意义
+fn handle_data<R: Read>(
+    socket: &mut R,
+    server: &Arc<FileServer>,
+    filename: &str,
+    offset: u64,
+    id: Option<u64>,
+    checkpoint: Option<u64>,
+) {
+    let transfer = match claim_transfer(server, filename, id, &[]) {
+        Ok(t) => t,
+        Err(reason) => {
+            warn!("Core: 丢弃 DATA: {}", reason);
+            METRICS.error();
+            return;
+        }
+    };
+    let mut file = match open_for_write(server, filename, offset) {
+        Some(f) => f,
+        None => return,
+    };
+    if let Some(f) = lock(&server.accepted).get_mut(filename).filter(|f| f.id == transfer) {
+        f.connections += 1;
+        f.idle_since = None;
+    }
+    let mut socket = CheckpointReader::new(socket, checkpoint);
 
-    // 使用 take 限制读取长度，防止读过界
-    let mut handle = file.take(length);
     let mut buffer = [0u8; 64 * 1024];
+    let mut last_progress_update = 0u64;
+    let mut written = Ok(());
+    loop {
+        match socket.read(&mut buffer) {
+            Ok(0) => break, // EOF
+            Ok(_) if receive_timed_out(server, filename) => break,
+            Ok(n) => {
+                if let Err(e) = file.write_all(&buffer[..n]) {
+                    written = Err(e);
+                    break;
+                }
+                METRICS.add_bytes_received(n as u64);
+
+                let (current_total, total) = {
+                    let mut accepted = lock(&server.accepted);
+                    match accepted.get_mut(filename).filter(|f| f.id == transfer) {
+                        Some(f) => {
+                            f.received += n as u64;
+                            (f.received, f.total)
+                        }
+                        // 同名文件已被新的传输重新登记，本连接的数据不再属于它
+                        None => {
+                            warn!("Core: {} 已被新的传输取代，停止写入旧传输的分片", filename);
+                            break;
+                        }
+                    }
+                };
+
+                if current_total - last_progress_update > 1024 * 1024 || current_total == total {
+                    server.callback.on_progress(current_total, total);
+                    last_progress_update = current_total;
+                }
+            }
+            Err(e) => {
+                match TransferError::from_io(e) {
+                    TransferError::ChecksumMismatch => checkpoint_failed(server, filename, transfer),
+                    _ => {
+                        receive_timed_out(server, filename);
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    // 先把这条连接缓冲的数据写进文件再登记连接结束
+    let written = written.and_then(|_| file.flush());
+    drop(file);
+    if let Err(e) = &written {
+        error!("写入文件失败: {:?}", e);
+    }
+
+    // 最后一条连接关闭时收尾：收全了按完成结束，没收全但达到部分完成比例的，
+    // 等一段时间确认发送方不再连上来。任何一条连接写盘失败，文件都不完整，直接按失败结束
+    let outcome = {
+        let mut accepted = lock(&server.accepted);
+        match accepted.get_mut(filename).filter(|f| f.id == transfer) {
+            Some(f) => {
+                f.connections = f.connections.saturating_sub(1);
+                let outcome = if f.finished {
+                    None
+                } else if written.is_err() {
+                    Some(DataOutcome::Failed)
+                } else if f.connections > 0 {
+                    None
+                } else if f.received >= f.total {
+                    Some(DataOutcome::Complete(f.total))
+                } else if reaches_min_completion(f.received, f.total, server.config.min_completion) {
+                    f.idle_since = Some(Instant::now());
+                    Some(DataOutcome::Idle)
+                } else {
+                    None
+                };
+                if matches!(outcome, Some(DataOutcome::Complete(_) | DataOutcome::Failed)) {
+                    f.finished = true;
+                    f.sink = None;
+                }
+                outcome
+            }
+            None => None,
+        }
+    };
+    match outcome {
+        Some(DataOutcome::Complete(total)) => finish_received(server, filename, total),
+        Some(DataOutcome::Idle) => settle_partial_later(server, filename, transfer),
+        Some(DataOutcome::Failed) => fail_received(server, filename, TransferError::Failed("写入文件失败".to_string())),
+        None => {}
+    }
+}
+
+// 最后一条 DATA 连接关闭（或写盘失败）时文件的结局
+enum DataOutcome {
+    // 收全了 total 字节
+    Complete(u64),
+    // 没收全，但达到了部分完成的比例
+    Idle,
+    Failed,
+}
+
+// 等 partial_idle_timeout 后再看：期间没有新的数据连接、仍然没收全，才按部分完成结束。
+// 期间有连接来过又走了，由那条连接关闭时安排的检查负责
+fn settle_partial_later(server: &Arc<FileServer>, filename: &str, transfer: u64) {
+    let (server, filename) = (server.clone(), filename.to_string());
+    let idle = server.config.partial_idle_timeout;
+    thread::spawn(move || {
+        thread::sleep(idle);
+        let settled = {
+            let mut accepted = lock(&server.accepted);
+            match accepted.get_mut(&filename).filter(|f| f.id == transfer) {
+                Some(f) if !f.finished
+                    && f.connections == 0
+                    && f.idle_since.is_some_and(|t| t.elapsed() >= idle)
+                    && reaches_min_completion(f.received, f.total, server.config.min_completion) =>
+                {
+                    f.finished = true;
+                    f.sink = None;
+                    Some((f.received, f.total))
+                }
+                _ => None,
+            }
+        };
+        if let Some((received, total)) = settled {
+            warn!("Core: {} 只收到 {}/{} 字节，{:?} 内没有新的连接，按部分完成处理", filename, received, total, idle);
+            server.partial_complete(&filename, received, total);
+        }
+    });
+}
+
+// 检查点对不上时提前结束这个文件。同一文件只回调一次失败，其余分片连接之后收满也不会再算作完成
+fn checkpoint_failed(server: &FileServer, filename: &str, transfer: u64) {
+    warn!("Core: {} 的检查点校验失败，提前中止接收", filename);
+    let first = match lock(&server.accepted).get_mut(filename).filter(|f| f.id == transfer) {
+        Some(f) if !f.finished => {
+            f.finished = true;
+            f.sink = None;
+            true
+        }
+        _ => false,
+    };
+    if first {
+        fail_received(server, filename, TransferError::ChecksumMismatch);
+    }
+}
+
+// 接收是否已超过 max_duration。第一个发现超时的连接负责回调，其余连接直接退出
+fn receive_timed_out(server: &FileServer, filename: &str) -> bool {
+    let max = match server.config.max_duration {
+        Some(max) => max,
+        None => return false,
+    };
+    let first = {
+        let mut accepted = lock(&server.accepted);
+        match accepted.get_mut(filename) {
+            Some(f) if f.started.elapsed() >= max => {
+                let first = !f.finished;
+                f.finished = true;
+                f.sink = None;
+                first
+            }
+            _ => return false,
+        }
+    };
+    if first {
+        warn!("Core: 接收 {} 超过 {:?}，已中止", filename, max);
+        METRICS.error();
+        server.callback.on_error(TransferError::Timeout);
+        server.complete(filename, false, format!("{}: {}", filename, TransferError::Timeout));
+    }
+    true
+}
+
+// 文件完整收到后结束接收。写到磁盘上的文件先核对长度：各连接收到的字节数加起来够了，
+// 不代表每个字节都落在了该落的位置（分片重叠、写入位置错位时文件会比预期短）；
+// 登记过预期校验和的再比对校验和，任何一项不符都按失败结束
+fn finish_received(server: &FileServer, filename: &str, expected_len: u64) {
+    let target = lock(&server.accepted).get(filename)
+        .filter(|f| f.sink.is_none() && !f.path.as_os_str().is_empty() && !f.patch && !f.filtered)
+        .map(|f| (f.path.clone(), f.expected_sha256));
+    if let Some((path, expected_sha256)) = target {
+        match fs::metadata(&path).map(|m| m.len()) {
+            Ok(len) if len == expected_len => {}
+            result => {
+                let actual = result.map_or_else(|e| e.to_string(), |len| len.to_string());
+                warn!("Core: {} 的长度为 {}，预期 {} 字节", filename, actual, expected_len);
+                let msg = format!("文件大小与预期不符 ({} / {} 字节)", actual, expected_len);
+                return fail_received(server, filename, TransferError::Failed(msg));
+            }
+        }
+        if let Some(expected) = expected_sha256 {
+            match checksum::file_sha256(&path) {
+                Ok(actual) if actual == expected => info!("Core: {} 校验和与预期一致", filename),
+                result => {
+                    if let Err(e) = result {
+                        error!("无法计算 {:?} 的校验和: {:?}", path, e);
+                    }
+                    warn!("Core: {} 的校验和与预期不符", filename);
+                    return fail_received(server, filename, TransferError::ChecksumMismatch);
+                }
+            }
+        }
+    }
+    let progress = lock(&server.accepted).get(filename).and_then(|f| Some((f.progress.clone()?, f.path.clone())));
+    if let Some((path, file)) = progress {
+        resume::mark_done(&path, filename, &file);
+    }
+    METRICS.transfer_received();
+    server.complete(filename, true, filename.to_string());
+}
+
+fn fail_received(server: &FileServer, filename: &str, error: TransferError) {
+    METRICS.error();
+    let msg = format!("{}: {}", filename, error);
+    server.callback.on_error(error);
+    server.complete(filename, false, msg);
+}
 
+// 文件收完后释放回调给的接收端，剩下的写入句柄随连接结束释放，管道另一头随即读到 EOF
+fn release_sink(server: &FileServer, filename: &str) {
+    if let Some(f) = lock(&server.accepted).get_mut(filename) {
+        f.sink = None;
+    }
+}
+
+// 没收全的文件是否达到了部分完成的比例，min_completion 为 1.0 时总是 false
+fn reaches_min_completion(received: u64, total: u64, min_completion: f64) -> bool {
+    min_completion < 1.0 && received < total && received as f64 >= total as f64 * min_completion
+}
+
+// 接收大小未知的流：u32 大端长度 + 数据 的帧序列，长度为 0 的帧表示结束，
+// 文件随数据到达逐步增长，收完回复 OK
+fn handle_stream_body<S: Read + Write>(socket: &mut S, server: &FileServer, filename: &str) {
+    let mut file = match open_for_write(server, filename, 0) {
+        Some(f) => f,
+        None => return,
+    };
+
+    let mut written = 0u64;
+    let mut last_progress_update = 0u64;
     loop {
-        let n = handle.read(&mut buffer)?;
-        if n == 0 { break; }
-        stream.write_all(&buffer[..n])?;
+        if receive_timed_out(server, filename) {
+            return;
+        }
+        let len = match socket.read_u32::<BigEndian>() {
+            Ok(len) => len as u64,
+            Err(_) if receive_timed_out(server, filename) => return,
+            Err(e) => {
+                error!("流式接收中断: {} ({:?})", filename, e);
+                METRICS.error();
+                server.complete(filename, false, format!("{}: 连接中断", filename));
+                return;
+            }
+        };
+        if len == 0 {
+            break;
+        }
 
-        // 更新进度（这里太频繁锁可能会影响性能，实际可以用 atomic 或者每传 1MB 更新一次）
-        if let Ok(mut p) = progress.lock() {
-            *p += n as u64;
+        let mut frame = (&mut *socket).take(len);
+        match io::copy(&mut frame, &mut file) {
+            Ok(n) if n == len => {}
+            Ok(_) | Err(_) => {
+                error!("流式接收数据帧不完整: {}", filename);
+                METRICS.error();
+                server.complete(filename, false, format!("{}: 数据不完整", filename));
+                return;
+            }
+        }
+        written += len;
+        METRICS.add_bytes_received(len);
+
+        if written - last_progress_update > 1024 * 1024 {
+            server.callback.on_progress(written, 0); // total 为 0 表示大小未知
+            last_progress_update = written;
         }
     }
-    Ok(())
-}
\ No newline at end of file
+
+    if let Err(e) = file.flush() {
+        error!("写入文件失败: {:?}", e);
+        METRICS.error();
+        server.complete(filename, false, format!("{}: 写入失败", filename));
+        return;
+    }
+    release_sink(server, filename);
+    let _ = socket.write_all(b"OK\n");
+    server.callback.on_progress(written, written);
+    finish_received(server, filename, written);
+}
+
+// 处理 MUX 长连接：同一条连接上依次出现 REQ 与带长度的 DATA|name|offset|len 帧，
+// 对方关闭连接即结束。进度按文件单独统计，避免和并行分片共用的计数器互相干扰。
+fn handle_mux_session<S: Read + Write>(mut socket: S, peer: &str, server: &FileServer, mut sizes: Vec<(String, u64)>) {
+
+    while let Some(header_str) = read_header_line(&mut socket) {
+        match parse_header(&header_str) {
+            Ok(Header::Req { name, size, sequential }) => {
+                if let Some(final_name) = handle_request(&mut socket, server, peer, name, size, sequential) {
+                    sizes.push((final_name, size.unwrap_or(0)));
+                }
+            }
+            Ok(Header::Data { name: filename, offset, len: Some(len), id, checkpoint: None }) => {
+                if let Err(reason) = claim_transfer(server, filename, id, &sizes) {
+                    warn!("Core: 丢弃 MUX 帧: {}", reason);
+                    return reply_error(&mut socket, &header_str, "UnknownTransfer");
+                }
+                // 续传的帧只是文件的一段，完成与否按整个文件的大小算
+                let total = sizes.iter().find(|(n, _)| n == filename).map(|(_, s)| *s)
+                    .or_else(|| lock(&server.accepted).get(filename).map(|f| f.total))
+                    .unwrap_or(len);
+                if receive_timed_out(server, filename) {
+                    return;
+                }
+
+                let mut file = match open_for_write(server, filename, offset) {
+                    Some(f) => f,
+                    None => return,
+                };
+
+                // take 保证只消费本帧的数据，后面的字节属于下一个头
+                let mut frame = (&mut socket).take(len);
+                match io::copy(&mut frame, &mut file).and_then(|n| file.flush().map(|_| n)) {
+                    Ok(n) if n == len => {
+                        METRICS.add_bytes_received(n);
+                        server.callback.on_progress(offset + n, total);
+                        if offset + n >= total {
+                            release_sink(server, filename);
+                            finish_received(server, filename, total);
+                        }
+                    }
+                    Ok(n) => {
+                        error!("MUX 帧数据不完整: {} ({} / {})", filename, n, len);
+                        if reaches_min_completion(offset + n, total, server.config.min_completion) {
+                            server.partial_complete(filename, offset + n, total);
+                        } else {
+                            METRICS.error();
+                            // 请求续传的目录传输中断在这个文件上，记下已经收到的部分
+                            drop(file);
+                            let progress = lock(&server.accepted).get(filename).and_then(|f| Some((f.progress.clone()?, f.path.clone())));
+                            if let Some((path, saved)) = progress.filter(|_| offset + n > 0) {
+                                resume::mark_partial(&path, filename, &saved, offset + n);
+                            }
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        error!("写入文件失败: {:?}", e);
+                        METRICS.error();
+                        return;
+                    }
+                }
+            }
+            // MUX 会话里的 DATA 必须带长度、不支持检查点，MUX、BATCH、PING、PATCH 这类只能作为连接首行的头也不能出现
+            Ok(Header::Data { .. }) => return reply_error(&mut socket, &header_str, "BadHeader"),
+            Ok(Header::Mux | Header::Batch { .. } | Header::Ping { .. } | Header::Patch { .. }) => {
+                return reply_error(&mut socket, &header_str, "UnknownType")
+            }
+            Err(reason) => return reply_error(&mut socket, &header_str, reason),
+        }
+    }
+}
+
+pub fn send_file(
+    target_ip: String,
+    port: u16,
+    file_path: String,
+    parallel_cnt: u64, // 并行线程数，建议 4-8，超过 DEFAULT_MAX_PARALLEL 时按上限处理
+    callback: Box<dyn TransferCallback> // 用于回传发送进度
+) {
+    send_file_tracked(target_ip, port, file_path, parallel_cnt, callback);
+}
+
+/// 与 send_file 相同，返回的句柄可以随时查询每个分片的发送进度
+pub fn send_file_tracked(
+    target_ip: String,
+    port: u16,
+    file_path: String,
+    parallel_cnt: u64,
+    callback: Box<dyn TransferCallback>,
+) -> SendHandle {
+    let handle = SendHandle::new(clamp_parallel(parallel_cnt, DEFAULT_MAX_PARALLEL));
+    let tracker = handle.clone();
+    let active = TRANSFERS.begin(None);
+    thread::spawn(move || {
+        let limits = SendLimits::new(None, active.token());
+        let result = transfer_file(&target_ip, port, &file_path, &tracker, &limits, None);
+        DEVICES.record_transfer(&target_ip, TransferDirection::Sent, result.is_ok());
+        match result {
+            Ok(_) => callback.on_complete(true, "发送完成".into()),
+            Err(e) => {
+                METRICS.error();
+                callback.on_error(e.clone());
+                callback.on_complete(false, e.to_string())
+            }
+        }
+    });
+    handle
+}
+
+/// 按 SendOptions 发送单个文件，adaptive 为 true 时自动决定并行连接数
+pub fn send_file_with_options(
+    target_ip: String,
+    port: u16,
+    file_path: String,
+    options: SendOptions,
+    callback: Box<dyn TransferCallback>,
+) {
+    let active = TRANSFERS.begin(options.cancel_token.clone());
+    thread::spawn(move || {
+        let limits = SendLimits::new(options.max_duration, active.token());
+        let parallel_cnt = options.effective_parallel();
+        let checkpoint = options.checkpoint_interval.map(|n| n.max(MIN_CHECKPOINT_INTERVAL));
+        let result = if options.adaptive {
+            transfer_file_adaptive(&target_ip, port, &file_path, parallel_cnt, &limits, checkpoint)
+        } else {
+            transfer_file(&target_ip, port, &file_path, &SendHandle::new(parallel_cnt), &limits, checkpoint)
+        };
+        DEVICES.record_transfer(&target_ip, TransferDirection::Sent, result.is_ok());
+        match result {
+            Ok(_) => callback.on_complete(true, "发送完成".into()),
+            Err(e) => {
+                METRICS.error();
+                callback.on_error(e.clone());
+                callback.on_complete(false, e.to_string())
+            }
+        }
+    });
+}
+
+/// 发送一段长度未知的数据（管道、标准输入等），读到 EOF 为止。
+/// 接收方 on_receive_request 收到的 file_size 为 0。
+pub fn send_stream(
+    target_ip: String,
+    port: u16,
+    file_name: String,
+    reader: Box<dyn Read + Send>,
+    callback: Box<dyn TransferCallback>,
+) {
+    let active = TRANSFERS.begin(None);
+    thread::spawn(move || {
+        let result = transfer_stream(&target_ip, port, &file_name, reader, &active.token(), callback.as_ref());
+        DEVICES.record_transfer(&target_ip, TransferDirection::Sent, result.is_ok());
+        match result {
+            Ok(total) => callback.on_complete(true, format!("发送完成 ({} 字节)", total)),
+            Err(msg) => {
+                METRICS.error();
+                callback.on_complete(false, msg)
+            }
+        }
+    });
+}
+
+fn transfer_stream(
+    target_ip: &str,
+    port: u16,
+    file_name: &str,
+    mut reader: Box<dyn Read + Send>,
+    cancel: &CancelToken,
+    callback: &dyn TransferCallback,
+) -> Result<u64, String> {
+    let mut stream = connect_peer(target_ip, port)
+        .map_err(|e| format!("连接失败: {:?}", e))?;
+
+    let req_msg = format!("REQ|{}|{}\n", file_name, UNKNOWN_SIZE);
+    stream.write_all(req_msg.as_bytes()).map_err(|e| e.to_string())?;
+    let response = read_header_line(&mut stream).ok_or("连接已断开")?;
+    accepted_name(&response, file_name)?;
+
+    let mut buffer = [0u8; 64 * 1024];
+    let mut total = 0u64;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("读取数据失败: {:?}", e)),
+        };
+        if cancel.is_cancelled() {
+            return Err(TransferError::Cancelled.to_string());
+        }
+        stream.write_u32::<BigEndian>(n as u32).map_err(|e| e.to_string())?;
+        stream.write_all(&buffer[..n]).map_err(|e| e.to_string())?;
+        total += n as u64;
+        METRICS.add_bytes_sent(n as u64);
+        callback.on_progress(total, 0);
+    }
+
+    // 结束帧，等对方确认全部落盘
+    stream.write_u32::<BigEndian>(0).map_err(|e| e.to_string())?;
+    match read_header_line(&mut stream) {
+        Some(line) if line == "OK" => {
+            METRICS.transfer_sent();
+            Ok(total)
+        }
+        _ => Err("对方未确认接收完成".into()),
+    }
+}
+
+/// 在调用方已经建立好的连接（SSH 端口转发、中继、自定义通道等）上发送一个文件，
+/// 发完关闭连接。使用 MUX 会话，对方用 serve_connection 或普通的文件服务接收
+pub fn send_file_over<S: Read + Write + Send + 'static>(
+    stream: S,
+    file_path: String,
+    callback: Box<dyn TransferCallback>,
+) {
+    let active = TRANSFERS.begin(None);
+    thread::spawn(move || {
+        let limits = SendLimits::new(None, active.token());
+        match transfer_over(stream, &file_path, &limits) {
+            Ok(()) => callback.on_complete(true, "发送完成".into()),
+            Err(e) => {
+                METRICS.error();
+                callback.on_error(e.clone());
+                callback.on_complete(false, e.to_string())
+            }
+        }
+    });
+}
+
+fn transfer_over<S: Read + Write>(mut stream: S, file_path: &str, limits: &SendLimits) -> Result<(), TransferError> {
+    write_auth(&mut stream).map_err(|e| e.to_string())?;
+    stream.write_all(b"MUX\n").map_err(|e| e.to_string())?;
+    send_file_on_mux(&mut stream, file_path, limits)
+}
+
+/// 向同一设备发送多个文件，结束后只回调一次 on_complete
+pub fn send_files(
+    target_ip: String,
+    port: u16,
+    file_paths: Vec<String>,
+    options: SendOptions,
+    callback: Box<dyn TransferCallback>,
+) {
+    let active = TRANSFERS.begin(options.cancel_token.clone());
+    thread::spawn(move || {
+        let limits = SendLimits::new(options.max_duration, active.token());
+        let result = if options.batch {
+            send_files_batch(&target_ip, port, &file_paths, &limits, callback.as_ref())
+        } else if options.reuse_connections {
+            send_files_reused(&target_ip, port, &file_paths, options.effective_parallel(), &limits, callback.as_ref())
+        } else {
+            send_files_sequential(&target_ip, port, &file_paths, options.effective_parallel(), &limits, callback.as_ref())
+        };
+        DEVICES.record_transfer(&target_ip, TransferDirection::Sent, result.is_ok());
+
+        match result {
+            Ok(count) => callback.on_complete(true, format!("发送完成 ({} 个文件)", count)),
+            Err(msg) => {
+                METRICS.error();
+                callback.on_complete(false, msg)
+            }
+        }
+    });
+}
+
+/// 发送整个目录并保留目录结构，对方只确认一次，文件保存在对方保存目录下的同名目录里。
+/// 目录里的符号链接按 options.symlinks 处理；文件在一条连接上依次发送，parallel_cnt 不起作用
+pub fn send_directory(
+    target_ip: String,
+    port: u16,
+    dir_path: String,
+    options: SendOptions,
+    callback: Box<dyn TransferCallback>,
+) {
+    let active = TRANSFERS.begin(options.cancel_token.clone());
+    thread::spawn(move || {
+        let limits = SendLimits::new(options.max_duration, active.token());
+        let result = directory::collect_files(Path::new(&dir_path), options.symlinks)
+            .map_err(|e| format!("无法读取目录 {}: {}", dir_path, e))
+            .and_then(|entries| {
+                let mut files = Vec::with_capacity(entries.files.len());
+                for (rel_path, path) in entries.files {
+                    let path = path.to_string_lossy();
+                    let (_, mut source) = inspect_source(&path).map_err(|msg| format!("{}: {}", path, msg))?;
+                    source.limits = limits.clone();
+                    files.push((rel_path, source));
+                }
+                send_manifest(&target_ip, port, "DIR", &files, &entries.empty_dirs, options.resume, callback.as_ref())
+            });
+        DEVICES.record_transfer(&target_ip, TransferDirection::Sent, result.is_ok());
+
+        match result {
+            Ok(count) => callback.on_complete(true, format!("发送完成 ({} 个文件)", count)),
+            Err(msg) => {
+                METRICS.error();
+                callback.on_complete(false, msg)
+            }
+        }
+    });
+}
+
+// 待发送的文件和开始发送时的大小、修改时间，发送途中据此判断文件是否被改动
+#[derive(Clone, Debug)]
+struct SourceFile {
+    path: String,
+    len: u64,
+    modified: Option<SystemTime>,
+    // 超过时限或被取消就中止发送
+    limits: SendLimits,
+    // 分片内检查点的间隔，None 表示不插入
+    checkpoint: Option<u64>,
+}
+
+// 一次发送的中止条件，同一次发送的所有文件和分片共用
+#[derive(Clone, Debug, Default)]
+struct SendLimits {
+    // 超过这个时间点就中止发送
+    deadline: Option<Instant>,
+    cancel: CancelToken,
+}
+
+impl SendLimits {
+    fn new(max_duration: Option<Duration>, cancel: CancelToken) -> Self {
+        Self { deadline: max_duration.map(|d| Instant::now() + d), cancel }
+    }
+
+    // 已被取消或超过时限时给出对应的错误
+    fn stop_reason(&self) -> Option<TransferError> {
+        if self.cancel.is_cancelled() {
+            Some(TransferError::Cancelled)
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(TransferError::Timeout)
+        } else {
+            None
+        }
+    }
+
+    // 距离时限还剩多少，用作写超时，避免卡住的写操作拖过时限
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|d| d.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)))
+    }
+}
+
+impl SourceFile {
+    // 已被取消或超过时限返回包着 Cancelled、Timeout 的错误，否则检查文件是否被改动
+    fn check(&self) -> io::Result<()> {
+        if let Some(reason) = self.limits.stop_reason() {
+            return Err(io::Error::other(reason));
+        }
+        self.check_unchanged()
+    }
+
+    // 重新读取文件状态，与开始时不一致则返回包着 FileChanged 的错误
+    fn check_unchanged(&self) -> io::Result<()> {
+        let meta = fs::metadata(&self.path)?;
+        if meta.len() != self.len || meta.modified().ok() != self.modified {
+            warn!("Core: {} 在发送过程中被修改", self.path);
+            return Err(io::Error::other(TransferError::FileChanged));
+        }
+        Ok(())
+    }
+
+    fn stopped(&self) -> bool {
+        self.limits.stop_reason().is_some()
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        self.limits.remaining()
+    }
+
+    // 写失败时如果已经被取消或超过时限，把错误换成 Cancelled 或 Timeout
+    fn explain(&self, e: io::Error) -> io::Error {
+        match self.limits.stop_reason() {
+            Some(reason) => io::Error::other(reason),
+            None => e,
+        }
+    }
+}
+
+// 校验待发送路径，返回 (文件名, 文件状态)
+fn inspect_source(file_path: &str) -> Result<(String, SourceFile), String> {
+    let path = Path::new(file_path);
+    // metadata 会解析符号链接，目录或特殊文件直接拒绝
+    let source = match path.metadata() {
+        Ok(meta) if meta.is_file() => SourceFile {
+            path: file_path.to_string(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            limits: SendLimits::default(),
+            checkpoint: None,
+        },
+        Ok(_) => return Err("不是普通文件".into()),
+        Err(_) => return Err("文件不存在".into()),
+    };
+
+    let file_name = match path.file_name() {
+        Some(n) => n.to_string_lossy().to_string(),
+        None => return Err("无效的文件路径".into()),
+    };
+    Ok((file_name, source))
+}
+
+// 完整发送一个文件：握手 + 并行分片，返回发送的字节数。分片数取自 tracker
+fn transfer_file(
+    target_ip: &str,
+    port: u16,
+    file_path: &str,
+    tracker: &SendHandle,
+    limits: &SendLimits,
+    checkpoint: Option<u64>,
+) -> Result<u64, TransferError> {
+    let (file_name, mut source) = inspect_source(file_path)?;
+    source.limits = limits.clone();
+    source.checkpoint = checkpoint;
+    let file_len = source.len;
+    let mut parallel_cnt = tracker.chunk_count() as u64;
+    // 目标是本进程自己的文件服务时不经过网络，握手和分片都直接交给它处理，
+    // 分片方式和进度与走网络时一致。要求配对的服务仍走网络，由它校验认证
+    let local = if is_local_address(target_ip) {
+        local_server(port).filter(|s| s.config.pairing.is_none())
+    } else {
+        None
+    };
+
+    // 1. 发送握手请求 (REQ)，只有一个分片时数据是顺序到达的，告诉接收方不必预分配
+    let accepted = match &local {
+        Some(server) => {
+            info!("Core: 目标 {}:{} 是本进程的文件服务，不经过网络直接交给它接收", target_ip, port);
+            request_local(server, &file_name, file_len, parallel_cnt == 1)?
+        }
+        None => request_send(target_ip, port, &file_name, file_len, parallel_cnt == 1)?,
+    };
+    let accepted = Arc::new(accepted);
+    match accepted.layout {
+        Layout::Sequential if parallel_cnt > 1 => {
+            info!("Core: 对方要求顺序写入，改为单连接发送");
+            parallel_cnt = 1;
+        }
+        Layout::MinChunk(min_chunk) => {
+            let cnt = parallel_cnt.min(file_len / min_chunk).max(1);
+            if cnt < parallel_cnt {
+                info!("Core: 对方希望分片不小于 {} 字节，改为 {} 个分片", min_chunk, cnt);
+                parallel_cnt = cnt;
+            }
+        }
+        _ => {}
+    }
+
+    // 2. 计算分片并并行发送
+    let chunk_size = file_len / parallel_cnt;
+    let mut handles = vec![];
+    // 使用原子布尔值标记是否有线程出错，任何一个线程出错则整体失败
+    let error_occurred = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    // FileChanged、Timeout 这类有明确原因的错误，优先于笼统的失败报告给回调
+    let typed_error = Arc::new(Mutex::new(None::<TransferError>));
+
+    info!("Core: 开始并行传输，线程数: {}", parallel_cnt);
+
+    for i in 0..parallel_cnt {
+        let ip = target_ip.to_string();
+        let local = local.clone();
+        let accepted = accepted.clone();
+        let source = source.clone();
+        let tracker = tracker.clone();
+        let error_flag = error_occurred.clone();
+        let typed_error = typed_error.clone();
+
+        // 计算当前线程负责的范围
+        let start = i * chunk_size;
+        let mut length = chunk_size;
+        if i == parallel_cnt - 1 {
+            length = file_len - start; // 最后一个线程处理剩余所有
+        }
+
+        let handle = thread::spawn(move || {
+            let progress = &tracker.chunks[i as usize];
+            let sent = match &local {
+                Some(server) => copy_chunk(server, &accepted, &source, start, length, progress),
+                None => send_chunk(&ip, port, &accepted, &source, start, length, progress),
+            };
+            if let Err(e) = sent {
+                error!("线程 {} 传输失败: {:?}", i, e);
+                let reason = TransferError::from_io(e);
+                if !matches!(reason, TransferError::Failed(_)) {
+                    lock(&typed_error).get_or_insert(reason);
+                }
+                error_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+        handles.push(handle);
+    }
+
+    // 等待所有线程完成
+    for h in handles {
+        let _ = h.join();
+    }
+
+    if let Some(reason) = lock(&typed_error).take() {
+        Err(reason)
+    } else if error_occurred.load(std::sync::atomic::Ordering::Relaxed) {
+        Err("传输过程中发生错误，请检查日志".to_string().into())
+    } else {
+        METRICS.transfer_sent();
+        Ok(file_len)
+    }
+}
+
+// 发给本进程自己的文件服务时的握手：直接交给接收流程处理 REQ，应答与网络握手相同
+fn request_local(server: &FileServer, file_name: &str, file_len: u64, sequential: bool) -> Result<Accepted, String> {
+    let mut reply = Vec::new();
+    let peer = Ipv4Addr::LOCALHOST.to_string();
+    if !guard_callback_panic(&peer, || {
+        handle_request(&mut reply, server, &peer, file_name, Some(file_len), sequential);
+    }) {
+        return Err("接收方处理请求时出错".to_string());
+    }
+    accepted_name(String::from_utf8_lossy(&reply).trim_end(), file_name)
+}
+
+// 与 send_chunk 相同，但不建立连接，把这一段源文件直接读给接收流程写入
+fn copy_chunk(
+    server: &Arc<FileServer>,
+    transfer: &Accepted,
+    source: &SourceFile,
+    offset: u64,
+    length: u64,
+    progress: &AtomicU64,
+) -> io::Result<()> {
+    source.check()?;
+    let mut file = File::open(&source.path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = LocalSource { file: file.take(length), source, progress, length, sent: 0, error: None };
+    let peer = Ipv4Addr::LOCALHOST.to_string();
+    if !guard_callback_panic(&peer, || handle_data(&mut reader, server, &transfer.name, offset, transfer.id, None)) {
+        return Err(io::Error::other("接收方处理数据时出错"));
+    }
+    if let Some(e) = reader.error {
+        return Err(e);
+    }
+    if reader.sent != length {
+        warn!("Core: {} 在发送过程中被截短", source.path);
+        return Err(io::Error::other(TransferError::FileChanged));
+    }
+    Ok(())
+}
+
+// copy_chunk 交给接收流程读取的数据源，与 send_chunk 一样记进度并检查取消、时限和文件改动。
+// 出错时记下原因再让读取失败，接收流程按连接中断处理
+struct LocalSource<'a> {
+    file: io::Take<File>,
+    source: &'a SourceFile,
+    progress: &'a AtomicU64,
+    length: u64,
+    sent: u64,
+    error: Option<io::Error>,
+}
+
+impl Read for LocalSource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        self.sent += n as u64;
+        let checkpoint = self.sent == self.length || self.sent % (1024 * 1024) < n as u64;
+        if n > 0
+            && (checkpoint || self.source.stopped())
+            && let Err(e) = self.source.check()
+        {
+            let kind = e.kind();
+            self.error = Some(e);
+            return Err(kind.into());
+        }
+        METRICS.add_bytes_sent(n as u64);
+        self.progress.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// 单独一条连接完成 REQ 握手，返回接收方确认的文件名、对分片方式的要求和传输编号，随后关闭握手连接
+fn request_send(
+    target_ip: &str,
+    port: u16,
+    file_name: &str,
+    file_len: u64,
+    sequential: bool,
+) -> Result<Accepted, String> {
+    let mut stream = connect_peer(target_ip, port)
+        .map_err(|e| format!("连接失败: {:?}", e))?;
+
+    let req_msg = if sequential {
+        format!("REQ|{}|{}|{}\n", file_name, file_len, SEQUENTIAL)
+    } else {
+        format!("REQ|{}|{}\n", file_name, file_len)
+    };
+    let _ = stream.write_all(req_msg.as_bytes());
+
+    // 等待响应
+    stream.set_read_timeout(Some(REPLY_TIMEOUT)).ok();
+    let response = read_reply_line(&mut stream)?;
+    accepted_name(&response, file_name)
+}
+
+// 等对方回应 REQ 的上限。对方可能要等用户点同意，比接收方的 header_timeout 宽得多
+const REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+// 一行应答最多这么多字节，超过仍没有换行按格式错误处理
+const MAX_REPLY_LEN: u64 = 4096;
+
+// 读一行应答（不含 '\n'）。应答必须以换行结尾，且之后不能紧跟着别的数据，
+// 多出来的内容说明对方重复应答（例如 ACC 之后又回 REJ）
+fn read_reply_line<R: Read>(stream: &mut R) -> Result<String, String> {
+    let mut reader = BufReader::new(stream.take(MAX_REPLY_LEN));
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => return Err("连接已断开".to_string()),
+        Ok(_) => {}
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            return Err("等待对方应答超时".to_string());
+        }
+        Err(e) => return Err(format!("读取应答失败: {:?}", e)),
+    }
+    match line.strip_suffix('\n') {
+        Some(reply) if reader.buffer().is_empty() => Ok(reply.to_string()),
+        _ => Err(format!("对方应答格式错误: {:?}", line + &String::from_utf8_lossy(reader.buffer()))),
+    }
+}
+
+// 自适应模式下每次分配给一条连接的数据量
+const ADAPTIVE_BLOCK: u64 = 4 * 1024 * 1024;
+// 两次吞吐测量之间的间隔
+const ADAPTIVE_INTERVAL: Duration = Duration::from_millis(500);
+// 新增一条连接后吞吐至少提升这么多才继续加
+const ADAPTIVE_MIN_GAIN: f64 = 1.1;
+
+// 自适应并行发送：文件切成固定大小的块，空闲连接依次领取。
+// 先用一条连接，每个测量周期比较总吞吐，有明显提升就再加一条，
+// 不再提升时撤掉最后加的那条并保持不变，连接数不超过 max_streams
+fn transfer_file_adaptive(
+    target_ip: &str,
+    port: u16,
+    file_path: &str,
+    max_streams: u64,
+    limits: &SendLimits,
+    checkpoint: Option<u64>,
+) -> Result<u64, TransferError> {
+    let (file_name, mut source) = inspect_source(file_path)?;
+    source.limits = limits.clone();
+    source.checkpoint = checkpoint;
+    let file_len = source.len;
+    let max_streams = max_streams.max(1);
+    let accepted = request_send(target_ip, port, &file_name, file_len, false)?;
+    let layout = accepted.layout;
+    // 空文件没有可测的吞吐，发一个空分片让接收方收尾即可；
+    // 对方要求顺序写入时整个文件走一条连接，分块的话后一块可能先于前一块写入
+    if file_len == 0 || layout == Layout::Sequential {
+        send_chunk(target_ip, port, &accepted, &source, 0, file_len, &AtomicU64::new(0))
+            .map_err(TransferError::from_io)?;
+        METRICS.transfer_sent();
+        return Ok(file_len);
+    }
+
+    // 对方希望的分片比默认块大时按对方的来，连接数也不超过能分出的块数
+    let block = match layout {
+        Layout::MinChunk(min_chunk) => min_chunk.max(ADAPTIVE_BLOCK),
+        _ => ADAPTIVE_BLOCK,
+    };
+    let max_streams = max_streams.min(file_len.div_ceil(block)).max(1);
+
+    let next_offset = AtomicU64::new(0);
+    let target = AtomicU64::new(1);
+    let failed = AtomicBool::new(false);
+    let first_error: Mutex<Option<TransferError>> = Mutex::new(None);
+    let sent = AtomicU64::new(0);
+
+    info!("Core: 开始自适应并行传输，最多 {} 条连接", max_streams);
+
+    thread::scope(|scope| {
+        let worker = |index: u64| {
+            let (next_offset, target, failed, first_error) = (&next_offset, &target, &failed, &first_error);
+            let (sent, accepted, source) = (&sent, &accepted, &source);
+            move || {
+                // 连接数被调低后，编号超出的连接发完手上的块就退出
+                while !failed.load(Ordering::Relaxed) && index < target.load(Ordering::Relaxed) {
+                    let offset = next_offset.fetch_add(block, Ordering::Relaxed);
+                    if offset >= file_len {
+                        break;
+                    }
+                    let length = block.min(file_len - offset);
+                    if let Err(e) = send_chunk(target_ip, port, accepted, source, offset, length, sent) {
+                        error!("连接 {} 传输失败: {:?}", index, e);
+                        failed.store(true, Ordering::Relaxed);
+                        lock(first_error).get_or_insert(TransferError::from_io(e));
+                    }
+                }
+            }
+        };
+
+        let mut handles = vec![scope.spawn(worker(0))];
+        let mut ramp = Ramp::new(max_streams);
+        let mut last_bytes = 0u64;
+        let mut last_check = std::time::Instant::now();
+
+        while !handles.iter().all(|h| h.is_finished()) {
+            thread::sleep(Duration::from_millis(50));
+            if ramp.settled || last_check.elapsed() < ADAPTIVE_INTERVAL {
+                continue;
+            }
+
+            let bytes = sent.load(Ordering::Relaxed);
+            let rate = (bytes - last_bytes) as f64 / last_check.elapsed().as_secs_f64();
+            last_bytes = bytes;
+            last_check = std::time::Instant::now();
+
+            let active = ramp.observe(rate);
+            target.store(active, Ordering::Relaxed);
+            while (handles.len() as u64) < active {
+                handles.push(scope.spawn(worker(handles.len() as u64)));
+            }
+        }
+    });
+
+    if failed.load(Ordering::Relaxed) {
+        match lock(&first_error).take() {
+            Some(reason @ (TransferError::FileChanged | TransferError::Timeout | TransferError::Cancelled)) => Err(reason),
+            detail => Err(format!("传输过程中发生错误: {}", detail.map(|e| e.to_string()).unwrap_or_default()).into()),
+        }
+    } else {
+        METRICS.transfer_sent();
+        Ok(file_len)
+    }
+}
+
+// 自适应模式的连接数：从一条开始，每个测量周期按总吞吐调整一次，
+// 有明显提升就加一条，到上限或不再提升（撤掉最后加的那条）后不再变化
+struct Ramp {
+    active: u64,
+    max: u64,
+    best_rate: f64,
+    settled: bool,
+}
+
+impl Ramp {
+    fn new(max: u64) -> Self {
+        Self { active: 1, max, best_rate: 0.0, settled: max <= 1 }
+    }
+
+    // 记下本周期的吞吐（字节/秒），返回调整后的连接数
+    fn observe(&mut self, rate: f64) -> u64 {
+        if rate > self.best_rate * ADAPTIVE_MIN_GAIN {
+            self.best_rate = rate;
+            if self.active < self.max {
+                self.active += 1;
+                info!("Core: 吞吐 {:.1} MB/s，增加到 {} 条连接", rate / 1e6, self.active);
+            } else {
+                self.settled = true;
+                info!("Core: 吞吐 {:.1} MB/s，已达到上限 {} 条连接", rate / 1e6, self.active);
+            }
+        } else {
+            if self.active > 1 {
+                self.active -= 1;
+            }
+            self.settled = true;
+            info!("Core: 吞吐 {:.1} MB/s 没有明显提升，保持 {} 条连接", rate / 1e6, self.active);
+        }
+        self.active
+    }
+}
+
+// 接收方在 ACC 里对分片方式的要求
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Layout {
+    // 由发送方决定
+    #[default]
+    Any,
+    // 只用一条连接顺序发送
+    Sequential,
+    // 每个分片不小于这么多字节
+    MinChunk(u64),
+}
+
+// 解析后的握手应答
+#[derive(Debug, PartialEq)]
+enum Reply<'a> {
+    // ACC[|name[|seq 或 chunk=N][|tid=N]]，name 为接收方确认的文件名，id 为传输编号
+    Accept { name: Option<&'a str>, layout: Layout, id: Option<u64> },
+    // REJ[|reason]
+    Reject(Option<&'a str>),
+    // ERR|reason，对方不认识这个请求
+    Error(&'a str),
+}
+
+// 按完整的 token 解析一行应答（不含 '\n'），ACCIDENT、ACCREJ 之类一律视为协议错误
+fn parse_reply(line: &str) -> Result<Reply<'_>, String> {
+    let parts: Vec<&str> = line.split('|').collect();
+    match parts.as_slice() {
+        ["ACC"] => Ok(Reply::Accept { name: None, layout: Layout::Any, id: None }),
+        ["ACC", name, flags @ ..] if flags.len() <= 2 => {
+            let malformed = || format!("对方应答格式错误: {:?}", line);
+            let (mut layout, mut id) = (None, None);
+            // 分片要求在前，传输编号在最后，各至多一段
+            for flag in flags {
+                match flag.strip_prefix(TRANSFER_ID_PREFIX) {
+                    Some(n) if id.is_none() => id = Some(n.parse().map_err(|_| malformed())?),
+                    None if layout.is_none() && id.is_none() => layout = Some(parse_layout(flag).ok_or_else(malformed)?),
+                    _ => return Err(malformed()),
+                }
+            }
+            Ok(Reply::Accept { name: Some(*name).filter(|n| !n.is_empty()), layout: layout.unwrap_or_default(), id })
+        }
+        ["REJ"] => Ok(Reply::Reject(None)),
+        ["REJ", reason] => Ok(Reply::Reject(Some(reason))),
+        ["ERR", reason] => Ok(Reply::Error(reason)),
+        _ => Err(format!("对方应答格式错误: {:?}", line)),
+    }
+}
+
+// ACC 第三段：seq 或 chunk=N（N 大于 0）
+fn parse_layout(flag: &str) -> Option<Layout> {
+    if flag == SEQUENTIAL {
+        return Some(Layout::Sequential);
+    }
+    flag.strip_prefix(CHUNK_PREFIX)
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .map(Layout::MinChunk)
+}
+
+// 对方接受请求时确认的内容
+struct Accepted {
+    // 接收方确认的文件名（旧版本只回 ACC，沿用原名），DATA 使用这个名字
+    name: String,
+    // 对分片方式的要求
+    layout: Layout,
+    // 接收方分配的传输编号，旧版本没有
+    id: Option<u64>,
+}
+
+// 解析握手应答，对方拒绝或应答格式不对时返回错误说明
+fn accepted_name(line: &str, requested: &str) -> Result<Accepted, String> {
+    match parse_reply(line)? {
+        Reply::Accept { name, layout, id } => {
+            Ok(Accepted { name: name.unwrap_or(requested).to_string(), layout, id })
+        }
+        refused => Err(refused_reason(&refused)),
+    }
+}
+
+// 握手未被接受时给出的错误说明，区分对方拒绝和对方不认识这个请求
+fn refused_reason(reply: &Reply) -> String {
+    match reply {
+        Reply::Error(reason) => format!("对方无法处理该请求: {}", reason),
+        _ => "对方拒绝接收".to_string(),
+    }
+}
+
+// 不复用连接：逐个文件走完整的 REQ + 并行分片流程
+fn send_files_sequential(
+    target_ip: &str,
+    port: u16,
+    file_paths: &[String],
+    parallel_cnt: u64,
+    limits: &SendLimits,
+    callback: &dyn TransferCallback,
+) -> Result<usize, String> {
+    for (i, file_path) in file_paths.iter().enumerate() {
+        transfer_file(target_ip, port, file_path, &SendHandle::new(parallel_cnt), limits, None)
+            .map_err(|msg| format!("{}: {}", file_path, msg))?;
+        callback.on_progress(i as u64 + 1, file_paths.len() as u64);
+    }
+    Ok(file_paths.len())
+}
+
+// 复用连接：开 min(parallel_cnt, 文件数) 条 MUX 长连接，各线程从队列里取文件，
+// 在自己的连接上依次发 REQ 和整文件的 DATA 帧
+fn send_files_reused(
+    target_ip: &str,
+    port: u16,
+    file_paths: &[String],
+    parallel_cnt: u64,
+    limits: &SendLimits,
+    callback: &dyn TransferCallback,
+) -> Result<usize, String> {
+    use std::sync::atomic::AtomicUsize;
+
+    let total = file_paths.len();
+    let pool_size = (parallel_cnt.max(1) as usize).min(total.max(1));
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let first_error = Mutex::new(None::<String>);
+
+    info!("Core: 复用 {} 条连接发送 {} 个文件", pool_size, total);
+
+    thread::scope(|scope| {
+        for worker in 0..pool_size {
+            let (next, done, failed, first_error) = (&next, &done, &failed, &first_error);
+            scope.spawn(move || {
+                let result = (|| -> Result<(), String> {
+                    let mut stream = connect_peer(target_ip, port)
+                        .map_err(|e| format!("连接失败: {:?}", e))?;
+                    stream.set_nodelay(true).ok();
+                    stream.write_all(b"MUX\n").map_err(|e| e.to_string())?;
+
+                    while !failed.load(Ordering::Relaxed) {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(file_path) = file_paths.get(idx) else { break };
+                        if let Some(remaining) = limits.remaining() {
+                            stream.set_write_timeout(Some(remaining)).ok();
+                        }
+                        send_file_on_mux(&mut stream, file_path, limits)
+                            .map_err(|msg| format!("{}: {}", file_path, msg))?;
+                        let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
+                        callback.on_progress(finished as u64, total as u64);
+                    }
+                    Ok(())
+                })();
+
+                if let Err(msg) = result {
+                    error!("连接 {} 传输失败: {}", worker, msg);
+                    failed.store(true, Ordering::Relaxed);
+                    lock(first_error).get_or_insert(msg);
+                }
+            });
+        }
+    });
+
+    match first_error.into_inner().unwrap_or_else(|e| e.into_inner()) {
+        Some(msg) => Err(msg),
+        None => Ok(total),
+    }
+}
+
+// 在已建立的 MUX 连接上发送一个文件: REQ|name|size -> ACC -> DATA|name|0|size + 数据
+fn send_file_on_mux<S: Read + Write>(
+    stream: &mut S,
+    file_path: &str,
+    limits: &SendLimits,
+) -> Result<(), TransferError> {
+    let (file_name, mut source) = inspect_source(file_path)?;
+    source.limits = limits.clone();
+    source.check().map_err(TransferError::from_io)?;
+    let file_len = source.len;
+
+    let req_msg = format!("REQ|{}|{}|{}\n", file_name, file_len, SEQUENTIAL);
+    stream.write_all(req_msg.as_bytes()).map_err(|e| e.to_string())?;
+
+    // 应答只有一行，逐字节读取，避免多读到后续数据
+    let response = read_header_line(stream).ok_or_else(|| "连接已断开".to_string())?;
+    let accepted = accepted_name(&response, &file_name)?;
+    send_mux_frame(stream, &accepted.name, accepted.id, &source, 0)
+}
+
+// 把文件从 offset 到末尾作为一个 DATA|name|offset|len[|tid=N] 帧写出，name 和 id 为接收方确认的名字和传输编号
+fn send_mux_frame<W: Write>(
+    stream: &mut W,
+    file_name: &str,
+    id: Option<u64>,
+    source: &SourceFile,
+    offset: u64,
+) -> Result<(), TransferError> {
+    let len = source.len - offset;
+    let header = data_header(file_name, offset, Some(len), id, None);
+    stream.write_all(header.as_bytes()).map_err(|e| e.to_string())?;
+
+    // 分块写出，每块之前检查是否已被取消
+    let mut file = File::open(&source.path).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    let mut file = file.take(len);
+    let mut buffer = [0u8; 64 * 1024];
+    let mut sent = 0u64;
+    loop {
+        let n = file.read(&mut buffer).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        if let Some(reason) = source.limits.stop_reason() {
+            return Err(reason);
+        }
+        stream.write_all(&buffer[..n]).map_err(|e| TransferError::from_io(source.explain(e)))?;
+        sent += n as u64;
+        METRICS.add_bytes_sent(n as u64);
+    }
+    if sent != len || source.check_unchanged().is_err() {
+        return Err(TransferError::FileChanged);
+    }
+    METRICS.transfer_sent();
+    Ok(())
+}
+
+// 批量发送：清单换来对方一次确认和每个文件的最终名字，
+// 之后在同一条连接上按顺序把每个文件作为 MUX 帧发出
+fn send_files_batch(
+    target_ip: &str,
+    port: u16,
+    file_paths: &[String],
+    limits: &SendLimits,
+    callback: &dyn TransferCallback,
+) -> Result<usize, String> {
+    let mut files = Vec::with_capacity(file_paths.len());
+    for file_path in file_paths {
+        let (file_name, mut source) = inspect_source(file_path).map_err(|msg| format!("{}: {}", file_path, msg))?;
+        source.limits = limits.clone();
+        files.push((file_name, source));
+    }
+    send_manifest(target_ip, port, "BATCH", &files, &[], false, callback)
+}
+
+// 发出 kind|n 加 (清单名, 文件) 清单，对方确认后在同一条连接上依次发送。
+// kind 为 BATCH 时对方只保留文件名，为 DIR 时按清单里的相对路径保存，
+// empty_dirs 只随 DIR 发送，对方直接创建，不回最终名字也没有数据。
+// resume 为 true 时请求续传：清单里附上每个文件的来源标识，对方在每个名字后附上核对无误、可以接着用的字节数，只发剩下的部分
+fn send_manifest(
+    target_ip: &str,
+    port: u16,
+    kind: &str,
+    files: &[(String, SourceFile)],
+    empty_dirs: &[String],
+    resume: bool,
+    callback: &dyn TransferCallback,
+) -> Result<usize, String> {
+    if files.is_empty() && empty_dirs.is_empty() {
+        return Ok(0);
+    }
+    let mut stream = connect_peer(target_ip, port)
+        .map_err(|e| format!("连接失败: {:?}", e))?;
+    stream.set_nodelay(true).ok();
+    let mut manifest = format!("{}|{}", kind, files.len() + empty_dirs.len());
+    if resume {
+        manifest.push_str(&format!("|{}", RESUME));
+    }
+    manifest.push('\n');
+    for (file_name, source) in files {
+        manifest.push_str(&format!("{}|{}", file_name, source.len));
+        if resume {
+            let identity = resume::SourceIdentity::of(Path::new(&source.path), source.modified)
+                .map_err(|e| format!("{}: {}", source.path, e))?;
+            manifest.push_str(&identity.manifest_suffix());
+        }
+        manifest.push('\n');
+    }
+    for dir in empty_dirs {
+        manifest.push_str(&format!("{}|{}\n", dir, EMPTY_DIR));
+    }
+    stream.write_all(manifest.as_bytes()).map_err(|e| e.to_string())?;
+
+    let response = read_header_line(&mut stream).ok_or_else(|| "连接已断开".to_string())?;
+    // 清单只接受不带文件名的 ACC，确认的名字随后逐行给出
+    match parse_reply(&response)? {
+        Reply::Accept { name: None, layout: Layout::Any, id: None } => {}
+        Reply::Accept { .. } => return Err(format!("对方应答格式错误: {:?}", response)),
+        refused => return Err(refused_reason(&refused)),
+    }
+    let names = (0..files.len())
+        .map(|_| read_header_line(&mut stream))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| "连接已断开".to_string())?;
+    info!("Core: 对方接受 {} 个文件，开始依次发送", files.len());
+
+    for (i, ((_, source), line)) in files.iter().zip(&names).enumerate() {
+        // 续传时名字后面是对方已有的字节数，不支持续传的对方只回名字
+        let (name, offset) = match line.rsplit_once('|').filter(|_| resume) {
+            Some((name, offset)) => (name, offset.parse::<u64>().map_err(|_| format!("对方应答格式错误: {:?}", line))?),
+            None => (line.as_str(), 0),
+        };
+        if offset > source.len {
+            return Err(format!("{}: 对方已有 {} 字节，超过文件大小 {}", source.path, offset, source.len));
+        }
+        // 空文件照样发一个空帧，对方收到才算完成
+        if offset == source.len && source.len > 0 {
+            info!("Core: 对方已有完整的 {}，跳过", name);
+            callback.on_progress(i as u64 + 1, files.len() as u64);
+            continue;
+        }
+        if let Some(remaining) = source.remaining() {
+            stream.set_write_timeout(Some(remaining)).ok();
+        }
+        source.check()
+            .map_err(TransferError::from_io)
+            .and_then(|_| send_mux_frame(&mut stream, name, None, source, offset))
+            .map_err(|msg| format!("{}: {}", source.path, msg))?;
+        callback.on_progress(i as u64 + 1, files.len() as u64);
+    }
+    Ok(files.len())
+}
+
+// DATA|name|offset[|len][|tid=N][|ck=N]
+fn data_header(filename: &str, offset: u64, len: Option<u64>, id: Option<u64>, checkpoint: Option<u64>) -> String {
+    let mut header = format!("DATA|{}|{}", filename, offset);
+    if let Some(len) = len {
+        header.push_str(&format!("|{}", len));
+    }
+    if let Some(id) = id {
+        header.push_str(&format!("|{}{}", TRANSFER_ID_PREFIX, id));
+    }
+    if let Some(interval) = checkpoint {
+        header.push_str(&format!("|{}{}", CHECKPOINT_PREFIX, interval));
+    }
+    header.push('\n');
+    header
+}
+
+// 源文件在开始、每发出约 1 MiB、以及最后一块数据发出前都会与快照比对，
+// 发现被改动就中断连接，接收方收不齐数据也就不会当作完成
+fn send_chunk(
+    ip: &str,
+    port: u16,
+    transfer: &Accepted,
+    source: &SourceFile,
+    offset: u64,
+    length: u64,
+    progress: &AtomicU64,
+) -> std::io::Result<()> {
+    source.check()?;
+    let mut file = File::open(&source.path)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut stream = connect_peer(ip, port)?;
+    stream.set_nodelay(true).ok();
+    stream.set_write_timeout(source.remaining())?;
+
+    // 发送数据头: DATA|filename|offset[|tid=N][|ck=N]\n
+    let header = data_header(&transfer.name, offset, None, transfer.id, source.checkpoint);
+    stream.write_all(header.as_bytes()).map_err(|e| source.explain(e))?;
+    let mut stream = CheckpointWriter::new(stream, source.checkpoint);
+
+    // 使用 take 限制读取长度，防止读过界
+    let mut handle = file.take(length);
+    let mut buffer = [0u8; 64 * 1024];
+    let mut sent = 0u64;
+
+    loop {
+        let n = handle.read(&mut buffer)?;
+        if n == 0 { break; }
+        sent += n as u64;
+        if sent == length || sent % (1024 * 1024) < n as u64 || source.stopped() {
+            source.check()?;
+        }
+        stream.write_all(&buffer[..n]).map_err(|e| source.explain(e))?;
+        METRICS.add_bytes_sent(n as u64);
+
+        progress.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    if sent != length {
+        // 文件被截短，读不够这一段
+        warn!("Core: {} 在发送过程中被截短", source.path);
+        return Err(io::Error::other(TransferError::FileChanged));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{exclusive_sends, file_server, file_server_with, serve_on_loopback, shared_sends, temp_dir, Event, Recorder};
+    use super::*;
+    use std::sync::mpsc;
+
+    // 登记一个已接受的文件，返回传输编号
+    fn register(server: &FileServer, dir: &Path, name: &str, size: u64) -> u64 {
+        create_accepted_file(server, dir, name, size, true, "127.0.0.1").unwrap().1
+    }
+
+    #[test]
+    fn untagged_data_to_unknown_name_is_refused() {
+        let dir = temp_dir("untagged-unknown");
+        fs::write(dir.join("victim.txt"), b"original").unwrap();
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+
+        handle_data(&mut &b"overwritten"[..], &server, "victim.txt", 0, None, None);
+
+        assert_eq!(fs::read(dir.join("victim.txt")).unwrap(), b"original");
+        assert!(recorder.events().is_empty());
+    }
+
+    #[test]
+    fn untagged_data_needs_opt_in_and_unfinished_transfer() {
+        let dir = temp_dir("untagged-legacy");
+        let (server, _) = file_server(&dir, ServerConfig::default());
+        let id = register(&server, &dir, "a.bin", 4);
+        assert!(claim_transfer(&server, "a.bin", None, &[]).is_err());
+        assert_eq!(claim_transfer(&server, "a.bin", Some(id), &[]), Ok(id));
+        assert!(claim_transfer(&server, "a.bin", Some(id + 1), &[]).is_err());
+        assert_eq!(claim_transfer(&server, "a.bin", None, &[("a.bin".to_string(), 4)]), Ok(id));
+
+        let config = ServerConfig { allow_untagged_data: true, ..ServerConfig::default() };
+        let (legacy, _) = file_server(&dir, config);
+        let id = register(&legacy, &dir, "b.bin", 4);
+        assert_eq!(claim_transfer(&legacy, "b.bin", None, &[]), Ok(id));
+        assert!(claim_transfer(&legacy, "missing.bin", None, &[]).is_err());
+        lock(&legacy.accepted).get_mut("b.bin").unwrap().finished = true;
+        assert!(claim_transfer(&legacy, "b.bin", None, &[]).is_err());
+    }
+
+    #[test]
+    fn tagged_data_is_written_to_registered_file() {
+        let dir = temp_dir("tagged");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let id = register(&server, &dir, "a.bin", 5);
+
+        handle_data(&mut &b"hello"[..], &server, "a.bin", 0, Some(id), None);
+
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"hello");
+        assert_eq!(recorder.events(), vec![Event::Complete(true, "a.bin".to_string())]);
+    }
+
+    #[test]
+    fn checkpoint_interval_below_minimum_is_refused() {
+        assert!(matches!(parse_header("DATA|a.bin|0|tid=1|ck=1"), Err("BadHeader")));
+        assert!(matches!(parse_header("DATA|a.bin|0|tid=1|ck=0"), Err("BadHeader")));
+        let header = data_header("a.bin", 0, None, Some(1), Some(MIN_CHECKPOINT_INTERVAL));
+        assert!(matches!(
+            parse_header(header.trim_end()),
+            Ok(Header::Data { checkpoint: Some(MIN_CHECKPOINT_INTERVAL), .. })
+        ));
+    }
+
+    #[test]
+    fn corrupted_checkpoint_fails_the_file_early() {
+        let dir = temp_dir("checkpoint");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let interval = MIN_CHECKPOINT_INTERVAL;
+        let size = interval * 4;
+        let id = register(&server, &dir, "a.bin", size);
+        let mut stream = Vec::new();
+        let mut writer = CheckpointWriter::new(&mut stream, Some(interval));
+        writer.write_all(&vec![5u8; size as usize]).unwrap();
+        // 第二段数据里改一个字节
+        stream[(interval + 32 + 10) as usize] ^= 0xff;
+
+        let mut reader = &stream[..];
+        handle_data(&mut reader, &server, "a.bin", 0, Some(id), Some(interval));
+
+        assert!(recorder.events().contains(&Event::Error(TransferError::ChecksumMismatch)));
+        // 读完第二个检查点就停下，后两段没有读
+        assert_eq!(reader.len() as u64, 2 * (interval + 32));
+    }
+
+    #[test]
+    fn finished_transfer_is_forgotten_and_late_data_refused() {
+        let dir = temp_dir("forget");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let id = register(&server, &dir, "a.bin", 5);
+
+        handle_data(&mut &b"hello"[..], &server, "a.bin", 0, Some(id), None);
+        assert!(lock(&server.accepted).is_empty());
+
+        handle_data(&mut &b"later"[..], &server, "a.bin", 0, Some(id), None);
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"hello");
+        assert_eq!(recorder.events().len(), 1);
+    }
+
+    // 读固定输入、把写出的内容收起来的连接
+    struct Duplex {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn repeated_batch_names_are_saved_separately() {
+        let dir = temp_dir("batch-repeat");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let mut manifest = Duplex { input: io::Cursor::new(b"a.txt|3\na.txt|2\n".to_vec()), output: Vec::new() };
+        let sizes = handle_batch(&mut manifest, &server, "127.0.0.1", 2, false, false).unwrap();
+        assert_eq!(String::from_utf8(manifest.output).unwrap(), "ACC\na.txt\na (1).txt\n");
+
+        let frames = b"DATA|a.txt|0|3\noneDATA|a (1).txt|0|2\ntw".to_vec();
+        handle_mux_session(Duplex { input: io::Cursor::new(frames), output: Vec::new() }, "127.0.0.1", &server, sizes);
+
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"one");
+        assert_eq!(fs::read(dir.join("a (1).txt")).unwrap(), b"tw");
+        assert_eq!(recorder.events().len(), 2);
+        assert!(lock(&server.accepted).is_empty());
+    }
+
+    // 数据读完后等 gate 放行才报告 EOF，模拟数据已经发完但还没关闭的分片连接
+    struct GatedReader {
+        data: io::Cursor<Vec<u8>>,
+        gate: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl Read for GatedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.data.read(buf)?;
+            if n == 0 {
+                let _ = self.gate.recv();
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn buffered_chunks_complete_only_after_every_connection_flushed() {
+        let dir = temp_dir("buffered");
+        let config = ServerConfig { write_buffer_cap: Some(64 * 1024), ..ServerConfig::default() };
+        let (server, recorder) = file_server(&dir, config);
+        let data: Vec<u8> = (0..400_000u32).map(|i| (i % 251) as u8).collect();
+        let (first, second) = data.split_at(200_000);
+        let id = create_accepted_file(&server, &dir, "a.bin", data.len() as u64, false, "127.0.0.1").unwrap().1;
+
+        let (release, gate) = std::sync::mpsc::channel();
+        let slow = {
+            let (server, first) = (server.clone(), first.to_vec());
+            thread::spawn(move || {
+                let mut reader = GatedReader { data: io::Cursor::new(first), gate };
+                handle_data(&mut reader, &server, "a.bin", 0, Some(id), None);
+            })
+        };
+        while lock(&server.accepted)["a.bin"].received < first.len() as u64 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // 计数已经够了，但第一条连接的数据可能还在写盘缓冲里，这时不能收尾
+        handle_data(&mut &second[..], &server, "a.bin", first.len() as u64, Some(id), None);
+        assert!(recorder.events().is_empty());
+
+        release.send(()).unwrap();
+        slow.join().unwrap();
+        assert_eq!(recorder.events(), vec![Event::Complete(true, "a.bin".to_string())]);
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_file_completes_when_its_connection_closes() {
+        let dir = temp_dir("empty");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let id = register(&server, &dir, "empty.bin", 0);
+
+        handle_data(&mut &b""[..], &server, "empty.bin", 0, Some(id), None);
+
+        assert_eq!(recorder.events(), vec![Event::Complete(true, "empty.bin".to_string())]);
+    }
+
+    fn lenient(min_completion: f64, idle_ms: u64) -> ServerConfig {
+        ServerConfig {
+            min_completion,
+            partial_idle_timeout: Duration::from_millis(idle_ms),
+            ..ServerConfig::default()
+        }
+    }
+
+    // 登记一个需要预分配的 1000 字节文件，模拟并行分片
+    fn register_parallel(server: &FileServer, dir: &Path) -> u64 {
+        create_accepted_file(server, dir, "f.bin", 1000, false, "127.0.0.1").unwrap().1
+    }
+
+    #[test]
+    fn ninety_nine_percent_fails_under_strict_setting() {
+        let dir = temp_dir("strict");
+        let (server, recorder) = file_server(&dir, lenient(1.0, 20));
+        let id = register_parallel(&server, &dir);
+
+        handle_data(&mut &[1u8; 990][..], &server, "f.bin", 0, Some(id), None);
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(recorder.events().is_empty());
+    }
+
+    #[test]
+    fn ninety_nine_percent_is_partial_under_lenient_setting_after_idle_timeout() {
+        let dir = temp_dir("lenient");
+        let (server, recorder) = file_server(&dir, lenient(0.95, 50));
+        let id = register_parallel(&server, &dir);
+
+        handle_data(&mut &[1u8; 990][..], &server, "f.bin", 0, Some(id), None);
+
+        // 连接刚关闭时还不能下结论
+        assert!(recorder.events().is_empty());
+        let partial = recorder.wait_for(|e| matches!(e, Event::Partial(..)));
+        assert_eq!(partial, Some(Event::Partial("f.bin".to_string(), 990, 1000)));
+        assert_eq!(fs::metadata(dir.join("f.bin")).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn gap_between_connections_is_not_partial_completion() {
+        let dir = temp_dir("gap");
+        let (server, recorder) = file_server(&dir, lenient(0.5, 300));
+        let id = register_parallel(&server, &dir);
+
+        // 自适应模式下一块数据一条连接，两条连接之间连接数为 0
+        handle_data(&mut &[1u8; 600][..], &server, "f.bin", 0, Some(id), None);
+        thread::sleep(Duration::from_millis(50));
+        handle_data(&mut &[2u8; 400][..], &server, "f.bin", 600, Some(id), None);
+
+        assert_eq!(recorder.events(), vec![Event::Complete(true, "f.bin".to_string())]);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(recorder.events().len(), 1);
+        let data = fs::read(dir.join("f.bin")).unwrap();
+        assert!(data[..600].iter().all(|&b| b == 1) && data[600..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn send_to_own_server_completes_without_the_network() {
+        let dir = temp_dir("self-send");
+        let (server, recorder) = file_server(&dir.join("inbox"), ServerConfig::default());
+        // 端口上没有任何监听，走网络的话连接会被拒绝
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        register_local_server(port, &server);
+        let source = dir.join("self.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        let tracker = SendHandle::new(4);
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &tracker, &SendLimits::default(), None);
+
+        assert_eq!(sent, Ok(data.len() as u64));
+        assert_eq!(tracker.total_sent(), data.len() as u64);
+        assert_eq!(recorder.events(), vec![Event::Complete(true, "self.bin".to_string())]);
+        assert_eq!(fs::read(dir.join("inbox/self.bin")).unwrap(), data);
+        assert!(lock(&server.accepted).is_empty());
+    }
+
+    #[test]
+    fn alias_cannot_break_discovery_packets() {
+        let handle = DiscoveryHandle::new("客厅|电脑".to_string());
+        assert_eq!(handle.alias(), "客厅_电脑");
+        handle.set_alias("HERE|evil|x|1\n");
+        assert_eq!(handle.alias(), "HERE_evil_x_1_");
+        let here = DiscoveryMessage::Here { device_id: "dev".into(), name: handle.alias(), port: 4061, free_space: None };
+        assert_eq!(DiscoveryMessage::decode(here.encode().as_bytes()), Some(here));
+    }
+
+    // 记下监听线程回调的发现结果和无效包
+    #[derive(Clone, Default)]
+    struct Sightings {
+        found: Arc<Mutex<Vec<DeviceInfo>>>,
+        invalid: Arc<Mutex<Vec<(usize, String)>>>,
+        stopped: Arc<Mutex<Vec<io::ErrorKind>>>,
+    }
+
+    impl DiscoveryCallback for Sightings {
+        fn on_device_found(&self, device_info: DeviceInfo) {
+            lock(&self.found).push(device_info);
+        }
+
+        fn on_invalid_packet(&self, raw: &[u8], _source: SocketAddr, reason: &str) {
+            lock(&self.invalid).push((raw.len(), reason.to_string()));
+        }
+
+        fn on_listener_stopped(&self, error: &io::Error) {
+            lock(&self.stopped).push(error.kind());
+        }
+    }
+
+    // 在回环地址上按 max_packet_size 启动监听，对 send 的每个包调用后返回收到的回调，然后停止监听
+    fn listen_and_send(max_packet_size: usize, packets: &[Vec<u8>]) -> Sightings {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let sightings = Sightings::default();
+        let config = DiscoveryConfig { bind_addr: Ipv4Addr::LOCALHOST, max_packet_size, ..DiscoveryConfig::default() };
+        let stop = Arc::new(AtomicBool::new(false));
+        let (_, thread) = listen_on(socket, port, "me".into(), "me".into(), Box::new(sightings.clone()), config, stop.clone());
+
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        for packet in packets {
+            sender.send_to(packet, (Ipv4Addr::LOCALHOST, port)).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lock(&sightings.found).len() + lock(&sightings.invalid).len() < packets.len() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        stop.store(true, Ordering::Relaxed);
+        sender.send_to(&[], (Ipv4Addr::LOCALHOST, port)).unwrap();
+        thread.join().unwrap();
+        sightings
+    }
+
+    fn here_named(device_id: &str, name: String) -> Vec<u8> {
+        DiscoveryMessage::Here { device_id: device_id.into(), name, port: 4061, free_space: None }.encode().into_bytes()
+    }
+
+    #[test]
+    fn oversized_announcement_is_reported_not_truncated() {
+        let sightings = listen_and_send(1024, &[here_named("big", "x".repeat(2048)), here_named("small", "y".into())]);
+
+        assert_eq!(*lock(&sightings.invalid), vec![(1024, "超过最大长度".to_string())]);
+        let found: Vec<String> = lock(&sightings.found).iter().map(|d| d.device_id.clone()).collect();
+        assert_eq!(found, vec!["small".to_string()]);
+    }
+
+    #[test]
+    fn larger_limit_reads_a_2kb_announcement_whole() {
+        let sightings = listen_and_send(4096, &[here_named("big", "x".repeat(2048))]);
+
+        assert!(lock(&sightings.invalid).is_empty());
+        assert_eq!(lock(&sightings.found)[0].name.len(), 2048);
+    }
+
+    #[test]
+    fn offline_broadcast_interval_backs_off_and_recovers() {
+        let mut interval = BROADCAST_INTERVAL;
+        let mut seen = Vec::new();
+        for _ in 0..5 {
+            interval = next_broadcast_interval(interval, false);
+            seen.push(interval.as_secs());
+        }
+        assert_eq!(seen, vec![10, 20, 40, 60, 60]);
+        assert_eq!(next_broadcast_interval(interval, true), BROADCAST_INTERVAL);
+    }
+
+    fn reply(bytes: &[u8]) -> Result<String, String> {
+        read_reply_line(&mut &bytes[..])
+    }
+
+    #[test]
+    fn reply_must_be_exactly_one_line() {
+        assert_eq!(reply(b"ACC|a.bin|tid=7\n"), Ok("ACC|a.bin|tid=7".to_string()));
+        assert!(reply(b"").is_err());
+        assert!(reply(b"ACC").is_err());
+        assert!(reply(b"ACC\nREJ\n").is_err());
+        assert!(reply(&[b'A'; MAX_REPLY_LEN as usize + 10]).is_err());
+    }
+
+    #[test]
+    fn reply_tokens_are_parsed_exactly() {
+        let accepted = accepted_name("ACC|a (1).bin|chunk=4096|tid=7", "a.bin").unwrap();
+        assert_eq!((accepted.name.as_str(), accepted.layout, accepted.id), ("a (1).bin", Layout::MinChunk(4096), Some(7)));
+        let legacy = accepted_name("ACC", "a.bin").unwrap();
+        assert_eq!((legacy.name.as_str(), legacy.layout, legacy.id), ("a.bin", Layout::Any, None));
+        for line in ["ACCIDENT", "ACCREJ", "ACC|a.bin|tid=x", "ACC|a.bin|tid=1|seq", "REJECTED", ""] {
+            assert!(accepted_name(line, "a.bin").is_err(), "{:?}", line);
+        }
+        assert!(matches!(parse_reply("REJ|LowSpace"), Ok(Reply::Reject(Some("LowSpace")))));
+        assert!(matches!(parse_reply("ERR|UnknownType"), Ok(Reply::Error("UnknownType"))));
+        assert!(accepted_name("REJ|LowSpace", "a.bin").is_err());
+    }
+
+    #[test]
+    fn only_regular_files_can_be_sent() {
+        let dir = temp_dir("inspect");
+        let file = dir.join("a.txt");
+        fs::write(&file, b"abc").unwrap();
+
+        let (name, source) = inspect_source(file.to_str().unwrap()).unwrap();
+        assert_eq!((name.as_str(), source.len), ("a.txt", 3));
+        assert_eq!(inspect_source(dir.to_str().unwrap()).err().as_deref(), Some("不是普通文件"));
+        let missing = dir.join("missing.txt");
+        assert_eq!(inspect_source(missing.to_str().unwrap()).err().as_deref(), Some("文件不存在"));
+        #[cfg(unix)]
+        {
+            // 指向文件的链接按文件发送，名字用链接自己的
+            let link = dir.join("link.txt");
+            std::os::unix::fs::symlink(&file, &link).unwrap();
+            assert_eq!(inspect_source(link.to_str().unwrap()).unwrap().0, "link.txt");
+            let dir_link = dir.join("dir-link");
+            std::os::unix::fs::symlink(&dir, &dir_link).unwrap();
+            assert!(inspect_source(dir_link.to_str().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn reused_connections_carry_every_file() {
+        let dir = temp_dir("reuse");
+        let (server, recorder) = file_server(&dir.join("inbox"), ServerConfig::default());
+        let (port, connections) = serve_on_loopback(server);
+        let paths: Vec<String> = (0..6)
+            .map(|i| {
+                let path = dir.join(format!("f{}.txt", i));
+                fs::write(&path, format!("file {}", i).repeat(i + 1)).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+
+        let progress = Recorder::default();
+        let sent = send_files_reused("127.0.0.1", port, &paths, 2, &SendLimits::default(), &progress);
+
+        assert_eq!(sent, Ok(6));
+        assert!(recorder.wait_len(6));
+        for (i, path) in paths.iter().enumerate() {
+            assert_eq!(fs::read(dir.join("inbox").join(format!("f{}.txt", i))).unwrap(), fs::read(path).unwrap());
+        }
+        // 6 个文件只用了 2 条连接
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn concurrent_renames_reserve_distinct_names() {
+        let dir = temp_dir("rename");
+        fs::write(dir.join("a.txt"), b"old").unwrap();
+
+        let names: Vec<String> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| create_target_file(&dir, "a.txt", ConflictPolicy::Rename).unwrap().1))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 8);
+        assert!(!names.contains(&"a.txt".to_string()));
+        assert!(names.contains(&"a (1).txt".to_string()));
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"old");
+        assert_eq!(create_target_file(&dir, "noext", ConflictPolicy::Rename).unwrap().1, "noext");
+        assert_eq!(create_target_file(&dir, "noext", ConflictPolicy::Rename).unwrap().1, "noext (1)");
+    }
+
+    // 按发送方 IP 分目录保存
+    struct PerSenderDir(PathBuf);
+
+    impl TransferCallback for PerSenderDir {
+        fn on_receive_request(&self, _file_name: String, _file_size: u64, sender_ip: String) -> ReceiveDecision {
+            ReceiveDecision::accept_into(self.0.join(sender_ip))
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+        fn on_complete(&self, _success: bool, _msg: String) {}
+    }
+
+    #[test]
+    fn callback_can_choose_the_save_directory() {
+        let dir = temp_dir("per-transfer-dir");
+        let server = file_server_with(&dir.join("default"), ServerConfig::default(), Box::new(PerSenderDir(dir.clone())));
+
+        let mut reply = Vec::new();
+        let name = handle_request(&mut reply, &server, "10.0.0.7", "a.bin", Some(4), true).unwrap();
+        let id = accepted_name(String::from_utf8(reply).unwrap().trim_end(), "a.bin").unwrap().id;
+        handle_data(&mut &b"data"[..], &server, &name, 0, id, None);
+
+        assert_eq!(fs::read(dir.join("10.0.0.7/a.bin")).unwrap(), b"data");
+        assert!(!dir.join("default/a.bin").exists());
+    }
+
+    // 每次最多读出 chunk 字节、事先不知道总长的数据源，模拟管道
+    struct Trickle {
+        data: io::Cursor<Vec<u8>>,
+        chunk: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.chunk);
+            self.data.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn piped_data_of_unknown_length_arrives_whole() {
+        let dir = temp_dir("stream");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let (port, _) = serve_on_loopback(server);
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7) as u8).collect();
+        let pipe = Trickle { data: io::Cursor::new(data.clone()), chunk: 3000 };
+
+        let sent = transfer_stream("127.0.0.1", port, "piped.bin", Box::new(pipe), &CancelToken::default(), &Recorder::default());
+
+        assert_eq!(sent, Ok(data.len() as u64));
+        assert!(recorder.wait_len(1));
+        assert_eq!(recorder.events(), vec![Event::Complete(true, "piped.bin".to_string())]);
+        assert_eq!(fs::read(dir.join("piped.bin")).unwrap(), data);
+    }
+
+    // 在回环地址上启动监听，返回端口、句柄和停止监听的方法
+    fn loopback_listener(sightings: Sightings, config: DiscoveryConfig) -> (u16, DiscoveryHandle, impl FnOnce()) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let config = DiscoveryConfig { bind_addr: Ipv4Addr::LOCALHOST, ..config };
+        let stop = Arc::new(AtomicBool::new(false));
+        let (handle, thread) = listen_on(socket, port, "me".into(), "me".into(), Box::new(sightings), config, stop.clone());
+        let shutdown = move || {
+            stop.store(true, Ordering::Relaxed);
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().send_to(&[], (Ipv4Addr::LOCALHOST, port)).unwrap();
+            thread.join().unwrap();
+        };
+        (port, handle, shutdown)
+    }
+
+    // 以 peer 的身份向 port 发一个 DISCOVER，返回收到的 HERE，超时返回 None
+    fn probe(port: u16) -> Option<DiscoveryMessage> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let own_port = socket.local_addr().unwrap().port();
+        let discover = DiscoveryMessage::Discover { device_id: "peer".into(), name: "peer".into(), port: own_port };
+        socket.send_to(discover.encode().as_bytes(), (Ipv4Addr::LOCALHOST, port)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let mut buf = [0u8; 1024];
+        let (size, _) = socket.recv_from(&mut buf).ok()?;
+        DiscoveryMessage::decode(&buf[..size])
+    }
+
+    fn here_name(message: Option<DiscoveryMessage>) -> Option<String> {
+        match message? {
+            DiscoveryMessage::Here { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn renamed_device_answers_with_the_new_alias() {
+        let (port, handle, shutdown) = loopback_listener(Sightings::default(), DiscoveryConfig::default());
+
+        assert_eq!(here_name(probe(port)), Some("me".to_string()));
+        handle.set_alias("客厅电脑");
+        assert_eq!(here_name(probe(port)), Some("客厅电脑".to_string()));
+        shutdown();
+    }
+
+    #[test]
+    fn transient_send_errors_are_retried_permanent_ones_are_not() {
+        let mut attempts = 0;
+        let sent = retry_transient(|| {
+            attempts += 1;
+            if attempts < 3 { Err(io::Error::from(io::ErrorKind::WouldBlock)) } else { Ok(5) }
+        });
+        assert_eq!((sent.unwrap(), attempts), (5, 3));
+
+        let mut attempts = 0;
+        let full = retry_transient(|| {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(ENOBUFS))
+        });
+        assert!(full.is_err());
+        assert_eq!(attempts, UDP_SEND_RETRIES + 1);
+
+        let mut attempts = 0;
+        let unreachable = retry_transient(|| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NetworkUnreachable))
+        });
+        assert_eq!(unreachable.unwrap_err().kind(), io::ErrorKind::NetworkUnreachable);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn request_near_the_free_space_limit_is_rejected() {
+        let dir = temp_dir("low-space");
+        let config = ServerConfig { free_space_ratio: Some(2.0), ..ServerConfig::default() };
+        let (server, _) = file_server(&dir, config);
+        // 留出余量，其他测试同时写盘也不影响结论
+        let margin = 16 << 20;
+        let half = fs2::available_space(&dir).unwrap() / 2;
+
+        let mut out = Vec::new();
+        assert_eq!(handle_request(&mut out, &server, "127.0.0.1", "big.bin", Some(half + margin), true), None);
+        assert_eq!(out, b"REJ|LowSpace\n");
+        assert!(!dir.join("big.bin").exists());
+
+        let mut out = Vec::new();
+        let fits = half.saturating_sub(margin);
+        assert_eq!(handle_request(&mut out, &server, "127.0.0.1", "fits.bin", Some(fits), true).as_deref(), Some("fits.bin"));
+        assert!(out.starts_with(b"ACC|fits.bin"));
+    }
+
+    #[test]
+    fn paused_broadcaster_still_answers_discover() {
+        let (port, handle, shutdown) = loopback_listener(Sightings::default(), DiscoveryConfig::default());
+        assert!(handle.announcing());
+
+        handle.set_broadcasting(false);
+        assert!(!handle.announcing());
+        assert!(here_name(probe(port)).is_some());
+
+        handle.set_broadcasting(true);
+        assert!(handle.announcing());
+        shutdown();
+    }
+
+    #[test]
+    fn invisible_listener_records_discover_without_answering() {
+        let sightings = Sightings::default();
+        let config = DiscoveryConfig { invisible: true, ..DiscoveryConfig::default() };
+        let (port, handle, shutdown) = loopback_listener(sightings.clone(), config);
+        assert!(!handle.announcing());
+
+        assert_eq!(probe(port), None);
+        let found: Vec<String> = lock(&sightings.found).iter().map(|d| d.device_id.clone()).collect();
+        assert_eq!(found, vec!["peer".to_string()]);
+        assert!(known_devices().iter().any(|d| d.device_id == "peer"));
+
+        handle.set_invisible(false);
+        assert!(here_name(probe(port)).is_some());
+        shutdown();
+    }
+
+    #[test]
+    fn server_keeps_serving_after_a_chunk_thread_panics_holding_the_lock() {
+        let dir = temp_dir("poisoned");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let id = register(&server, &dir, "a.bin", 4);
+
+        let poisoner = server.clone();
+        let crashed = thread::spawn(move || {
+            let _accepted = lock(&poisoner.accepted);
+            panic!("分片线程崩溃");
+        }).join();
+        assert!(crashed.is_err() && server.accepted.is_poisoned());
+
+        handle_data(&mut &b"abcd"[..], &server, "a.bin", 0, Some(id), None);
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"abcd");
+        assert!(recorder.wait_for(|e| *e == Event::Complete(true, "a.bin".into())).is_some());
+    }
+
+    // 把 header 当作连接首行交给 dispatch_header，返回写回对方的内容
+    fn dispatch(server: &Arc<FileServer>, header: &str) -> String {
+        let mut socket = Duplex { input: io::Cursor::new(Vec::new()), output: Vec::new() };
+        dispatch_header(&mut socket, header.to_string(), "127.0.0.1", server);
+        String::from_utf8(socket.output).unwrap()
+    }
+
+    #[test]
+    fn unknown_header_gets_an_explicit_error() {
+        let (server, recorder) = file_server(&temp_dir("unknown-type"), ServerConfig::default());
+
+        assert_eq!(dispatch(&server, "HELLO|1"), "ERR|UnknownType\n");
+        assert_eq!(dispatch(&server, "GET / HTTP/1.1"), "ERR|UnknownType\n");
+        assert_eq!(dispatch(&server, "REQ"), "ERR|BadHeader\n");
+        assert!(recorder.events().is_empty());
+    }
+
+    #[test]
+    fn single_stream_file_grows_without_preallocation() {
+        let dir = temp_dir("sequential");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+
+        let mut out = Vec::new();
+        handle_request(&mut out, &server, "127.0.0.1", "seq.bin", Some(6), true).unwrap();
+        handle_request(&mut out, &server, "127.0.0.1", "par.bin", Some(6), false).unwrap();
+        assert_eq!(fs::metadata(dir.join("seq.bin")).unwrap().len(), 0);
+        assert_eq!(fs::metadata(dir.join("par.bin")).unwrap().len(), 6);
+
+        let id = lock(&server.accepted)["seq.bin"].id;
+        handle_data(&mut &b"abc"[..], &server, "seq.bin", 0, Some(id), None);
+        assert_eq!(fs::metadata(dir.join("seq.bin")).unwrap().len(), 3);
+        handle_data(&mut &b"def"[..], &server, "seq.bin", 3, Some(id), None);
+        assert_eq!(fs::read(dir.join("seq.bin")).unwrap(), b"abcdef");
+        assert!(recorder.wait_for(|e| *e == Event::Complete(true, "seq.bin".into())).is_some());
+    }
+
+    // 拒绝所有请求，记下处理每个请求的线程
+    #[derive(Clone, Default)]
+    struct ThreadLog(Arc<Mutex<Vec<thread::ThreadId>>>);
+
+    impl TransferCallback for ThreadLog {
+        fn on_receive_request(&self, _file_name: String, _file_size: u64, _sender_ip: String) -> ReceiveDecision {
+            lock(&self.0).push(thread::current().id());
+            thread::sleep(Duration::from_millis(5));
+            ReceiveDecision::reject()
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+        fn on_complete(&self, _success: bool, _msg: String) {}
+    }
+
+    #[test]
+    fn worker_pool_bounds_connection_threads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = ThreadLog::default();
+        let config = ServerConfig { worker_threads: Some(2), ..ServerConfig::default() };
+        let stop = Arc::new(AtomicBool::new(false));
+        let dir = temp_dir("pool");
+        let (_, serving) = serve_on(listener, dir.to_string_lossy().into_owned(), Box::new(log.clone()), config, stop.clone());
+
+        let clients: Vec<_> = (0..20)
+            .map(|i| thread::spawn(move || {
+                let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                socket.write_all(format!("REQ|f{}.bin|1\n", i).as_bytes()).unwrap();
+                let mut reply = String::new();
+                socket.read_to_string(&mut reply).unwrap();
+                reply
+            }))
+            .collect();
+        for client in clients {
+            assert_eq!(client.join().unwrap(), "REJ\n");
+        }
+
+        let mut threads = lock(&log.0).clone();
+        assert_eq!(threads.len(), 20);
+        threads.sort_by_key(|id| format!("{:?}", id));
+        threads.dedup();
+        assert!(threads.len() <= 2, "{} 个线程处理了连接", threads.len());
+
+        stop.store(true, Ordering::Relaxed);
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn start_errors_say_why_the_bind_failed() {
+        let tcp = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let dir = temp_dir("start-error").to_string_lossy().into_owned();
+        let served = try_start_file_server_with_config(port, dir, Box::new(Recorder::default()), ServerConfig::default());
+        assert!(matches!(served, Err(StartError::PortInUse)));
+
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = udp.local_addr().unwrap().port();
+        let listening = try_start_listening_with_config(port, "me".into(), "me".into(), Box::new(Sightings::default()), DiscoveryConfig::default());
+        assert!(matches!(listening, Err(StartError::PortInUse)));
+
+        // 本机没有这个地址（TEST-NET-1）
+        let config = DiscoveryConfig { bind_addr: Ipv4Addr::new(192, 0, 2, 1), ..DiscoveryConfig::default() };
+        let listening = try_start_listening_with_config(0, "me".into(), "me".into(), Box::new(Sightings::default()), config);
+        assert!(matches!(listening, Err(StartError::NoInterface)));
+    }
+
+    fn iface(name: &str, ip: [u8; 4], prefix: u32) -> NetworkInterface {
+        let ip = Ipv4Addr::from(ip);
+        let netmask = Ipv4Addr::from(u32::MAX << (32 - prefix));
+        NetworkInterface { name: name.into(), ip, netmask, broadcast: caculate_broadcast(ip, netmask) }
+    }
+
+    #[test]
+    fn broadcast_targets_follow_the_selected_interface() {
+        let ifaces = [iface("eth0", [192, 168, 1, 20], 24), iface("wlan0", [10, 0, 8, 3], 16)];
+        assert_eq!(ifaces[1].subnet(), "10.0.0.0/16");
+
+        assert_eq!(select_broadcast_targets(&ifaces, None), vec!["192.168.1.255", "10.0.255.255"]);
+        assert_eq!(select_broadcast_targets(&ifaces, Some("wlan0")), vec!["10.0.255.255"]);
+        // 选中的网卡断开后退回到所有网卡
+        assert_eq!(select_broadcast_targets(&ifaces, Some("eth1")).len(), 2);
+    }
+
+    // 对覆盖确认固定给出 answer 的回调
+    struct ConfirmOverwrite(Option<bool>);
+
+    impl TransferCallback for ConfirmOverwrite {
+        fn on_receive_request(&self, _file_name: String, _file_size: u64, _sender_ip: String) -> ReceiveDecision {
+            ReceiveDecision::accept()
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+        fn on_complete(&self, _success: bool, _msg: String) {}
+
+        fn on_overwrite_confirm(&self, _existing_path: &Path) -> Option<bool> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn denied_overwrite_keeps_the_existing_file() {
+        let config = || ServerConfig { conflict_policy: ConflictPolicy::Overwrite, ..ServerConfig::default() };
+        for (answer, expected) in [(Some(false), "a (1).txt"), (Some(true), "a.txt"), (None, "a.txt")] {
+            let dir = temp_dir("overwrite");
+            fs::write(dir.join("a.txt"), b"original").unwrap();
+            let server = file_server_with(&dir, config(), Box::new(ConfirmOverwrite(answer)));
+
+            let mut out = Vec::new();
+            let saved = handle_request(&mut out, &server, "127.0.0.1", "a.txt", Some(3), true);
+            assert_eq!(saved.as_deref(), Some(expected), "{:?}", answer);
+            let untouched = fs::read(dir.join("a.txt")).unwrap() == b"original";
+            assert_eq!(untouched, answer == Some(false), "{:?}", answer);
+        }
+    }
+
+    // 把发现的设备送进通道
+    struct Found(mpsc::Sender<DeviceInfo>);
+
+    impl DiscoveryCallback for Found {
+        fn on_device_found(&self, device_info: DeviceInfo) {
+            let _ = self.0.send(device_info);
+        }
+    }
+
+    // 两个节点用同一个端口号，分别绑在 127.0.0.1 和 127.0.0.2 上，macOS 默认没有后者
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    #[test]
+    fn two_loopback_nodes_discover_each_other() {
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let (a_addr, b_addr) = (Ipv4Addr::LOCALHOST, Ipv4Addr::new(127, 0, 0, 2));
+
+        // 周期广播发往对方和自己的回环地址，自己发出的 DISCOVER 会回到自己的监听线程
+        let node = |id: &str, bind_addr, peer| {
+            let (tx, rx) = mpsc::channel();
+            let config = DiscoveryConfig { bind_addr, broadcast_targets: vec![peer, bind_addr], ..DiscoveryConfig::default() };
+            let handle = try_start_listening_with_config(port, id.into(), id.into(), Box::new(Found(tx)), config).unwrap();
+            start_discovery_broadcaster_with(port, id.into(), &handle);
+            (handle, rx)
+        };
+        let (a, a_found) = node("loop-a", a_addr, b_addr);
+        let (b, b_found) = node("loop-b", b_addr, a_addr);
+
+        let seen_by_b = b_found.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((seen_by_b.device_id.as_str(), seen_by_b.ip.as_str()), ("loop-a", "127.0.0.1"));
+        let seen_by_a = a_found.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((seen_by_a.device_id.as_str(), seen_by_a.ip.as_str(), seen_by_a.control_port), ("loop-b", "127.0.0.2", port));
+        // 对方的 DISCOVER 和 HERE 可能都会报上来，但自己发出的包不会被当作新设备
+        thread::sleep(Duration::from_millis(200));
+        a.stop();
+        b.stop();
+        assert!(a_found.try_iter().all(|d| d.device_id == "loop-b"));
+        assert!(b_found.try_iter().all(|d| d.device_id == "loop-a"));
+        // 监听线程退出后端口随之释放
+        for addr in [a_addr, b_addr] {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while UdpSocket::bind((addr, port)).is_err() {
+                assert!(Instant::now() < deadline, "{} 上的监听没有退出", addr);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    // 按 rate 模拟限速的链路，一直测量到 Ramp 稳定，返回过程中的连接数
+    fn ramp_until_settled(max: u64, rate: impl Fn(u64) -> f64) -> Vec<u64> {
+        let mut ramp = Ramp::new(max);
+        let mut seen = vec![ramp.active];
+        while !ramp.settled {
+            seen.push(ramp.observe(rate(ramp.active)));
+            assert!(seen.len() < 100, "没有稳定下来");
+        }
+        seen
+    }
+
+    #[test]
+    fn adaptive_ramp_stops_at_the_cap() {
+        // 每条连接限速 10 MB/s，加连接总有提升
+        assert_eq!(ramp_until_settled(4, |n| n as f64 * 10e6), vec![1, 2, 3, 4, 4]);
+        assert_eq!(ramp_until_settled(1, |n| n as f64 * 10e6), vec![1]);
+    }
+
+    #[test]
+    fn adaptive_ramp_backs_off_when_more_streams_stop_helping() {
+        // 链路总共 25 MB/s，第四条连接没有带来提升
+        assert_eq!(ramp_until_settled(8, |n| (n as f64 * 10e6).min(25e6)), vec![1, 2, 3, 4, 3]);
+    }
+
+    #[test]
+    fn garbage_packets_reach_the_invalid_packet_hook() {
+        let packets = [b"GET / HTTP/1.1".to_vec(), vec![0xff, 0xfe, b'|'], b"HERE|dev|name|port".to_vec()];
+        let sightings = listen_and_send(1024, &packets);
+
+        assert_eq!(
+            *lock(&sightings.invalid),
+            vec![(14, "未知的消息类型".to_string()), (3, "不是有效的 UTF-8".to_string()), (18, "端口无效".to_string())]
+        );
+        assert!(lock(&sightings.found).is_empty());
+    }
+
+    #[test]
+    fn empty_header_line_gets_an_error_reply() {
+        let (server, _) = file_server(&temp_dir("empty-header"), ServerConfig::default());
+        let (port, _) = serve_on_loopback(server.clone());
+
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket.write_all(b"\n").unwrap();
+        let mut reply = String::new();
+        socket.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ERR|EmptyHeader\n");
+
+        assert_eq!(dispatch(&server, " \t\r"), "ERR|EmptyHeader\n");
+        assert_eq!(dispatch(&server, "|||"), "ERR|UnknownType\n");
+    }
+
+    #[test]
+    fn chunk_counters_add_up_to_the_file_size() {
+        let dir = temp_dir("chunks");
+        let (server, recorder) = file_server(&dir.join("inbox"), ServerConfig::default());
+        let (port, _) = serve_on_loopback(server);
+        let source = dir.join("chunks.bin");
+        let data: Vec<u8> = (0..1_000_003u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        let tracker = SendHandle::new(4);
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &tracker, &SendLimits::default(), None);
+
+        assert_eq!(sent, Ok(data.len() as u64));
+        let chunks = tracker.chunk_progress();
+        assert_eq!(chunks.len(), 4);
+        // 前三个分片等长，余数落在最后一个
+        assert_eq!(chunks[..3], [250_000; 3]);
+        assert_eq!(chunks.iter().sum::<u64>(), data.len() as u64);
+        assert!(recorder.wait_len(1));
+        assert_eq!(fs::read(dir.join("inbox/chunks.bin")).unwrap(), data);
+    }
+
+    // 同意请求的同时把发送方的源文件截短，模拟传输途中文件被改动
+    struct TruncateSource(PathBuf);
+
+    impl TransferCallback for TruncateSource {
+        fn on_receive_request(&self, _file_name: String, _file_size: u64, _sender_ip: String) -> ReceiveDecision {
+            fs::OpenOptions::new().write(true).open(&self.0).unwrap().set_len(10).unwrap();
+            ReceiveDecision::accept()
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+        fn on_complete(&self, _success: bool, _msg: String) {}
+    }
+
+    #[test]
+    fn truncated_source_aborts_with_file_changed() {
+        let dir = temp_dir("truncated");
+        let source = dir.join("shrinking.bin");
+        fs::write(&source, vec![1u8; 200_000]).unwrap();
+        let server = file_server_with(&dir.join("inbox"), ServerConfig::default(), Box::new(TruncateSource(source.clone())));
+        let (port, _) = serve_on_loopback(server);
+
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &SendHandle::new(4), &SendLimits::default(), None);
+
+        assert_eq!(sent, Err(TransferError::FileChanged));
+    }
+
+    #[test]
+    fn file_crosses_a_caller_provided_stream() {
+        let _sends = shared_sends();
+        let dir = temp_dir("byos");
+        let source = dir.join("over.bin");
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 241) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let (sender_end, receiver_end) = testing::duplex();
+        let sender = Recorder::default();
+        let receiver = Recorder::default();
+
+        send_file_over(sender_end, source.to_string_lossy().into_owned(), Box::new(sender.clone()));
+        let inbox = dir.join("inbox");
+        fs::create_dir_all(&inbox).unwrap();
+        serve_connection(receiver_end, inbox.to_string_lossy().into_owned(), Box::new(receiver.clone()), ServerConfig::default());
+
+        assert!(sender.wait_len(1));
+        assert_eq!(sender.events(), vec![Event::Complete(true, "发送完成".into())]);
+        assert_eq!(receiver.events(), vec![Event::Complete(true, "over.bin".into())]);
+        assert_eq!(fs::read(inbox.join("over.bin")).unwrap(), data);
+    }
+
+    // 像管道一样只能顺序写入的接收端
+    #[derive(Clone, Default)]
+    struct PipeSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for PipeSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            lock(&self.0).extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ReceiveSink for PipeSink {}
+
+    struct IntoSink(PipeSink);
+
+    impl TransferCallback for IntoSink {
+        fn on_receive_request(&self, _file_name: String, _file_size: u64, _sender_ip: String) -> ReceiveDecision {
+            ReceiveDecision::accept_into_sink(self.0.clone())
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+        fn on_complete(&self, _success: bool, _msg: String) {}
+    }
+
+    #[test]
+    fn non_seekable_sink_downgrades_to_one_sequential_stream() {
+        let dir = temp_dir("pipe-sink");
+        let pipe = PipeSink::default();
+        let server = file_server_with(&dir, ServerConfig::default(), Box::new(IntoSink(pipe.clone())));
+
+        let mut out = Vec::new();
+        handle_request(&mut out, &server, "127.0.0.1", "probe.bin", Some(10), false).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("|seq"));
+
+        let (port, _) = serve_on_loopback(server);
+        let source = dir.join("piped.bin");
+        let data: Vec<u8> = (0..400_000u32).map(|i| (i % 239) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let tracker = SendHandle::new(4);
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &tracker, &SendLimits::default(), None);
+
+        assert_eq!(sent, Ok(data.len() as u64));
+        assert_eq!(tracker.chunk_progress(), vec![data.len() as u64, 0, 0, 0]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lock(&pipe.0).len() < data.len() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*lock(&pipe.0), data);
+    }
+
+    #[test]
+    fn persistent_recv_errors_back_off_then_stop_the_listener() {
+        // 读超时 1ms 且没有人发包，每次 recv_from 都立即出错
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let sightings = Sightings::default();
+        let config = DiscoveryConfig { bind_addr: Ipv4Addr::LOCALHOST, ..DiscoveryConfig::default() };
+        let started = Instant::now();
+        let (_, thread) = listen_on(socket, port, "me".into(), "me".into(), Box::new(sightings.clone()), config, Arc::new(AtomicBool::new(false)));
+
+        thread.join().unwrap();
+
+        // 空转的话 10 次失败只需要十几毫秒，退避后要等 50ms * (1 + 2 + ... + 9)
+        assert!(started.elapsed() >= RECV_ERROR_BACKOFF * 45);
+        let stopped = lock(&sightings.stopped).clone();
+        assert_eq!(stopped.len(), 1);
+        assert!(matches!(stopped[0], io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    #[test]
+    fn unicast_discover_brings_the_stub_into_the_device_list() {
+        let sightings = Sightings::default();
+        let (port, handle, shutdown) = loopback_listener(sightings.clone(), DiscoveryConfig::default());
+        handle.set_alias("浏览器");
+        // 桩设备与监听使用同一个端口号，绑在 127.0.0.2 上
+        let stub = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, 2), port)).unwrap();
+        stub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        discover_unicast("127.0.0.2", port, "me".into(), &handle).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let (size, from) = stub.recv_from(&mut buf).unwrap();
+        let discover = DiscoveryMessage::Discover { device_id: "me".into(), name: "浏览器".into(), port };
+        assert_eq!(DiscoveryMessage::decode(&buf[..size]), Some(discover));
+        let here = here_named("unicast-stub", "打印机".into());
+        stub.send_to(&here, (from.ip(), port)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lock(&sightings.found).is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let found = lock(&sightings.found).clone();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].device_id.as_str(), found[0].name.as_str(), found[0].ip.as_str()), ("unicast-stub", "打印机", "127.0.0.2"));
+        shutdown();
+    }
+
+    // 慢速接收端桩：REQ 一律同意，数据连接每 20ms 只读 16KiB
+    fn slow_receiver() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for socket in listener.incoming().flatten() {
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(socket);
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.starts_with("REQ|") {
+                        let _ = reader.get_mut().write_all(b"ACC\n");
+                        return;
+                    }
+                    let mut buf = [0u8; 16 * 1024];
+                    while let Ok(1..) = reader.read(&mut buf) {
+                        thread::sleep(Duration::from_millis(20));
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn slow_transfer_is_aborted_at_the_deadline() {
+        let dir = temp_dir("deadline");
+        let source = dir.join("slow.bin");
+        fs::write(&source, vec![3u8; 32 << 20]).unwrap();
+        let port = slow_receiver();
+        let limits = SendLimits::new(Some(Duration::from_millis(300)), CancelToken::default());
+
+        let started = Instant::now();
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &SendHandle::new(4), &limits, None);
+
+        assert_eq!(sent, Err(TransferError::Timeout));
+        assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn cancel_all_stops_every_send_in_flight() {
+        let _sends = exclusive_sends();
+        let dir = temp_dir("cancel-all");
+        let port = slow_receiver();
+        let senders: Vec<Recorder> = (0..3)
+            .map(|i| {
+                let source = dir.join(format!("slow{}.bin", i));
+                fs::write(&source, vec![i as u8; 32 << 20]).unwrap();
+                let sender = Recorder::default();
+                send_file_with_options("127.0.0.1".into(), port, source.to_string_lossy().into_owned(), SendOptions::default(), Box::new(sender.clone()));
+                sender
+            })
+            .collect();
+        // 等数据开始流动，接收方每 20ms 才读 16 KiB，三个发送都还远没有发完
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(active_transfer_count(), 3);
+
+        assert_eq!(cancel_all_transfers(), 3);
+
+        for sender in &senders {
+            assert_eq!(sender.wait_for(|e| matches!(e, Event::Error(_))), Some(Event::Error(TransferError::Cancelled)));
+            assert!(sender.wait_for(|e| matches!(e, Event::Complete(false, _))).is_some());
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while active_transfer_count() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(active_transfer_count(), 0);
+    }
+
+    // 同意所有请求，记下每次询问的 (名字, 大小) 和完成的文件
+    #[derive(Clone, Default)]
+    struct Prompts {
+        asked: Arc<Mutex<Vec<(String, u64)>>>,
+        completed: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TransferCallback for Prompts {
+        fn on_receive_request(&self, file_name: String, file_size: u64, _sender_ip: String) -> ReceiveDecision {
+            lock(&self.asked).push((file_name, file_size));
+            ReceiveDecision::accept()
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+        fn on_complete(&self, success: bool, msg: String) {
+            assert!(success, "{}", msg);
+            lock(&self.completed).push(msg);
+        }
+    }
+
+    #[test]
+    fn batch_of_three_files_is_accepted_once() {
+        let _sends = shared_sends();
+        let dir = temp_dir("batch-send");
+        let prompts = Prompts::default();
+        let server = file_server_with(&dir.join("inbox"), ServerConfig::default(), Box::new(prompts.clone()));
+        let (port, _) = serve_on_loopback(server);
+        let paths: Vec<String> = [("a.txt", 10usize), ("b.txt", 20), ("c.txt", 30)]
+            .iter()
+            .map(|(name, len)| {
+                let path = dir.join(name);
+                fs::write(&path, vec![b'x'; *len]).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        let sender = Recorder::default();
+
+        let options = SendOptions { batch: true, ..SendOptions::default() };
+        send_files("127.0.0.1".into(), port, paths, options, Box::new(sender.clone()));
+
+        assert_eq!(sender.wait_for(|e| matches!(e, Event::Complete(..))), Some(Event::Complete(true, "发送完成 (3 个文件)".into())));
+        assert_eq!(*lock(&prompts.asked), vec![("a.txt 等 3 个文件".to_string(), 60)]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lock(&prompts.completed).len() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*lock(&prompts.completed), vec!["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(fs::read(dir.join("inbox/c.txt")).unwrap().len(), 30);
+    }
+
+    // 写出 ACC 的那一刻检查文件已经登记并按大小预分配
+    struct CheckOnAccept {
+        server: Arc<FileServer>,
+        path: PathBuf,
+        size: u64,
+    }
+
+    impl Write for CheckOnAccept {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.starts_with(b"ACC") {
+                assert!(!lock(&self.server.accepted).is_empty());
+                assert_eq!(fs::metadata(&self.path).unwrap().len(), self.size);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn file_is_ready_before_acc_is_sent() {
+        let dir = temp_dir("acc-order");
+        let (server, _) = file_server(&dir, ServerConfig::default());
+        let mut socket = CheckOnAccept { server: server.clone(), path: dir.join("ready.bin"), size: 5000 };
+        assert!(handle_request(&mut socket, &server, "127.0.0.1", "ready.bin", Some(5000), false).is_some());
+
+        // 分片连接紧跟在 ACC 之后到达，反复发送也不会遇到文件还没准备好
+        let (port, _) = serve_on_loopback(server);
+        let outbox = temp_dir("acc-order-src");
+        for i in 0..20 {
+            let source = outbox.join(format!("src-{}.bin", i));
+            fs::write(&source, vec![i as u8; 64 * 1024 + i]).unwrap();
+            let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &SendHandle::new(8), &SendLimits::default(), None);
+            assert_eq!(sent, Ok(64 * 1024 + i as u64));
+        }
+    }
+
+    #[test]
+    fn wrong_expected_checksum_fails_an_otherwise_valid_file() {
+        let dir = temp_dir("expected-sha");
+        let checksums = Arc::new(ExpectedChecksums::new());
+        checksums.insert("abc.txt", &"0".repeat(64)).unwrap();
+        // sha256("abc")
+        checksums.insert("ok.txt", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap();
+        let config = ServerConfig { expected_checksums: Some(checksums), ..ServerConfig::default() };
+        let (server, recorder) = file_server(&dir, config);
+
+        for name in ["abc.txt", "ok.txt"] {
+            let id = register(&server, &dir, name, 3);
+            handle_data(&mut &b"abc"[..], &server, name, 0, Some(id), None);
+        }
+
+        assert!(recorder.wait_len(3));
+        let events = recorder.events();
+        assert!(events.contains(&Event::Error(TransferError::ChecksumMismatch)));
+        assert!(events.iter().any(|e| matches!(e, Event::Complete(false, msg) if msg.contains("abc.txt"))));
+        assert!(events.contains(&Event::Complete(true, "ok.txt".into())));
+        // 校验失败的文件保留在磁盘上
+        assert_eq!(fs::read(dir.join("abc.txt")).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn absurd_parallel_count_is_clamped_and_still_succeeds() {
+        let _sends = shared_sends();
+        assert_eq!(clamp_parallel(1000, DEFAULT_MAX_PARALLEL), DEFAULT_MAX_PARALLEL);
+        assert_eq!(clamp_parallel(0, DEFAULT_MAX_PARALLEL), 1);
+        assert_eq!(clamp_parallel(8, 0), 1);
+        let options = SendOptions { parallel_cnt: 1000, max_parallel: 4, ..SendOptions::default() };
+        assert_eq!(options.effective_parallel(), 4);
+
+        let dir = temp_dir("clamp");
+        let (server, recorder) = file_server(&dir.join("inbox"), ServerConfig::default());
+        let (port, connections) = serve_on_loopback(server);
+        let source = dir.join("many.bin");
+        let data: Vec<u8> = (0..500_000u32).map(|i| (i % 199) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let sender = Recorder::default();
+
+        let handle = send_file_tracked("127.0.0.1".into(), port, source.to_string_lossy().into_owned(), 1000, Box::new(sender.clone()));
+
+        assert_eq!(handle.chunk_count() as u64, DEFAULT_MAX_PARALLEL);
+        assert!(sender.wait_len(1));
+        assert_eq!(sender.events(), vec![Event::Complete(true, "发送完成".into())]);
+        assert!(recorder.wait_len(1));
+        assert_eq!(fs::read(dir.join("inbox/many.bin")).unwrap(), data);
+        // 一条握手连接加上每个分片一条
+        assert_eq!(connections.load(Ordering::Relaxed), 1 + DEFAULT_MAX_PARALLEL);
+    }
+
+    #[test]
+    fn empty_subfolders_arrive_with_the_directory() {
+        let _sends = shared_sends();
+        let dir = temp_dir("empty-dirs");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("empty")).unwrap();
+        fs::create_dir_all(tree.join("full/nested/deeper")).unwrap();
+        fs::write(tree.join("full/f.txt"), b"f").unwrap();
+        let (server, _) = file_server(&dir.join("inbox"), ServerConfig::default());
+        let (port, _) = serve_on_loopback(server);
+        let sender = Recorder::default();
+
+        send_directory("127.0.0.1".into(), port, tree.to_string_lossy().into_owned(), SendOptions::default(), Box::new(sender.clone()));
+
+        assert!(sender.wait_len(1));
+        assert!(matches!(&sender.events()[0], Event::Complete(true, _)), "{:?}", sender.events());
+        let inbox = dir.join("inbox/tree");
+        assert!(inbox.join("empty").is_dir());
+        assert!(inbox.join("full/nested/deeper").is_dir());
+        assert_eq!(fs::read(inbox.join("full/f.txt")).unwrap(), b"f");
+    }
+
+    #[test]
+    fn file_one_byte_short_on_disk_is_not_a_success() {
+        let dir = temp_dir("short-file");
+        let (server, recorder) = file_server(&dir, ServerConfig::default());
+        let id = register(&server, &dir, "short.bin", 5);
+
+        // 第二段错位一个字节，收到的字节数够了，磁盘上的文件却只有 4 字节
+        handle_data(&mut &b"abc"[..], &server, "short.bin", 0, Some(id), None);
+        handle_data(&mut &b"de"[..], &server, "short.bin", 2, Some(id), None);
+
+        assert_eq!(fs::metadata(dir.join("short.bin")).unwrap().len(), 4);
+        assert!(recorder.wait_len(2));
+        let events = recorder.events();
+        assert!(matches!(&events[0], Event::Error(TransferError::Failed(msg)) if msg.contains("4 / 5")), "{:?}", events);
+        assert!(matches!(&events[1], Event::Complete(false, _)), "{:?}", events);
+    }
+
+    // 第一次进度回调 panic，之后照常转给 Recorder
+    struct PanicOnce {
+        panicked: AtomicBool,
+        inner: Recorder,
+    }
+
+    impl TransferCallback for PanicOnce {
+        fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> ReceiveDecision {
+            self.inner.on_receive_request(file_name, file_size, sender_ip)
+        }
+
+        fn on_progress(&self, _transferred: u64, _total: u64) {
+            if !self.panicked.swap(true, Ordering::SeqCst) {
+                panic!("进度回调崩溃");
+            }
+        }
+
+        fn on_complete(&self, success: bool, msg: String) {
+            self.inner.on_complete(success, msg);
+        }
+    }
+
+    #[test]
+    fn panicking_progress_callback_aborts_only_that_transfer() {
+        let dir = temp_dir("panic-callback");
+        let recorder = Recorder::default();
+        let callback = PanicOnce { panicked: AtomicBool::new(false), inner: recorder.clone() };
+        let server = file_server_with(&dir.join("inbox"), ServerConfig::default(), Box::new(callback));
+        let (port, _) = serve_on_loopback(server);
+        let first = dir.join("first.bin");
+        let second = dir.join("second.bin");
+        fs::write(&first, b"first").unwrap();
+        fs::write(&second, b"second").unwrap();
+
+        let _ = transfer_file("127.0.0.1", port, first.to_str().unwrap(), &SendHandle::new(1), &SendLimits::default(), None);
+
+        let sent = transfer_file("127.0.0.1", port, second.to_str().unwrap(), &SendHandle::new(1), &SendLimits::default(), None);
+        assert_eq!(sent, Ok(6));
+        assert!(recorder.wait_for(|e| *e == Event::Complete(true, "second.bin".into())).is_some());
+        assert_eq!(fs::read(dir.join("inbox/second.bin")).unwrap(), b"second");
+        // 崩溃的那一次只中止了自己的传输
+        assert!(!recorder.events().contains(&Event::Complete(true, "first.bin".into())));
+    }
+
+    #[test]
+    fn discover_socket_carries_the_configured_ttl() {
+        assert_eq!(broadcast_socket(Ipv4Addr::LOCALHOST, 4).unwrap().ttl().unwrap(), 4);
+        assert_eq!(
+            broadcast_socket(Ipv4Addr::LOCALHOST, DiscoveryConfig::default().broadcast_ttl).unwrap().ttl().unwrap(),
+            DEFAULT_BROADCAST_TTL
+        );
+    }
+
+    #[test]
+    fn here_goes_back_to_the_discover_source_port() {
+        let (port, _handle, shutdown) = loopback_listener(Sightings::default(), DiscoveryConfig::default());
+        let advertised = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let ephemeral = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let discover = DiscoveryMessage::Discover {
+            device_id: "peer".into(),
+            name: "peer".into(),
+            port: advertised.local_addr().unwrap().port(),
+        };
+        ephemeral.send_to(discover.encode().as_bytes(), (Ipv4Addr::LOCALHOST, port)).unwrap();
+
+        let mut buf = [0u8; 1024];
+        ephemeral.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let (size, _) = ephemeral.recv_from(&mut buf).unwrap();
+        assert!(matches!(DiscoveryMessage::decode(&buf[..size]), Some(DiscoveryMessage::Here { .. })));
+        // 通告的端口上是对方的监听线程，也会收到一份
+        advertised.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let (size, _) = advertised.recv_from(&mut buf).unwrap();
+        assert!(matches!(DiscoveryMessage::decode(&buf[..size]), Some(DiscoveryMessage::Here { .. })));
+        shutdown();
+    }
+
+    #[test]
+    fn free_space_travels_from_here_to_device_info() {
+        let config = DiscoveryConfig { save_dir: Some(temp_dir("advertised")), ..DiscoveryConfig::default() };
+        let (port, _handle, shutdown) = loopback_listener(Sightings::default(), config);
+        let here = probe(port).unwrap();
+        shutdown();
+        let free = match &here {
+            DiscoveryMessage::Here { free_space, .. } => free_space.unwrap(),
+            other => panic!("{:?}", other),
+        };
+        // 按 MiB 取整后公布
+        assert!(free > 0 && free % FREE_SPACE_UNIT == 0);
+        let source = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        assert_eq!(here.device_info(source).unwrap().free_space, Some(free));
+
+        // 没设置保存目录时不公布
+        let (port, _handle, shutdown) = loopback_listener(Sightings::default(), DiscoveryConfig::default());
+        assert!(matches!(probe(port), Some(DiscoveryMessage::Here { free_space: None, .. })));
+        shutdown();
+    }
+
+    #[test]
+    fn receiver_asking_for_two_large_chunks_gets_two() {
+        let dir = temp_dir("preferred-chunk");
+        let config = ServerConfig { preferred_chunk_size: Some(500_000), ..ServerConfig::default() };
+        let (server, recorder) = file_server(&dir.join("inbox"), config);
+        let (port, _) = serve_on_loopback(server);
+        let source = dir.join("card.bin");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 249) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        let tracker = SendHandle::new(4);
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &tracker, &SendLimits::default(), None);
+
+        assert_eq!(sent, Ok(data.len() as u64));
+        // 只用了两个分片，其余的进度槽位保持为 0
+        assert_eq!(tracker.chunk_progress(), vec![500_000, 500_000, 0, 0]);
+        assert!(recorder.wait_len(1));
+        assert_eq!(fs::read(dir.join("inbox/card.bin")).unwrap(), data);
+    }
+
+    // 等一个来自 device_id 的 DISCOVER，wait 内没有收到返回 false
+    fn heard_discover_from(socket: &UdpSocket, device_id: &str, wait: Duration) -> bool {
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let deadline = Instant::now() + wait;
+        let mut buf = [0u8; 1024];
+        while Instant::now() < deadline {
+            if let Ok((size, _)) = socket.recv_from(&mut buf)
+                && matches!(DiscoveryMessage::decode(&buf[..size]), Some(DiscoveryMessage::Discover { device_id: id, .. }) if id == device_id)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn foreground_resumes_broadcasting_and_announces_at_once() {
+        // 广播会回环到本机绑定在 0.0.0.0 的套接字上
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let handle = DiscoveryHandle::new("me".into());
+        *lock(&handle.identity) = Some((port, "fg-me".into()));
+
+        handle.enter_background();
+        assert!(!handle.is_broadcasting() && !handle.announcing());
+
+        handle.enter_foreground();
+        assert!(handle.is_broadcasting() && handle.announcing());
+        assert!(heard_discover_from(&socket, "fg-me", Duration::from_secs(2)));
+
+        // 隐身时回到前台也不宣告
+        handle.set_invisible(true);
+        handle.enter_foreground();
+        assert!(!heard_discover_from(&socket, "fg-me", Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn peer_answering_only_the_second_discover_is_still_found() {
+        let peer = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let stub = thread::spawn(move || {
+            // 第一个 DISCOVER 当作丢失，只回复第二个
+            let mut buf = [0u8; 1024];
+            let mut seen = 0;
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            while let Ok((size, from)) = peer.recv_from(&mut buf) {
+                if !matches!(DiscoveryMessage::decode(&buf[..size]), Some(DiscoveryMessage::Discover { device_id, .. }) if device_id == "retry-me") {
+                    continue;
+                }
+                seen += 1;
+                if seen == 2 {
+                    let here = DiscoveryMessage::Here { device_id: "late".into(), name: "late".into(), port, free_space: None };
+                    peer.send_to(here.encode().as_bytes(), from).unwrap();
+                    break;
+                }
+            }
+            seen
+        });
+
+        let handle = DiscoveryHandle::new("me".into());
+        let retry = DiscoverRetry { retries: 3, interval: Duration::from_millis(100) };
+        let found = discover_devices(port, "retry-me".into(), &handle, Duration::from_secs(2), retry).unwrap();
+
+        assert_eq!(stub.join().unwrap(), 2);
+        assert_eq!(found.iter().map(|d| d.device_id.as_str()).collect::<Vec<_>>(), vec!["late"]);
+    }
+
+    #[test]
+    fn discovery_and_transfer_share_one_port_number() {
+        let port = TcpListener::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let dir = temp_dir("shared-port").to_string_lossy().into_owned();
+
+        let served = try_start_file_server_with_config(port, dir, Box::new(Recorder::default()), ServerConfig::default());
+        let listening = try_start_listening_with_config(port, "me".into(), "me".into(), Box::new(Sightings::default()), DiscoveryConfig::default());
+
+        // UDP 和 TCP 是各自的端口空间，两边都能起来并各自工作
+        assert!(served.is_ok(), "{:?}", served.err());
+        assert!(listening.is_ok(), "{:?}", listening.err());
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok());
+        assert!(here_name(probe(port)).is_some());
+    }
+
+    #[test]
+    fn mapped_ipv6_address_matches_its_plain_ipv4_form() {
+        assert_eq!(canonical_ip("::ffff:192.168.1.5"), "192.168.1.5");
+        assert_eq!(canonical_ip("fe80::1"), "fe80::1");
+        assert_eq!(canonical_ip("not an ip"), "not an ip");
+
+        let device = DeviceInfo { device_id: "d".into(), name: "d".into(), ip: "192.168.1.5".into(), control_port: 4061, free_space: None };
+        assert!(device.has_ip("::ffff:192.168.1.5"));
+        assert!(!device.has_ip("::ffff:192.168.1.6"));
+
+        // 双栈套接字上收到的 HERE 也记成 IPv4 形式
+        let here = DiscoveryMessage::Here { device_id: "d".into(), name: "d".into(), port: 4061, free_space: None };
+        let source: SocketAddr = "[::ffff:192.168.1.5]:4061".parse().unwrap();
+        assert_eq!(here.device_info(source).unwrap().ip, "192.168.1.5");
+    }
+
+    // 把写入的文本转成大写再交给下层 writer
+    struct Upper(Box<dyn Write + Send>);
+
+    impl Write for Upper {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write_all(&buf.to_ascii_uppercase())?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    struct Uppercase;
+
+    impl TransferFilter for Uppercase {
+        fn wrap_writer(&self, _name: &str, _size: u64, file: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+            Box::new(Upper(file))
+        }
+    }
+
+    #[test]
+    fn filter_sees_the_received_byte_stream() {
+        let dir = temp_dir("filter");
+        let config = ServerConfig { filter: Some(Arc::new(Uppercase)), ..ServerConfig::default() };
+        let (server, recorder) = file_server(&dir.join("inbox"), config);
+        let (port, _) = serve_on_loopback(server);
+        let source = dir.join("note.txt");
+        let text = "hello, 局域网 world\n".repeat(20_000);
+        fs::write(&source, &text).unwrap();
+
+        let sent = transfer_file("127.0.0.1", port, source.to_str().unwrap(), &SendHandle::new(4), &SendLimits::default(), None);
+
+        assert_eq!(sent, Ok(text.len() as u64));
+        assert!(recorder.wait_for(|e| *e == Event::Complete(true, "note.txt".into())).is_some());
+        assert_eq!(fs::read_to_string(dir.join("inbox/note.txt")).unwrap(), text.to_ascii_uppercase());
+    }
+
+    // 转发到本机 port 的代理，返回代理的端口和已经转发给 port 的字节数
+    fn counting_proxy(port: u16) -> (u16, Arc<AtomicU64>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let forwarded = Arc::new(AtomicU64::new(0));
+        let counter = forwarded.clone();
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let upstream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+                let (mut from_client, mut to_upstream) = (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    let mut buf = [0u8; 16 * 1024];
+                    while let Ok(n) = from_client.read(&mut buf) {
+                        if n == 0 || to_upstream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                        counter.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                    let _ = to_upstream.shutdown(std::net::Shutdown::Write);
+                });
+                let (mut from_upstream, mut to_client) = (upstream, client);
+                thread::spawn(move || {
+                    let _ = io::copy(&mut from_upstream, &mut to_client);
+                    let _ = to_client.shutdown(std::net::Shutdown::Write);
+                });
+            }
+        });
+        (proxy_port, forwarded)
+    }
+
+    const RESUME_NAMES: [&str; 5] = ["a.bin", "b.bin", "c.bin", "d.bin", "e.bin"];
+
+    // dir/photos 下放 5 个各 10000 字节的文件，按 DIR|5|resume 交给 server：
+    // a、b 收完，c 收到 4000 字节时连接断开。返回 photos 目录
+    fn interrupted_directory(dir: &Path, server: &Arc<FileServer>) -> PathBuf {
+        let photos = dir.join("photos");
+        fs::create_dir_all(&photos).unwrap();
+        let mut manifest = String::new();
+        for (name, i) in RESUME_NAMES.iter().zip(0u8..) {
+            let path = photos.join(name);
+            fs::write(&path, vec![b'a' + i; 10_000]).unwrap();
+            let (_, source) = inspect_source(path.to_str().unwrap()).unwrap();
+            let identity = resume::SourceIdentity::of(&path, source.modified).unwrap();
+            manifest.push_str(&format!("photos/{}|10000{}\n", name, identity.manifest_suffix()));
+        }
+        let mut input = manifest.into_bytes();
+        for (name, i) in RESUME_NAMES.iter().zip(0u8..).take(2) {
+            input.extend(data_header(&format!("photos/{}", name), 0, Some(10_000), None, None).as_bytes());
+            input.extend(vec![b'a' + i; 10_000]);
+        }
+        input.extend(data_header("photos/c.bin", 0, Some(10_000), None, None).as_bytes());
+        input.extend([b'c'; 4_000]);
+        let mut socket = Duplex { input: io::Cursor::new(input), output: Vec::new() };
+        dispatch_header(&mut socket, "DIR|5|resume".to_string(), "127.0.0.1", server);
+        assert_eq!(fs::metadata(dir.join("inbox/photos/c.bin")).unwrap().len(), 4_000);
+        photos
+    }
+
+    // 以续传方式经 counting_proxy 重新发送 photos，等 expected 里的文件都在接收方收完，返回经过代理的字节数
+    fn resend_directory(photos: &Path, server: Arc<FileServer>, recorder: &Recorder, expected: &[&str]) -> u64 {
+        let (port, _) = serve_on_loopback(server);
+        let (proxy, forwarded) = counting_proxy(port);
+        let sender = Recorder::default();
+        let options = SendOptions { resume: true, ..SendOptions::default() };
+        send_directory("127.0.0.1".into(), proxy, photos.to_string_lossy().into_owned(), options, Box::new(sender.clone()));
+
+        assert!(sender.wait_len(1));
+        assert!(matches!(&sender.events()[0], Event::Complete(true, _)), "{:?}", sender.events());
+        for name in expected {
+            let name = format!("photos/{}", name);
+            assert!(recorder.wait_for(|e| *e == Event::Complete(true, name.clone())).is_some(), "{}", name);
+        }
+        forwarded.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn resumed_directory_sends_only_the_unfinished_files() {
+        let _sends = shared_sends();
+        let dir = temp_dir("resume-dir");
+        let (server, recorder) = file_server(&dir.join("inbox"), ServerConfig::default());
+        let photos = interrupted_directory(&dir, &server);
+
+        let forwarded = resend_directory(&photos, server, &recorder, &["c.bin", "d.bin", "e.bin"]);
+
+        // c 的后 6000 字节加上 d、e，再加清单和帧头，a、b 没有再发
+        assert!((26_000..27_000).contains(&forwarded), "{}", forwarded);
+        for name in RESUME_NAMES {
+            assert_eq!(fs::read(dir.join("inbox/photos").join(name)).unwrap(), fs::read(photos.join(name)).unwrap(), "{}", name);
+        }
+        // 整个目录收完后续传进度随即删除
+        assert!(!dir.join("inbox/.photos.locsd-progress").exists());
+    }
+
+    #[test]
+    fn changed_sources_are_resent_in_full_after_an_interruption() {
+        let _sends = shared_sends();
+        let dir = temp_dir("resume-changed");
+        let (server, recorder) = file_server(&dir.join("inbox"), ServerConfig::default());
+        let photos = interrupted_directory(&dir, &server);
+        // 中断之后收完的 a 和收了一半的 c 在发送方被改过，大小不变
+        fs::write(photos.join("a.bin"), vec![b'A'; 10_000]).unwrap();
+        fs::write(photos.join("c.bin"), vec![b'C'; 10_000]).unwrap();
+
+        let forwarded = resend_directory(&photos, server, &recorder, &["a.bin", "c.bin", "d.bin", "e.bin"]);
+
+        // a、c 整个重发，b 没有改过照样跳过
+        assert!((40_000..41_000).contains(&forwarded), "{}", forwarded);
+        for name in RESUME_NAMES {
+            assert_eq!(fs::read(dir.join("inbox/photos").join(name)).unwrap(), fs::read(photos.join(name)).unwrap(), "{}", name);
+        }
+    }
+}
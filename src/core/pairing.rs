@@ -0,0 +1,473 @@
+//! 一次性配对：接收方 begin_pairing 生成 6 位短码显示在屏幕上，发送方输入同一个短码
+//! 调用 confirm_pairing。双方交换临时 X25519 公钥，再用短码对双方 ID 和公钥给出证明：
+//! 发送方先发证明的承诺，看到接收方的证明后才公开自己的，窃听或冒充任何一方都只有一次
+//! 猜中短码的机会，没法离线穷举。证明通过后双方用协商出的密钥掩码交换身份密钥并各自保存，
+//! 之后任何一方发起的连接都先发 AUTH 行，对方用保存的密钥校验，只接收已配对设备的文件。
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::{lock, read_header_line};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 配对码的有效期
+pub const PAIRING_CODE_TTL: Duration = Duration::from_secs(120);
+// AUTH 行里的时间戳与本机时间最多相差多少秒
+const AUTH_MAX_SKEW: u64 = 60;
+const KEY_LEN: usize = 32;
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 配对信息：本机身份密钥和已配对设备的密钥，指定路径时改动会立即写回磁盘
+pub struct PairingStore {
+    device_id: String,
+    key: Vec<u8>,
+    path: Option<PathBuf>,
+    peers: Mutex<HashMap<String, Vec<u8>>>,
+    pending: Mutex<Option<(String, Instant)>>,
+    // 最近见过的 AUTH 随机数，防止重放
+    seen_nonces: Mutex<HashMap<String, u64>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoreFile {
+    key: String,
+    peers: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for PairingStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PairingStore")
+            .field("device_id", &self.device_id)
+            .field("paired", &lock(&self.peers).len())
+            .finish()
+    }
+}
+
+impl PairingStore {
+    /// 只保存在内存里，进程退出后配对关系和身份密钥都会丢失
+    pub fn in_memory(device_id: &str) -> Self {
+        Self::with_parts(device_id, random_bytes(KEY_LEN), None, HashMap::new())
+    }
+
+    /// 从文件加载，文件不存在时生成新的身份密钥并写入
+    pub fn open(device_id: &str, path: &Path) -> io::Result<Self> {
+        let file: StoreFile = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => StoreFile::default(),
+            Err(e) => return Err(e),
+        };
+
+        let key = from_hex(&file.key).filter(|k| k.len() == KEY_LEN).unwrap_or_else(|| random_bytes(KEY_LEN));
+        let peers = file.peers.iter()
+            .filter_map(|(id, secret)| Some((id.clone(), from_hex(secret)?)))
+            .collect();
+        let store = Self::with_parts(device_id, key, Some(path.to_path_buf()), peers);
+        store.save()?;
+        Ok(store)
+    }
+
+    fn with_parts(device_id: &str, key: Vec<u8>, path: Option<PathBuf>, peers: HashMap<String, Vec<u8>>) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            key,
+            path,
+            peers: Mutex::new(peers),
+            pending: Mutex::new(None),
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = StoreFile {
+            key: to_hex(&self.key),
+            peers: lock(&self.peers).iter().map(|(id, k)| (id.clone(), to_hex(k))).collect(),
+        };
+        let text = serde_json::to_string_pretty(&file).map_err(io::Error::other)?;
+        fs::write(path, text)
+    }
+
+    /// 生成新的配对码并等待对方确认，之前未使用的配对码随之作废
+    pub fn begin_pairing(&self) -> String {
+        let bytes = random_bytes(4);
+        let n = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % 1_000_000;
+        let code = format!("{:06}", n);
+        *lock(&self.pending) = Some((code.clone(), Instant::now()));
+        info!("Core: 等待配对，配对码已生成");
+        code
+    }
+
+    /// 取消正在等待的配对
+    pub fn cancel_pairing(&self) {
+        lock(&self.pending).take();
+    }
+
+    pub fn is_paired(&self, device_id: &str) -> bool {
+        lock(&self.peers).contains_key(device_id)
+    }
+
+    pub fn paired_devices(&self) -> Vec<String> {
+        lock(&self.peers).keys().cloned().collect()
+    }
+
+    pub fn unpair(&self, device_id: &str) -> io::Result<()> {
+        lock(&self.peers).remove(device_id);
+        self.save()
+    }
+
+    // 发送方每条连接开头的认证行：AUTH|设备ID|时间戳|随机数|HMAC
+    pub(crate) fn auth_line(&self) -> String {
+        let payload = format!("{}|{}|{}", self.device_id, unix_now(), to_hex(&random_bytes(16)));
+        format!("AUTH|{}|{}\n", payload, to_hex(&mac(&self.key, &payload)))
+    }
+
+    // 校验 AUTH 行，成功时返回对方的设备 ID，失败时返回回给对方的错误原因
+    pub(crate) fn verify_auth(&self, line: &str) -> Result<String, &'static str> {
+        let parts: Vec<&str> = line.split('|').collect();
+        if parts.len() != 5 || parts[0] != "AUTH" {
+            return Err("BadHeader");
+        }
+        let (device_id, nonce) = (parts[1], parts[3]);
+        let timestamp: u64 = parts[2].parse().map_err(|_| "BadHeader")?;
+
+        let key = lock(&self.peers).get(device_id).cloned().ok_or("NotPaired")?;
+        let now = unix_now();
+        if now.abs_diff(timestamp) > AUTH_MAX_SKEW {
+            return Err("AuthExpired");
+        }
+        if !verify_mac(&key, &parts[1..4].join("|"), parts[4]) {
+            return Err("AuthFailed");
+        }
+
+        let mut seen = lock(&self.seen_nonces);
+        seen.retain(|_, t| now.abs_diff(*t) <= AUTH_MAX_SKEW * 2);
+        if seen.insert(nonce.to_string(), timestamp).is_some() {
+            return Err("AuthReplay");
+        }
+        Ok(device_id.to_string())
+    }
+
+    // 接收方处理 PAIR 请求，对应发送方的 confirm_pairing。配对码只能用一次，收到请求就作废
+    pub(crate) fn accept_pairing<S: Read + Write>(&self, socket: &mut S, line: &str) -> Result<String, &'static str> {
+        let code = match lock(&self.pending).take() {
+            Some((code, started)) if started.elapsed() <= PAIRING_CODE_TTL => code,
+            _ => return Err("NoPairing"),
+        };
+
+        // PAIR|对方ID|对方临时公钥
+        let parts: Vec<&str> = line.split('|').collect();
+        if parts.len() != 3 {
+            return Err("BadHeader");
+        }
+        let peer_id = parts[1];
+        let peer_public = parse_public_key(parts[2]).ok_or("BadHeader")?;
+
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+        socket.write_all(format!("PAIRING|{}|{}\n", self.device_id, to_hex(public.as_bytes())).as_bytes())
+            .map_err(|_| "Io")?;
+        let transcript = Transcript::new(peer_id, &peer_public, &self.device_id, &public);
+
+        // 先收到对方的承诺再给出自己的证明：对方在看到证明之前已经定下了它的证明，
+        // 之后即使从我们的证明里离线试出配对码，也没法再改成对的
+        let commitment = read_field(socket, "COMMIT")?;
+        socket.write_all(format!("PROOF|{}\n", to_hex(&transcript.proof(&code, "R"))).as_bytes())
+            .map_err(|_| "Io")?;
+
+        // OPEN|承诺随机数|对方的证明|对方的身份密钥（用协商出的密钥掩码）
+        let open = read_header_line(socket).ok_or("Io")?;
+        let parts: Vec<&str> = open.split('|').collect();
+        if parts.len() != 4 || parts[0] != "OPEN" {
+            return Err("BadHeader");
+        }
+        let (nonce, proof) = (from_hex(parts[1]).ok_or("BadHeader")?, from_hex(parts[2]).ok_or("BadHeader")?);
+        if to_hex(&commit(&nonce, &proof)) != commitment || !verify_mac(code.as_bytes(), &transcript.proof_data("S"), parts[2]) {
+            warn!("Core: 来自 {} 的配对码不正确", peer_id);
+            return Err("BadCode");
+        }
+
+        let shared = secret.diffie_hellman(&peer_public);
+        if !shared.was_contributory() {
+            return Err("BadHeader");
+        }
+        let session = transcript.session_key(shared.as_bytes());
+        let peer_key = unmask(parts[3], &session, "S").ok_or("BadHeader")?;
+
+        // 先保存再回 KEY，对方收到 KEY 时这边已经认它了
+        self.add_peer(peer_id, peer_key);
+        if socket.write_all(format!("KEY|{}\n", to_hex(&mask(&self.key, &session, "R"))).as_bytes()).is_err() {
+            let _ = self.unpair(peer_id);
+            return Err("Io");
+        }
+        info!("Core: 已与 {} 完成配对", peer_id);
+        Ok(peer_id.to_string())
+    }
+
+    fn add_peer(&self, device_id: &str, key: Vec<u8>) {
+        lock(&self.peers).insert(device_id.to_string(), key);
+        if let Err(e) = self.save() {
+            warn!("Core: 保存配对信息失败: {:?}", e);
+        }
+    }
+}
+
+/// 用对方屏幕上显示的配对码与其完成配对，成功时返回对方的设备 ID，双方都记下对方的身份密钥。
+/// 之后需要通过 set_pairing_identity 让发送的连接带上认证
+pub fn confirm_pairing(target_ip: &str, port: u16, code: &str, store: &PairingStore) -> Result<String, String> {
+    let mut stream = TcpStream::connect(format!("{}:{}", target_ip, port))
+        .map_err(|e| format!("连接失败: {:?}", e))?;
+    stream.set_read_timeout(Some(PAIRING_TIMEOUT)).ok();
+
+    let secret = EphemeralSecret::random();
+    let public = PublicKey::from(&secret);
+    stream.write_all(format!("PAIR|{}|{}\n", store.device_id, to_hex(public.as_bytes())).as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    // PAIRING|对方ID|对方临时公钥
+    let reply = read_reply(&mut stream)?;
+    let parts: Vec<&str> = reply.split('|').collect();
+    let peer = match parts[..] {
+        ["PAIRING", id, key] => parse_public_key(key).map(|k| (id, k)),
+        _ => None,
+    };
+    let (peer_id, peer_public) = peer.ok_or("无法识别的配对应答")?;
+    let transcript = Transcript::new(&store.device_id, &public, peer_id, &peer_public);
+
+    // 先只发证明的承诺，确认对方也知道配对码后才公开证明
+    let proof = transcript.proof(code, "S");
+    let nonce = random_bytes(KEY_LEN);
+    stream.write_all(format!("COMMIT|{}\n", to_hex(&commit(&nonce, &proof))).as_bytes())
+        .map_err(|e| e.to_string())?;
+    let peer_proof = read_reply(&mut stream)?;
+    match peer_proof.strip_prefix("PROOF|") {
+        Some(tag) if verify_mac(code.as_bytes(), &transcript.proof_data("R"), tag) => {}
+        _ => return Err("配对码不正确".into()),
+    }
+
+    let shared = secret.diffie_hellman(&peer_public);
+    if !shared.was_contributory() {
+        return Err("对方的公钥无效".into());
+    }
+    let session = transcript.session_key(shared.as_bytes());
+    let open = format!("OPEN|{}|{}|{}\n", to_hex(&nonce), to_hex(&proof), to_hex(&mask(&store.key, &session, "S")));
+    stream.write_all(open.as_bytes()).map_err(|e| e.to_string())?;
+
+    let key_line = read_reply(&mut stream)?;
+    let peer_key = key_line.strip_prefix("KEY|")
+        .and_then(|masked| unmask(masked, &session, "R"))
+        .ok_or("对方未确认配对")?;
+    store.add_peer(peer_id, peer_key);
+    info!("Core: 已与 {} 完成配对", peer_id);
+    Ok(peer_id.to_string())
+}
+
+// 读一行应答，对方回 ERR 时转成给用户看的原因
+fn read_reply<R: Read>(stream: &mut R) -> Result<String, String> {
+    let reply = read_header_line(stream).ok_or("连接已断开")?;
+    match reply.strip_prefix("ERR|") {
+        Some(reason) => Err(match reason.split('|').next().unwrap_or(reason) {
+            "BadCode" => "配对码不正确".to_string(),
+            "NoPairing" => "对方没有在等待配对".to_string(),
+            other => format!("对方拒绝配对: {}", other),
+        }),
+        None => Ok(reply),
+    }
+}
+
+// 读一行 PREFIX|值，返回值
+fn read_field<R: Read>(socket: &mut R, prefix: &str) -> Result<String, &'static str> {
+    let line = read_header_line(socket).ok_or("Io")?;
+    match line.split_once('|') {
+        Some((p, value)) if p == prefix => Ok(value.to_string()),
+        _ => Err("BadHeader"),
+    }
+}
+
+// 双方的设备 ID 和临时公钥，证明和会话密钥都绑定在上面，中间人换掉任何一个公钥证明就对不上
+struct Transcript(String);
+
+impl Transcript {
+    fn new(sender_id: &str, sender_key: &PublicKey, receiver_id: &str, receiver_key: &PublicKey) -> Self {
+        Self(format!("{}|{}|{}|{}", sender_id, to_hex(sender_key.as_bytes()), receiver_id, to_hex(receiver_key.as_bytes())))
+    }
+
+    // role 为 S（发送方）或 R（接收方），两边的证明不同，不能把对方的证明原样发回去
+    fn proof_data(&self, role: &str) -> String {
+        format!("PROOF|{}|{}", role, self.0)
+    }
+
+    fn proof(&self, code: &str, role: &str) -> Vec<u8> {
+        mac(code.as_bytes(), &self.proof_data(role))
+    }
+
+    fn session_key(&self, shared: &[u8]) -> Vec<u8> {
+        mac(shared, &format!("KEY|{}", self.0))
+    }
+}
+
+fn commit(nonce: &[u8], proof: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce);
+    hasher.update(proof);
+    hasher.finalize().to_vec()
+}
+
+// 身份密钥与按 role 从会话密钥派生的掩码异或，只有完成密钥协商的双方能解开
+fn mask(key: &[u8], session: &[u8], role: &str) -> Vec<u8> {
+    let pad = mac(session, &format!("MASK|{}", role));
+    key.iter().zip(&pad).map(|(a, b)| a ^ b).collect()
+}
+
+fn unmask(masked_hex: &str, session: &[u8], role: &str) -> Option<Vec<u8>> {
+    from_hex(masked_hex).filter(|m| m.len() == KEY_LEN).map(|m| mask(&m, session, role))
+}
+
+fn parse_public_key(hex: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = from_hex(hex)?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+fn mac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut m = HmacSha256::new_from_slice(key).expect("HMAC 接受任意长度的密钥");
+    m.update(data.as_bytes());
+    m.finalize().into_bytes().to_vec()
+}
+
+fn verify_mac(key: &[u8], data: &str, tag_hex: &str) -> bool {
+    let tag = match from_hex(tag_hex) {
+        Some(t) => t,
+        None => return false,
+    };
+    let mut m = HmacSha256::new_from_slice(key).expect("HMAC 接受任意长度的密钥");
+    m.update(data.as_bytes());
+    m.verify_slice(&tag).is_ok()
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    getrandom::getrandom(&mut buf).expect("系统随机数不可用");
+    buf
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub(super) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(super) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    // 在回环端口上让 receiver 处理一次配对请求，sender 用 code 确认，返回双方的结果
+    fn pair(receiver: &Arc<PairingStore>, sender: &PairingStore, code: &str) -> (Result<String, String>, Result<String, &'static str>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let receiver = receiver.clone();
+        let serving = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let line = read_header_line(&mut socket).unwrap();
+            let result = receiver.accept_pairing(&mut socket, &line);
+            if let Err(reason) = result {
+                let _ = socket.write_all(format!("ERR|{}\n", reason).as_bytes());
+            }
+            result
+        });
+        let confirmed = confirm_pairing("127.0.0.1", port, code, sender);
+        (confirmed, serving.join().unwrap())
+    }
+
+    #[test]
+    fn round_trip_pairs_both_sides() {
+        let receiver = Arc::new(PairingStore::in_memory("receiver"));
+        let sender = PairingStore::in_memory("sender");
+        let code = receiver.begin_pairing();
+
+        let (confirmed, accepted) = pair(&receiver, &sender, &code);
+
+        assert_eq!(confirmed, Ok("receiver".to_string()));
+        assert_eq!(accepted, Ok("sender".to_string()));
+        assert!(receiver.is_paired("sender") && sender.is_paired("receiver"));
+        assert_eq!(receiver.verify_auth(sender.auth_line().trim_end()), Ok("sender".to_string()));
+        assert_eq!(sender.verify_auth(receiver.auth_line().trim_end()), Ok("receiver".to_string()));
+    }
+
+    #[test]
+    fn wrong_code_is_refused_and_burns_the_code() {
+        let receiver = Arc::new(PairingStore::in_memory("receiver"));
+        let sender = PairingStore::in_memory("sender");
+        let code = receiver.begin_pairing();
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+
+        let (confirmed, accepted) = pair(&receiver, &sender, &wrong);
+        assert_eq!(confirmed, Err("配对码不正确".to_string()));
+        assert!(accepted.is_err());
+        assert!(receiver.paired_devices().is_empty() && sender.paired_devices().is_empty());
+
+        // 猜错一次后原来的配对码也不能再用
+        let (confirmed, accepted) = pair(&receiver, &sender, &code);
+        assert_eq!(confirmed, Err("对方没有在等待配对".to_string()));
+        assert_eq!(accepted, Err("NoPairing"));
+    }
+
+    #[test]
+    fn expired_code_is_refused() {
+        let receiver = Arc::new(PairingStore::in_memory("receiver"));
+        let sender = PairingStore::in_memory("sender");
+        let code = receiver.begin_pairing();
+        let expired = Instant::now().checked_sub(PAIRING_CODE_TTL + Duration::from_secs(1)).unwrap();
+        lock(&receiver.pending).as_mut().unwrap().1 = expired;
+
+        let (confirmed, accepted) = pair(&receiver, &sender, &code);
+        assert_eq!(confirmed, Err("对方没有在等待配对".to_string()));
+        assert_eq!(accepted, Err("NoPairing"));
+        assert!(!receiver.is_paired("sender") && !sender.is_paired("receiver"));
+    }
+
+    #[test]
+    fn auth_from_unpaired_or_replayed_line_is_refused() {
+        let receiver = Arc::new(PairingStore::in_memory("receiver"));
+        let sender = PairingStore::in_memory("sender");
+        let line = sender.auth_line();
+        assert_eq!(receiver.verify_auth(line.trim_end()), Err("NotPaired"));
+
+        let code = receiver.begin_pairing();
+        pair(&receiver, &sender, &code).0.unwrap();
+        let line = sender.auth_line();
+        assert!(receiver.verify_auth(line.trim_end()).is_ok());
+        assert_eq!(receiver.verify_auth(line.trim_end()), Err("AuthReplay"));
+    }
+}
@@ -0,0 +1,91 @@
+//! 传输预设：按网络环境把并行数、连接复用、超时等参数打包成几组现成的组合，
+//! 不了解各项参数的用户选一个预设即可。预设只是生成普通的 SendOptions / ServerConfig，
+//! 需要改个别参数时用结构体更新语法覆盖：
+//! `SendOptions { parallel_cnt: 2, ..TransferProfile::Wifi.send_options() }`
+
+use std::time::Duration;
+
+use super::{SendOptions, ServerConfig};
+
+/// 传输预设
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransferProfile {
+    /// 有线局域网：8 条并行连接，多文件复用连接；接收端消息头超时 5 秒
+    FastLan,
+    /// Wi-Fi：自适应并行，最多 4 条，多文件复用连接；接收端消息头超时 10 秒
+    #[default]
+    Wifi,
+    /// 慢速或不稳定的链路：单条连接；接收端消息头超时 30 秒
+    SlowLink,
+}
+
+impl TransferProfile {
+    pub const ALL: [TransferProfile; 3] = [TransferProfile::FastLan, TransferProfile::Wifi, TransferProfile::SlowLink];
+
+    /// 用于界面显示的名字
+    pub fn label(self) -> &'static str {
+        match self {
+            TransferProfile::FastLan => "有线局域网",
+            TransferProfile::Wifi => "Wi-Fi",
+            TransferProfile::SlowLink => "慢速链路",
+        }
+    }
+
+    /// 发送端参数，未涉及的字段取 SendOptions::default()
+    pub fn send_options(self) -> SendOptions {
+        let (parallel_cnt, adaptive, reuse_connections) = match self {
+            TransferProfile::FastLan => (8, false, true),
+            TransferProfile::Wifi => (4, true, true),
+            TransferProfile::SlowLink => (1, false, false),
+        };
+        SendOptions { parallel_cnt, adaptive, reuse_connections, ..SendOptions::default() }
+    }
+
+    /// 接收端参数，未涉及的字段取 ServerConfig::default()。
+    /// 预设都不开写盘缓冲（write_buffer_cap），收到即写，需要时自行覆盖
+    pub fn server_config(self) -> ServerConfig {
+        let header_timeout = match self {
+            TransferProfile::FastLan => 5,
+            TransferProfile::Wifi => 10,
+            TransferProfile::SlowLink => 30,
+        };
+        ServerConfig { header_timeout: Some(Duration::from_secs(header_timeout)), ..ServerConfig::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_produce_documented_send_options() {
+        let expected = [
+            (TransferProfile::FastLan, 8, false, true),
+            (TransferProfile::Wifi, 4, true, true),
+            (TransferProfile::SlowLink, 1, false, false),
+        ];
+        for (profile, parallel_cnt, adaptive, reuse_connections) in expected {
+            let options = profile.send_options();
+            assert_eq!(options.parallel_cnt, parallel_cnt, "{:?}", profile);
+            assert_eq!(options.adaptive, adaptive, "{:?}", profile);
+            assert_eq!(options.reuse_connections, reuse_connections, "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn profiles_produce_documented_server_config() {
+        let expected = [(TransferProfile::FastLan, 5), (TransferProfile::Wifi, 10), (TransferProfile::SlowLink, 30)];
+        for (profile, header_timeout) in expected {
+            let config = profile.server_config();
+            assert_eq!(config.header_timeout, Some(Duration::from_secs(header_timeout)), "{:?}", profile);
+            assert_eq!(config.write_buffer_cap, None, "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn fields_can_be_overridden() {
+        let options = SendOptions { parallel_cnt: 2, ..TransferProfile::FastLan.send_options() };
+        assert_eq!(options.parallel_cnt, 2);
+        assert!(options.reuse_connections);
+    }
+}
@@ -0,0 +1,136 @@
+//! 空闲连接回收：连上传输端口却迟迟不发消息头的连接（端口扫描、有问题的对端）
+//! 会让处理线程一直卡在逐字节读头部上。每条连接在收到第一行头部之前登记在这里，
+//! 超过时限的由后台线程直接关闭，阻塞的读操作随之返回，线程得以释放。
+
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+
+use super::lock;
+
+pub(crate) struct IdleReaper {
+    timeout: Duration,
+    next_id: AtomicU64,
+    // 连接编号 -> (用于关闭的句柄, 对方地址, 连上的时间)
+    conns: Mutex<HashMap<u64, (TcpStream, String, Instant)>>,
+}
+
+impl IdleReaper {
+    // 创建并启动回收线程，服务端释放后线程自行退出
+    pub(crate) fn start(timeout: Duration) -> Arc<Self> {
+        let reaper = Arc::new(Self {
+            timeout,
+            next_id: AtomicU64::new(0),
+            conns: Mutex::new(HashMap::new()),
+        });
+        let weak = Arc::downgrade(&reaper);
+        let interval = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        thread::spawn(move || reap_loop(weak, interval));
+        reaper
+    }
+
+    // 登记一条还没发头部的连接，返回的守卫释放时注销
+    pub(crate) fn watch(self: &Arc<Self>, socket: &TcpStream, peer: &str) -> Option<IdleGuard> {
+        let handle = match socket.try_clone() {
+            Ok(h) => h,
+            Err(e) => {
+                error!("Core: 无法登记连接 {}，不做超时回收: {:?}", peer, e);
+                return None;
+            }
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        lock(&self.conns).insert(id, (handle, peer.to_string(), Instant::now()));
+        Some(IdleGuard { reaper: self.clone(), id })
+    }
+
+    fn reap(&self) {
+        lock(&self.conns).retain(|_, (socket, peer, since)| {
+            if since.elapsed() < self.timeout {
+                return true;
+            }
+            warn!("Core: {} 连接后 {:?} 内没有发来消息头，关闭连接", peer, self.timeout);
+            let _ = socket.shutdown(Shutdown::Both);
+            false
+        });
+    }
+}
+
+fn reap_loop(reaper: Weak<IdleReaper>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match reaper.upgrade() {
+            Some(reaper) => reaper.reap(),
+            None => break,
+        }
+    }
+}
+
+// 收到头部（或连接结束）后释放，连接不再受回收影响
+pub(crate) struct IdleGuard {
+    reaper: Arc<IdleReaper>,
+    id: u64,
+}
+
+impl Drop for IdleGuard {
+    fn drop(&mut self) {
+        lock(&self.reaper.conns).remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn silent_connections_are_closed_within_the_deadline() {
+        let timeout = Duration::from_millis(200);
+        let reaper = IdleReaper::start(timeout);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 客户端连上后一个字节也不发
+        let clients: Vec<TcpStream> = (0..10).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        let started = Instant::now();
+        let handlers: Vec<_> = (0..10)
+            .map(|_| {
+                let (mut socket, peer) = listener.accept().unwrap();
+                let guard = reaper.watch(&socket, &peer.to_string());
+                thread::spawn(move || {
+                    let mut buf = [0u8; 1];
+                    let n = socket.read(&mut buf).unwrap_or(0);
+                    drop(guard);
+                    n
+                })
+            })
+            .collect();
+        for handler in handlers {
+            assert_eq!(handler.join().unwrap(), 0);
+        }
+
+        assert!(started.elapsed() < timeout * 4, "{:?}", started.elapsed());
+        assert!(lock(&reaper.conns).is_empty());
+        drop(clients);
+    }
+
+    #[test]
+    fn connection_that_sent_its_header_is_left_alone() {
+        let reaper = IdleReaper::start(Duration::from_millis(50));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut socket, peer) = listener.accept().unwrap();
+
+        drop(reaper.watch(&socket, &peer.to_string()));
+        thread::sleep(Duration::from_millis(200));
+
+        std::io::Write::write_all(&mut client, b"x").unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(socket.read(&mut buf).unwrap(), 1);
+    }
+}
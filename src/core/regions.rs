@@ -0,0 +1,189 @@
+//! 区段同步：备份、同步工具只想发送大文件里改动过的区段。接收方必须已经有这个文件，
+//! 各区段按原偏移写回去，不截断、不预分配，区段之外的内容保持不变。
+//! 握手用 PATCH|name|total（total 为所有区段的字节数之和），之后每个区段走一条 DATA 连接。
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use log::{info, warn};
+
+use super::metrics::METRICS;
+use super::registry::DEVICES;
+use super::transfers::TRANSFERS;
+use super::{
+    accepted_name, connect_peer, inspect_source, lock, read_header_line, sanitize_file_name, send_chunk,
+    AcceptedFile, FileServer, SendLimits, TransferCallback, TransferDirection, TransferError, NEXT_TRANSFER_ID,
+    TRANSFER_ID_PREFIX,
+};
+
+/// 只发送 file_path 中 regions 指定的 (offset, len) 区段，接收方把它们写到已有同名文件的相同偏移处。
+/// 区段不能重叠，也不能超出源文件；接收方没有这个文件时拒绝。
+/// 进度按已发送的区段字节数回调，完成或失败时回调 on_complete
+pub fn send_file_regions(
+    target_ip: String,
+    port: u16,
+    file_path: String,
+    regions: Vec<(u64, u64)>,
+    callback: Box<dyn TransferCallback>,
+) {
+    let active = TRANSFERS.begin(None);
+    thread::spawn(move || {
+        let limits = SendLimits::new(None, active.token());
+        let result = transfer_regions(&target_ip, port, &file_path, regions, &limits, callback.as_ref());
+        DEVICES.record_transfer(&target_ip, TransferDirection::Sent, result.is_ok());
+        match result {
+            Ok(total) => callback.on_complete(true, format!("发送完成 ({} 字节)", total)),
+            Err(e) => {
+                METRICS.error();
+                callback.on_error(e.clone());
+                callback.on_complete(false, e.to_string())
+            }
+        }
+    });
+}
+
+// 按偏移排序并检查区段：不能为空、不能重叠、不能超出文件，返回排好序的区段和总字节数
+fn check_regions(mut regions: Vec<(u64, u64)>, file_len: u64) -> Result<(Vec<(u64, u64)>, u64), String> {
+    regions.retain(|&(_, len)| len > 0);
+    if regions.is_empty() {
+        return Err("没有要发送的区段".into());
+    }
+    regions.sort_unstable();
+    let mut end = 0u64;
+    for &(offset, len) in &regions {
+        if offset < end {
+            return Err(format!("区段 {}+{} 与前一个区段重叠", offset, len));
+        }
+        end = offset.checked_add(len).filter(|&e| e <= file_len)
+            .ok_or_else(|| format!("区段 {}+{} 超出文件大小 {}", offset, len, file_len))?;
+    }
+    let total = regions.iter().map(|&(_, len)| len).sum();
+    Ok((regions, total))
+}
+
+fn transfer_regions(
+    target_ip: &str,
+    port: u16,
+    file_path: &str,
+    regions: Vec<(u64, u64)>,
+    limits: &SendLimits,
+    callback: &dyn TransferCallback,
+) -> Result<u64, TransferError> {
+    let (file_name, mut source) = inspect_source(file_path)?;
+    source.limits = limits.clone();
+    let (regions, total) = check_regions(regions, source.len)?;
+
+    let mut stream = connect_peer(target_ip, port).map_err(|e| format!("连接失败: {:?}", e))?;
+    stream.write_all(format!("PATCH|{}|{}\n", file_name, total).as_bytes()).map_err(|e| e.to_string())?;
+    let response = read_header_line(&mut stream).ok_or_else(|| "连接已断开".to_string())?;
+    let accepted = accepted_name(&response, &file_name)?;
+    drop(stream);
+    info!("Core: 对方接受 {} 的 {} 个区段，共 {} 字节", file_name, regions.len(), total);
+
+    let progress = AtomicU64::new(0);
+    for (offset, len) in regions {
+        send_chunk(target_ip, port, &accepted, &source, offset, len, &progress).map_err(TransferError::from_io)?;
+        callback.on_progress(progress.load(Ordering::Relaxed), total);
+    }
+    METRICS.transfer_sent();
+    Ok(total)
+}
+
+// 处理 PATCH：目标文件必须已经存在且是普通文件，登记后回 ACC|name|tid=N，
+// 之后的 DATA 只覆盖各自的区段，收满 size 字节即完成
+pub(super) fn handle_patch<W: Write>(socket: &mut W, server: &FileServer, sender_ip: &str, name: &str, size: u64) {
+    let filename = match sanitize_file_name(name) {
+        Some(n) => n,
+        None => {
+            let _ = socket.write_all(b"REJ|BadName\n");
+            return;
+        }
+    };
+
+    let decision = server.callback.on_receive_request(filename.clone(), size, sender_ip.to_string());
+    if !decision.accept {
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ\n");
+        return;
+    }
+    if decision.sink.is_some() {
+        warn!("Core: 区段同步不支持写入自定义接收端，拒绝 {}", filename);
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ|SinkUnsupported\n");
+        return;
+    }
+
+    let dir = decision.dir.unwrap_or_else(|| PathBuf::from(server.save_dir.as_str()));
+    let path = dir.join(&filename);
+    // 符号链接可能指到保存目录之外，和接收新文件时一样不跟随
+    if !std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+        warn!("Core: 没有可供写入区段的 {:?}，拒绝", path);
+        METRICS.reject();
+        let _ = socket.write_all(b"REJ|NoBase\n");
+        return;
+    }
+
+    let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    lock(&server.accepted).insert(filename.clone(), AcceptedFile {
+        id,
+        path,
+        total: size,
+        received: 0,
+        connections: 0,
+        idle_since: None,
+        finished: false,
+        sink: None,
+        started: Instant::now(),
+        peer: sender_ip.to_string(),
+        expected_sha256: None,
+        patch: true,
+        filtered: false,
+        progress: None,
+    });
+    info!("Core: 接受 {} 的区段写入 ({} 字节)", filename, size);
+    let _ = socket.write_all(format!("ACC|{}|{}{}\n", filename, TRANSFER_ID_PREFIX, id).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::{file_server, serve_on_loopback, temp_dir, Event, Recorder};
+    use crate::core::ServerConfig;
+    use std::fs;
+
+    #[test]
+    fn only_the_two_regions_change_on_the_receiver() {
+        let dir = temp_dir("regions");
+        let inbox = dir.join("inbox");
+        fs::create_dir_all(&inbox).unwrap();
+        fs::write(inbox.join("disk.img"), vec![0u8; 10_000]).unwrap();
+        // 源文件处处不同，接收方只应该拿到指定区段的内容
+        let source = dir.join("disk.img");
+        fs::write(&source, vec![0xffu8; 10_000]).unwrap();
+        let (server, recorder) = file_server(&inbox, ServerConfig::default());
+        let (port, _) = serve_on_loopback(server);
+
+        let sent = transfer_regions("127.0.0.1", port, source.to_str().unwrap(), vec![(7_000, 500), (100, 20)], &SendLimits::default(), &Recorder::default());
+
+        assert_eq!(sent, Ok(520));
+        assert!(recorder.wait_for(|e| matches!(e, Event::Complete(true, _))).is_some());
+        let received = fs::read(inbox.join("disk.img")).unwrap();
+        assert_eq!(received.len(), 10_000);
+        for (i, &b) in received.iter().enumerate() {
+            let inside = (100..120).contains(&i) || (7_000..7_500).contains(&i);
+            assert_eq!(b, if inside { 0xff } else { 0 }, "偏移 {}", i);
+        }
+    }
+
+    #[test]
+    fn overlapping_or_out_of_range_regions_are_refused() {
+        assert_eq!(check_regions(vec![(10, 5), (0, 4), (20, 0)], 100), Ok((vec![(0, 4), (10, 5)], 9)));
+        assert!(check_regions(vec![(0, 10), (5, 10)], 100).is_err());
+        assert!(check_regions(vec![(95, 10)], 100).is_err());
+        assert!(check_regions(vec![(u64::MAX, 2)], 100).is_err());
+        assert!(check_regions(vec![(0, 0)], 100).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+use serde::Serialize;
+
+use super::{canonical_ip, list_interfaces, lock, DeviceInfo, TransferDirection};
+
+/// 进程内见过的所有设备，发现线程收到有效的 DISCOVER/HERE 时更新
+pub struct DeviceRegistry {
+    devices: Mutex<BTreeMap<String, DeviceRecord>>,
+    // 按 IP 记录最近一次传输结果，传输时只知道对方 IP
+    last_transfers: Mutex<BTreeMap<String, LastTransfer>>,
+}
+
+/// 与某台设备最近一次传输的结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct LastTransfer {
+    pub direction: TransferDirection,
+    pub success: bool,
+    /// 传输结束时的 Unix 时间戳（秒）
+    pub timestamp: u64,
+}
+
+/// 一台设备最近一次的通告信息
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DeviceRecord {
+    pub device_id: String,
+    pub name: String,
+    /// 最近一次收到通告的地址
+    pub ip: String,
+    /// 收到过这台设备通告的所有地址（多网卡设备会有多个），按首次出现的顺序
+    pub addresses: Vec<String>,
+    pub control_port: u16,
+    /// 最后一次收到通告的 Unix 时间戳（秒）
+    pub last_seen: u64,
+    /// 累计收到的通告包数
+    pub packet_count: u64,
+    /// 与该设备 IP 最近一次传输的结果
+    pub last_transfer: Option<LastTransfer>,
+}
+
+pub(crate) static DEVICES: DeviceRegistry = DeviceRegistry::new();
+
+impl DeviceRegistry {
+    const fn new() -> Self {
+        Self {
+            devices: Mutex::new(BTreeMap::new()),
+            last_transfers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn record(&self, device: &DeviceInfo) {
+        let now = unix_now();
+        let mut devices = lock(&self.devices);
+        let record = devices.entry(device.device_id.clone()).or_insert_with(|| DeviceRecord {
+            device_id: device.device_id.clone(),
+            name: String::new(),
+            ip: String::new(),
+            addresses: Vec::new(),
+            control_port: 0,
+            last_seen: 0,
+            packet_count: 0,
+            last_transfer: None,
+        });
+        record.name = device.name.clone();
+        record.ip = canonical_ip(&device.ip);
+        if !record.addresses.contains(&record.ip) {
+            if !record.addresses.is_empty() {
+                info!("Core: 设备 {} 出现在新地址 {}", device.device_id, record.ip);
+            }
+            record.addresses.push(record.ip.clone());
+        }
+        record.control_port = device.control_port;
+        record.last_seen = now;
+        record.packet_count += 1;
+    }
+
+    // 传输结束时调用，ip 为对方地址，按 IPv4 形式记录，与发现到的地址对得上
+    pub(crate) fn record_transfer(&self, ip: &str, direction: TransferDirection, success: bool) {
+        let result = LastTransfer { direction, success, timestamp: unix_now() };
+        lock(&self.last_transfers).insert(canonical_ip(ip), result);
+    }
+
+    // 删除设备，最近一次传输的记录保留（对方发来 BYE 时）
+    pub(crate) fn remove(&self, device_id: &str) -> Option<DeviceRecord> {
+        lock(&self.devices).remove(device_id)
+    }
+
+    // 删除设备及其各个地址上最近一次传输的记录，自检结束时清掉试传留下的痕迹
+    pub(crate) fn forget(&self, device_id: &str) {
+        if let Some(record) = self.remove(device_id) {
+            let mut last_transfers = lock(&self.last_transfers);
+            for address in &record.addresses {
+                last_transfers.remove(address);
+            }
+        }
+    }
+
+    // 连接 ip 时依次尝试的地址：ip 所属设备的所有已知地址，与本机同网段的优先，
+    // 同等条件下 ip 本身优先。不认识的 ip 只返回它自己
+    pub(crate) fn connect_candidates(&self, ip: &str) -> Vec<String> {
+        let ip = canonical_ip(ip);
+        let mut candidates = vec![ip.clone()];
+        if let Some(record) = lock(&self.devices).values().find(|d| d.addresses.contains(&ip)) {
+            candidates.extend(record.addresses.iter().filter(|a| **a != ip).cloned());
+        }
+        if candidates.len() > 1 {
+            prefer_local_subnet(&mut candidates);
+        }
+        candidates
+    }
+
+    fn snapshot(&self) -> Vec<DeviceRecord> {
+        let last_transfers = lock(&self.last_transfers);
+        lock(&self.devices).values()
+            .map(|d| DeviceRecord { last_transfer: last_transfers.get(&d.ip).copied(), ..d.clone() })
+            .collect()
+    }
+}
+
+// 与本机某块网卡同网段的地址排到前面，其余保持原来的顺序
+fn prefer_local_subnet(addresses: &mut [String]) {
+    let interfaces = list_interfaces();
+    addresses.sort_by_key(|a| {
+        let local = a.parse().is_ok_and(|ip| interfaces.iter().any(|i| i.contains(ip)));
+        !local
+    });
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// 当前已知的所有设备，按设备 ID 排序
+pub fn known_devices() -> Vec<DeviceRecord> {
+    DEVICES.snapshot()
+}
+
+/// 这台设备所有已知的地址，与本机同网段的排在前面；没收到过它的通告时为空
+pub fn device_addresses(device_id: &str) -> Vec<String> {
+    let mut addresses = lock(&DEVICES.devices).get(device_id).map(|d| d.addresses.clone()).unwrap_or_default();
+    prefer_local_subnet(&mut addresses);
+    addresses
+}
+
+/// 与该 IP 最近一次传输的结果，没有传输过时为 None
+pub fn last_transfer(ip: &str) -> Option<LastTransfer> {
+    lock(&DEVICES.last_transfers).get(&canonical_ip(ip)).copied()
+}
+
+/// 导出设备列表，前面是便于阅读的表格，后面附上同样内容的 JSON，方便排查问题时分享
+pub fn export_devices_snapshot() -> String {
+    let devices = known_devices();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# 设备列表 ({} 台)", devices.len());
+    let _ = writeln!(out, "# 设备ID\t名称\t地址\t最后在线\t通告包数\t最近传输");
+    for d in &devices {
+        let last = match d.last_transfer {
+            Some(t) => format!("{}@{}", if t.success { "成功" } else { "失败" }, t.timestamp),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{}\t{}\t{}:{}\t{}\t{}\t{}",
+            d.device_id, d.name, d.ip, d.control_port, d.last_seen, d.packet_count, last
+        );
+    }
+    let _ = writeln!(out);
+    let json = serde_json::to_string_pretty(&devices).unwrap_or_else(|_| "[]".to_string());
+    let _ = writeln!(out, "{}", json);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(device_id: &str, ip: &str) -> DeviceInfo {
+        DeviceInfo { device_id: device_id.into(), name: "书房电脑".into(), ip: ip.into(), control_port: 4061, free_space: None }
+    }
+
+    #[test]
+    fn snapshot_lists_a_seeded_device() {
+        DEVICES.record(&device("snapshot-dev", "10.9.8.7"));
+        DEVICES.record(&device("snapshot-dev", "10.9.8.7"));
+
+        let snapshot = export_devices_snapshot();
+        let row = snapshot.lines().find(|l| l.starts_with("snapshot-dev\t")).unwrap();
+        let fields: Vec<&str> = row.split('\t').collect();
+        assert_eq!(fields[..3], ["snapshot-dev", "书房电脑", "10.9.8.7:4061"]);
+        assert_eq!(fields[4..], ["2", "-"]);
+
+        let json = &snapshot[snapshot.find("\n[").unwrap()..];
+        let devices: serde_json::Value = serde_json::from_str(json).unwrap();
+        let record = devices.as_array().unwrap().iter().find(|d| d["device_id"] == "snapshot-dev").unwrap();
+        assert_eq!(record["packet_count"], 2);
+        assert_eq!(record["control_port"], 4061);
+        assert!(record["last_seen"].as_u64().unwrap() > 0);
+        DEVICES.forget("snapshot-dev");
+    }
+
+    #[test]
+    fn last_transfer_follows_the_device_address() {
+        DEVICES.record(&device("last-dev", "10.9.8.20"));
+        assert_eq!(last_transfer("10.9.8.20"), None);
+
+        DEVICES.record_transfer("10.9.8.20", TransferDirection::Sent, false);
+        // 接收时对方地址可能是 IPv4 映射的 IPv6 形式
+        DEVICES.record_transfer("::ffff:10.9.8.20", TransferDirection::Received, true);
+
+        let last = last_transfer("10.9.8.20").unwrap();
+        assert_eq!((last.direction, last.success), (TransferDirection::Received, true));
+        let record = known_devices().into_iter().find(|d| d.device_id == "last-dev").unwrap();
+        assert_eq!(record.last_transfer, Some(last));
+        DEVICES.forget("last-dev");
+        assert_eq!(last_transfer("10.9.8.20"), None);
+    }
+
+    #[test]
+    fn one_device_on_two_addresses_is_a_single_entry() {
+        DEVICES.record(&device("multi-dev", "10.9.7.1"));
+        DEVICES.record(&device("multi-dev", "::ffff:10.9.7.2"));
+        DEVICES.record(&device("multi-dev", "10.9.7.1"));
+
+        let records: Vec<DeviceRecord> = known_devices().into_iter().filter(|d| d.device_id == "multi-dev").collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].addresses, ["10.9.7.1", "10.9.7.2"]);
+        assert_eq!(device_addresses("multi-dev"), ["10.9.7.1", "10.9.7.2"]);
+        assert_eq!(DEVICES.connect_candidates("10.9.7.2"), ["10.9.7.2", "10.9.7.1"]);
+        assert_eq!(DEVICES.connect_candidates("10.9.7.99"), ["10.9.7.99"]);
+        DEVICES.forget("multi-dev");
+    }
+
+    #[test]
+    fn address_on_a_local_subnet_is_tried_first() {
+        // 需要本机至少有一块带 IPv4 地址的网卡
+        let Some(local) = list_interfaces().first().map(|i| i.ip.to_string()) else { return };
+        DEVICES.record(&device("subnet-dev", "203.0.113.9"));
+        DEVICES.record(&device("subnet-dev", &local));
+
+        assert_eq!(DEVICES.connect_candidates("203.0.113.9"), [local, "203.0.113.9".to_string()]);
+        DEVICES.forget("subnet-dev");
+    }
+}
@@ -0,0 +1,298 @@
+//! 目录传输的续传。发送方用 DIR|n|resume 发起目录传输时，清单每一行在 name|size 之后附上
+//! 源文件的修改时间和 SHA-256；接收方为这次传输在保存目录里记一份进度文件（每行一条 JSON）：
+//! 接受时记下每个文件保存成的名字、大小和来源标识，收完一个文件记下收到内容的 SHA-256，
+//! 连接中断时记下收了一半的文件已有的字节数和这部分的 SHA-256。
+//! 重新发起同一目录时，来源标识和磁盘上的内容都核对得上的文件才续传：收完的直接跳过，
+//! 收了一部分的从断点接着发，其余的一律从头重收。整个目录收完后进度文件随即删除。
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::checksum::{file_sha256, prefix_sha256};
+use super::pairing::to_hex;
+use super::{lock, sanitize_relative_path, will_be_within, AcceptedFile, FileServer, NEXT_TRANSFER_ID};
+
+/// 发送方在续传清单里为每个文件附上的来源标识，两次发送之间源文件被改过就对不上
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct SourceIdentity {
+    // 修改时间，UNIX 纪元以来的纳秒数，取不到时为 0
+    pub(super) mtime: u64,
+    // 整个文件的 SHA-256，十六进制
+    pub(super) sha256: String,
+}
+
+impl SourceIdentity {
+    // 读一遍源文件得出标识
+    pub(super) fn of(path: &Path, modified: Option<SystemTime>) -> io::Result<Self> {
+        let mtime = modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok(Self { mtime, sha256: to_hex(&file_sha256(path)?) })
+    }
+
+    // 清单行里附在 name|size 之后的部分
+    pub(super) fn manifest_suffix(&self) -> String {
+        format!("|{}|{}", self.mtime, self.sha256)
+    }
+}
+
+// 从续传清单的一行里拆出 name|size 和末尾的来源标识，末尾不是标识（例如空目录）时返回 None
+pub(super) fn split_identity(line: &str) -> Option<(&str, SourceIdentity)> {
+    let (rest, sha256) = line.rsplit_once('|')?;
+    let (entry, mtime) = rest.rsplit_once('|')?;
+    if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) || !entry.contains('|') {
+        return None;
+    }
+    let identity = SourceIdentity { mtime: mtime.parse().ok()?, sha256: sha256.to_ascii_lowercase() };
+    Some((entry, identity))
+}
+
+// 进度文件里的一行
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ProgressLine {
+    // 清单里的一个文件：清单中的相对路径、保存成的名字（相对保存目录）、大小和来源标识
+    Entry { name: String, saved_as: String, size: u64, source: Option<SourceIdentity> },
+    // saved_as 已完整收到，sha256 是收到内容的 SHA-256
+    Done { saved_as: String, sha256: String },
+    // 连接中断时 saved_as 只收到前 received 字节，sha256 是这部分的 SHA-256
+    Partial { saved_as: String, received: u64, sha256: String },
+}
+
+// 上次目录传输中的一个文件
+pub(super) struct PreviousEntry {
+    pub(super) saved_as: String,
+    pub(super) size: u64,
+    pub(super) source: Option<SourceIdentity>,
+    // 收完时内容的 SHA-256
+    pub(super) done: Option<String>,
+    // 中断时已收到的字节数和这部分的 SHA-256，以最后一次记录为准
+    pub(super) partial: Option<(u64, String)>,
+}
+
+// 这次清单里的一个文件，写进新的进度文件
+pub(super) struct ProgressEntry {
+    pub(super) name: String,
+    pub(super) saved_as: String,
+    pub(super) size: u64,
+    pub(super) source: Option<SourceIdentity>,
+    // 上次已经收完、这次直接跳过时为收到内容的 SHA-256
+    pub(super) done: Option<String>,
+}
+
+// 进度文件放在保存目录里，按目录传输的顶层目录命名，例如 .photos.locsd-progress
+pub(super) fn progress_path(dir: &Path, files: &[(String, u64)]) -> Option<PathBuf> {
+    let top = files.first()?.0.split('/').next()?;
+    Some(dir.join(format!(".{}.locsd-progress", top)))
+}
+
+// 读取上次的进度，按清单中的相对路径索引。文件不存在时为空，无法解析的行跳过
+pub(super) fn load_progress(path: &Path) -> HashMap<String, PreviousEntry> {
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return HashMap::new(),
+    };
+    let mut entries = HashMap::new();
+    let mut done = HashMap::new();
+    let mut partial = HashMap::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        match serde_json::from_str(&line) {
+            Ok(ProgressLine::Entry { name, saved_as, size, source }) => {
+                entries.insert(name, PreviousEntry { saved_as, size, source, done: None, partial: None });
+            }
+            Ok(ProgressLine::Done { saved_as, sha256 }) => {
+                partial.remove(&saved_as);
+                done.insert(saved_as, sha256);
+            }
+            Ok(ProgressLine::Partial { saved_as, received, sha256 }) => {
+                partial.insert(saved_as, (received, sha256));
+            }
+            Err(e) => warn!("Core: 跳过无法解析的续传进度: {}", e),
+        }
+    }
+    for entry in entries.values_mut() {
+        entry.done = done.get(&entry.saved_as).cloned();
+        entry.partial = partial.get(&entry.saved_as).cloned();
+    }
+    entries
+}
+
+// 用这次的清单重写进度文件
+pub(super) fn start_progress(path: &Path, files: &[ProgressEntry]) -> io::Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    for f in files {
+        let line = ProgressLine::Entry { name: f.name.clone(), saved_as: f.saved_as.clone(), size: f.size, source: f.source.clone() };
+        writeln!(out, "{}", serde_json::to_string(&line).map_err(io::Error::other)?)?;
+    }
+    for f in files {
+        if let Some(sha256) = &f.done {
+            let line = ProgressLine::Done { saved_as: f.saved_as.clone(), sha256: sha256.clone() };
+            writeln!(out, "{}", serde_json::to_string(&line).map_err(io::Error::other)?)?;
+        }
+    }
+    out.flush()
+}
+
+// 在进度文件末尾追加一行
+fn append(path: &Path, line: &ProgressLine) -> io::Result<()> {
+    let line = serde_json::to_string(line).map_err(io::Error::other)?;
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+// 记下 saved_as（保存在 file）已经收完，清单里的文件全部收完时删除进度文件
+pub(super) fn mark_done(path: &Path, saved_as: &str, file: &Path) {
+    let result = file_sha256(file).and_then(|digest| {
+        append(path, &ProgressLine::Done { saved_as: saved_as.to_string(), sha256: to_hex(&digest) })
+    });
+    if let Err(e) = result {
+        warn!("Core: 无法记录 {} 的续传进度: {:?}", saved_as, e);
+        return;
+    }
+    if load_progress(path).values().all(|e| e.done.is_some()) {
+        info!("Core: 目录传输已全部收完，删除续传进度 {:?}", path);
+        let _ = fs::remove_file(path);
+    }
+}
+
+// 连接中断时记下 saved_as（保存在 file）已经收到前 received 字节，续传时核对这部分没有被改过
+pub(super) fn mark_partial(path: &Path, saved_as: &str, file: &Path, received: u64) {
+    let result = prefix_sha256(file, received).and_then(|digest| {
+        append(path, &ProgressLine::Partial { saved_as: saved_as.to_string(), received, sha256: to_hex(&digest) })
+    });
+    match result {
+        Ok(()) => info!("Core: {} 收到 {} 字节时中断，已记录续传进度", saved_as, received),
+        Err(e) => warn!("Core: 无法记录 {} 的续传进度: {:?}", saved_as, e),
+    }
+}
+
+// 上次的记录与这次的文件是否对得上：existing 是磁盘上已有的字节数，返回可以从哪个偏移续传。
+// 来源标识缺失或不同、磁盘上的内容与记录的不符时返回 0，整个文件从头重收
+fn verified_offset(previous: &PreviousEntry, source: Option<&SourceIdentity>, file: &Path, existing: Option<u64>) -> u64 {
+    let source = match (previous.source.as_ref(), source) {
+        (Some(before), Some(now)) if before == now => now,
+        _ => return 0,
+    };
+    if let Some(done) = &previous.done {
+        let intact = existing == Some(previous.size)
+            && *done == source.sha256
+            && file_sha256(file).is_ok_and(|digest| to_hex(&digest) == *done);
+        return if intact { previous.size } else { 0 };
+    }
+    match &previous.partial {
+        Some((received, sha256))
+            if *received < previous.size
+                && existing == Some(*received)
+                && prefix_sha256(file, *received).is_ok_and(|digest| to_hex(&digest) == *sha256) =>
+        {
+            *received
+        }
+        _ => 0,
+    }
+}
+
+// 续传上次已接受的清单文件 name，source 是这次清单里附带的来源标识：返回对方还要从哪个偏移发起。
+// 上次已经收完且核对无误的文件返回 size，不再登记，对方直接跳过；收了一部分且核对无误的保留已有数据并从断点续写；
+// 其余情况（源文件改过、已收到的内容不符、文件不见了）清空后从头接收
+pub(super) fn resume_accepted_file(
+    server: &FileServer,
+    dir: &Path,
+    name: &str,
+    previous: &PreviousEntry,
+    source: Option<&SourceIdentity>,
+    sender_ip: &str,
+) -> Option<u64> {
+    // 进度文件在保存目录里，里面的名字同样不可信，先确认落在保存目录之内再创建目录
+    let saved_as = sanitize_relative_path(&previous.saved_as)?;
+    let path = dir.join(&saved_as);
+    let parent = path.parent()?;
+    if !will_be_within(dir, parent) {
+        warn!("Core: {:?} 解析后不在保存目录之内，拒绝续传", path);
+        return None;
+    }
+    if let Err(e) = fs::create_dir_all(parent) {
+        error!("Core: 无法创建保存目录 {:?}: {:?}", parent, e);
+        return None;
+    }
+    // 不跟随符号链接，已有的必须是普通文件
+    let existing = match fs::symlink_metadata(&path) {
+        Ok(meta) if meta.is_file() => Some(meta.len()),
+        Ok(_) => return None,
+        Err(_) => None,
+    };
+    let offset = verified_offset(previous, source, &path, existing);
+    // 空文件照常登记，对方仍会发一个空帧，收到后才算完成
+    if offset == previous.size && previous.size > 0 {
+        return Some(offset);
+    }
+    if offset == 0 && existing.is_some_and(|len| len > 0) {
+        info!("Core: {} 与上次的记录对不上，从头重收", saved_as);
+    }
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(&path).ok()?;
+    file.set_len(offset).ok()?;
+    drop(file);
+
+    let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    lock(&server.accepted).insert(saved_as, AcceptedFile {
+        id,
+        path,
+        total: previous.size,
+        received: offset,
+        connections: 0,
+        idle_since: None,
+        finished: false,
+        sink: None,
+        started: Instant::now(),
+        peer: sender_ip.to_string(),
+        expected_sha256: server.config.expected_checksums.as_ref().and_then(|c| c.get(name)),
+        patch: false,
+        filtered: false,
+        progress: None,
+    });
+    Some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::{file_server, temp_dir};
+    use crate::core::ServerConfig;
+
+    const DIGEST: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn identity_is_split_off_the_manifest_line() {
+        let line = format!("photos/a|b.bin|10|1700000000123|{}", DIGEST.to_uppercase());
+        let (entry, identity) = split_identity(&line).unwrap();
+        assert_eq!(entry, "photos/a|b.bin|10");
+        assert_eq!(identity, SourceIdentity { mtime: 1_700_000_000_123, sha256: DIGEST.into() });
+
+        // 空目录行和不带标识的旧格式都不当作标识
+        assert!(split_identity("photos/empty|dir").is_none());
+        assert!(split_identity("photos/a.bin|10").is_none());
+        assert!(split_identity(&format!("a.bin|x|{}", DIGEST)).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tampered_progress_cannot_create_directories_outside_the_save_dir() {
+        let root = temp_dir("resume-escape");
+        let (inbox, outside) = (root.join("inbox"), root.join("outside"));
+        fs::create_dir_all(&inbox).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, inbox.join("link")).unwrap();
+        let (server, _) = file_server(&inbox, ServerConfig::default());
+        let previous = PreviousEntry { saved_as: "link/made/x.bin".into(), size: 4, source: None, done: None, partial: None };
+
+        assert_eq!(resume_accepted_file(&server, &inbox, "photos/x.bin", &previous, None, "peer"), None);
+        assert!(!outside.join("made").exists());
+    }
+}
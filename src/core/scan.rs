@@ -0,0 +1,191 @@
+//! 子网扫描：广播被路由器或防火墙拦截时，逐个尝试连接网段内每个地址的传输端口，
+//! 找出正在运行的设备。/24 网段在超时较多时要扫很久，返回的句柄可以随时取消。
+
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use super::NetworkInterface;
+
+// 网段大于这个主机数时只扫描本机所在的 /24
+const MAX_SCAN_HOSTS: u32 = 1024;
+
+pub trait ScanCallback: Send + Sync {
+    /// 该地址的传输端口可以连上
+    fn on_host_found(&self, ip: String, port: u16);
+
+    /// 扫描结束（全部地址已尝试或被取消），默认忽略
+    fn on_scan_finished(&self, _cancelled: bool) {}
+}
+
+/// 子网扫描参数
+#[derive(Clone, Debug)]
+pub struct ScanConfig {
+    /// 同时进行的连接尝试数
+    pub concurrency: usize,
+    /// 单个地址的连接超时，也决定了取消后最多还要等多久所有线程才退出
+    pub connect_timeout: Duration,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 32,
+            connect_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// 扫描句柄
+#[derive(Clone)]
+pub struct ScanHandle {
+    cancelled: Arc<AtomicBool>,
+    workers: Arc<AtomicUsize>,
+}
+
+impl ScanHandle {
+    /// 停止扫描，立即返回。不再发起新的连接，正在进行的连接会在超时内结束，
+    /// 结果不再回调
+    pub fn cancel(&self) {
+        if !self.cancelled.swap(true, Ordering::Relaxed) {
+            info!("Core: 子网扫描已取消");
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// 所有扫描线程都已退出
+    pub fn is_finished(&self) -> bool {
+        self.workers.load(Ordering::Acquire) == 0
+    }
+}
+
+pub fn scan_subnet(iface: &NetworkInterface, port: u16, callback: Box<dyn ScanCallback>) -> ScanHandle {
+    scan_subnet_with_config(iface, port, callback, ScanConfig::default())
+}
+
+pub fn scan_subnet_with_config(
+    iface: &NetworkInterface,
+    port: u16,
+    callback: Box<dyn ScanCallback>,
+    config: ScanConfig,
+) -> ScanHandle {
+    let hosts = Arc::new(scan_targets(iface));
+    let concurrency = config.concurrency.clamp(1, hosts.len().max(1));
+    info!("Core: 开始扫描 {} 的 {} 个地址，并发 {}", iface.subnet(), hosts.len(), concurrency);
+
+    let handle = ScanHandle {
+        cancelled: Arc::new(AtomicBool::new(false)),
+        workers: Arc::new(AtomicUsize::new(concurrency)),
+    };
+    let callback: Arc<dyn ScanCallback> = Arc::from(callback);
+    let next = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..concurrency {
+        let hosts = hosts.clone();
+        let next = next.clone();
+        let callback = callback.clone();
+        let handle = handle.clone();
+        let timeout = config.connect_timeout;
+        thread::spawn(move || {
+            while !handle.is_cancelled() {
+                let ip = match hosts.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    Some(ip) => *ip,
+                    None => break,
+                };
+                let addr = SocketAddr::from((ip, port));
+                if TcpStream::connect_timeout(&addr, timeout).is_ok() && !handle.is_cancelled() {
+                    debug!("Core: 扫描发现 {}", addr);
+                    callback.on_host_found(ip.to_string(), port);
+                }
+            }
+            // 最后一个退出的线程负责通知扫描结束
+            if handle.workers.fetch_sub(1, Ordering::AcqRel) == 1 {
+                callback.on_scan_finished(handle.is_cancelled());
+            }
+        });
+    }
+    handle
+}
+
+// 网段内除网络地址、广播地址和本机之外的所有地址
+fn scan_targets(iface: &NetworkInterface) -> Vec<Ipv4Addr> {
+    let ip = u32::from(iface.ip);
+    let netmask = u32::from(iface.netmask);
+    let mask = if !netmask < MAX_SCAN_HOSTS { netmask } else { 0xFFFF_FF00 };
+    let network = ip & mask;
+    let broadcast = network | !mask;
+    (network + 1..broadcast)
+        .filter(|&host| host != ip)
+        .map(Ipv4Addr::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    // 记下找到的地址数和结束通知，每个地址处理得慢一些，整段扫完要很久
+    #[derive(Clone, Default)]
+    struct Tally {
+        found: Arc<AtomicUsize>,
+        finished: Arc<Mutex<Option<bool>>>,
+    }
+
+    impl ScanCallback for Tally {
+        fn on_host_found(&self, _ip: String, _port: u16) {
+            self.found.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        fn on_scan_finished(&self, cancelled: bool) {
+            *self.finished.lock().unwrap() = Some(cancelled);
+        }
+    }
+
+    fn iface(ip: [u8; 4], prefix: u32) -> NetworkInterface {
+        let netmask = Ipv4Addr::from(u32::MAX << (32 - prefix));
+        let ip = Ipv4Addr::from(ip);
+        NetworkInterface { name: "test0".into(), ip, netmask, broadcast: Ipv4Addr::from(u32::from(ip) | !u32::from(netmask)) }
+    }
+
+    #[test]
+    fn targets_skip_network_broadcast_and_self() {
+        let hosts = scan_targets(&iface([192, 168, 1, 20], 24));
+        assert_eq!(hosts.len(), 253);
+        assert_eq!((hosts[0], hosts[252]), (Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 254)));
+        assert!(!hosts.contains(&Ipv4Addr::new(192, 168, 1, 20)));
+        // 太大的网段只扫本机所在的 /24
+        assert_eq!(scan_targets(&iface([10, 1, 2, 3], 8)).len(), 253);
+    }
+
+    #[test]
+    fn cancel_stops_the_scan_without_waiting_for_every_timeout() {
+        // 不论这些地址是不可达、超时还是能连上，逐个扫完 1022 个地址都远超几秒
+        let tally = Tally::default();
+        let config = ScanConfig { concurrency: 4, connect_timeout: Duration::from_millis(300) };
+        let handle = scan_subnet_with_config(&iface([10, 254, 0, 1], 22), 4061, Box::new(tally.clone()), config);
+
+        let started = Instant::now();
+        handle.cancel();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(handle.is_cancelled());
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        // 最后一个线程先减计数再回调，两者都等到
+        while (!handle.is_finished() || tally.finished.lock().unwrap().is_none()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handle.is_finished());
+        assert_eq!(*tally.finished.lock().unwrap(), Some(true));
+        assert!(tally.found.load(Ordering::Relaxed) <= 4);
+    }
+}
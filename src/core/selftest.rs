@@ -0,0 +1,330 @@
+//! 自检：在本进程内用回环地址把发现、握手、传输、校验完整走一遍，帮用户区分是网络问题还是程序问题。
+//! 监听、文件服务和发送走的都是正式流程（start_listening、try_start_file_server、send_file 的同一套代码），
+//! 只是绑定在 127.0.0.1 上，不经过真实网卡，传输统计里会计入这次试传。
+//! 跑完关闭用到的端口并删除临时文件，可以反复执行。
+
+use std::fs;
+use std::net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use super::checksum::file_sha256;
+use super::registry::DEVICES;
+use super::{
+    listen_on, lock, recv_discovery, send_file_with_options, serve_on, Datagram, DeviceInfo, DiscoveryCallback,
+    DiscoveryConfig, DiscoveryMessage, ReceiveDecision, SendOptions, ServerConfig, TransferCallback,
+};
+
+// 每一步最多等这么久
+const STAGE_TIMEOUT: Duration = Duration::from_secs(5);
+// 自检两端使用的设备 ID
+const SERVER_ID: &str = "selftest-server";
+const CLIENT_ID: &str = "selftest-client";
+
+/// 自检的各个步骤，按执行顺序排列
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestStage {
+    /// 在回环地址上绑定发现端口和传输端口
+    Bind,
+    /// DISCOVER 发出后收到 HERE
+    Discover,
+    /// 接收方收到发送请求
+    Handshake,
+    /// 发送方和接收方都回调完成
+    Transfer,
+    /// 收到的文件与发出的 SHA-256 一致
+    Checksum,
+}
+
+impl std::fmt::Display for SelfTestStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelfTestStage::Bind => write!(f, "绑定端口"),
+            SelfTestStage::Discover => write!(f, "设备发现"),
+            SelfTestStage::Handshake => write!(f, "握手"),
+            SelfTestStage::Transfer => write!(f, "传输"),
+            SelfTestStage::Checksum => write!(f, "校验"),
+        }
+    }
+}
+
+/// 一个步骤的结果，detail 为通过时的说明或失败的原因
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StageResult {
+    pub stage: SelfTestStage,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// 自检结果。某一步失败后后面的步骤不再执行，也不出现在 stages 里
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub stages: Vec<StageResult>,
+}
+
+impl SelfTestReport {
+    /// 所有步骤都执行并通过
+    pub fn passed(&self) -> bool {
+        self.stages.len() == 5 && self.stages.iter().all(|s| s.passed)
+    }
+
+    /// 第一个失败的步骤
+    pub fn failed_stage(&self) -> Option<SelfTestStage> {
+        self.stages.iter().find(|s| !s.passed).map(|s| s.stage)
+    }
+
+    fn pass(&mut self, stage: SelfTestStage, detail: String) {
+        self.stages.push(StageResult { stage, passed: true, detail });
+    }
+
+    fn fail(&mut self, stage: SelfTestStage, detail: String) {
+        warn!("Core: 自检在“{}”一步失败: {}", stage, detail);
+        self.stages.push(StageResult { stage, passed: false, detail });
+    }
+}
+
+impl std::fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for s in &self.stages {
+            writeln!(f, "{} {}: {}", if s.passed { "✓" } else { "✗" }, s.stage, s.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// 自检参数
+#[derive(Clone, Debug)]
+pub struct SelfTestConfig {
+    /// 发现（UDP）和传输（TCP）共用的端口，与正式运行时一样；0 表示由系统分配
+    pub port: u16,
+    /// 试传文件的大小
+    pub payload_size: usize,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self { port: 0, payload_size: 256 * 1024 }
+    }
+}
+
+/// 按默认参数自检，端口由系统分配，不会与正在运行的发现和文件服务冲突
+pub fn self_test() -> SelfTestReport {
+    self_test_with_config(SelfTestConfig::default())
+}
+
+/// 按 config 自检，会阻塞到全部步骤完成或某一步失败（每步最多约 5 秒）
+pub fn self_test_with_config(config: SelfTestConfig) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    let work_dir = std::env::temp_dir().join(format!("locsd-selftest-{}-{}", std::process::id(), unique_suffix()));
+    run(&config, &work_dir, &mut report);
+    let _ = fs::remove_dir_all(&work_dir);
+    DEVICES.forget(CLIENT_ID);
+    if report.passed() {
+        info!("Core: 自检通过");
+    }
+    report
+}
+
+fn run(config: &SelfTestConfig, work_dir: &Path, report: &mut SelfTestReport) {
+    // 1. 绑定：先让系统分配 TCP 端口，UDP 绑同一个端口号
+    let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, config.port)) {
+        Ok(l) => l,
+        Err(e) => return report.fail(SelfTestStage::Bind, format!("TCP 端口 {} 绑定失败: {}", config.port, e)),
+    };
+    let port = match listener.local_addr() {
+        Ok(a) => a.port(),
+        Err(e) => return report.fail(SelfTestStage::Bind, format!("无法读取绑定的端口: {}", e)),
+    };
+    let udp = match UdpSocket::bind((Ipv4Addr::LOCALHOST, port)) {
+        Ok(s) => s,
+        Err(e) => return report.fail(SelfTestStage::Bind, format!("UDP 端口 {} 绑定失败: {}", port, e)),
+    };
+    report.pass(SelfTestStage::Bind, format!("UDP/TCP 端口 {}", port));
+
+    // 2. 发现：正式的监听线程收到 DISCOVER 后回 HERE，通告端口
+    let mut services = Services { port, stop: Arc::new(AtomicBool::new(false)), threads: Vec::new() };
+    let discovery = DiscoveryConfig { bind_addr: Ipv4Addr::LOCALHOST, ..DiscoveryConfig::default() };
+    let max_packet_size = discovery.max_packet_size;
+    let (_, listening) = listen_on(
+        udp, port, SERVER_ID.to_string(), "自检".to_string(), Box::new(IgnoreDevices), discovery, services.stop.clone(),
+    );
+    services.threads.push(listening);
+    match discover(port, max_packet_size) {
+        Ok(announced) if announced == port => report.pass(SelfTestStage::Discover, format!("收到 HERE，端口 {}", port)),
+        Ok(announced) => return report.fail(SelfTestStage::Discover, format!("HERE 通告的端口 {} 与监听端口 {} 不符", announced, port)),
+        Err(e) => return report.fail(SelfTestStage::Discover, e),
+    }
+
+    // 3. 握手、传输、校验：正式的文件服务接收，send_file 的流程发送
+    let source_path = work_dir.join("selftest.bin");
+    let save_dir = work_dir.join("received");
+    if let Err(e) = fs::create_dir_all(&save_dir).and_then(|_| fs::write(&source_path, payload(config.payload_size))) {
+        return report.fail(SelfTestStage::Handshake, format!("无法准备试传文件: {}", e));
+    }
+    let (events, steps) = mpsc::channel();
+    let receiver = Box::new(SelfTestCallback { events: Mutex::new(events.clone()), sender: false });
+    let (_, serving) = serve_on(
+        listener, save_dir.to_string_lossy().into_owned(), receiver, ServerConfig::default(), services.stop.clone(),
+    );
+    services.threads.push(serving);
+    let sender = Box::new(SelfTestCallback { events: Mutex::new(events), sender: true });
+    send_file_with_options(
+        Ipv4Addr::LOCALHOST.to_string(), port, source_path.to_string_lossy().into_owned(), SendOptions::default(), sender,
+    );
+    transfer(&source_path, &save_dir, &steps, report);
+}
+
+// 握手、传输、校验三步，按两端回调的先后判断进行到哪一步
+fn transfer(source_path: &Path, save_dir: &Path, steps: &mpsc::Receiver<Step>, report: &mut SelfTestReport) {
+    match steps.recv_timeout(STAGE_TIMEOUT) {
+        Ok(Step::Requested(name)) => report.pass(SelfTestStage::Handshake, format!("对方收到 {} 的请求", name)),
+        Ok(Step::Sent(_, msg)) => return report.fail(SelfTestStage::Handshake, format!("发送方在握手前失败: {}", msg)),
+        Ok(Step::Received(_, msg)) => return report.fail(SelfTestStage::Handshake, format!("接收方在握手前结束: {}", msg)),
+        Err(_) => return report.fail(SelfTestStage::Handshake, "等待对方收到请求超时".to_string()),
+    }
+
+    // 两端都回调完成才算传完，收到的文件名取自接收方的完成消息
+    let (mut sent, mut received) = (None, None);
+    while sent.is_none() || received.is_none() {
+        match steps.recv_timeout(STAGE_TIMEOUT) {
+            Ok(Step::Sent(ok, msg)) => sent = Some((ok, msg)),
+            Ok(Step::Received(ok, msg)) => received = Some((ok, msg)),
+            Ok(Step::Requested(_)) => {}
+            Err(_) => return report.fail(SelfTestStage::Transfer, "等待传输完成超时".to_string()),
+        }
+        if let Some((false, msg)) = &sent {
+            return report.fail(SelfTestStage::Transfer, format!("发送失败: {}", msg));
+        }
+        if let Some((false, msg)) = &received {
+            return report.fail(SelfTestStage::Transfer, format!("接收失败: {}", msg));
+        }
+    }
+    let received_name = received.map(|(_, name)| name).unwrap_or_default();
+    let len = fs::metadata(source_path).map(|m| m.len()).unwrap_or(0);
+    report.pass(SelfTestStage::Transfer, format!("{} 字节已收完", len));
+
+    let received = save_dir.join(&received_name);
+    match (file_sha256(source_path), file_sha256(&received)) {
+        (Ok(sent), Ok(got)) if sent == got => report.pass(SelfTestStage::Checksum, "SHA-256 一致".to_string()),
+        (Ok(_), Ok(_)) => report.fail(SelfTestStage::Checksum, "收到的文件与发出的 SHA-256 不一致".to_string()),
+        (Err(e), _) | (_, Err(e)) => report.fail(SelfTestStage::Checksum, format!("无法计算校验和: {}", e)),
+    }
+}
+
+// 向回环上的发现端口发 DISCOVER，返回 HERE 里通告的端口。超过 max_size 的应答按失败报告
+fn discover(port: u16, max_size: usize) -> Result<u16, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).map_err(|e| format!("无法创建发现套接字: {}", e))?;
+    socket.set_read_timeout(Some(STAGE_TIMEOUT)).ok();
+    let discover = DiscoveryMessage::Discover { device_id: CLIENT_ID.to_string(), name: "自检".to_string(), port: 0 };
+    socket.send_to(discover.encode().as_bytes(), (Ipv4Addr::LOCALHOST, port))
+        .map_err(|e| format!("DISCOVER 发送失败: {}", e))?;
+    let mut buf = vec![0u8; max_size + 1];
+    loop {
+        let n = match recv_discovery(&socket, &mut buf, max_size) {
+            Ok(Datagram::Packet(n, _)) => n,
+            Ok(Datagram::Oversized(_)) => return Err(format!("HERE 应答超过 {} 字节", max_size)),
+            Err(_) => return Err("没有收到 HERE 应答".to_string()),
+        };
+        match DiscoveryMessage::parse(&buf[..n]) {
+            Ok(DiscoveryMessage::Here { device_id, port, .. }) if device_id == SERVER_ID => return Ok(port),
+            Ok(_) => continue,
+            Err(reason) => return Err(format!("HERE 应答无法解析: {}", reason)),
+        }
+    }
+}
+
+// 自检启动的监听和文件服务，结束时置位 stop，再各发一个包、连一次把阻塞的线程叫醒，等线程退出、端口关闭
+struct Services {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Services {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Ok(socket) = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)) {
+            let _ = socket.send_to(&[], (Ipv4Addr::LOCALHOST, self.port));
+        }
+        let _ = TcpStream::connect_timeout(&(Ipv4Addr::LOCALHOST, self.port).into(), STAGE_TIMEOUT);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// 试传的内容，不是全零，写错位置时校验能看出来
+fn payload(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+fn unique_suffix() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+// 两端回调按发生顺序交给自检线程
+enum Step {
+    // 接收方收到请求，附文件名
+    Requested(String),
+    // 接收方完成，成功时附保存的文件名
+    Received(bool, String),
+    // 发送方完成
+    Sent(bool, String),
+}
+
+// 自检两端共用的回调：接收端全部接受，发送端只关心完成
+struct SelfTestCallback {
+    events: Mutex<mpsc::Sender<Step>>,
+    sender: bool,
+}
+
+impl TransferCallback for SelfTestCallback {
+    fn on_receive_request(&self, file_name: String, _file_size: u64, _sender_ip: String) -> ReceiveDecision {
+        let _ = lock(&self.events).send(Step::Requested(file_name));
+        ReceiveDecision::accept()
+    }
+
+    fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+    fn on_complete(&self, success: bool, msg: String) {
+        let step = if self.sender { Step::Sent(success, msg) } else { Step::Received(success, msg) };
+        let _ = lock(&self.events).send(step);
+    }
+}
+
+// 自检的监听只负责回 HERE，发现的设备不需要处理
+struct IgnoreDevices;
+
+impl DiscoveryCallback for IgnoreDevices {
+    fn on_device_found(&self, _device: DeviceInfo) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::shared_sends;
+
+    #[test]
+    fn passes_repeatedly_and_leaves_no_device_behind() {
+        let _sends = shared_sends();
+        for _ in 0..2 {
+            let report = self_test();
+            assert!(report.passed(), "{}", report);
+        }
+        assert!(super::super::known_devices().iter().all(|d| d.device_id != CLIENT_ID));
+    }
+
+    #[test]
+    fn occupied_port_fails_at_bind() {
+        let held = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = held.local_addr().unwrap().port();
+        let report = self_test_with_config(SelfTestConfig { port, ..SelfTestConfig::default() });
+        assert_eq!(report.failed_stage(), Some(SelfTestStage::Bind));
+        assert_eq!(report.stages.len(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::process::ChildStdin;
+use std::sync::{Arc, Mutex};
+
+use super::lock;
+
+/// 接收数据的去处，代替保存目录里的文件，例如把数据直接写进转码进程的标准输入或命名管道。
+/// 不能随机写入的接收端会让发送方改用单条连接顺序发送
+pub trait ReceiveSink: Write + Send {
+    /// 能否定位到任意偏移写入
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    /// 定位到 offset，只在 is_seekable 返回 true 时调用
+    fn seek_to(&mut self, _offset: u64) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "接收端不支持定位"))
+    }
+}
+
+impl ReceiveSink for File {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+}
+
+impl ReceiveSink for ChildStdin {}
+
+// TransferFilter 包装出的 writer，输出长度和输入不一定相同，只能顺序写入
+impl ReceiveSink for Box<dyn Write + Send> {}
+
+/// 接收时插在网络数据和保存文件之间的变换，例如解压、重新编码。
+/// 配置在 ServerConfig::filter 上后对每个存入保存目录的文件生效，变换后的长度与对方声明的不同，
+/// 所以这类文件只按单条连接顺序接收，收完也不再核对长度和预期校验和
+pub trait TransferFilter: Send + Sync {
+    /// 包装写往保存文件的 file，name 为最终文件名（目录传输时带相对路径），
+    /// size 为对方声明的大小（未知时为 0）。默认原样返回，不做变换
+    fn wrap_writer(&self, _name: &str, _size: u64, file: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        file
+    }
+}
+
+impl std::fmt::Debug for dyn TransferFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TransferFilter")
+    }
+}
+
+/// 收进内存的接收端，写入超过 limit 字节时失败，避免对方发来的大文件耗尽内存。
+/// clone 出的副本共享同一块缓冲，交给 ReceiveDecision 之后仍可用副本取出数据
+#[derive(Clone)]
+pub struct MemorySink {
+    buf: Arc<Mutex<Vec<u8>>>,
+    pos: usize,
+    limit: usize,
+}
+
+impl MemorySink {
+    pub fn new(limit: usize) -> Self {
+        Self { buf: Arc::new(Mutex::new(Vec::new())), pos: 0, limit }
+    }
+
+    /// 取出已收到的数据，缓冲随之清空
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *lock(&self.buf))
+    }
+}
+
+impl Write for MemorySink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let end = self.pos + data.len();
+        if end > self.limit {
+            return Err(io::Error::other(format!("超过内存接收上限 {} 字节", self.limit)));
+        }
+        let mut buf = lock(&self.buf);
+        if buf.len() < end {
+            buf.resize(end, 0);
+        }
+        buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ReceiveSink for MemorySink {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.pos = usize::try_from(offset).unwrap_or(usize::MAX);
+        Ok(())
+    }
+}
+
+/// 多条数据连接共享的接收端，同时记录当前写到的位置
+#[derive(Clone)]
+pub struct SharedSink(Arc<Mutex<(Box<dyn ReceiveSink>, u64)>>);
+
+impl SharedSink {
+    pub(crate) fn new(sink: Box<dyn ReceiveSink>) -> Self {
+        Self(Arc::new(Mutex::new((sink, 0))))
+    }
+
+    pub(crate) fn is_seekable(&self) -> bool {
+        lock(&self.0).0.is_seekable()
+    }
+
+    // 从 offset 开始写入的句柄，每条数据连接一个
+    pub(crate) fn writer(&self, offset: u64) -> SinkWriter {
+        SinkWriter { sink: self.clone(), pos: offset }
+    }
+}
+
+impl std::fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedSink")
+    }
+}
+
+impl PartialEq for SharedSink {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedSink {}
+
+// 一条连接的写入位置。不能定位的接收端只接受正好接在已写数据之后的写入
+pub(crate) struct SinkWriter {
+    sink: SharedSink,
+    pos: u64,
+}
+
+impl Write for SinkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = lock(&self.sink.0);
+        let (sink, cursor) = &mut *inner;
+        if *cursor != self.pos {
+            if !sink.is_seekable() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "接收端只能顺序写入"));
+            }
+            sink.seek_to(self.pos)?;
+        }
+        let n = sink.write(buf)?;
+        self.pos += n as u64;
+        *cursor = self.pos;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(&self.sink.0).0.flush()
+    }
+}
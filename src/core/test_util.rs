@@ -0,0 +1,137 @@
+//! 单元测试共用的临时目录、记录回调和文件服务，文件服务也可以挂到回环地址上走真实的连接
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{handle_incoming_connection, lock, FileServer, ReceiveDecision, ServerConfig, TransferCallback, TransferError};
+
+// 等待回调时的上限，超过按测试失败处理
+const WAIT: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+// 经过全局发送表的测试共享这把锁，要取消全部发送的测试独占它
+static SENDS: RwLock<()> = RwLock::new(());
+
+/// 通过公开的发送接口发送的测试持有它，期间不会被别的测试的“取消全部”打断
+pub(crate) fn shared_sends() -> RwLockReadGuard<'static, ()> {
+    SENDS.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// 持有期间没有别的测试在发送，全局发送表里只有自己登记的发送
+pub(crate) fn exclusive_sends() -> RwLockWriteGuard<'static, ()> {
+    SENDS.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// 每次调用都返回一个新建的空目录
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("locsd-test-{}-{}-{}", std::process::id(), name, n));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// 回调收到的事件
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    Complete(bool, String),
+    Partial(String, u64, u64),
+    Error(TransferError),
+}
+
+/// 同意所有请求并记下完成、部分完成和错误回调
+#[derive(Clone, Default)]
+pub(crate) struct Recorder {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Recorder {
+    pub(crate) fn events(&self) -> Vec<Event> {
+        lock(&self.events).clone()
+    }
+
+    /// 等到出现满足 pred 的事件并返回它，超时返回 None
+    pub(crate) fn wait_for(&self, pred: impl Fn(&Event) -> bool) -> Option<Event> {
+        let deadline = Instant::now() + WAIT;
+        while Instant::now() < deadline {
+            if let Some(e) = lock(&self.events).iter().find(|e| pred(e)) {
+                return Some(e.clone());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    /// 等到至少记下 n 个事件，超时返回 false
+    pub(crate) fn wait_len(&self, n: usize) -> bool {
+        let deadline = Instant::now() + WAIT;
+        while lock(&self.events).len() < n {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+impl TransferCallback for Recorder {
+    fn on_receive_request(&self, _file_name: String, _file_size: u64, _sender_ip: String) -> ReceiveDecision {
+        ReceiveDecision::accept()
+    }
+
+    fn on_progress(&self, _transferred: u64, _total: u64) {}
+
+    fn on_complete(&self, success: bool, msg: String) {
+        lock(&self.events).push(Event::Complete(success, msg));
+    }
+
+    fn on_partial_complete(&self, file_name: String, received: u64, total: u64) {
+        lock(&self.events).push(Event::Partial(file_name, received, total));
+    }
+
+    fn on_error(&self, error: TransferError) {
+        lock(&self.events).push(Event::Error(error));
+    }
+}
+
+/// 保存到 dir 的文件服务，不启动监听
+pub(crate) fn file_server(dir: &Path, config: ServerConfig) -> (Arc<FileServer>, Recorder) {
+    let recorder = Recorder::default();
+    (file_server_with(dir, config, Box::new(recorder.clone())), recorder)
+}
+
+/// 与 file_server 相同，回调由测试自己提供
+pub(crate) fn file_server_with(dir: &Path, config: ServerConfig, callback: Box<dyn TransferCallback>) -> Arc<FileServer> {
+    Arc::new(FileServer {
+        save_dir: dir.to_string_lossy().into_owned(),
+        config,
+        callback,
+        accepted: Mutex::new(HashMap::new()),
+        reaper: None,
+    })
+}
+
+/// 在 127.0.0.1 的空闲端口上为 server 接受连接，返回端口和已接受的连接数。
+/// 不登记为本进程的文件服务，发往这里的数据都走连接。监听线程随测试进程结束
+pub(crate) fn serve_on_loopback(server: Arc<FileServer>) -> (u16, Arc<AtomicU64>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicU64::new(0));
+    let accepted = connections.clone();
+    thread::spawn(move || {
+        for socket in listener.incoming().flatten() {
+            accepted.fetch_add(1, Ordering::Relaxed);
+            let server = server.clone();
+            thread::spawn(move || handle_incoming_connection(socket, server));
+        }
+    });
+    (port, connections)
+}
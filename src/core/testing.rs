@@ -0,0 +1,287 @@
+//! 测试用的传输层：内存中的双向管道，以及在任意连接外面注入延迟、限速、丢包重传和
+//! 连接重置的包装。配合 serve_connection / send_file_over，不依赖真实网络也能稳定地
+//! 复现重试、续传、超时等路径。只在启用 testing 特性或本库自己的测试中编译。
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// 创建一对相连的内存管道，一端写入的数据从另一端读出；一端释放后另一端读到 EOF
+pub fn duplex() -> (PipeEnd, PipeEnd) {
+    let (a_tx, b_rx) = mpsc::channel();
+    let (b_tx, a_rx) = mpsc::channel();
+    (PipeEnd::new(a_tx, a_rx), PipeEnd::new(b_tx, b_rx))
+}
+
+/// duplex 创建的管道的一端
+pub struct PipeEnd {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    read_timeout: Option<Duration>,
+}
+
+impl PipeEnd {
+    fn new(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> Self {
+        Self { tx, rx, pending: VecDeque::new(), read_timeout: None }
+    }
+
+    /// 与 TcpStream::set_read_timeout 相同，超时后 read 返回 TimedOut
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let chunk = match self.read_timeout {
+                Some(timeout) => match self.rx.recv_timeout(timeout) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                },
+                None => match self.rx.recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return Ok(0),
+                },
+            };
+            self.pending.extend(chunk);
+        }
+        let n = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 注入的故障。延迟、限速和丢包只作用在写方向，两端都包上即可模拟双向的链路
+#[derive(Clone, Debug)]
+pub struct FaultConfig {
+    /// 每个包额外的延迟
+    pub latency: Duration,
+    /// 带宽上限（字节/秒），None 表示不限
+    pub bandwidth: Option<u64>,
+    /// 每个包丢失的概率（0.0 - 1.0）。流式连接不会真的丢数据，丢包表现为等待重传
+    pub drop_rate: f64,
+    /// 丢包后重传前等待的时间
+    pub retransmit_delay: Duration,
+    /// 每次读写时连接被重置的概率（0.0 - 1.0），重置后之后的读写都返回 ConnectionReset
+    pub reset_rate: f64,
+    /// 累计写出这么多字节后强制重置连接，None 表示不强制
+    pub reset_after: Option<u64>,
+    /// 写入超过这个大小时按包拆开，延迟和丢包都按包计算
+    pub packet_size: usize,
+    /// 随机数种子，相同的种子和读写顺序得到相同的故障序列
+    pub seed: u64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            bandwidth: None,
+            drop_rate: 0.0,
+            retransmit_delay: Duration::from_millis(200),
+            reset_rate: 0.0,
+            reset_after: None,
+            packet_size: 1400,
+            seed: 1,
+        }
+    }
+}
+
+/// 按 FaultConfig 注入故障的连接包装
+pub struct FaultyStream<S> {
+    inner: S,
+    config: FaultConfig,
+    rng: u64,
+    reset: bool,
+    written: u64,
+}
+
+impl<S> FaultyStream<S> {
+    pub fn new(inner: S, config: FaultConfig) -> Self {
+        // xorshift 的状态不能为 0
+        let rng = config.seed.max(1);
+        Self { inner, config, rng, reset: false, written: 0 }
+    }
+
+    /// 连接是否已经被注入的重置断开
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    // xorshift64*，返回 [0, 1) 的伪随机数
+    fn roll(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn check_reset(&mut self) -> io::Result<()> {
+        if !self.reset && self.config.reset_rate > 0.0 && self.roll() < self.config.reset_rate {
+            self.reset = true;
+        }
+        if self.reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "注入的连接重置"));
+        }
+        Ok(())
+    }
+
+    // 发出 len 字节的一个包之前等待的时间
+    fn packet_delay(&mut self, len: usize) -> Duration {
+        let mut delay = self.config.latency;
+        if self.config.drop_rate > 0.0 && self.roll() < self.config.drop_rate {
+            delay += self.config.retransmit_delay;
+        }
+        if let Some(bandwidth) = self.config.bandwidth.filter(|b| *b > 0) {
+            delay += Duration::from_secs_f64(len as f64 / bandwidth as f64);
+        }
+        delay
+    }
+}
+
+impl<S: Read> Read for FaultyStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_reset()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for FaultyStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.config.reset_after.is_some_and(|limit| self.written >= limit) {
+            self.reset = true;
+        }
+        self.check_reset()?;
+        let mut len = buf.len().min(self.config.packet_size.max(1));
+        if let Some(limit) = self.config.reset_after {
+            // 正好在限额处断开，之前的字节都送达
+            len = len.min((limit - self.written) as usize);
+        }
+        let delay = self.packet_delay(len);
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        let n = self.inner.write(&buf[..len])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{shared_sends, temp_dir, Event, Recorder};
+    use super::super::{send_file_over, serve_connection, ConflictPolicy, ServerConfig};
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    // 逐字节写到重置为止，返回重置前成功写出的次数
+    fn writes_until_reset(config: FaultConfig) -> usize {
+        let mut stream = FaultyStream::new(Vec::new(), config);
+        let mut n = 0;
+        while stream.write(&[0]).is_ok() {
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_fault_sequence() {
+        let rolls = |seed| {
+            let mut stream = FaultyStream::new(Vec::<u8>::new(), FaultConfig { seed, ..FaultConfig::default() });
+            (0..32).map(|_| stream.roll()).collect::<Vec<_>>()
+        };
+        assert_eq!(rolls(7), rolls(7));
+        assert_ne!(rolls(7), rolls(8));
+
+        let config = |seed| FaultConfig {
+            drop_rate: 0.5,
+            retransmit_delay: Duration::ZERO,
+            reset_rate: 0.01,
+            seed,
+            ..FaultConfig::default()
+        };
+        assert_eq!(writes_until_reset(config(7)), writes_until_reset(config(7)));
+        assert_ne!(writes_until_reset(config(7)), writes_until_reset(config(8)));
+    }
+
+    #[test]
+    fn forced_reset_cuts_the_stream_at_the_limit() {
+        let mut stream = FaultyStream::new(Vec::new(), FaultConfig { reset_after: Some(3000), ..FaultConfig::default() });
+        let err = stream.write_all(&[1u8; 5000]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(stream.is_reset());
+        assert_eq!(stream.inner.len(), 3000);
+    }
+
+    // 在一对内存管道上发一次文件，发送端包上故障注入，等两端都结束后返回两端的回调记录
+    fn send_once(path: &Path, save_dir: &Path, faults: FaultConfig) -> (Recorder, Recorder) {
+        let _sends = shared_sends();
+        let (near, far) = duplex();
+        let received = Recorder::default();
+        let config = ServerConfig { conflict_policy: ConflictPolicy::Overwrite, ..ServerConfig::default() };
+        let serving = thread::spawn({
+            let received = received.clone();
+            let save_dir = save_dir.to_string_lossy().into_owned();
+            move || serve_connection(far, save_dir, Box::new(received), config)
+        });
+        let sent = Recorder::default();
+        send_file_over(FaultyStream::new(near, faults), path.to_string_lossy().into_owned(), Box::new(sent.clone()));
+        serving.join().unwrap();
+        sent.wait_for(|e| matches!(e, Event::Complete(..))).expect("发送端没有结束");
+        (sent, received)
+    }
+
+    #[test]
+    fn reset_transfer_fails_and_a_retry_over_the_lossy_link_delivers() {
+        let source = temp_dir("faulty-source");
+        let save_dir = temp_dir("faulty-save");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let path = source.join("big.bin");
+        fs::write(&path, &data).unwrap();
+        let lossy = FaultConfig {
+            latency: Duration::from_millis(1),
+            drop_rate: 0.2,
+            retransmit_delay: Duration::from_millis(5),
+            packet_size: 8 * 1024,
+            seed: 42,
+            ..FaultConfig::default()
+        };
+
+        // 第一次写出 50000 字节后连接被重置：发送端报错，接收端没有收完
+        let (sent, received) = send_once(&path, &save_dir, FaultConfig { reset_after: Some(50_000), ..lossy.clone() });
+        let events = sent.events();
+        assert!(events.iter().any(|e| matches!(e, Event::Error(_))), "{:?}", events);
+        assert!(events.iter().any(|e| matches!(e, Event::Complete(false, _))), "{:?}", events);
+        assert!(!received.events().iter().any(|e| matches!(e, Event::Complete(true, _))));
+        assert!(fs::metadata(save_dir.join("big.bin")).map_or(0, |m| m.len()) < data.len() as u64);
+
+        // 同样的延迟和丢包下重试一次，文件完整送达
+        let (sent, received) = send_once(&path, &save_dir, lossy);
+        assert!(matches!(sent.wait_for(|e| matches!(e, Event::Complete(..))), Some(Event::Complete(true, _))));
+        assert!(received.wait_for(|e| matches!(e, Event::Complete(true, _))).is_some(), "{:?}", received.events());
+        assert_eq!(fs::read(save_dir.join("big.bin")).unwrap(), data);
+    }
+}
@@ -0,0 +1,124 @@
+//! 本进程发起的发送都登记在全局表里，“取消全部”和退出前的清理一次触发所有发送的取消标记。
+//! 发送线程在每次读写数据前检查标记，取消后中止并回调 on_error(Cancelled)。
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use super::lock;
+
+/// 一次发送的取消标记，克隆出的副本共享同一个状态。
+/// 放进 SendOptions::cancel_token 后可以单独取消这一次发送
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// 进行中的发送，按登记顺序编号
+pub(crate) struct TransferTable {
+    active: Mutex<BTreeMap<u64, CancelToken>>,
+    next_id: AtomicU64,
+}
+
+pub(crate) static TRANSFERS: TransferTable = TransferTable::new();
+
+impl TransferTable {
+    const fn new() -> Self {
+        Self {
+            active: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    // 登记一次发送，token 为 None 时新建一个。返回的句柄释放时自动注销
+    pub(crate) fn begin(&'static self, token: Option<CancelToken>) -> ActiveTransfer {
+        let token = token.unwrap_or_default();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        lock(&self.active).insert(id, token.clone());
+        ActiveTransfer { table: self, id, token }
+    }
+
+    fn cancel_all(&self) -> usize {
+        let active = lock(&self.active);
+        for token in active.values() {
+            token.cancel();
+        }
+        if !active.is_empty() {
+            info!("Core: 已取消全部 {} 个发送", active.len());
+        }
+        active.len()
+    }
+
+    fn count(&self) -> usize {
+        lock(&self.active).len()
+    }
+}
+
+// 登记中的一次发送，发送线程结束时随之释放
+pub(crate) struct ActiveTransfer {
+    table: &'static TransferTable,
+    id: u64,
+    token: CancelToken,
+}
+
+impl ActiveTransfer {
+    pub(crate) fn token(&self) -> CancelToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        lock(&self.table.active).remove(&self.id);
+    }
+}
+
+/// 取消所有进行中的发送，返回取消了几个。没有进行中的发送时什么也不做
+pub fn cancel_all_transfers() -> usize {
+    TRANSFERS.cancel_all()
+}
+
+/// 进行中的发送个数
+pub fn active_transfer_count() -> usize {
+    TRANSFERS.count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 全局表会被其他测试里的发送共用，这里用一张独立的表
+    static TABLE: TransferTable = TransferTable::new();
+
+    #[test]
+    fn cancel_all_reaches_every_active_transfer() {
+        assert_eq!(TABLE.cancel_all(), 0);
+
+        let own = CancelToken::new();
+        let transfers = [TABLE.begin(None), TABLE.begin(Some(own.clone())), TABLE.begin(None)];
+        assert_eq!(TABLE.count(), 3);
+        assert!(transfers.iter().all(|t| !t.token().is_cancelled()));
+
+        assert_eq!(TABLE.cancel_all(), 3);
+        assert!(transfers.iter().all(|t| t.token().is_cancelled()));
+        assert!(own.is_cancelled());
+
+        drop(transfers);
+        assert_eq!(TABLE.count(), 0);
+        assert_eq!(TABLE.cancel_all(), 0);
+    }
+}
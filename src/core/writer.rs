@@ -0,0 +1,141 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+// 队列里每个数据块的上限，和接收缓冲区大小一致
+const CHUNK: usize = 64 * 1024;
+
+enum Msg {
+    Data(Vec<u8>),
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+/// 把写入交给后台线程完成的 Writer。
+/// 队列中最多积压约 `cap` 字节，满了以后 write 会阻塞，
+/// 调用方因此停止从 socket 读数据，TCP 窗口把压力传回发送端，磁盘慢时内存不会无限增长。
+pub(crate) struct BoundedWriter {
+    tx: Option<SyncSender<Msg>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl BoundedWriter {
+    pub(crate) fn new<W: Write + Send + 'static>(inner: W, cap: usize) -> Self {
+        let slots = (cap / CHUNK).max(1);
+        let (tx, rx) = mpsc::sync_channel(slots);
+        let worker = thread::spawn(move || drain(inner, rx));
+        Self { tx: Some(tx), worker: Some(worker) }
+    }
+
+    fn send(&self, msg: Msg) -> io::Result<()> {
+        self.tx
+            .as_ref()
+            .and_then(|tx| tx.send(msg).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "写入线程已退出"))
+    }
+}
+
+fn drain<W: Write>(mut inner: W, rx: Receiver<Msg>) {
+    // 第一次写失败后丢弃后续数据，错误在下一次 flush 时报告
+    let mut failed: Option<io::Error> = None;
+    for msg in rx {
+        match msg {
+            Msg::Data(buf) => {
+                if failed.is_none()
+                    && let Err(e) = inner.write_all(&buf)
+                {
+                    failed = Some(e);
+                }
+            }
+            Msg::Flush(reply) => {
+                let result = match failed.take() {
+                    Some(e) => Err(e),
+                    None => inner.flush(),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+impl Write for BoundedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK);
+        self.send(Msg::Data(buf[..n].to_vec()))?;
+        Ok(n)
+    }
+
+    /// 等待队列中已有的数据全部写完
+    fn flush(&mut self) -> io::Result<()> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.send(Msg::Flush(reply_tx))?;
+        reply_rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "写入线程已退出")))
+    }
+}
+
+impl Drop for BoundedWriter {
+    fn drop(&mut self) {
+        // 关闭队列后等后台线程把剩余数据写完
+        drop(self.tx.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // 每写一块都要等一会儿的慢盘
+    struct SlowDisk(Arc<AtomicUsize>);
+
+    impl Write for SlowDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(2));
+            self.0.fetch_add(buf.len(), Ordering::SeqCst);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_disk_keeps_queue_bounded() {
+        let cap = 4 * CHUNK;
+        let written = Arc::new(AtomicUsize::new(0));
+        let mut writer = BoundedWriter::new(SlowDisk(written.clone()), cap);
+        let block = vec![7u8; CHUNK];
+        let mut queued = 0;
+        for _ in 0..64 {
+            writer.write_all(&block).unwrap();
+            queued += block.len();
+            // 队列里的、后台线程手上正在写的、以及刚交出去的这一块
+            assert!(queued - written.load(Ordering::SeqCst) <= cap + 2 * CHUNK);
+        }
+        writer.flush().unwrap();
+        assert_eq!(written.load(Ordering::SeqCst), 64 * CHUNK);
+    }
+
+    #[test]
+    fn flush_reports_write_errors() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut writer = BoundedWriter::new(Broken, CHUNK);
+        writer.write_all(b"data").unwrap();
+        assert!(writer.flush().is_err());
+    }
+}
@@ -1,3 +1,34 @@
 pub mod core;
 
-pub mod platforms;
\ No newline at end of file
+pub mod platforms;
+
+pub use prelude::*;
+
+/// 作为 Rust 库使用时常用的类型和入口函数，`use localsend_core::prelude::*;` 即可一次引入
+///
+/// ```
+/// use localsend_core::prelude::*;
+///
+/// let device = DeviceInfo {
+///     device_id: "dev-1".to_string(),
+///     name: "测试设备".to_string(),
+///     ip: "192.168.1.20".to_string(),
+///     control_port: 53317,
+///     free_space: None,
+/// };
+/// assert_eq!(device.name, "测试设备");
+/// ```
+pub mod prelude {
+    pub use crate::core::{
+        BatchRequest, CancelToken, ConflictPolicy, DeviceInfo, DiscoverRetry, DiscoveryCallback,
+        DiscoveryConfig, DiscoveryHandle, ReceiveDecision, SelfTestReport, SendHandle, SendOptions,
+        ServerConfig, StartError, TransferCallback, TransferError, TransferProfile,
+    };
+    pub use crate::core::{
+        active_transfer_count, cancel_all_transfers, discover_devices, self_test, send_directory,
+        send_discover_once, send_file, send_file_tracked, send_file_regions, send_file_with_options,
+        send_files, start_discovery_broadcaster, start_file_server, start_file_server_with_config,
+        start_listening, start_listening_with_config, try_start_discovery_broadcaster_with,
+        try_start_file_server_with_config, try_start_listening_with_config,
+    };
+}
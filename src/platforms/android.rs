@@ -1,9 +1,34 @@
 use jni::objects::{JClass, JString, JValue, GlobalRef};
+use jni::sys::{jboolean, jstring, JNI_FALSE, JNI_TRUE};
 use jni::{JavaVM, JNIEnv};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use log::{info, error, debug, LevelFilter};
 use android_logger::Config;
-use crate::core::{self, DeviceInfo, DiscoveryCallback, TransferCallback};
+use crate::core::{self, DeviceInfo, DiscoveryCallback, DiscoveryHandle, ReceiveDecision, TransferCallback};
+
+// startDiscovery 启动后保存的句柄，供改名等接口使用
+static DISCOVERY: Mutex<Option<DiscoveryHandle>> = Mutex::new(None);
+
+// 创建 Java 字符串，失败（JNI 无法编码、内存不足等）时记录日志并返回 None。
+// 回调跑在网络线程上，这里 unwrap 的话 panic 会直接让整个应用崩溃
+fn safe_new_string<'local>(env: &JNIEnv<'local>, s: &str, what: &str) -> Option<JString<'local>> {
+    // 失败时可能留下待处理的 Java 异常，不清掉的话后续 JNI 调用都会失败
+    checked_string(env.new_string(s), what, || {
+        let _ = env.exception_clear();
+    })
+}
+
+// new_string 的结果：失败时记录日志、调用 clear 收拾现场并返回 None
+fn checked_string<T>(result: jni::errors::Result<T>, what: &str, clear: impl FnOnce()) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            error!("Android: 无法创建 Java 字符串 ({}): {:?}", what, e);
+            clear();
+            None
+        }
+    }
+}
 
 struct AndroidDiscoveryBridge {
     jvm: Arc<JavaVM>,
@@ -21,7 +46,7 @@ impl DiscoveryCallback for AndroidDiscoveryBridge {
                 device_info.control_port,
             );
 
-            if let Ok(j_msg) = env.new_string(msg) {
+            if let Some(j_msg) = safe_new_string(&env, &msg, "设备信息") {
                 let result = env.call_static_method(
                     &self.class_ref,
                     "onDeviceFound",
@@ -56,10 +81,15 @@ impl TransferCallback for AndroidTransferBridge {
     // 除非我们在 Rust 这边做更复杂的异步等待。
     //
     // 现在的逻辑是：调用 Java 静态方法，获取返回值 (boolean)。
-    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> bool {
+    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> ReceiveDecision {
         if let Ok(mut env) = self.jvm.attach_current_thread() {
-            let j_filename = env.new_string(file_name).unwrap();
-            let j_sender_ip = env.new_string(sender_ip).unwrap();
+            // 文件名来自对方，无法转成 Java 字符串时没法询问用户，按拒绝处理
+            let (Some(j_filename), Some(j_sender_ip)) = (
+                safe_new_string(&env, &file_name, "文件名"),
+                safe_new_string(&env, &sender_ip, "发送方 IP"),
+            ) else {
+                return ReceiveDecision::reject();
+            };
             // Java long 对应 Rust i64 (JNI 中 jlong 是 i64)
             let j_size = file_size as i64;
 
@@ -75,14 +105,14 @@ impl TransferCallback for AndroidTransferBridge {
             );
 
             match result {
-                Ok(val) => val.z().unwrap_or(false), // .z() 获取 boolean 值
+                Ok(val) => val.z().unwrap_or(false).into(), // .z() 获取 boolean 值
                 Err(e) => {
                     error!("Android Transfer Request 回调失败: {:?}", e);
-                    false // 出错默认拒绝
+                    ReceiveDecision::reject() // 出错默认拒绝
                 }
             }
         } else {
-            false
+            ReceiveDecision::reject()
         }
     }
 
@@ -99,7 +129,10 @@ impl TransferCallback for AndroidTransferBridge {
 
     fn on_complete(&self, success: bool, msg: String) {
         if let Ok(mut env) = self.jvm.attach_current_thread() {
-            let j_msg = env.new_string(msg).unwrap_or_else(|_| env.new_string("").unwrap());
+            // 消息转换失败时退回空串，再失败就传 null，完成通知本身不能丢
+            let j_msg = safe_new_string(&env, &msg, "完成消息")
+                .or_else(|| safe_new_string(&env, "", "完成消息"))
+                .unwrap_or_default();
             let _ = env.call_static_method(
                 &self.class_ref,
                 "onTransferComplete",
@@ -139,12 +172,81 @@ pub extern "C" fn Java_com_yukon_localsend_RustSDK_startDiscovery(
         .expect("Couldn't get java string!")
         .into();
 
-    core::start_listening(
+    let handle = core::start_listening(
         4060,
         device_name.clone(),
         device_name,
         Box::new(bridge)
     );
+    if let Ok(mut slot) = DISCOVERY.lock() {
+        *slot = Some(handle);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_com_yukon_localsend_RustSDK_setAlias(
+    mut env: JNIEnv,
+    _class: JClass,
+    user_alias: JString,
+) -> jboolean {
+    let name: String = match env.get_string(&user_alias) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Android: setAlias 读取字符串失败: {:?}", e);
+            return JNI_FALSE;
+        }
+    };
+
+    match DISCOVERY.lock().ok().and_then(|slot| slot.clone()) {
+        Some(handle) => {
+            handle.set_alias(&name);
+            JNI_TRUE
+        }
+        None => {
+            error!("Android: setAlias 调用时发现服务尚未启动");
+            JNI_FALSE
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_com_yukon_localsend_RustSDK_getAlias(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let alias = DISCOVERY.lock().ok()
+        .and_then(|slot| slot.clone())
+        .map(|handle| handle.alias())
+        .unwrap_or_default();
+
+    match safe_new_string(&env, &alias, "设备名") {
+        Some(s) => s.into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+// 应用回到前台时调用：恢复周期广播并立即宣告本机
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_com_yukon_localsend_RustSDK_onForeground(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    match DISCOVERY.lock().ok().and_then(|slot| slot.clone()) {
+        Some(handle) => handle.enter_foreground(),
+        None => error!("Android: onForeground 调用时发现服务尚未启动"),
+    }
+}
+
+// 应用进入后台时调用：暂停周期广播，继续监听和接收
+#[unsafe(no_mangle)]
+pub extern "C" fn Java_com_yukon_localsend_RustSDK_onBackground(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    match DISCOVERY.lock().ok().and_then(|slot| slot.clone()) {
+        Some(handle) => handle.enter_background(),
+        None => error!("Android: onBackground 调用时发现服务尚未启动"),
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -223,3 +325,19 @@ pub extern "C" fn Java_com_yukon_localsend_RustSDK_sendFile(
         Box::new(bridge)
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_jni_call_is_logged_cleared_and_skipped() {
+        let mut cleared = false;
+        let value = checked_string::<i32>(Err(jni::errors::Error::NullPtr("new_string")), "文件名", || cleared = true);
+        assert_eq!((value, cleared), (None, true));
+
+        let mut cleared = false;
+        assert_eq!(checked_string(Ok(7), "文件名", || cleared = true), Some(7));
+        assert!(!cleared);
+    }
+}
@@ -1,7 +1,16 @@
-use crate::core::{self, DeviceInfo, DiscoveryCallback, TransferCallback};
+use crate::core::{self, DeviceInfo, DiscoveryCallback, DiscoveryHandle, ReceiveDecision, SendHandle, TransferCallback};
 use log::{info, error, debug};
+use std::collections::HashMap;
 use std::ffi::{CStr, CString, c_char};
-use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// rust_start_discovery 启动后保存的句柄，供改名等接口使用
+static DISCOVERY: Mutex<Option<DiscoveryHandle>> = Mutex::new(None);
+
+// rust_send_file_polled 启动的发送，按 transfer_id 查询进度：(进度句柄, 文件大小)
+static POLLED_TRANSFERS: Mutex<Option<HashMap<u64, (SendHandle, u64)>>> = Mutex::new(None);
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
 
 pub type OnDeviceFoundCallback = extern "C" fn(*const c_char);
 
@@ -50,11 +59,11 @@ unsafe impl Send for WindowsTransferBridge {}
 unsafe impl Sync for WindowsTransferBridge {}
 
 impl TransferCallback for WindowsTransferBridge {
-    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> bool {
+    fn on_receive_request(&self, file_name: String, file_size: u64, sender_ip: String) -> ReceiveDecision {
         let fname = CString::new(file_name).unwrap();
         let ip = CString::new(sender_ip).unwrap();
 
-        (self.on_request)(fname.as_ptr(), file_size, ip.as_ptr())
+        (self.on_request)(fname.as_ptr(), file_size, ip.as_ptr()).into()
     }
 
     fn on_progress(&self, transferred: u64, total: u64) {
@@ -67,8 +76,13 @@ impl TransferCallback for WindowsTransferBridge {
     }
 }
 
+/// 启动发现服务
+///
+/// # Safety
+///
+/// user_alias 为空，或指向以 0 结尾、在调用期间有效的字符串
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_start_discovery(
+pub unsafe extern "C" fn rust_start_discovery(
     port: u16,
     user_alias: *const c_char,
     callback: OnDeviceFoundCallback
@@ -91,16 +105,71 @@ pub extern "C" fn rust_start_discovery(
         callback_ptr: callback,
     };
 
-    core::start_listening(
+    let handle = core::start_listening(
         port,
         "windows_pc".into(),
         device_name,
         Box::new(bridge)
     );
+    if let Ok(mut slot) = DISCOVERY.lock() {
+        *slot = Some(handle);
+    }
+}
+
+/// 运行时修改设备名，发现服务未启动时返回 false
+///
+/// # Safety
+///
+/// user_alias 为空，或指向以 0 结尾、在调用期间有效的字符串
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_set_alias(user_alias: *const c_char) -> bool {
+    if user_alias.is_null() {
+        return false;
+    }
+    let name = unsafe { CStr::from_ptr(user_alias).to_string_lossy().into_owned() };
+
+    match DISCOVERY.lock().ok().and_then(|slot| slot.clone()) {
+        Some(handle) => {
+            handle.set_alias(&name);
+            true
+        }
+        None => {
+            error!("Windows: setAlias 调用时发现服务尚未启动");
+            false
+        }
+    }
+}
+
+/// 把当前设备名（UTF-8，带结尾 0）写入 buf，返回需要的缓冲区长度；
+/// 返回值大于 buf_len 时表示缓冲区不够，内容未写入
+///
+/// # Safety
+///
+/// buf 为空，或指向至少 buf_len 字节、可写的缓冲区
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_get_alias(buf: *mut c_char, buf_len: usize) -> usize {
+    let alias = match DISCOVERY.lock().ok().and_then(|slot| slot.clone()) {
+        Some(handle) => handle.alias(),
+        None => return 0,
+    };
+    let c_alias = CString::new(alias).unwrap_or_default();
+    let bytes = c_alias.as_bytes_with_nul();
+
+    if !buf.is_null() && bytes.len() <= buf_len {
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, buf, bytes.len());
+        }
+    }
+    bytes.len()
 }
 
+/// 立即广播一次 DISCOVER
+///
+/// # Safety
+///
+/// user_alias 为空，或指向以 0 结尾、在调用期间有效的字符串
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_discover_once(port: u16, user_alias: *const c_char,) {
+pub unsafe extern "C" fn rust_discover_once(port: u16, user_alias: *const c_char,) {
     debug!("Windows: FFI discoverOnce 被调用");
     let device_name = if user_alias.is_null() {
         "Unknown Windows PC".to_string()
@@ -114,8 +183,13 @@ pub extern "C" fn rust_discover_once(port: u16, user_alias: *const c_char,) {
     core::send_discover_once(port, "windows_pc".into(), device_name);
 }
 
+/// 启动文件接收服务
+///
+/// # Safety
+///
+/// save_dir 为空，或指向以 0 结尾、在调用期间有效的字符串
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_start_file_server(
+pub unsafe extern "C" fn rust_start_file_server(
     port: u16,
     save_dir: *const c_char,
     on_request: OnReceiveRequestCallback,
@@ -145,8 +219,13 @@ pub extern "C" fn rust_start_file_server(
     );
 }
 
+/// 发送单个文件
+///
+/// # Safety
+///
+/// target_ip 和 file_path 都必须指向以 0 结尾、在调用期间有效的字符串
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_send_file(
+pub unsafe extern "C" fn rust_send_file(
     target_ip: *const c_char,
     port: u16,
     file_path: *const c_char,
@@ -173,4 +252,167 @@ pub extern "C" fn rust_send_file(
         parallel_cnt,
         Box::new(bridge),
     );
-}
\ No newline at end of file
+}
+
+// 轮询模式下只需要完成通知，进度由 C 端自己查询
+struct WindowsCompletionBridge {
+    on_complete: Option<OnTransferCompleteCallback>,
+}
+
+unsafe impl Send for WindowsCompletionBridge {}
+unsafe impl Sync for WindowsCompletionBridge {}
+
+impl TransferCallback for WindowsCompletionBridge {
+    fn on_receive_request(&self, _: String, _: u64, _: String) -> ReceiveDecision {
+        ReceiveDecision::reject()
+    }
+
+    fn on_progress(&self, _: u64, _: u64) {}
+
+    fn on_complete(&self, success: bool, msg: String) {
+        if let Some(on_complete) = self.on_complete {
+            let c_msg = CString::new(msg).unwrap_or_default();
+            on_complete(success, c_msg.as_ptr());
+        }
+    }
+}
+
+/// 与 rust_send_file 相同，但不推送进度，返回 transfer_id 供 rust_get_transfer_progress 轮询。
+/// on_complete 可以为空；参数无效时返回 0。不再查询后调用 rust_release_transfer 释放
+///
+/// # Safety
+///
+/// target_ip 和 file_path 为空，或指向以 0 结尾、在调用期间有效的字符串
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_send_file_polled(
+    target_ip: *const c_char,
+    port: u16,
+    file_path: *const c_char,
+    parallel_cnt: u64,
+    on_complete: Option<OnTransferCompleteCallback>,
+) -> u64 {
+    if target_ip.is_null() || file_path.is_null() {
+        return 0;
+    }
+    let ip = unsafe { CStr::from_ptr(target_ip).to_string_lossy().into_owned() };
+    let path = unsafe { CStr::from_ptr(file_path).to_string_lossy().into_owned() };
+
+    info!("Windows: sendFilePolled {} -> {}", path, ip);
+
+    // 文件读不到时照常交给 core，失败由 on_complete 报告，这里总大小记为 0
+    let total = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    let handle = core::send_file_tracked(ip, port, path, parallel_cnt, Box::new(WindowsCompletionBridge { on_complete }));
+    if let Ok(mut transfers) = POLLED_TRANSFERS.lock() {
+        transfers.get_or_insert_with(HashMap::new).insert(id, (handle, total));
+    }
+    id
+}
+
+/// 读取 rust_send_file_polled 启动的发送进度，只读共享计数，不跨越 FFI 回调。
+/// transfer_id 未知或指针为空时返回 false
+///
+/// # Safety
+///
+/// transferred 和 total 为空，或各自指向一个可写的 u64
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_get_transfer_progress(transfer_id: u64, transferred: *mut u64, total: *mut u64) -> bool {
+    if transferred.is_null() || total.is_null() {
+        return false;
+    }
+    let progress = POLLED_TRANSFERS.lock().ok().and_then(|transfers| {
+        let (handle, size) = transfers.as_ref()?.get(&transfer_id)?;
+        Some((handle.total_sent(), *size))
+    });
+    match progress {
+        Some((sent, size)) => {
+            unsafe {
+                *transferred = sent;
+                *total = size;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// 释放 transfer_id 对应的进度记录，之后再查询返回 false
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_release_transfer(transfer_id: u64) {
+    if let Ok(mut transfers) = POLLED_TRANSFERS.lock()
+        && let Some(transfers) = transfers.as_mut()
+    {
+        transfers.remove(&transfer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    static COMPLETED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_complete(success: bool, _msg: *const c_char) {
+        assert!(success);
+        COMPLETED.store(true, Ordering::SeqCst);
+    }
+
+    // 接收端桩：REQ 一律同意，数据读得很慢，发送进行中有足够的时间查询进度
+    fn slow_receiver() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for socket in listener.incoming().flatten() {
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(socket);
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.starts_with("REQ|") {
+                        let _ = reader.get_mut().write_all(b"ACC\n");
+                        return;
+                    }
+                    let mut buf = [0u8; 64 * 1024];
+                    while let Ok(1..) = reader.read(&mut buf) {
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn progress_can_be_polled_mid_transfer() {
+        let path = std::env::temp_dir().join(format!("locsd-polled-{}.bin", std::process::id()));
+        let len = 16u64 << 20;
+        std::fs::write(&path, vec![9u8; len as usize]).unwrap();
+        let ip = CString::new("127.0.0.1").unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let id = unsafe { rust_send_file_polled(ip.as_ptr(), slow_receiver(), c_path.as_ptr(), 1, Some(on_complete)) };
+        assert_ne!(id, 0);
+
+        let (mut transferred, mut total) = (0u64, 0u64);
+        let mut seen_midway = false;
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !COMPLETED.load(Ordering::SeqCst) && Instant::now() < deadline {
+            assert!(unsafe { rust_get_transfer_progress(id, &mut transferred, &mut total) });
+            assert_eq!(total, len);
+            seen_midway |= transferred > 0 && transferred < len;
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(COMPLETED.load(Ordering::SeqCst));
+        assert!(seen_midway);
+        assert!(unsafe { rust_get_transfer_progress(id, &mut transferred, &mut total) });
+        assert_eq!(transferred, len);
+
+        rust_release_transfer(id);
+        assert!(!unsafe { rust_get_transfer_progress(id, &mut transferred, &mut total) });
+        let _ = std::fs::remove_file(&path);
+    }
+}